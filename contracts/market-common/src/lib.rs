@@ -0,0 +1,1050 @@
+//! Market cell data layout
+//!
+//! The one binary encoding of market cell data, shared by the market type
+//! script and the off-chain code that builds market transactions, so what the
+//! server writes is exactly what the contract parses.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+
+/// Collateral unit of markets that don't record their own (100 CKB)
+pub const DEFAULT_SHANNONS_PER_TOKEN: u64 = 10_000_000_000;
+
+/// Largest `decimals` a market may declare (10^18 base units per token)
+pub const MAX_DECIMALS: u8 = 18;
+
+/// Basis points in a whole payout
+pub const MAX_FEE_BPS: u16 = 10_000;
+
+/// Longest question or outcome label, in UTF-8 bytes (a one-byte length prefix)
+pub const MAX_METADATA_FIELD: usize = 255;
+
+/// Bytes of the amount at the start of every token cell's data
+/// The amount is a u128 LE, the same prefix sUDT and xUDT cells carry.
+pub const TOKEN_AMOUNT_LEN: usize = 16;
+
+/// Amount held by a YES/NO token cell
+///
+/// Reads the data the way sUDT does: the first 16 bytes are the amount and
+/// anything after them (a time lock's unlock time, a limit order's price) is
+/// ignored, so sUDT indexers see the same balances as the market scripts.
+pub fn token_amount(data: &[u8]) -> Option<u128> {
+    read_u128(data, 0).ok()
+}
+
+/// Data of a plain token cell holding `amount`, identical to an sUDT cell's
+pub fn token_data(amount: u128) -> [u8; TOKEN_AMOUNT_LEN] {
+    amount.to_le_bytes()
+}
+
+/// Bytes of a serialized CellInput: since (u64 LE), then the outpoint's tx
+/// hash and index (u32 LE)
+pub const CELL_INPUT_LEN: usize = 44;
+
+/// What a market's Type ID hashes: the creation's whole first CellInput,
+/// then the market output's index as a u64 LE
+///
+/// blake2b (ckb-default-hash) of this is the Type ID the market script checks
+/// on creation, so clients derive a market's id from the same bytes before
+/// the creation is on chain. The `since` is part of it, not just the outpoint.
+///
+/// Byte for byte, as the molecule CellInput struct serializes (no header,
+/// integers little-endian) and as ckb-sdk's `calculate_type_id` hashes:
+/// - bytes 0-7: since (u64 LE)
+/// - bytes 8-39: previous output tx hash (as stored, not reversed)
+/// - bytes 40-43: previous output index (u32 LE)
+/// - bytes 44-51: market output index (u64 LE)
+pub fn type_id_preimage(first_input: &[u8; CELL_INPUT_LEN], output_index: u64) -> [u8; CELL_INPUT_LEN + 8] {
+    let mut preimage = [0u8; CELL_INPUT_LEN + 8];
+    preimage[..CELL_INPUT_LEN].copy_from_slice(first_input);
+    preimage[CELL_INPUT_LEN..].copy_from_slice(&output_index.to_le_bytes());
+    preimage
+}
+
+/// Why cell data is not valid market data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataError {
+    /// Shorter than the 35-byte core, or than the metadata it declares
+    LengthNotEnough,
+    Encoding,
+    /// A flag or byte outside its allowed values
+    InvalidMarketData,
+}
+
+impl core::fmt::Display for DataError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DataError::LengthNotEnough => f.write_str("market data is shorter than 35 bytes"),
+            DataError::Encoding => f.write_str("market data field could not be decoded"),
+            DataError::InvalidMarketData => f.write_str("market data holds an invalid value"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DataError {}
+
+/// Market data structure
+/// Format:
+/// - bytes 0-31: token_code_hash (32 bytes) - hash of the token contract binary
+/// - byte 32: hash_type (1 byte) - ScriptHashType for tokens
+///   (0 = Data, 1 = Type, 2 = Data1, 4 = Data2)
+/// - byte 33: resolved (0 or 1, any other value is invalid)
+/// - byte 34: outcome (0 or 1, true = YES wins; 0x02 = cancelled, resolved markets only;
+///   any other value is invalid)
+/// - byte 35: pending_outcome (0 = none, 0x01 = YES proposed, 0x02 = NO proposed,
+///   0x03 = cancellation proposed)
+/// - bytes 36-43: proposed_at (u64 LE, unix seconds recorded by the proposer)
+/// - bytes 44-59: yes_reserve (u128 LE, AMM markets only)
+/// - bytes 60-75: no_reserve (u128 LE, AMM markets only)
+/// - bytes 76-83: claim_deadline (u64 LE, unix seconds, sweepable markets only)
+/// - bytes 84-115: sweeper_lock_hash (32 bytes, sweepable markets only)
+/// - byte 116: swept (0 or 1, sweepable markets only)
+/// - byte 117: decimals (base units per token = 10^decimals, at most MAX_DECIMALS)
+/// - bytes 118-125: shannons_per_token (u64 LE, the collateral unit, non-zero)
+/// - bytes 126-141: yes_supply (u128 LE, supply-tracking markets only)
+/// - bytes 142-157: no_supply (u128 LE, supply-tracking markets only)
+/// - bytes 158-165: resolve_after (u64 LE, unix seconds, markets with a resolution window only)
+/// - bytes 166-173: dispute_until (u64 LE, block number, written when a resolver market is finalized)
+/// - bytes 174-175: fee_bps (u16 LE, share of each claim owed to the fee recipient, fee markets only)
+/// - bytes 176-207: fee_recipient_lock_hash (32 bytes, fee markets only)
+/// - bytes 208-215: scalar floor (u64 LE, scalar markets only)
+/// - bytes 216-223: scalar ceiling (u64 LE, above the floor)
+/// - bytes 224-231: scalar value (u64 LE, between floor and ceiling; the
+///   resolved value once a scalar market resolves)
+/// - bytes 232-: metadata (markets describing themselves only): question length
+///   (u8) and UTF-8 question, label count (u8), then each outcome label as
+///   length (u8) and UTF-8 text; nothing may follow the last label
+///
+/// Bytes 35-43 are optional for markets created before two-step resolution;
+/// a 35-byte cell parses as having no pending proposal. Each later section is
+/// optional too, and a market using one zero-fills the sections before it:
+/// zero reserves and all-zero sweep, fee or scalar terms parse as absent. Without byte 117
+/// tokens have no decimals; without bytes 118-125 the collateral unit is
+/// DEFAULT_SHANNONS_PER_TOKEN. Zero supplies are real supplies, not padding,
+/// so a market with a resolution window or dispute period always records
+/// supplies. A zero resolve_after or dispute_until means there is none.
+/// Metadata comes last, so the core fields keep their offsets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarketData {
+    pub token_code_hash: [u8; 32],
+    pub hash_type: u8,
+    pub resolved: bool,
+    pub outcome: bool,
+    pub cancelled: bool,
+    pub pending_outcome: Option<Proposal>,
+    pub proposed_at: u64,
+    /// (YES, NO) reserves the creator seeded an AMM market with
+    pub seed_reserves: Option<(u128, u128)>,
+    pub sweep: Option<Sweep>,
+    /// Token amounts are in units of 10^-decimals tokens
+    pub decimals: u8,
+    /// Shannons backing one collateral unit (a complete set of a uniform market)
+    pub shannons_per_token: u64,
+    /// Outstanding YES and NO tokens, for markets that record them
+    pub supplies: Option<Supplies>,
+    /// Unix seconds before which no outcome may be proposed (0 = any time)
+    pub resolve_after: u64,
+    /// Block number until which the outcome may be corrected and claims wait
+    /// (0 = no dispute period)
+    pub dispute_until: u64,
+    /// Protocol fee taken out of every claim, fixed at creation
+    pub fee: Option<ProtocolFee>,
+    /// Range of a scalar market, which pays out along it instead of all or
+    /// nothing; the range is fixed at creation
+    pub scalar: Option<Scalar>,
+    /// What the market is about, fixed at creation
+    pub metadata: Option<Metadata>,
+}
+
+/// Range and value of a scalar market
+///
+/// YES tokens are LONG and NO tokens SHORT. Once resolved, a LONG token pays
+/// the share of a complete set's collateral that `value` lies along the range,
+/// from nothing at `floor` to all of it at `ceiling`; a SHORT token pays the
+/// rest, so a LONG and a SHORT together always return one set.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Scalar {
+    pub floor: u64,
+    pub ceiling: u64,
+    pub value: u64,
+}
+
+impl Scalar {
+    /// Shannons one LONG token pays when a complete set holds `set_ratio`
+    /// Rounds down, leaving the odd shannon to SHORT. Split into quotient and
+    /// remainder of the span so no intermediate product can overflow.
+    pub fn long_ratio(&self, set_ratio: u128) -> u128 {
+        let span = (self.ceiling - self.floor) as u128;
+        let offset = (self.value - self.floor) as u128;
+        set_ratio / span * offset + set_ratio % span * offset / span
+    }
+
+    /// Shannons one SHORT token pays when a complete set holds `set_ratio`
+    pub fn short_ratio(&self, set_ratio: u128) -> u128 {
+        set_ratio - self.long_ratio(set_ratio)
+    }
+
+    /// Shannons per token of one side (`long` = YES tokens)
+    pub fn side_ratio(&self, long: bool, set_ratio: u128) -> u128 {
+        if long { self.long_ratio(set_ratio) } else { self.short_ratio(set_ratio) }
+    }
+}
+
+/// Share of each claim's payout owed to the market operator
+///
+/// A claim still takes its full payout out of the market cell, but
+/// `fee_on(payout)` of it must go to cells locked by `recipient_lock_hash`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ProtocolFee {
+    /// Basis points of the payout, at most MAX_FEE_BPS
+    pub fee_bps: u16,
+    pub recipient_lock_hash: [u8; 32],
+}
+
+impl ProtocolFee {
+    /// Fee owed on a payout of `payout` shannons, rounded down
+    pub fn fee_on(&self, payout: u64) -> u64 {
+        // fee_bps <= 10_000, so the fee never exceeds the payout
+        (payout as u128 * self.fee_bps as u128 / MAX_FEE_BPS as u128) as u64
+    }
+}
+
+/// Question and outcome labels a market records on-chain
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Metadata {
+    pub question: String,
+    /// Labels in outcome order (NO, YES for a binary market)
+    pub outcome_labels: Vec<String>,
+}
+
+impl Metadata {
+    /// Metadata that fits the encoding: every field at most
+    /// MAX_METADATA_FIELD bytes and at most 255 labels
+    pub fn new(question: &str, outcome_labels: &[&str]) -> Result<Self, DataError> {
+        if question.len() > MAX_METADATA_FIELD
+            || outcome_labels.len() > u8::MAX as usize
+            || outcome_labels.iter().any(|label| label.len() > MAX_METADATA_FIELD)
+        {
+            return Err(DataError::InvalidMarketData);
+        }
+        Ok(Metadata {
+            question: question.into(),
+            outcome_labels: outcome_labels.iter().map(|&label| label.into()).collect(),
+        })
+    }
+
+    /// Parse the metadata section, which must run to the end of the data
+    fn from_bytes(data: &[u8]) -> Result<Self, DataError> {
+        let mut rest = data;
+        let question = read_str(&mut rest)?;
+        let (&count, tail) = rest.split_first().ok_or(DataError::LengthNotEnough)?;
+        rest = tail;
+        let outcome_labels = (0..count).map(|_| read_str(&mut rest)).collect::<Result<Vec<_>, _>>()?;
+        if !rest.is_empty() {
+            return Err(DataError::InvalidMarketData);
+        }
+        Ok(Metadata { question, outcome_labels })
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        write_str(bytes, &self.question);
+        bytes.push(self.outcome_labels.len() as u8);
+        for label in &self.outcome_labels {
+            write_str(bytes, label);
+        }
+    }
+}
+
+/// Terms for sweeping collateral that winners never claimed
+///
+/// Once the market is resolved and `claim_deadline` has passed, a transaction
+/// spending an input locked by `sweeper_lock_hash` may take everything above
+/// the market cell's occupied capacity. The market is then marked swept and
+/// no further claims or refunds are accepted.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Sweep {
+    pub claim_deadline: u64,
+    pub sweeper_lock_hash: [u8; 32],
+    pub swept: bool,
+}
+
+/// Outstanding token supplies recorded in the market cell
+///
+/// When present the market script requires every transition to move them by
+/// exactly the tokens minted and burned, so readers can trust them without
+/// scanning token cells.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Supplies {
+    pub yes: u128,
+    pub no: u128,
+}
+
+/// Resolution waiting out the resolution delay before it can be finalized
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Proposal {
+    Yes,
+    No,
+    Cancel,
+}
+
+impl Proposal {
+    /// pending_outcome byte in market data
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Proposal::Yes => 0x01,
+            Proposal::No => 0x02,
+            Proposal::Cancel => 0x03,
+        }
+    }
+
+    /// Whether resolved market data records exactly this resolution
+    pub fn is_finalized_in(&self, data: &MarketData) -> bool {
+        match self {
+            Proposal::Yes => data.outcome && !data.cancelled,
+            Proposal::No => !data.outcome && !data.cancelled,
+            Proposal::Cancel => data.cancelled,
+        }
+    }
+}
+
+impl MarketData {
+    /// Open market for tokens of `token_code_hash`/`hash_type`, with every
+    /// optional section absent
+    pub fn new(token_code_hash: [u8; 32], hash_type: u8) -> Self {
+        MarketData {
+            token_code_hash,
+            hash_type,
+            resolved: false,
+            outcome: false,
+            cancelled: false,
+            pending_outcome: None,
+            proposed_at: 0,
+            seed_reserves: None,
+            sweep: None,
+            decimals: 0,
+            shannons_per_token: DEFAULT_SHANNONS_PER_TOKEN,
+            supplies: None,
+            resolve_after: 0,
+            dispute_until: 0,
+            fee: None,
+            scalar: None,
+            metadata: None,
+        }
+    }
+
+    /// Parse market data from cell data
+    pub fn from_bytes(data: &[u8]) -> Result<Self, DataError> {
+        if data.len() < 35 {
+            return Err(DataError::LengthNotEnough);
+        }
+
+        let mut token_code_hash = [0u8; 32];
+        token_code_hash.copy_from_slice(&data[0..32]);
+        let hash_type = data[32];
+        // Flags are exact bytes, so a tampered value can't be normalized away
+        let resolved = match data[33] {
+            0x00 => false,
+            0x01 => true,
+            _ => return Err(DataError::InvalidMarketData),
+        };
+        let (outcome, cancelled) = match data[34] {
+            0x00 => (false, false),
+            0x01 => (true, false),
+            0x02 => (false, true),
+            _ => return Err(DataError::InvalidMarketData),
+        };
+
+        // Only a resolution can cancel a market
+        if cancelled && !resolved {
+            return Err(DataError::InvalidMarketData);
+        }
+
+        let (pending_outcome, proposed_at) = if data.len() >= 44 {
+            let pending_outcome = match data[35] {
+                0x00 => None,
+                0x01 => Some(Proposal::Yes),
+                0x02 => Some(Proposal::No),
+                0x03 => Some(Proposal::Cancel),
+                _ => return Err(DataError::InvalidMarketData),
+            };
+            (pending_outcome, read_u64(data, 36)?)
+        } else {
+            (None, 0)
+        };
+
+        let decimals = data.get(117).copied().unwrap_or(0);
+        if decimals > MAX_DECIMALS {
+            return Err(DataError::InvalidMarketData);
+        }
+
+        let shannons_per_token = if data.len() >= 126 { read_u64(data, 118)? } else { DEFAULT_SHANNONS_PER_TOKEN };
+
+        let resolve_after = if data.len() >= 166 { read_u64(data, 158)? } else { 0 };
+        let dispute_until = if data.len() >= 174 { read_u64(data, 166)? } else { 0 };
+        let fee = if data.len() >= 208 {
+            let fee_bps = u16::from_le_bytes([data[174], data[175]]);
+            let mut recipient_lock_hash = [0u8; 32];
+            recipient_lock_hash.copy_from_slice(&data[176..208]);
+            let fee = ProtocolFee { fee_bps, recipient_lock_hash };
+            if fee_bps > MAX_FEE_BPS {
+                return Err(DataError::InvalidMarketData);
+            }
+            // All-zero fee terms in front of metadata are padding
+            if fee == ProtocolFee::default() {
+                None
+            } else {
+                Some(fee)
+            }
+        } else {
+            None
+        };
+        let scalar = if data.len() >= 232 {
+            let scalar = Scalar { floor: read_u64(data, 208)?, ceiling: read_u64(data, 216)?, value: read_u64(data, 224)? };
+            // All-zero scalar terms in front of metadata are padding
+            if scalar == Scalar::default() {
+                None
+            } else if scalar.floor >= scalar.ceiling || scalar.value < scalar.floor || scalar.value > scalar.ceiling {
+                return Err(DataError::InvalidMarketData);
+            } else {
+                Some(scalar)
+            }
+        } else {
+            None
+        };
+        let metadata = if data.len() > 232 { Some(Metadata::from_bytes(&data[232..])?) } else { None };
+
+        let supplies = if data.len() >= 158 {
+            Some(Supplies { yes: read_u128(data, 126)?, no: read_u128(data, 142)? })
+        } else {
+            None
+        };
+
+        let sweep = if data.len() >= 117 {
+            let mut sweeper_lock_hash = [0u8; 32];
+            sweeper_lock_hash.copy_from_slice(&data[84..116]);
+            let swept = match data[116] {
+                0x00 => false,
+                0x01 => true,
+                _ => return Err(DataError::InvalidMarketData),
+            };
+            let sweep = Sweep {
+                claim_deadline: read_u64(data, 76)?,
+                sweeper_lock_hash,
+                swept,
+            };
+            // All-zero sweep terms in front of a later section are padding
+            if data.len() > 117 && sweep == Sweep::default() {
+                None
+            } else {
+                Some(sweep)
+            }
+        } else {
+            None
+        };
+
+        let seed_reserves = if data.len() >= 76 {
+            let yes_reserve = read_u128(data, 44)?;
+            let no_reserve = read_u128(data, 60)?;
+            // Zero reserves in front of a later section are padding, not a seed
+            if data.len() > 76 && yes_reserve == 0 && no_reserve == 0 {
+                None
+            } else {
+                Some((yes_reserve, no_reserve))
+            }
+        } else {
+            None
+        };
+
+        Ok(MarketData {
+            token_code_hash,
+            hash_type,
+            resolved,
+            outcome,
+            cancelled,
+            pending_outcome,
+            proposed_at,
+            seed_reserves,
+            sweep,
+            decimals,
+            shannons_per_token,
+            supplies,
+            resolve_after,
+            dispute_until,
+            fee,
+            scalar,
+            metadata,
+        })
+    }
+
+    /// Whether claims and refunds must still wait for the dispute period at `tip`
+    pub fn in_dispute(&self, tip: u64) -> bool {
+        tip < self.dispute_until
+    }
+
+    /// Whether the market's unclaimed collateral has been swept
+    pub fn is_swept(&self) -> bool {
+        self.sweep.is_some_and(|sweep| sweep.swept)
+    }
+
+    /// Whether the market may be consumed without re-creating it
+    ///
+    /// Only a finalized market with nothing left to pay out can close: every
+    /// winning token claimed, or for a cancelled market every complete set
+    /// refunded. Markets that don't record supplies can't show that and never close.
+    pub fn is_closable(&self) -> bool {
+        let Some(supplies) = self.supplies else {
+            return false;
+        };
+        if !self.resolved || self.pending_outcome.is_some() {
+            return false;
+        }
+
+        let outstanding = if self.cancelled {
+            supplies.yes.min(supplies.no)
+        } else if let Some(scalar) = self.scalar {
+            // Only sides that pay something can still be claimed
+            let long = if scalar.value > scalar.floor { supplies.yes } else { 0 };
+            let short = if scalar.value < scalar.ceiling { supplies.no } else { 0 };
+            long.max(short)
+        } else if self.outcome {
+            supplies.yes
+        } else {
+            supplies.no
+        };
+        outstanding == 0
+    }
+
+    /// Serialize market data to bytes (44 bytes, 76 with seed reserves, 117 with
+    /// sweep terms, 118 with decimals, 126 with a non-default collateral unit,
+    /// 158 with supplies, 166 with a resolution window, 174 with a dispute period,
+    /// 208 with a protocol fee, 232 with a scalar range, more with metadata, whose fields must fit as
+    /// Metadata::new checks)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = alloc::vec![0u8; 44];
+        bytes[0..32].copy_from_slice(&self.token_code_hash);
+        bytes[32] = self.hash_type;
+        bytes[33] = if self.resolved { 1 } else { 0 };
+        bytes[34] = if self.cancelled { 0x02 } else if self.outcome { 1 } else { 0 };
+        bytes[35] = self.pending_outcome.map_or(0x00, Proposal::to_byte);
+        bytes[36..44].copy_from_slice(&self.proposed_at.to_le_bytes());
+        let has_scalar = self.scalar.is_some() || self.metadata.is_some();
+        let has_fee = self.fee.is_some() || has_scalar;
+        let has_dispute = self.dispute_until != 0 || has_fee;
+        let has_window = self.resolve_after != 0 || has_dispute;
+        let has_supplies = self.supplies.is_some() || has_window;
+        let has_ratio = self.shannons_per_token != DEFAULT_SHANNONS_PER_TOKEN || has_supplies;
+        let has_decimals = self.decimals != 0 || has_ratio;
+        let has_sweep = self.sweep.is_some() || has_decimals;
+        if self.seed_reserves.is_some() || has_sweep {
+            let (yes_reserve, no_reserve) = self.seed_reserves.unwrap_or((0, 0));
+            bytes.extend_from_slice(&yes_reserve.to_le_bytes());
+            bytes.extend_from_slice(&no_reserve.to_le_bytes());
+        }
+        if has_sweep {
+            let sweep = self.sweep.unwrap_or_default();
+            bytes.extend_from_slice(&sweep.claim_deadline.to_le_bytes());
+            bytes.extend_from_slice(&sweep.sweeper_lock_hash);
+            bytes.push(if sweep.swept { 1 } else { 0 });
+        }
+        if has_decimals {
+            bytes.push(self.decimals);
+        }
+        if has_ratio {
+            bytes.extend_from_slice(&self.shannons_per_token.to_le_bytes());
+        }
+        if has_supplies {
+            let supplies = self.supplies.unwrap_or_default();
+            bytes.extend_from_slice(&supplies.yes.to_le_bytes());
+            bytes.extend_from_slice(&supplies.no.to_le_bytes());
+        }
+        if has_window {
+            bytes.extend_from_slice(&self.resolve_after.to_le_bytes());
+        }
+        if has_dispute {
+            bytes.extend_from_slice(&self.dispute_until.to_le_bytes());
+        }
+        if has_fee {
+            let fee = self.fee.unwrap_or_default();
+            bytes.extend_from_slice(&fee.fee_bps.to_le_bytes());
+            bytes.extend_from_slice(&fee.recipient_lock_hash);
+        }
+        if has_scalar {
+            let scalar = self.scalar.unwrap_or_default();
+            bytes.extend_from_slice(&scalar.floor.to_le_bytes());
+            bytes.extend_from_slice(&scalar.ceiling.to_le_bytes());
+            bytes.extend_from_slice(&scalar.value.to_le_bytes());
+        }
+        if let Some(metadata) = &self.metadata {
+            metadata.write_to(&mut bytes);
+        }
+        bytes
+    }
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, DataError> {
+    let bytes = data.get(offset..offset + 8).ok_or(DataError::LengthNotEnough)?;
+    Ok(u64::from_le_bytes(bytes.try_into().map_err(|_| DataError::Encoding)?))
+}
+
+fn read_u128(data: &[u8], offset: usize) -> Result<u128, DataError> {
+    let bytes = data.get(offset..offset + 16).ok_or(DataError::LengthNotEnough)?;
+    Ok(u128::from_le_bytes(bytes.try_into().map_err(|_| DataError::Encoding)?))
+}
+
+/// Take a length-prefixed UTF-8 string off the front of `rest`
+fn read_str(rest: &mut &[u8]) -> Result<String, DataError> {
+    let (&len, tail) = rest.split_first().ok_or(DataError::LengthNotEnough)?;
+    let text = tail.get(..len as usize).ok_or(DataError::LengthNotEnough)?;
+    *rest = &tail[len as usize..];
+    core::str::from_utf8(text).map(String::from).map_err(|_| DataError::Encoding)
+}
+
+fn write_str(bytes: &mut Vec<u8>, text: &str) {
+    bytes.push(text.len() as u8);
+    bytes.extend_from_slice(text.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// sUDT's reading of cell data: a u128 LE amount in the first 16 bytes,
+    /// trailing bytes ignored (RFC 0025)
+    fn sudt_amount(data: &[u8]) -> Option<u128> {
+        let amount: [u8; 16] = data.get(..16)?.try_into().ok()?;
+        Some(u128::from_le_bytes(amount))
+    }
+
+    #[test]
+    fn token_data_matches_sudt_layout() {
+        let amount = (1u128 << 100) + 42;
+        let plain = token_data(amount);
+        assert_eq!(plain.len(), TOKEN_AMOUNT_LEN);
+        assert_eq!(sudt_amount(&plain), Some(amount));
+        assert_eq!(token_amount(&plain), Some(amount));
+
+        // Time-locked (amount + unlock time) and limit order (amount + price)
+        // cells only append to the sUDT layout
+        let mut locked = plain.to_vec();
+        locked.extend_from_slice(&1_767_225_600u64.to_le_bytes());
+        let mut order = plain.to_vec();
+        order.extend_from_slice(&5_000_000_000u128.to_le_bytes());
+        for data in [&locked[..], &order[..]] {
+            assert_eq!(token_amount(data), sudt_amount(data));
+            assert_eq!(token_amount(data), Some(amount));
+        }
+
+        assert_eq!(token_amount(&plain[..15]), None);
+        assert_eq!(sudt_amount(&plain[..15]), None);
+    }
+
+    #[test]
+    fn type_id_preimage_is_input_then_index() {
+        let mut input = [0u8; CELL_INPUT_LEN];
+        input[0] = 0x07;
+        input[39] = 0x01;
+        let preimage = type_id_preimage(&input, 0x0102);
+        assert_eq!(&preimage[..CELL_INPUT_LEN], &input[..]);
+        assert_eq!(&preimage[CELL_INPUT_LEN..], &[0x02, 0x01, 0, 0, 0, 0, 0, 0]);
+        assert_ne!(&preimage[CELL_INPUT_LEN..], &0x0102u64.to_be_bytes());
+    }
+
+    fn sample_market() -> MarketData {
+        MarketData {
+            pending_outcome: Some(Proposal::Yes),
+            proposed_at: 1_767_225_600,
+            ..MarketData::new([0xab; 32], 1)
+        }
+    }
+
+    #[test]
+    fn market_data_round_trip() {
+        let bytes = sample_market().to_bytes();
+        let parsed = MarketData::from_bytes(&bytes).unwrap();
+
+        assert_eq!(parsed.token_code_hash, [0xab; 32]);
+        assert_eq!(parsed.hash_type, 1);
+        assert!(!parsed.resolved);
+        assert!(!parsed.outcome);
+        assert!(!parsed.cancelled);
+        assert_eq!(parsed.pending_outcome, Some(Proposal::Yes));
+        assert_eq!(parsed.proposed_at, 1_767_225_600);
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn market_data_seed_reserves_round_trip() {
+        let mut market = sample_market();
+        market.seed_reserves = Some((5, 5));
+        let bytes = market.to_bytes();
+        assert_eq!(bytes.len(), 76);
+        assert_eq!(MarketData::from_bytes(&bytes).unwrap().seed_reserves, Some((5, 5)));
+        assert_eq!(MarketData::from_bytes(&bytes[..44]).unwrap().seed_reserves, None);
+    }
+
+    #[test]
+    fn market_data_sweep_round_trip() {
+        let mut market = sample_market();
+        market.sweep = Some(Sweep { claim_deadline: 1_798_761_600, sweeper_lock_hash: [0x5e; 32], swept: false });
+        let bytes = market.to_bytes();
+        assert_eq!(bytes.len(), 117);
+        assert_eq!(&bytes[44..76], &[0u8; 32]);
+        let parsed = MarketData::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.seed_reserves, None);
+        assert_eq!(parsed.sweep, market.sweep);
+        assert!(!parsed.is_swept());
+        assert_eq!(parsed.to_bytes(), bytes);
+
+        market.seed_reserves = Some((5, 5));
+        market.sweep = Some(Sweep { swept: true, ..market.sweep.unwrap() });
+        let parsed = MarketData::from_bytes(&market.to_bytes()).unwrap();
+        assert_eq!(parsed.seed_reserves, Some((5, 5)));
+        assert!(parsed.is_swept());
+
+        let mut bytes = market.to_bytes();
+        bytes[116] = 0x02;
+        assert_eq!(MarketData::from_bytes(&bytes).err(), Some(DataError::InvalidMarketData));
+    }
+
+    #[test]
+    fn market_data_decimals_round_trip() {
+        let mut market = sample_market();
+        market.decimals = 8;
+        let bytes = market.to_bytes();
+        assert_eq!(bytes.len(), 118);
+        let parsed = MarketData::from_bytes(&bytes).unwrap();
+        assert_eq!((parsed.decimals, parsed.seed_reserves, parsed.sweep), (8, None, None));
+        assert_eq!(parsed.to_bytes(), bytes);
+
+        assert_eq!(MarketData::from_bytes(&bytes[..44]).unwrap().decimals, 0);
+
+        let mut bytes = bytes;
+        bytes[117] = MAX_DECIMALS + 1;
+        assert_eq!(MarketData::from_bytes(&bytes).err(), Some(DataError::InvalidMarketData));
+    }
+
+    #[test]
+    fn market_data_collateral_unit_round_trip() {
+        let mut market = sample_market();
+        market.shannons_per_token = 5_00000000;
+        let bytes = market.to_bytes();
+        assert_eq!(bytes.len(), 126);
+        assert_eq!(bytes[117], 0);
+        let parsed = MarketData::from_bytes(&bytes).unwrap();
+        assert_eq!((parsed.shannons_per_token, parsed.decimals, parsed.sweep), (5_00000000, 0, None));
+        assert_eq!(parsed.to_bytes(), bytes);
+
+        // Markets without the field keep the original 100 CKB unit
+        assert_eq!(MarketData::from_bytes(&bytes[..118]).unwrap().shannons_per_token, DEFAULT_SHANNONS_PER_TOKEN);
+        assert_eq!(sample_market().to_bytes().len(), 44);
+    }
+
+    #[test]
+    fn market_data_metadata_round_trip() {
+        let mut market = sample_market();
+        market.metadata = Some(Metadata::new("Will it rain in Lisbon on 1 May?", &["No", "Yes"]).unwrap());
+        let bytes = market.to_bytes();
+        assert_eq!(bytes.len(), 232 + 1 + 32 + 1 + 3 + 4);
+        assert_eq!(&bytes[158..232], &[0u8; 74]);
+        let parsed = MarketData::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, MarketData { supplies: Some(Supplies::default()), ..market.clone() });
+        assert_eq!(parsed.to_bytes(), bytes);
+
+        // The core fields keep their offsets
+        assert_eq!(MarketData::from_bytes(&bytes[..232]).unwrap().metadata, None);
+        assert_eq!(bytes[35], 0x01);
+
+        // Nothing may follow the labels, and text must be UTF-8
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(MarketData::from_bytes(&trailing).err(), Some(DataError::InvalidMarketData));
+        assert_eq!(MarketData::from_bytes(&bytes[..bytes.len() - 1]).err(), Some(DataError::LengthNotEnough));
+        let mut invalid = bytes;
+        invalid[233] = 0xff;
+        assert_eq!(MarketData::from_bytes(&invalid).err(), Some(DataError::Encoding));
+    }
+
+    #[test]
+    fn market_data_protocol_fee_round_trip() {
+        let mut market = sample_market();
+        market.fee = Some(ProtocolFee { fee_bps: 250, recipient_lock_hash: [0xfe; 32] });
+        let bytes = market.to_bytes();
+        assert_eq!(bytes.len(), 208);
+        assert_eq!(&bytes[174..176], &250u16.to_le_bytes());
+        let parsed = MarketData::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.fee, market.fee);
+        assert_eq!(parsed.to_bytes(), bytes);
+        assert_eq!(MarketData::from_bytes(&bytes[..174]).unwrap().fee, None);
+
+        // Metadata zero-fills the fee terms, which then parse as no fee
+        let described = MarketData { metadata: Some(Metadata::default()), ..sample_market() };
+        assert_eq!(MarketData::from_bytes(&described.to_bytes()).unwrap().fee, None);
+
+        let mut bytes = bytes;
+        bytes[174..176].copy_from_slice(&(MAX_FEE_BPS + 1).to_le_bytes());
+        assert_eq!(MarketData::from_bytes(&bytes).err(), Some(DataError::InvalidMarketData));
+    }
+
+    #[test]
+    fn market_data_scalar_round_trip() {
+        let mut market = sample_market();
+        market.scalar = Some(Scalar { floor: 10, ceiling: 40, value: 10 });
+        let bytes = market.to_bytes();
+        assert_eq!(bytes.len(), 232);
+        assert_eq!(&bytes[208..216], &10u64.to_le_bytes());
+        let parsed = MarketData::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.scalar, market.scalar);
+        assert_eq!(parsed.to_bytes(), bytes);
+        assert_eq!(MarketData::from_bytes(&bytes[..208]).unwrap().scalar, None);
+
+        // Metadata zero-fills the range, which then parses as a binary market
+        let described = MarketData { metadata: Some(Metadata::default()), ..sample_market() };
+        assert_eq!(MarketData::from_bytes(&described.to_bytes()).unwrap().scalar, None);
+
+        // An empty range, or a value outside it, is invalid
+        for (floor, ceiling, value) in [(40, 40, 40), (40, 10, 20), (10, 40, 9), (10, 40, 41)] {
+            let mut bytes = bytes.clone();
+            bytes[208..216].copy_from_slice(&u64::to_le_bytes(floor));
+            bytes[216..224].copy_from_slice(&u64::to_le_bytes(ceiling));
+            bytes[224..232].copy_from_slice(&u64::to_le_bytes(value));
+            assert_eq!(MarketData::from_bytes(&bytes).err(), Some(DataError::InvalidMarketData));
+        }
+    }
+
+    #[test]
+    fn scalar_payouts_at_floor_midpoint_and_ceiling() {
+        let set = DEFAULT_SHANNONS_PER_TOKEN as u128;
+        let at = |value| Scalar { floor: 0, ceiling: 100, value };
+        assert_eq!((at(0).long_ratio(set), at(0).short_ratio(set)), (0, set));
+        assert_eq!((at(50).long_ratio(set), at(50).short_ratio(set)), (set / 2, set / 2));
+        assert_eq!((at(100).long_ratio(set), at(100).short_ratio(set)), (set, 0));
+        assert_eq!(at(25).side_ratio(true, set), set / 4);
+
+        // A third of 100 shannons rounds down for LONG; SHORT gets the odd shannon
+        let third = Scalar { floor: 0, ceiling: 3, value: 1 };
+        assert_eq!((third.long_ratio(100), third.short_ratio(100)), (33, 67));
+
+        // No overflow across the widest range
+        let wide = Scalar { floor: 0, ceiling: u64::MAX, value: u64::MAX / 2 };
+        assert_eq!(wide.long_ratio(u64::MAX as u128 * 8), (u64::MAX / 2) as u128 * 8);
+    }
+
+    #[test]
+    fn resolved_scalar_closes_once_paying_sides_are_claimed() {
+        let resolved = |value, yes, no| MarketData {
+            resolved: true,
+            supplies: Some(Supplies { yes, no }),
+            scalar: Some(Scalar { floor: 0, ceiling: 100, value }),
+            ..MarketData::new([0xab; 32], 1)
+        };
+        assert!(!resolved(50, 3, 0).is_closable());
+        assert!(!resolved(50, 0, 3).is_closable());
+        assert!(resolved(50, 0, 0).is_closable());
+        // At the ceiling SHORT pays nothing, so unclaimed SHORT doesn't hold the market open
+        assert!(resolved(100, 0, 3).is_closable());
+        assert!(!resolved(0, 0, 3).is_closable());
+    }
+
+    #[test]
+    fn protocol_fee_rounds_down() {
+        let fee = ProtocolFee { fee_bps: 250, recipient_lock_hash: [0xfe; 32] };
+        // 2.5% of 500 CKB
+        assert_eq!(fee.fee_on(500_00000000), 12_50000000);
+        assert_eq!(fee.fee_on(39), 0);
+        assert_eq!(fee.fee_on(40), 1);
+        assert_eq!(ProtocolFee { fee_bps: MAX_FEE_BPS, ..fee }.fee_on(u64::MAX), u64::MAX);
+        assert_eq!(ProtocolFee { fee_bps: 0, ..fee }.fee_on(u64::MAX), 0);
+    }
+
+    #[test]
+    fn metadata_fields_must_fit_their_length_prefix() {
+        let long = "x".repeat(MAX_METADATA_FIELD + 1);
+        assert_eq!(Metadata::new(&long, &[]).err(), Some(DataError::InvalidMarketData));
+        assert_eq!(Metadata::new("q", &[&long]).err(), Some(DataError::InvalidMarketData));
+        assert!(Metadata::new(&long[1..], &[]).is_ok());
+        assert_eq!(Metadata::new("", &[]).unwrap().outcome_labels.len(), 0);
+    }
+
+    #[test]
+    fn only_finished_markets_close() {
+        let market = |resolved, outcome, cancelled, yes, no| MarketData {
+            resolved,
+            outcome,
+            cancelled,
+            supplies: Some(Supplies { yes, no }),
+            ..MarketData::new([0xab; 32], 1)
+        };
+        // Open market mid-mint
+        assert!(!market(false, false, false, 0, 0).is_closable());
+        // YES won and every YES was claimed; unclaimed NO is worthless
+        assert!(market(true, true, false, 0, 7).is_closable());
+        assert!(!market(true, true, false, 1, 7).is_closable());
+        assert!(market(true, false, false, 3, 0).is_closable());
+        // Cancelled: unpaired leftovers refund nothing, complete sets do
+        assert!(market(true, false, true, 4, 0).is_closable());
+        assert!(!market(true, false, true, 1, 1).is_closable());
+        let pending = MarketData { pending_outcome: Some(Proposal::No), ..market(true, true, false, 0, 0) };
+        assert!(!pending.is_closable());
+        let untracked = MarketData { supplies: None, ..market(true, true, false, 0, 0) };
+        assert!(!untracked.is_closable());
+    }
+
+    #[test]
+    fn market_data_supplies_round_trip() {
+        let mut market = sample_market();
+        market.supplies = Some(Supplies::default());
+        let bytes = market.to_bytes();
+        assert_eq!(bytes.len(), 158);
+        // The collateral unit is written out, never left as zero padding
+        assert_eq!(&bytes[118..126], &DEFAULT_SHANNONS_PER_TOKEN.to_le_bytes());
+        let parsed = MarketData::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.supplies, Some(Supplies { yes: 0, no: 0 }));
+        assert_eq!((parsed.seed_reserves, parsed.sweep, parsed.decimals), (None, None, 0));
+        assert_eq!(parsed, market);
+
+        market.supplies = Some(Supplies { yes: 7, no: 3 });
+        assert_eq!(MarketData::from_bytes(&market.to_bytes()).unwrap(), market);
+        assert_eq!(MarketData::from_bytes(&bytes[..126]).unwrap().supplies, None);
+    }
+
+    #[test]
+    fn market_data_large_supplies_round_trip() {
+        let mut market = sample_market();
+        market.supplies = Some(Supplies { yes: u128::MAX, no: u128::MAX - 1 });
+        let bytes = market.to_bytes();
+        assert_eq!(&bytes[126..142], &[0xff; 16]);
+        assert_eq!(bytes[142], 0xfe);
+        assert_eq!(MarketData::from_bytes(&bytes).unwrap(), market);
+
+        // Supplies above u64 must not be truncated to their low half
+        market.supplies = Some(Supplies { yes: 1 << 64, no: (1 << 100) + 7 });
+        assert_eq!(MarketData::from_bytes(&market.to_bytes()).unwrap(), market);
+    }
+
+    #[test]
+    fn market_data_flag_bytes_are_strict() {
+        let mut bytes = sample_market().to_bytes();
+        bytes[35] = 0x00;
+        bytes[33] = 0x01;
+        bytes[34] = 0x01;
+        let parsed = MarketData::from_bytes(&bytes).unwrap();
+        assert!(parsed.resolved && parsed.outcome && !parsed.cancelled);
+
+        // resolved is a plain boolean
+        bytes[33] = 0x02;
+        assert_eq!(MarketData::from_bytes(&bytes), Err(DataError::InvalidMarketData));
+        bytes[33] = 0x7f;
+        assert_eq!(MarketData::from_bytes(&bytes), Err(DataError::InvalidMarketData));
+
+        // outcome is 0, 1 or 0x02 (cancelled); anything else is rejected, not read as YES
+        bytes[33] = 0x01;
+        bytes[34] = 0x03;
+        assert_eq!(MarketData::from_bytes(&bytes), Err(DataError::InvalidMarketData));
+        bytes[34] = 0xff;
+        assert_eq!(MarketData::from_bytes(&bytes), Err(DataError::InvalidMarketData));
+    }
+
+    #[test]
+    fn market_data_resolution_window_round_trip() {
+        let mut market = sample_market();
+        market.supplies = Some(Supplies { yes: 7, no: 3 });
+        market.resolve_after = 1_798_761_600;
+        let bytes = market.to_bytes();
+        assert_eq!(bytes.len(), 166);
+        assert_eq!(MarketData::from_bytes(&bytes).unwrap(), market);
+        assert_eq!(MarketData::from_bytes(&bytes[..158]).unwrap().resolve_after, 0);
+
+        // The window needs the supplies in front of it, which are then recorded as zero
+        market.supplies = None;
+        let parsed = MarketData::from_bytes(&market.to_bytes()).unwrap();
+        assert_eq!((parsed.supplies, parsed.resolve_after), (Some(Supplies::default()), 1_798_761_600));
+    }
+
+    #[test]
+    fn market_data_dispute_period_round_trip() {
+        let mut market = sample_market();
+        market.resolved = true;
+        market.outcome = true;
+        market.pending_outcome = None;
+        market.supplies = Some(Supplies { yes: 7, no: 3 });
+        market.dispute_until = 18_280;
+        let bytes = market.to_bytes();
+        assert_eq!(bytes.len(), 174);
+        assert_eq!(&bytes[158..166], &[0u8; 8]);
+        let parsed = MarketData::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, market);
+        assert_eq!(parsed.resolve_after, 0);
+        assert_eq!(MarketData::from_bytes(&bytes[..166]).unwrap().dispute_until, 0);
+
+        assert!(parsed.in_dispute(18_279));
+        assert!(!parsed.in_dispute(18_280));
+        assert!(!sample_market().in_dispute(0));
+    }
+
+    #[test]
+    fn server_encoded_market_parses() {
+        // A devnet server market after minting 10 sets: Data1 tokens, 100 CKB
+        // unit, supplies recorded, nothing else set
+        let mut bytes = [0u8; 158];
+        bytes[0..32].copy_from_slice(&[0xcd; 32]);
+        bytes[32] = 2;
+        bytes[118..126].copy_from_slice(&10_000_000_000u64.to_le_bytes());
+        bytes[126..142].copy_from_slice(&10u128.to_le_bytes());
+        bytes[142..158].copy_from_slice(&10u128.to_le_bytes());
+
+        let parsed = MarketData::from_bytes(&bytes).unwrap();
+        let expected = MarketData {
+            supplies: Some(Supplies { yes: 10, no: 10 }),
+            ..MarketData::new([0xcd; 32], 2)
+        };
+        assert_eq!(parsed, expected);
+        assert_eq!(expected.to_bytes(), bytes.to_vec());
+    }
+
+    #[test]
+    fn market_data_legacy_layout_has_no_proposal() {
+        let bytes = sample_market().to_bytes();
+        let parsed = MarketData::from_bytes(&bytes[..35]).unwrap();
+
+        assert_eq!(parsed.pending_outcome, None);
+        assert_eq!(parsed.proposed_at, 0);
+    }
+
+    #[test]
+    fn market_data_rejects_short_and_invalid_data() {
+        let mut bytes = sample_market().to_bytes();
+        assert_eq!(MarketData::from_bytes(&bytes[..34]).err(), Some(DataError::LengthNotEnough));
+
+        bytes[35] = 0x04;
+        assert_eq!(MarketData::from_bytes(&bytes).err(), Some(DataError::InvalidMarketData));
+    }
+
+    #[test]
+    fn market_data_cancellation_encoding() {
+        let mut market = sample_market();
+        market.pending_outcome = Some(Proposal::Cancel);
+        let bytes = market.to_bytes();
+        assert_eq!(bytes[35], 0x03);
+        assert_eq!(MarketData::from_bytes(&bytes).unwrap().pending_outcome, Some(Proposal::Cancel));
+
+        market.resolved = true;
+        market.cancelled = true;
+        market.pending_outcome = None;
+        let bytes = market.to_bytes();
+        assert_eq!(bytes[34], 0x02);
+        let parsed = MarketData::from_bytes(&bytes).unwrap();
+        assert!(parsed.cancelled);
+        assert!(!parsed.outcome);
+        assert!(Proposal::Cancel.is_finalized_in(&parsed));
+        assert!(!Proposal::No.is_finalized_in(&parsed));
+
+        // Cancelled is a resolution, never an open-market state
+        let mut unresolved = bytes;
+        unresolved[33] = 0;
+        assert_eq!(MarketData::from_bytes(&unresolved).err(), Some(DataError::InvalidMarketData));
+    }
+}
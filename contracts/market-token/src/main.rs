@@ -11,6 +11,15 @@
 //!       - For each seller, sum required payments across ALL their orders
 //!       - Validate: sum(CKB outputs to seller) == total_required_for_seller
 //!       - This enables multi-order market buys in a single transaction!
+//!    c. Frozen tokens (freeze flag in args): the market cell must be a cell dep
+//!       and unresolved; after resolution only transactions with the market cell
+//!       in inputs (claims/burns) can move them
+//!
+//! Time-locked token cells:
+//! - Data: [amount: u128][unlock_time: u64] (24 bytes)
+//! - Spending one requires an absolute timestamp `since` >= unlock_time on that input,
+//!   whether or not the market cell is present (claims respect the lock too)
+//! - Supply accounting (here and in the market script) reads only the amount
 //!
 //! Limit order cell structure:
 //! - Lock: AlwaysSuccess (permissionless spending)
@@ -36,7 +45,7 @@ use ckb_std::{
     debug,
     high_level::{
         load_cell_capacity, load_cell_data, load_cell_lock, load_cell_lock_hash,
-        load_cell_type, load_cell_type_hash, load_script, QueryIter,
+        load_cell_type, load_cell_type_hash, load_input_since, load_script, QueryIter,
     },
 };
 
@@ -54,6 +63,11 @@ enum Error {
     // Limit order validation errors
     LimitOrderPaymentMismatch = 20,
     LimitOrderInvalidAmount = 21,
+    // Lock-time validation errors
+    TokensLocked = 30,
+    // Freeze validation errors
+    TokensFrozen = 31,
+    MarketDepMissing = 32,
 }
 
 impl From<ckb_std::error::SysError> for Error {
@@ -85,13 +99,18 @@ impl TokenType {
     }
 }
 
+/// Token flag mirrored from the market args: frozen once the market resolves
+const FLAG_FREEZE_AFTER_RESOLUTION: u8 = 0x01;
+
 /// Type script args structure
 /// Format:
 /// - bytes 0-31: market_type_hash (32 bytes)
 /// - byte 32: token_id (1 byte: 0x01 = YES, 0x02 = NO)
+/// - byte 33: flags (optional, copied from the market args by the market script)
 struct TypeScriptArgs {
     market_type_hash: [u8; 32],
     token_id: TokenType,
+    flags: u8,
 }
 
 impl TypeScriptArgs {
@@ -104,20 +123,22 @@ impl TypeScriptArgs {
         market_type_hash.copy_from_slice(&data[0..32]);
 
         let token_id = TokenType::from_u8(data[32])?;
+        let flags = data.get(33).copied().unwrap_or(0);
 
         Ok(TypeScriptArgs {
             market_type_hash,
             token_id,
+            flags,
         })
     }
 }
 
 /// Parse token cell data
 /// Returns: (amount, limit_price)
-/// Supports both 16-byte (old) and 32-byte (new with limit_price) formats
+/// Supports 16-byte (old), 24-byte (time-locked) and 32-byte (new with limit_price) formats
 fn parse_token_data(data: &[u8]) -> Result<(u128, u128), Error> {
-    if data.len() == 16 {
-        // Backward compatible: old format (just amount)
+    if data.len() == 16 || data.len() == 24 {
+        // Backward compatible: old format (just amount), or amount + unlock_time
         let amount = u128::from_le_bytes(
             data[0..16].try_into().map_err(|_| Error::Encoding)?
         );
@@ -136,6 +157,60 @@ fn parse_token_data(data: &[u8]) -> Result<(u128, u128), Error> {
     }
 }
 
+/// Parse the unlock time of a time-locked (24-byte) token cell
+/// Returns None for every other layout.
+fn parse_unlock_time(data: &[u8]) -> Result<Option<u64>, Error> {
+    if data.len() != 24 {
+        return Ok(None);
+    }
+    let unlock_time = u64::from_le_bytes(
+        data[16..24].try_into().map_err(|_| Error::Encoding)?
+    );
+    Ok(Some(unlock_time))
+}
+
+/// Seconds encoded in an absolute timestamp `since`, or None for any other kind
+fn absolute_timestamp_since(since: u64) -> Option<u64> {
+    const SINCE_RELATIVE_FLAG: u64 = 1 << 63;
+    const SINCE_METRIC_MASK: u64 = 0b11 << 61;
+    const SINCE_METRIC_TIMESTAMP: u64 = 0b10 << 61;
+    const SINCE_VALUE_MASK: u64 = 0x00ff_ffff_ffff_ffff;
+
+    if since & SINCE_RELATIVE_FLAG != 0 || since & SINCE_METRIC_MASK != SINCE_METRIC_TIMESTAMP {
+        return None;
+    }
+
+    Some(since & SINCE_VALUE_MASK)
+}
+
+/// Reject spending time-locked token inputs before their unlock time
+///
+/// The input's absolute timestamp `since` must be at least the unlock time,
+/// so the transaction cannot be committed before the tokens unlock.
+fn validate_unlock_times(current_script_hash: &[u8]) -> Result<(), Error> {
+    for (i, cell_type_hash) in QueryIter::new(load_cell_type_hash, Source::Input).enumerate() {
+        if let Some(type_hash) = cell_type_hash {
+            if type_hash.as_slice() == current_script_hash {
+                let data = load_cell_data(i, Source::Input)?;
+                if let Some(unlock_time) = parse_unlock_time(&data)? {
+                    let since = load_input_since(i, Source::Input)?;
+                    match absolute_timestamp_since(since) {
+                        Some(timestamp) if timestamp >= unlock_time => {
+                            debug!("Input {} unlocked at {} (since {})", i, unlock_time, timestamp);
+                        }
+                        _ => {
+                            debug!("Input {} locked until {}, since {:#x}", i, unlock_time, since);
+                            return Err(Error::TokensLocked);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
 /// Sum token amounts from inputs or outputs
 fn sum_token_amounts(source: Source) -> Result<u128, Error> {
     let mut total: u128 = 0;
@@ -173,6 +248,30 @@ fn market_cell_in_inputs(market_type_hash: &[u8; 32]) -> bool {
     false
 }
 
+/// Reject moving frozen tokens once their market has resolved
+///
+/// Without the market cell in inputs this script cannot see the market, so
+/// transfers of freezable tokens must carry the (live) market cell as a cell
+/// dep. Its data byte 33 is the market's resolved flag.
+fn validate_not_frozen(market_type_hash: &[u8; 32]) -> Result<(), Error> {
+    for (i, cell_type_hash) in QueryIter::new(load_cell_type_hash, Source::CellDep).enumerate() {
+        if let Some(type_hash) = cell_type_hash {
+            if type_hash.as_slice() == market_type_hash {
+                let data = load_cell_data(i, Source::CellDep)?;
+                let resolved = *data.get(33).ok_or(Error::LengthNotEnough)? != 0;
+                if resolved {
+                    debug!("Market resolved - frozen tokens can only move with the market cell");
+                    return Err(Error::TokensFrozen);
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    debug!("Frozen tokens require the market cell as a cell dep");
+    Err(Error::MarketDepMissing)
+}
+
 /// Find matching output token cell for partial fill validation
 /// Returns the amount in the matching output, or 0 if not found
 ///
@@ -443,6 +542,10 @@ fn main() -> Result<(), Error> {
 
     debug!("Input amount: {}, Output amount: {}", input_amount, output_amount);
 
+    // Time-locked tokens stay locked even when the market cell is present
+    let current_script_hash = script.calc_script_hash();
+    validate_unlock_times(current_script_hash.as_slice())?;
+
     // Check if market cell is in inputs
     if market_cell_in_inputs(&args.market_type_hash) {
         // Market cell present - market type script will validate everything
@@ -458,9 +561,13 @@ fn main() -> Result<(), Error> {
 
     debug!("Token conservation check passed - output ({}) <= input ({})", output_amount, input_amount);
 
+    // Frozen tokens only move with the market cell once it resolves
+    if args.flags & FLAG_FREEZE_AFTER_RESOLUTION != 0 {
+        validate_not_frozen(&args.market_type_hash)?;
+    }
+
     // Validate limit order payments (CUMULATIVE per seller)
     // This groups all orders by seller and validates total payment per seller
-    let current_script_hash = script.calc_script_hash();
     let hash_bytes: [u8; 32] = current_script_hash.as_slice().try_into().map_err(|_| Error::Encoding)?;
     validate_limit_orders_cumulative(&hash_bytes)?;
 
@@ -3,9 +3,14 @@
 //! Validates prediction market state transitions.
 //! Ensures token minting/burning matches market supply changes.
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![cfg_attr(not(test), no_main)]
 
+// Unit tests build under std for the host; the alloc crate is otherwise
+// brought in by ckb_std::entry!
+#[cfg(test)]
+extern crate alloc;
+
 use ckb_std::{
     ckb_constants::Source,
     ckb_types::{
@@ -15,14 +20,17 @@ use ckb_std::{
     },
     debug,
     high_level::{
-        load_cell_capacity, load_cell_data, load_cell_lock, load_cell_type, load_cell_type_hash,
-        load_input, load_script, load_witness_args, QueryIter,
+        load_cell_capacity, load_cell_data, load_cell_occupied_capacity, load_cell_lock, load_cell_lock_hash, load_cell_type, load_cell_type_hash,
+        load_header, load_input, load_input_since, load_script, load_witness_args, QueryIter,
     },
 };
 use alloc::vec::Vec;
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use market_common::{type_id_preimage, DataError, MarketData, Proposal, ProtocolFee, Scalar, Supplies, CELL_INPUT_LEN};
 
 /// Error codes
 #[repr(i8)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
 enum Error {
     IndexOutOfBound = 1,
     ItemMissing,
@@ -35,9 +43,37 @@ enum Error {
     UnequalSupplyIncrease = 13,
     InsufficientCollateral = 14,
     LockScriptChanged = 15,
+    TokenLockChanged = 16,
+    MintingDuringClaim = 17,
+    FractionalCollateral = 18,
+    MintLockMismatch = 19,
     // Type ID validation errors
     InvalidTypeId = 20,
     TypeIdMismatch = 21,
+    // Resolution validation errors
+    ResolutionNotProposed = 30,
+    ResolutionPending = 31,
+    FinalizeTooEarly = 32,
+    OracleQuorumNotMet = 33,
+    DuplicateOracleSigner = 34,
+    InvalidOracleSignature = 35,
+    // Sweep validation errors
+    SweepTooEarly = 36,
+    SweepUnauthorized = 37,
+    MarketSwept = 38,
+    // Resolver authorization errors
+    UnauthorizedResolver = 39,
+    // Token cell layout errors
+    TooManyTokenCells = 40,
+    // Resolution window errors
+    ResolutionTooEarly = 41,
+    // Dispute period errors
+    ClaimDuringDispute = 42,
+    DisputeClosed = 43,
+    // Protocol fee errors
+    MissingProtocolFee = 44,
+    // Proposal amendment errors
+    ProposalFixed = 45,
 }
 
 impl From<ckb_std::error::SysError> for Error {
@@ -52,50 +88,383 @@ impl From<ckb_std::error::SysError> for Error {
     }
 }
 
-/// Market data structure
-/// Format:
-/// - bytes 0-31: token_code_hash (32 bytes) - hash of the token contract binary
-/// - byte 32: hash_type (1 byte) - ScriptHashType for tokens
-/// - byte 33: resolved (0 or 1)
-/// - byte 34: outcome (0 or 1, true = YES wins)
+impl From<DataError> for Error {
+    fn from(err: DataError) -> Self {
+        match err {
+            DataError::LengthNotEnough => Error::LengthNotEnough,
+            DataError::Encoding => Error::Encoding,
+            DataError::InvalidMarketData => Error::InvalidMarketData,
+        }
+    }
+}
+
+/// Market type args: Type ID (32 bytes), optionally followed by outcome weights
+/// (8 bytes) and then optionally by a flags byte
+const TYPE_ID_ARGS_LEN: usize = 32;
+const WEIGHTED_ARGS_LEN: usize = 40;
+
+/// Market flag: once resolved, tokens can only move alongside the market cell
+/// Tokens of such markets carry the flags byte after the token id in their args,
+/// so the token script can enforce the freeze without the market in inputs.
+const FLAG_FREEZE_AFTER_RESOLUTION: u8 = 0x01;
+
+/// Market flag: proposals need an M-of-N oracle quorum (see OracleQuorum)
+/// The quorum section follows the flags byte in the market args.
+const FLAG_ORACLE_QUORUM: u8 = 0x02;
+/// Market flag: AMM mode, where creation seeds the pool with complete sets whose
+/// YES/NO reserves are recorded in the market data (see MarketData)
+const FLAG_AMM: u8 = 0x04;
+/// Market flag: proposals must be signed by one resolver key, whose blake160
+/// pubkey hash (RESOLVER_HASH_LEN bytes) follows the flags byte in the market
+/// args. Exclusive with FLAG_ORACLE_QUORUM.
+const FLAG_RESOLVER: u8 = 0x08;
+/// Market flag: minted tokens must go to one lock, whose lock hash
+/// (TOKEN_LOCK_HASH_LEN bytes) ends the market args, after any quorum or
+/// resolver section
+const FLAG_TOKEN_LOCK: u8 = 0x10;
+/// Market flag: a mint adds at most one YES and one NO token cell, so minted
+/// sets can't be scattered over many dust cells
+const FLAG_SINGLE_CELL_MINT: u8 = 0x20;
+
+const KNOWN_FLAGS: u8 = FLAG_FREEZE_AFTER_RESOLUTION | FLAG_ORACLE_QUORUM | FLAG_AMM | FLAG_RESOLVER
+    | FLAG_TOKEN_LOCK | FLAG_SINGLE_CELL_MINT;
+
+/// Flags the token script reads, appended to the token args
+const TOKEN_FLAGS: u8 = FLAG_FREEZE_AFTER_RESOLUTION;
+
+/// Compressed secp256k1 public key and recoverable signature sizes
+const ORACLE_PUBKEY_LEN: usize = 33;
+const ORACLE_SIGNATURE_LEN: usize = 65;
+
+/// blake160 of the resolver's compressed public key
+const RESOLVER_HASH_LEN: usize = 20;
+
+/// Lock script hash minted tokens must carry
+const TOKEN_LOCK_HASH_LEN: usize = 32;
+
+/// Token ids of the market's outcomes (1..=N, YES then NO)
+/// A complete set holds one token of every id.
+const OUTCOME_TOKEN_IDS: [u8; 2] = [0x01, 0x02];
+
+/// Minimum time a proposed outcome must stand before it can be finalized (24 hours)
+const RESOLUTION_DELAY_SECONDS: u64 = 24 * 60 * 60;
+
+/// Blocks from a resolver market's proposal until claims unlock (about 48 hours
+/// at 10-second blocks, so about a day remains after the earliest finalization).
+/// Until then the resolver may correct the outcome.
+const DISPUTE_PERIOD_BLOCKS: u64 = 17_280;
+
+/// Per-outcome collateral weights, in units of the market's shannons_per_token
+///
+/// Uniform markets (32-byte args) cost 1 unit per complete set and pay 1 unit
+/// per winning token. Weighted markets append to the Type ID:
+/// - bytes 32-35: weight_yes (u32 LE, non-zero)
+/// - bytes 36-39: weight_no (u32 LE, non-zero)
+/// A complete set then costs weight_yes + weight_no units and each winning
+/// token pays its own weight.
 #[derive(Debug)]
-struct MarketData {
-    token_code_hash: [u8; 32],
-    hash_type: u8,
-    resolved: bool,
-    outcome: bool,
+struct CollateralWeights {
+    set_units: u128,
+    yes_units: u128,
+    no_units: u128,
 }
 
-impl MarketData {
-    /// Parse market data from cell data
-    fn from_bytes(data: &[u8]) -> Result<Self, Error> {
-        if data.len() < 35 {
-            return Err(Error::LengthNotEnough);
+impl CollateralWeights {
+    /// Parse weights from market type script args
+    fn from_args(args: &[u8]) -> Result<Self, Error> {
+        let (args, _flags) = split_market_args(args)?;
+        match args.len() {
+            TYPE_ID_ARGS_LEN => Ok(CollateralWeights {
+                set_units: 1,
+                yes_units: 1,
+                no_units: 1,
+            }),
+            WEIGHTED_ARGS_LEN => {
+                let yes = u32::from_le_bytes(args[32..36].try_into().map_err(|_| Error::Encoding)?);
+                let no = u32::from_le_bytes(args[36..40].try_into().map_err(|_| Error::Encoding)?);
+                if yes == 0 || no == 0 {
+                    debug!("Outcome weights must be non-zero");
+                    return Err(Error::InvalidTypeId);
+                }
+                Ok(CollateralWeights {
+                    set_units: yes as u128 + no as u128,
+                    yes_units: yes as u128,
+                    no_units: no as u128,
+                })
+            }
+            _ => Err(Error::InvalidTypeId),
         }
+    }
 
-        let mut token_code_hash = [0u8; 32];
-        token_code_hash.copy_from_slice(&data[0..32]);
-        let hash_type = data[32];
-        let resolved = data[33] != 0;
-        let outcome = data[34] != 0;
+    /// Shannons per complete set minted (or returned by burning)
+    fn set_ratio(&self, shannons_per_token: u64) -> u128 {
+        self.set_units * shannons_per_token as u128
+    }
 
-        Ok(MarketData {
-            token_code_hash,
-            hash_type,
-            resolved,
-            outcome,
-        })
+    /// Shannons paid per winning token of `outcome`
+    fn claim_ratio(&self, outcome: bool, shannons_per_token: u64) -> u128 {
+        let units = if outcome { self.yes_units } else { self.no_units };
+        units * shannons_per_token as u128
     }
+}
 
-    /// Serialize market data to bytes
-    fn to_bytes(&self) -> [u8; 35] {
-        let mut bytes = [0u8; 35];
-        bytes[0..32].copy_from_slice(&self.token_code_hash);
-        bytes[32] = self.hash_type;
-        bytes[33] = if self.resolved { 1 } else { 0 };
-        bytes[34] = if self.outcome { 1 } else { 0 };
-        bytes
+/// Split market type args into (Type ID + weights, flags)
+/// Args without a trailing flags byte have no flags set. With FLAG_ORACLE_QUORUM
+/// the oracle quorum section follows the flags byte (see `oracle_quorum`), with
+/// FLAG_RESOLVER the resolver pubkey hash (see `market_resolver`). With
+/// FLAG_TOKEN_LOCK the args end with the token lock hash (see `token_lock`).
+fn split_market_args(args: &[u8]) -> Result<(&[u8], u8), Error> {
+    let (base_len, flags) = market_args_layout(args)?;
+    Ok((&args[..base_len], flags))
+}
+
+/// Length of the Type ID + weights part of market args and the flags
+///
+/// Layouts are told apart by length: with a flags byte the args end there
+/// unless the quorum or resolver flag is set, in which case that section must
+/// account for exactly the remaining bytes (less the token lock hash, if any).
+fn market_args_layout(args: &[u8]) -> Result<(usize, u8), Error> {
+    if args.len() == TYPE_ID_ARGS_LEN || args.len() == WEIGHTED_ARGS_LEN {
+        return Ok((args.len(), 0));
+    }
+
+    for base_len in [TYPE_ID_ARGS_LEN, WEIGHTED_ARGS_LEN] {
+        let Some(&flags) = args.get(base_len) else {
+            continue;
+        };
+        let Some((rest, _)) = split_token_lock(&args[base_len + 1..], flags) else {
+            continue;
+        };
+        let fits = match (flags & FLAG_ORACLE_QUORUM != 0, flags & FLAG_RESOLVER != 0) {
+            (true, true) => false,
+            (true, false) => OracleQuorum::section_fits(rest),
+            (false, true) => rest.len() == RESOLVER_HASH_LEN,
+            (false, false) => rest.is_empty(),
+        };
+        if fits && flags & !KNOWN_FLAGS == 0 {
+            return Ok((base_len, flags));
+        }
+    }
+
+    debug!("Market args must be {} or {} bytes (plus optional flags, oracle quorum or resolver, and token lock), got {}",
+           TYPE_ID_ARGS_LEN, WEIGHTED_ARGS_LEN, args.len());
+    Err(Error::InvalidTypeId)
+}
+
+/// Split the args after the flags byte into the quorum or resolver section and
+/// the token lock hash, which is there only with FLAG_TOKEN_LOCK
+fn split_token_lock(rest: &[u8], flags: u8) -> Option<(&[u8], Option<&[u8]>)> {
+    if flags & FLAG_TOKEN_LOCK == 0 {
+        return Some((rest, None));
+    }
+    let (section, lock_hash) = rest.split_at(rest.len().checked_sub(TOKEN_LOCK_HASH_LEN)?);
+    Some((section, Some(lock_hash)))
+}
+
+/// Flags, quorum or resolver section and token lock hash of market args
+fn market_flag_sections(args: &[u8]) -> Result<(u8, &[u8], Option<&[u8]>), Error> {
+    let (base_len, flags) = market_args_layout(args)?;
+    let rest = args.get(base_len + 1..).unwrap_or_default();
+    let (section, lock_hash) = split_token_lock(rest, flags).ok_or(Error::InvalidTypeId)?;
+    Ok((flags, section, lock_hash))
+}
+
+/// M-of-N oracles whose signatures a resolution proposal needs
+///
+/// Market args section after the flags byte (FLAG_ORACLE_QUORUM set):
+/// - byte 0: threshold M (1..=N)
+/// - byte 1: key count N
+/// - N × 33 bytes: distinct compressed secp256k1 public keys
+///
+/// Proposals carry the signatures as concatenated 65-byte recoverable
+/// signatures (r || s || recovery id) in the `input_type` field of the market
+/// input's witness, each over `proposed_message`.
+struct OracleQuorum<'a> {
+    threshold: usize,
+    keys: Vec<&'a [u8]>,
+}
+
+impl<'a> OracleQuorum<'a> {
+    /// Whether `section` is exactly as long as its key count says
+    fn section_fits(section: &[u8]) -> bool {
+        section.len() >= 2 && section.len() == 2 + section[1] as usize * ORACLE_PUBKEY_LEN
+    }
+
+    fn from_section(section: &'a [u8]) -> Result<Self, Error> {
+        if !Self::section_fits(section) {
+            return Err(Error::InvalidTypeId);
+        }
+        let threshold = section[0] as usize;
+        let keys: Vec<&[u8]> = section[2..].chunks(ORACLE_PUBKEY_LEN).collect();
+
+        if threshold == 0 || threshold > keys.len() {
+            debug!("Oracle threshold {} must be between 1 and {}", threshold, keys.len());
+            return Err(Error::InvalidTypeId);
+        }
+        if keys.iter().enumerate().any(|(i, key)| keys[..i].contains(key)) {
+            debug!("Oracle keys must be distinct");
+            return Err(Error::InvalidTypeId);
+        }
+
+        Ok(OracleQuorum { threshold, keys })
+    }
+
+    /// Check that at least `threshold` distinct oracles signed `message`
+    /// Every signature must recover to an oracle key, and each key may sign once.
+    fn verify(&self, message: &[u8; 32], signatures: &[u8]) -> Result<(), Error> {
+        if signatures.len() % ORACLE_SIGNATURE_LEN != 0 {
+            debug!("Oracle signatures must be {} bytes each", ORACLE_SIGNATURE_LEN);
+            return Err(Error::InvalidOracleSignature);
+        }
+
+        let mut signers: Vec<usize> = Vec::new();
+        for signature in signatures.chunks(ORACLE_SIGNATURE_LEN) {
+            let key = recover_oracle_key(message, signature).ok_or(Error::InvalidOracleSignature)?;
+            let signer = self.keys.iter()
+                .position(|oracle| *oracle == key.as_slice())
+                .ok_or(Error::InvalidOracleSignature)?;
+            if signers.contains(&signer) {
+                debug!("Oracle {} signed more than once", signer);
+                return Err(Error::DuplicateOracleSigner);
+            }
+            signers.push(signer);
+        }
+
+        if signers.len() < self.threshold {
+            debug!("{} oracle signatures, {} required", signers.len(), self.threshold);
+            return Err(Error::OracleQuorumNotMet);
+        }
+        Ok(())
+    }
+}
+
+/// Oracle quorum from market args, if the market has one
+fn oracle_quorum(args: &[u8]) -> Result<Option<OracleQuorum<'_>>, Error> {
+    let (flags, section, _) = market_flag_sections(args)?;
+    if flags & FLAG_ORACLE_QUORUM == 0 {
+        return Ok(None);
+    }
+    OracleQuorum::from_section(section).map(Some)
+}
+
+/// Resolver pubkey hash from market args, if the market has one
+fn market_resolver(args: &[u8]) -> Result<Option<[u8; RESOLVER_HASH_LEN]>, Error> {
+    let (flags, section, _) = market_flag_sections(args)?;
+    if flags & FLAG_RESOLVER == 0 {
+        return Ok(None);
+    }
+    let hash = section.try_into().map_err(|_| Error::InvalidTypeId)?;
+    Ok(Some(hash))
+}
+
+/// Lock hash minted tokens must carry, if the market has one
+fn token_lock(args: &[u8]) -> Result<Option<[u8; TOKEN_LOCK_HASH_LEN]>, Error> {
+    let (_, _, lock_hash) = market_flag_sections(args)?;
+    lock_hash.map(|hash| hash.try_into().map_err(|_| Error::InvalidTypeId)).transpose()
+}
+
+/// Whether `signature` is the resolver's recoverable signature over `message`
+fn resolver_signed(resolver_hash: &[u8; RESOLVER_HASH_LEN], message: &[u8; 32], signature: &[u8]) -> bool {
+    if signature.len() != ORACLE_SIGNATURE_LEN {
+        return false;
+    }
+    let Some(key) = recover_oracle_key(message, signature) else {
+        return false;
+    };
+    let key_hash = ckb_std::ckb_types::packed::CellOutput::calc_data_hash(&key);
+    key_hash.as_slice()[..RESOLVER_HASH_LEN] == resolver_hash[..]
+}
+
+/// Message oracles and resolvers sign for a proposal on `output_data`:
+/// blake2b(market_type_hash || market input outpoint || pending_outcome byte
+/// || value as u64 LE, on a scalar market)
+///
+/// Bound to the market cell being spent, so a signature can't be replayed
+/// once the proposal has been withdrawn. It is the message of an amendment
+/// from no proposal to this one.
+fn proposed_message(market_type_hash: &[u8; 32], market_outpoint: &[u8], proposal: Proposal, scalar: Option<Scalar>) -> [u8; 32] {
+    amendment_message(market_type_hash, market_outpoint, Some(proposal), scalar)
+}
+
+/// Message a resolver signs to correct a finalized outcome:
+/// blake2b(market_type_hash || market input outpoint || corrected outcome byte)
+///
+/// The outpoint ties the signature to one market cell, so an earlier
+/// correction can't be replayed after a later one.
+fn correction_message(market_type_hash: &[u8; 32], market_outpoint: &[u8], outcome: Proposal) -> [u8; 32] {
+    let mut data = Vec::with_capacity(32 + market_outpoint.len() + 1);
+    data.extend_from_slice(market_type_hash);
+    data.extend_from_slice(market_outpoint);
+    data.push(outcome.to_byte());
+
+    let hash = ckb_std::ckb_types::packed::CellOutput::calc_data_hash(&data);
+    let mut message = [0u8; 32];
+    message.copy_from_slice(hash.as_slice());
+    message
+}
+
+/// Message signed to amend a pending proposal:
+/// blake2b(market_type_hash || market input outpoint || new pending_outcome
+/// byte, 0 to withdraw it || value as u64 LE, on a scalar market)
+///
+/// Bound to the market cell like a correction, so a signature for an earlier
+/// amendment can't be replayed to flip the proposal back.
+fn amendment_message(market_type_hash: &[u8; 32], market_outpoint: &[u8], proposal: Option<Proposal>, scalar: Option<Scalar>) -> [u8; 32] {
+    let mut data = Vec::with_capacity(32 + market_outpoint.len() + 1 + 8);
+    data.extend_from_slice(market_type_hash);
+    data.extend_from_slice(market_outpoint);
+    data.push(proposal.map_or(0, Proposal::to_byte));
+    if let Some(scalar) = scalar {
+        data.extend_from_slice(&scalar.value.to_le_bytes());
+    }
+
+    let hash = ckb_std::ckb_types::packed::CellOutput::calc_data_hash(&data);
+    let mut message = [0u8; 32];
+    message.copy_from_slice(hash.as_slice());
+    message
+}
+
+/// Compressed public key that produced a recoverable signature over `message`
+fn recover_oracle_key(message: &[u8; 32], signature: &[u8]) -> Option<[u8; ORACLE_PUBKEY_LEN]> {
+    let recovery_id = RecoveryId::from_byte(*signature.get(64)?)?;
+    let signature = Signature::from_slice(signature.get(..64)?).ok()?;
+    let key = VerifyingKey::recover_from_prehash(message, &signature, recovery_id).ok()?;
+    key.to_encoded_point(true).as_bytes().try_into().ok()
+}
+
+/// Oracle signatures from the market input's witness (`input_type` field)
+fn load_oracle_signatures() -> Result<Vec<u8>, Error> {
+    let witness = load_witness_args(0, Source::GroupInput)?;
+    Ok(witness.input_type().to_opt().map(|bytes| bytes.raw_data().to_vec()).unwrap_or_default())
+}
+
+/// Check that a market capacity change is exactly `token_delta` base units at
+/// `ratio` shannons per whole token, i.e. token_delta × ratio / 10^decimals
+///
+/// Every branch that moves collateral (mint, burn, refund, claim, AMM seeding)
+/// goes through here. A product that overflows or doesn't fit a capacity is an
+/// Encoding error, an amount worth a fraction of a shannon is
+/// FractionalCollateral, and any other mismatch is InsufficientCollateral.
+///
+/// The u64 capacity is the hard ceiling on one collateral move: at 100 CKB per
+/// token no more than 1_844_674_407 tokens fit. Clients should refuse larger
+/// amounts before building, since no transaction can carry them.
+fn check_collateral_ratio(capacity_delta: u64, token_delta: u128, ratio: u128, decimals: u8) -> Result<(), Error> {
+    let scale = 10u128.checked_pow(decimals as u32).ok_or(Error::Encoding)?;
+    let shannons = token_delta.checked_mul(ratio).ok_or(Error::Encoding)?;
+    if shannons % scale != 0 {
+        debug!("{} base units at {} decimals are worth a fraction of a shannon", token_delta, decimals);
+        return Err(Error::FractionalCollateral);
     }
+    let expected: u64 = (shannons / scale).try_into().map_err(|_| Error::Encoding)?;
+
+    if capacity_delta != expected {
+        debug!("Capacity change ({}) must equal {} tokens at {} shannons each ({})",
+               capacity_delta, token_delta, ratio, expected);
+        return Err(Error::InsufficientCollateral);
+    }
+
+    Ok(())
 }
 
 /// Count market cells in a source (should only be 0 or 1)
@@ -124,7 +493,7 @@ fn load_market_data(source: Source) -> Result<MarketData, Error> {
         if let Some(type_hash) = cell_type_hash {
             if type_hash.as_slice() == script_hash.as_slice() {
                 let data = load_cell_data(i, source)?;
-                return MarketData::from_bytes(&data);
+                return Ok(MarketData::from_bytes(&data)?);
             }
         }
     }
@@ -163,37 +532,36 @@ fn load_market_lock(source: Source) -> Result<ckb_std::ckb_types::packed::Script
     Err(Error::ItemMissing)
 }
 
-/// Check if transaction has a witness (signature provided for resolution)
-/// Returns true if witness with lock field exists, false otherwise
-fn has_witness() -> bool {
-    // Try to load witness args from first input
+/// Check that the market input's witness carries the resolver's signature
+/// over `message` in its `lock` field (the market lock is always-success, so
+/// the field is otherwise unused)
+fn has_witness(resolver_hash: &[u8; RESOLVER_HASH_LEN], message: &[u8; 32]) -> bool {
     match load_witness_args(0, Source::GroupInput) {
-        Ok(witness_args) => {
-            // Check if lock field exists and is not empty
-            match witness_args.lock().to_opt() {
-                Some(lock) => {
-                    let lock_bytes = lock.raw_data();
-                    !lock_bytes.is_empty()
-                }
-                None => false,
-            }
-        }
+        Ok(witness_args) => match witness_args.lock().to_opt() {
+            Some(lock) => resolver_signed(resolver_hash, message, &lock.raw_data()),
+            None => false,
+        },
         Err(_) => false,
     }
 }
 
 /// Derive expected token type script hash for a given token type
 /// token_id: 0x01 for YES, 0x02 for NO
+/// flags: the market's token-facing flags (TOKEN_FLAGS), appended to the token args when non-zero
 fn derive_token_type_hash(
     token_code_hash: &[u8; 32],
     hash_type: u8,
     market_type_hash: &[u8; 32],
     token_id: u8,
+    flags: u8,
 ) -> Result<[u8; 32], Error> {
-    // Build args: market_type_hash (32 bytes) + token_id (1 byte)
+    // Build args: market_type_hash (32 bytes) + token_id (1 byte) [+ flags (1 byte)]
     let mut args = Vec::new();
     args.extend_from_slice(market_type_hash);
     args.push(token_id);
+    if flags != 0 {
+        args.push(flags);
+    }
 
     // Convert hash_type to ScriptHashType
     let script_hash_type = match hash_type {
@@ -225,6 +593,13 @@ struct TokenCounts {
     no_tokens: u128,
 }
 
+impl TokenCounts {
+    /// Counts in OUTCOME_TOKEN_IDS order
+    fn by_outcome(&self) -> [u128; OUTCOME_TOKEN_IDS.len()] {
+        [self.yes_tokens, self.no_tokens]
+    }
+}
+
 /// Count YES and NO tokens in a given source
 /// Only counts tokens that match the expected type script hashes
 fn count_tokens(
@@ -269,6 +644,169 @@ fn count_tokens(
     Ok(counts)
 }
 
+/// Count YES and NO token cells (not amounts) in a given source
+fn count_token_cells(
+    source: Source,
+    expected_yes_hash: &[u8; 32],
+    expected_no_hash: &[u8; 32],
+) -> Result<[usize; OUTCOME_TOKEN_IDS.len()], Error> {
+    let mut cells = [0; OUTCOME_TOKEN_IDS.len()];
+
+    for cell_type_hash in QueryIter::new(load_cell_type_hash, source).flatten() {
+        if cell_type_hash.as_slice() == expected_yes_hash {
+            cells[0] += 1;
+        } else if cell_type_hash.as_slice() == expected_no_hash {
+            cells[1] += 1;
+        }
+    }
+
+    Ok(cells)
+}
+
+/// Count YES and NO tokens per lock hash in a given source
+fn count_tokens_by_lock(
+    source: Source,
+    expected_yes_hash: &[u8; 32],
+    expected_no_hash: &[u8; 32],
+) -> Result<Vec<([u8; 32], TokenCounts)>, Error> {
+    let mut balances: Vec<([u8; 32], TokenCounts)> = Vec::new();
+
+    for (i, cell_type_hash) in QueryIter::new(load_cell_type_hash, source).enumerate() {
+        let is_yes = match cell_type_hash {
+            Some(type_hash) if type_hash.as_slice() == expected_yes_hash => true,
+            Some(type_hash) if type_hash.as_slice() == expected_no_hash => false,
+            _ => continue,
+        };
+
+        let data = load_cell_data(i, source)?;
+        if data.len() < 16 {
+            return Err(Error::LengthNotEnough);
+        }
+        let amount = u128::from_le_bytes(
+            data[0..16].try_into().map_err(|_| Error::Encoding)?
+        );
+
+        let lock_hash = load_cell_lock_hash(i, source)?;
+        let index = match balances.iter().position(|(hash, _)| *hash == lock_hash) {
+            Some(index) => index,
+            None => {
+                balances.push((lock_hash, TokenCounts::default()));
+                balances.len() - 1
+            }
+        };
+
+        let counts = &mut balances[index].1;
+        if is_yes {
+            counts.yes_tokens = counts.yes_tokens.checked_add(amount).ok_or(Error::Encoding)?;
+        } else {
+            counts.no_tokens = counts.no_tokens.checked_add(amount).ok_or(Error::Encoding)?;
+        }
+    }
+
+    Ok(balances)
+}
+
+/// Check that every lock holding tokens in the inputs still holds at least as many
+/// of each side in the outputs
+///
+/// The complete-set check only compares totals, so without this a mint could
+/// carry someone else's spendable token cells (e.g. always-success limit orders)
+/// to a new lock while the totals still add up.
+fn prior_tokens_kept(
+    input_balances: &[([u8; 32], TokenCounts)],
+    output_balances: &[([u8; 32], TokenCounts)],
+) -> Result<(), Error> {
+    for (lock_hash, before) in input_balances {
+        let (yes_after, no_after) = output_balances.iter()
+            .find(|(hash, _)| hash == lock_hash)
+            .map(|(_, after)| (after.yes_tokens, after.no_tokens))
+            .unwrap_or((0, 0));
+
+        if yes_after < before.yes_tokens || no_after < before.no_tokens {
+            debug!("Lock {:?} held YES={} NO={} before, YES={} NO={} after",
+                   lock_hash, before.yes_tokens, before.no_tokens, yes_after, no_after);
+            return Err(Error::TokenLockChanged);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that a mint adds at most one token cell of each outcome
+///
+/// Counted net of the token cells spent alongside, so merging prior cells into
+/// the minted ones is fine. Splitting a mint over several cells takes a
+/// separate transfer.
+fn single_cell_mint(
+    input_cells: &[usize; OUTCOME_TOKEN_IDS.len()],
+    output_cells: &[usize; OUTCOME_TOKEN_IDS.len()],
+) -> Result<(), Error> {
+    for (before, after) in input_cells.iter().zip(output_cells) {
+        if *after > before + 1 {
+            debug!("Mint went from {} to {} token cells of one outcome", before, after);
+            return Err(Error::TooManyTokenCells);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that every lock whose YES or NO balance grew is the market's token lock
+///
+/// Compared per lock rather than per cell, so prior token cells merged into a
+/// mint may keep whatever lock they had.
+fn minted_to_token_lock(
+    input_balances: &[([u8; 32], TokenCounts)],
+    output_balances: &[([u8; 32], TokenCounts)],
+    token_lock: &[u8; TOKEN_LOCK_HASH_LEN],
+) -> Result<(), Error> {
+    for (lock_hash, after) in output_balances {
+        let (yes_before, no_before) = input_balances.iter()
+            .find(|(hash, _)| hash == lock_hash)
+            .map(|(_, before)| (before.yes_tokens, before.no_tokens))
+            .unwrap_or((0, 0));
+
+        let gained = after.yes_tokens > yes_before || after.no_tokens > no_before;
+        if gained && lock_hash != token_lock {
+            debug!("Lock {:?} received minted tokens, only {:?} may", lock_hash, token_lock);
+            return Err(Error::MintLockMismatch);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that recorded supplies move by exactly the tokens minted and burned
+///
+/// Supplies are optional, but a market either records them for its whole life
+/// or never does.
+fn supplies_tracked(
+    input: Option<Supplies>,
+    output: Option<Supplies>,
+    input_counts: &TokenCounts,
+    output_counts: &TokenCounts,
+) -> Result<(), Error> {
+    let (input, output) = match (input, output) {
+        (None, None) => return Ok(()),
+        (Some(input), Some(output)) => (input, output),
+        _ => {
+            debug!("Supplies cannot be added or dropped after creation");
+            return Err(Error::InvalidMarketData);
+        }
+    };
+
+    let track = |supply: u128, before: u128, after: u128| {
+        supply.checked_add(after).and_then(|total| total.checked_sub(before))
+    };
+    let yes = track(input.yes, input_counts.yes_tokens, output_counts.yes_tokens);
+    let no = track(input.no, input_counts.no_tokens, output_counts.no_tokens);
+    if yes != Some(output.yes) || no != Some(output.no) {
+        debug!("Supplies YES={} NO={} don't follow the tokens, expected {:?} {:?}", output.yes, output.no, yes, no);
+        return Err(Error::InvalidMarketData);
+    }
+    Ok(())
+}
+
 /// Validate market creation (no input market cell)
 fn validate_creation(output_data: &MarketData) -> Result<(), Error> {
     debug!("Validating market creation");
@@ -285,10 +823,100 @@ fn validate_creation(output_data: &MarketData) -> Result<(), Error> {
         return Err(Error::InvalidMarketData);
     }
 
+    // No resolution can be proposed at creation
+    if output_data.pending_outcome.is_some() || output_data.proposed_at != 0 {
+        debug!("Market cannot have a pending resolution at creation");
+        return Err(Error::InvalidMarketData);
+    }
+
+    if output_data.shannons_per_token == 0 {
+        debug!("Collateral unit must be non-zero");
+        return Err(Error::InvalidMarketData);
+    }
+
+    if let Some(sweep) = output_data.sweep {
+        if sweep.swept || sweep.claim_deadline == 0 {
+            debug!("A sweepable market needs a claim deadline and cannot start swept");
+            return Err(Error::InvalidMarketData);
+        }
+    }
+
     debug!("Market creation valid");
     Ok(())
 }
 
+/// Validate the token cells a creation mints
+///
+/// Only AMM markets may mint at creation: the creator seeds the pool with
+/// complete sets, the recorded reserves must equal the YES and NO minted, and
+/// the market must hold its own occupied capacity plus the sets' collateral.
+/// Other markets start without tokens or reserves. Resolver markets must
+/// record supplies.
+fn validate_creation_seed(output_data: &MarketData) -> Result<(), Error> {
+    let market_script = load_script()?;
+    let market_args = market_script.args().raw_data();
+    let (_, flags) = split_market_args(&market_args)?;
+    let (yes_hash, no_hash) = expected_token_hashes(output_data)?;
+
+    let input_counts = count_tokens(Source::Input, &yes_hash, &no_hash)?;
+    let output_counts = count_tokens(Source::Output, &yes_hash, &no_hash)?;
+    if input_counts.yes_tokens != 0 || input_counts.no_tokens != 0 {
+        debug!("A market being created cannot have tokens in inputs");
+        return Err(Error::InvalidMarketData);
+    }
+    // Recorded supplies start at what the creation mints
+    supplies_tracked(output_data.supplies.map(|_| Supplies::default()), output_data.supplies, &input_counts, &output_counts)?;
+    // Finalizing a resolver market writes dispute_until, which the data layout
+    // puts after the supplies: without them from the start it could never finalize
+    if market_resolver(&market_args)?.is_some() && output_data.supplies.is_none() {
+        debug!("A market with a resolver must record supplies from creation");
+        return Err(Error::InvalidMarketData);
+    }
+
+    let Some((yes_reserve, no_reserve)) = output_data.seed_reserves else {
+        if output_counts.yes_tokens != 0 || output_counts.no_tokens != 0 {
+            debug!("Only AMM markets can mint at creation");
+            return Err(Error::InvalidMarketData);
+        }
+        return Ok(());
+    };
+
+    if flags & FLAG_AMM == 0 {
+        debug!("Seed reserves recorded on a market without the AMM flag");
+        return Err(Error::InvalidMarketData);
+    }
+    if output_counts.yes_tokens != yes_reserve || output_counts.no_tokens != no_reserve {
+        debug!("Reserves YES={} NO={} don't match minted YES={} NO={}",
+               yes_reserve, no_reserve, output_counts.yes_tokens, output_counts.no_tokens);
+        return Err(Error::InvalidMarketData);
+    }
+
+    let sets = complete_sets_delta(&input_counts, &output_counts)?;
+    let index = find_market_output_index()? as usize;
+    let deposited = load_cell_capacity(index, Source::Output)?
+        .checked_sub(load_cell_occupied_capacity(index, Source::Output)?)
+        .ok_or(Error::InsufficientCollateral)?;
+    // Seeded sets need their collateral on top of the occupied capacity
+    let set_ratio = CollateralWeights::from_args(&market_args)?.set_ratio(output_data.shannons_per_token);
+    check_collateral_ratio(deposited, sets, set_ratio, output_data.decimals)?;
+
+    debug!("AMM market seeded with {} complete sets", sets);
+    Ok(())
+}
+
+/// YES and NO token type hashes the market derives for its tokens
+fn expected_token_hashes(data: &MarketData) -> Result<([u8; 32], [u8; 32]), Error> {
+    let market_script = load_script()?;
+    let mut market_type_hash = [0u8; 32];
+    market_type_hash.copy_from_slice(market_script.calc_script_hash().as_slice());
+    let (_, flags) = split_market_args(&market_script.args().raw_data())?;
+
+    let derive = |token_id| {
+        derive_token_type_hash(&data.token_code_hash, data.hash_type, &market_type_hash, token_id, flags & TOKEN_FLAGS)
+    };
+    Ok((derive(OUTCOME_TOKEN_IDS[0])?, derive(OUTCOME_TOKEN_IDS[1])?))
+}
+
 /// Validate lock script is preserved (prevents market hijacking)
 fn validate_lock_preserved() -> Result<(), Error> {
     debug!("Validating lock script preservation");
@@ -306,28 +934,54 @@ fn validate_lock_preserved() -> Result<(), Error> {
     Ok(())
 }
 
-/// Validate claim transaction (winning tokens → CKB after resolution)
-fn validate_claim(
-    market_data: &MarketData,
-    input_capacity: u64,
-    output_capacity: u64,
-    input_counts: &TokenCounts,
-    output_counts: &TokenCounts,
-) -> Result<(), Error> {
-    debug!("Validating claim transaction");
+/// Complete sets added between `before` and `after` token counts
+/// Minting passes (input, output); burning passes (output, input).
+/// Every outcome token id must change by the same non-zero amount.
+fn complete_sets_delta(before: &TokenCounts, after: &TokenCounts) -> Result<u128, Error> {
+    let mut deltas = [0u128; OUTCOME_TOKEN_IDS.len()];
+    for (delta, (before, after)) in deltas.iter_mut().zip(before.by_outcome().iter().zip(after.by_outcome())) {
+        *delta = after.checked_sub(*before).ok_or(Error::Encoding)?;
+    }
+
+    if deltas.iter().all(|delta| *delta == 0) {
+        debug!("No tokens changed but capacity changed");
+        return Err(Error::SupplyDecrease);
+    }
+
+    // A set missing any outcome is not a complete set
+    if let Some(i) = deltas.iter().position(|delta| *delta == 0) {
+        debug!("Outcome token id {} missing from complete set change", OUTCOME_TOKEN_IDS[i]);
+        return Err(Error::UnequalSupplyIncrease);
+    }
+
+    // Validate equal change across outcomes
+    let sets = deltas[0];
+    if deltas.iter().any(|delta| *delta != sets) {
+        debug!("Unequal complete set change: {:?}", deltas);
+        return Err(Error::UnequalSupplyIncrease);
+    }
 
-    const SHANNONS_PER_TOKEN: u128 = 10_000_000_000; // 100 CKB per token
+    Ok(sets)
+}
 
+/// Winning tokens burned by a claim
+/// Losing tokens must pass through untouched and at least one winning token must burn;
+/// more winning tokens out than in is a mint and fails with MintingDuringClaim.
+fn claimed_winning_tokens(
+    outcome: bool,
+    input_counts: &TokenCounts,
+    output_counts: &TokenCounts,
+) -> Result<u128, Error> {
     // Determine which token won based on outcome
-    let (winning_burned, losing_input, losing_output) = if market_data.outcome {
+    let (winning_burned, losing_input, losing_output) = if outcome {
         // YES won (outcome = true)
         let yes_burned = input_counts.yes_tokens.checked_sub(output_counts.yes_tokens)
-            .ok_or(Error::Encoding)?;
+            .ok_or(Error::MintingDuringClaim)?;
         (yes_burned, input_counts.no_tokens, output_counts.no_tokens)
     } else {
         // NO won (outcome = false)
         let no_burned = input_counts.no_tokens.checked_sub(output_counts.no_tokens)
-            .ok_or(Error::Encoding)?;
+            .ok_or(Error::MintingDuringClaim)?;
         (no_burned, input_counts.yes_tokens, output_counts.yes_tokens)
     };
 
@@ -343,20 +997,41 @@ fn validate_claim(
         return Err(Error::SupplyDecrease);
     }
 
-    let capacity_decrease = input_capacity - output_capacity;
+    Ok(winning_burned)
+}
+
+/// Whether a payout from a market resolved to `outcome` burns losing tokens
+/// Claims leave losing tokens alone, so burning them marks a complete-set redemption.
+fn losing_tokens_burned(outcome: bool, input_counts: &TokenCounts, output_counts: &TokenCounts) -> bool {
+    if outcome {
+        output_counts.no_tokens < input_counts.no_tokens
+    } else {
+        output_counts.yes_tokens < input_counts.yes_tokens
+    }
+}
+
+/// Validate claim transaction (winning tokens → CKB after resolution)
+fn validate_claim(
+    market_data: &MarketData,
+    weights: &CollateralWeights,
+    input_capacity: u64,
+    output_capacity: u64,
+    input_counts: &TokenCounts,
+    output_counts: &TokenCounts,
+) -> Result<(), Error> {
+    debug!("Validating claim transaction");
+
+    let winning_burned = claimed_winning_tokens(market_data.outcome, input_counts, output_counts)?;
 
-    // Validate 1:100 ratio (1 winning token = 100 CKB)
-    let expected_capacity_decrease = winning_burned
-        .checked_mul(SHANNONS_PER_TOKEN)
-        .ok_or(Error::Encoding)?;
+    let capacity_decrease = input_capacity - output_capacity;
 
-    let expected_capacity_u64: u64 = expected_capacity_decrease.try_into()
-        .map_err(|_| Error::Encoding)?;
+    // Validate payout ratio (1 winning token = weight × shannons_per_token)
+    let claim_ratio = weights.claim_ratio(market_data.outcome, market_data.shannons_per_token);
+    check_collateral_ratio(capacity_decrease, winning_burned, claim_ratio, market_data.decimals)?;
 
-    if capacity_decrease != expected_capacity_u64 {
-        debug!("Capacity decrease ({}) must equal tokens claimed ({}) at 100 CKB per token",
-               capacity_decrease, expected_capacity_u64);
-        return Err(Error::InsufficientCollateral);
+    // Fee markets divert part of the payout to the operator
+    if let Some(fee) = market_data.fee {
+        check_protocol_fee(&fee, capacity_decrease, net_capacity_to(&fee.recipient_lock_hash)?)?;
     }
 
     debug!("Claim validation passed: {} winning tokens claimed for {} CKB",
@@ -364,48 +1039,605 @@ fn validate_claim(
     Ok(())
 }
 
-/// Validate market state transition (input -> output)
-fn validate_transition(input_data: &MarketData, output_data: &MarketData) -> Result<(), Error> {
-    debug!("Validating market transition");
-    debug!("Input: resolved={}, outcome={}", input_data.resolved, input_data.outcome);
-    debug!("Output: resolved={}, outcome={}", output_data.resolved, output_data.outcome);
+/// Validate a claim on a resolved scalar market
+///
+/// Any mix of LONG (YES) and SHORT (NO) tokens may be burned; each pays its
+/// side's share of the set collateral at the resolved value. The outcome flag
+/// plays no part.
+fn validate_scalar_claim(
+    market_data: &MarketData,
+    scalar: &Scalar,
+    weights: &CollateralWeights,
+    input_capacity: u64,
+    output_capacity: u64,
+    input_counts: &TokenCounts,
+    output_counts: &TokenCounts,
+) -> Result<(), Error> {
+    debug!("Validating scalar claim at value {} in [{}, {}]", scalar.value, scalar.floor, scalar.ceiling);
 
-    // CRITICAL: Ensure lock script doesn't change (prevent hijacking)
-    validate_lock_preserved()?;
+    let long_burned = input_counts.yes_tokens.checked_sub(output_counts.yes_tokens).ok_or(Error::MintingDuringClaim)?;
+    let short_burned = input_counts.no_tokens.checked_sub(output_counts.no_tokens).ok_or(Error::MintingDuringClaim)?;
+    if long_burned == 0 && short_burned == 0 {
+        debug!("No LONG or SHORT tokens burned");
+        return Err(Error::SupplyDecrease);
+    }
 
-    // Validate token_code_hash and hash_type don't change
-    if input_data.token_code_hash != output_data.token_code_hash {
-        debug!("token_code_hash cannot change");
-        return Err(Error::InvalidMarketData);
+    let capacity_decrease = input_capacity - output_capacity;
+    let payout = scalar_payout(
+        scalar,
+        weights.set_ratio(market_data.shannons_per_token),
+        long_burned,
+        short_burned,
+        market_data.decimals,
+    )?;
+    if capacity_decrease != payout {
+        debug!("Capacity change ({}) must equal the scalar payout {} for {} LONG + {} SHORT",
+               capacity_decrease, payout, long_burned, short_burned);
+        return Err(Error::InsufficientCollateral);
     }
-    if input_data.hash_type != output_data.hash_type {
-        debug!("hash_type cannot change");
-        return Err(Error::InvalidMarketData);
+
+    if let Some(fee) = market_data.fee {
+        check_protocol_fee(&fee, capacity_decrease, net_capacity_to(&fee.recipient_lock_hash)?)?;
     }
 
-    // Load capacities to determine operation type
-    let input_capacity = load_market_capacity(Source::Input)?;
-    let output_capacity = load_market_capacity(Source::Output)?;
+    debug!("Scalar claim validation passed");
+    Ok(())
+}
 
-    // Derive expected token type script hashes
-    let market_script = load_script()?;
-    let market_type_hash_full = market_script.calc_script_hash();
-    let mut market_type_hash = [0u8; 32];
-    market_type_hash.copy_from_slice(market_type_hash_full.as_slice());
+/// Shannons owed for `long` LONG and `short` SHORT tokens of a resolved
+/// scalar market whose complete set holds `set_ratio` shannons
+fn scalar_payout(scalar: &Scalar, set_ratio: u128, long: u128, short: u128, decimals: u8) -> Result<u64, Error> {
+    let scale = 10u128.checked_pow(decimals as u32).ok_or(Error::Encoding)?;
+    let long_shannons = long.checked_mul(scalar.long_ratio(set_ratio)).ok_or(Error::Encoding)?;
+    let short_shannons = short.checked_mul(scalar.short_ratio(set_ratio)).ok_or(Error::Encoding)?;
+    let shannons = long_shannons.checked_add(short_shannons).ok_or(Error::Encoding)?;
+    if shannons % scale != 0 {
+        debug!("{} LONG + {} SHORT base units are worth a fraction of a shannon", long, short);
+        return Err(Error::FractionalCollateral);
+    }
+    (shannons / scale).try_into().map_err(|_| Error::Encoding)
+}
 
-    let expected_yes_hash = derive_token_type_hash(
-        &input_data.token_code_hash,
-        input_data.hash_type,
-        &market_type_hash,
-        0x01,
-    )?;
+/// Check that a claim paying out `payout` shannons gave the fee recipient at
+/// least the fee it owes (`received`, net of the recipient's own inputs)
+fn check_protocol_fee(fee: &ProtocolFee, payout: u64, received: u64) -> Result<(), Error> {
+    let owed = fee.fee_on(payout);
+    if received < owed {
+        debug!("Claim of {} shannons owes {} to the fee recipient, which received {}", payout, owed, received);
+        return Err(Error::MissingProtocolFee);
+    }
+    Ok(())
+}
 
-    let expected_no_hash = derive_token_type_hash(
-        &input_data.token_code_hash,
-        input_data.hash_type,
-        &market_type_hash,
-        0x02,
-    )?;
+/// Capacity the transaction moves to cells locked by `lock_hash`: their
+/// outputs minus their inputs, so the recipient's own cells passing through
+/// don't count as payment
+fn net_capacity_to(lock_hash: &[u8; 32]) -> Result<u64, Error> {
+    let total = |source: Source| -> Result<u128, Error> {
+        let mut sum = 0u128;
+        for (i, cell_lock_hash) in QueryIter::new(load_cell_lock_hash, source).enumerate() {
+            if &cell_lock_hash == lock_hash {
+                sum += load_cell_capacity(i, source)? as u128;
+            }
+        }
+        Ok(sum)
+    };
+    let received = total(Source::Output)?.saturating_sub(total(Source::Input)?);
+    Ok(received.try_into().unwrap_or(u64::MAX))
+}
+
+/// Validate a complete-set burn (N YES + N NO → N sets of collateral)
+/// Used for burns on open markets and for refunds after cancellation.
+fn validate_complete_set_burn(
+    market_data: &MarketData,
+    weights: &CollateralWeights,
+    input_capacity: u64,
+    output_capacity: u64,
+    input_counts: &TokenCounts,
+    output_counts: &TokenCounts,
+) -> Result<(), Error> {
+    // Calculate complete sets burned (equal YES/NO decrease)
+    let sets_burned = complete_sets_delta(output_counts, input_counts)?;
+
+    let capacity_decrease = input_capacity - output_capacity;
+
+    // Validate capacity decrease matches supply decrease
+    // 1 YES + 1 NO (complete set) = set weight × shannons_per_token
+    // So burning N YES + N NO should return N × set weight × shannons_per_token
+    check_collateral_ratio(capacity_decrease, sets_burned, weights.set_ratio(market_data.shannons_per_token), market_data.decimals)?;
+
+    debug!("Burning validation passed: -{} CKB capacity for {} complete sets",
+           capacity_decrease / 100_000_000, sets_burned);
+    Ok(())
+}
+
+/// Validate resolution proposal (unresolved, nothing pending -> outcome pending)
+fn validate_propose(
+    input_data: &MarketData,
+    output_data: &MarketData,
+    input_capacity: u64,
+    output_capacity: u64,
+) -> Result<(), Error> {
+    debug!("Resolution proposal detected");
+
+    // Proposing must not move collateral (token counts are checked by the
+    // unchanged-capacity branch of the caller)
+    if output_capacity != input_capacity {
+        debug!("Capacity cannot change while proposing a resolution");
+        return Err(Error::InvalidMarketData);
+    }
+
+    if output_data.outcome != input_data.outcome {
+        debug!("Outcome cannot change while proposing a resolution");
+        return Err(Error::InvalidMarketData);
+    }
+
+    if output_data.proposed_at == 0 {
+        debug!("proposed_at must be set when proposing a resolution");
+        return Err(Error::InvalidMarketData);
+    }
+
+    // A cancellation may come early (the event might never happen), an outcome may not
+    if output_data.pending_outcome != Some(Proposal::Cancel) {
+        validate_resolution_window(input_data.resolve_after)?;
+    }
+
+    debug!("Resolution proposal validation passed");
+    Ok(())
+}
+
+const SINCE_RELATIVE_FLAG: u64 = 1 << 63;
+const SINCE_METRIC_MASK: u64 = 0b11 << 61;
+const SINCE_METRIC_TIMESTAMP: u64 = 0b10 << 61;
+const SINCE_VALUE_MASK: u64 = 0x00ff_ffff_ffff_ffff;
+
+/// Unix seconds encoded in an absolute timestamp `since`, or None for any other kind
+fn absolute_timestamp_since(since: u64) -> Option<u64> {
+    if since & SINCE_RELATIVE_FLAG != 0 || since & SINCE_METRIC_MASK != SINCE_METRIC_TIMESTAMP {
+        return None;
+    }
+
+    Some(since & SINCE_VALUE_MASK)
+}
+
+/// Block number encoded in an absolute block-number `since`, or None for any other kind
+fn absolute_block_since(since: u64) -> Option<u64> {
+    if since & (SINCE_RELATIVE_FLAG | SINCE_METRIC_MASK) != 0 {
+        return None;
+    }
+
+    Some(since & SINCE_VALUE_MASK)
+}
+
+/// Seconds encoded in a relative timestamp `since`, or None for any other kind
+fn relative_timestamp_since(since: u64) -> Option<u64> {
+    if since & SINCE_RELATIVE_FLAG == 0 || since & SINCE_METRIC_MASK != SINCE_METRIC_TIMESTAMP {
+        return None;
+    }
+
+    Some(since & SINCE_VALUE_MASK)
+}
+
+/// Check that the market input's absolute timestamp `since` is at or past
+/// `resolve_after`
+///
+/// The `since` can only be satisfied once the chain's median time has reached
+/// it, which keeps outcomes from being proposed before the event.
+fn validate_resolution_window(resolve_after: u64) -> Result<(), Error> {
+    if resolve_after == 0 {
+        return Ok(());
+    }
+
+    let since = load_input_since(0, Source::GroupInput)?;
+    match absolute_timestamp_since(since) {
+        Some(timestamp) if timestamp >= resolve_after => Ok(()),
+        _ => {
+            debug!("Proposal needs an absolute timestamp since at or after {}, got {:#x}", resolve_after, since);
+            Err(Error::ResolutionTooEarly)
+        }
+    }
+}
+
+/// Check that the market input waited at least RESOLUTION_DELAY_SECONDS
+///
+/// The market cell is recreated by every transaction, so a relative
+/// timestamp `since` on the market input measures how long the proposal
+/// has stood unchanged on-chain.
+fn validate_finalize_delay() -> Result<(), Error> {
+    let since = load_input_since(0, Source::GroupInput)?;
+
+    let elapsed = match relative_timestamp_since(since) {
+        Some(elapsed) => elapsed,
+        None => {
+            debug!("Finalize requires a relative timestamp since on the market input, got {:#x}", since);
+            return Err(Error::FinalizeTooEarly);
+        }
+    };
+
+    if elapsed < RESOLUTION_DELAY_SECONDS {
+        debug!("Finalize delay {}s is shorter than required {}s", elapsed, RESOLUTION_DELAY_SECONDS);
+        return Err(Error::FinalizeTooEarly);
+    }
+
+    Ok(())
+}
+
+/// Validate an amendment of a pending proposal (pending -> pending with a new
+/// outcome or value, or pending -> open when withdrawn)
+///
+/// Only a market's resolver or oracle quorum may amend, signing
+/// amendment_message; a permissionless market's proposal is fixed, as
+/// anyone could otherwise keep restarting it. The market cell is recreated,
+/// so the finalize delay starts over from the amendment.
+#[allow(clippy::too_many_arguments)]
+fn validate_amendment(
+    input_data: &MarketData,
+    output_data: &MarketData,
+    input_capacity: u64,
+    output_capacity: u64,
+    input_counts: &TokenCounts,
+    output_counts: &TokenCounts,
+    market_type_hash: &[u8; 32],
+    market_args: &[u8],
+) -> Result<(), Error> {
+    debug!("Pending proposal amendment detected");
+
+    // Claims, mints and burns wait for finalization
+    if output_capacity != input_capacity
+        || input_counts.yes_tokens != output_counts.yes_tokens
+        || input_counts.no_tokens != output_counts.no_tokens
+    {
+        debug!("Pending resolution must be finalized before any other operation");
+        return Err(Error::ResolutionPending);
+    }
+
+    if output_data.outcome != input_data.outcome || output_data.cancelled != input_data.cancelled {
+        debug!("Outcome cannot change while amending a proposal");
+        return Err(Error::InvalidMarketData);
+    }
+
+    match output_data.pending_outcome {
+        Some(proposal) => {
+            if output_data.proposed_at == 0 {
+                debug!("proposed_at must be set when amending a proposal");
+                return Err(Error::InvalidMarketData);
+            }
+            if proposal != Proposal::Cancel {
+                validate_resolution_window(input_data.resolve_after)?;
+            }
+        }
+        None => {
+            if output_data.proposed_at != 0 {
+                debug!("Withdrawing a proposal must clear proposed_at");
+                return Err(Error::InvalidMarketData);
+            }
+        }
+    }
+
+    let market_outpoint = load_input(0, Source::GroupInput)?.previous_output();
+    let message = amendment_message(market_type_hash, market_outpoint.as_slice(), output_data.pending_outcome, output_data.scalar);
+    if let Some(quorum) = oracle_quorum(market_args)? {
+        quorum.verify(&message, &load_oracle_signatures()?)?;
+        debug!("Amendment signed by an oracle quorum of {}", quorum.threshold);
+    } else if let Some(resolver) = market_resolver(market_args)? {
+        if !has_witness(&resolver, &message) {
+            debug!("Amendment is not signed by the market's resolver");
+            return Err(Error::UnauthorizedResolver);
+        }
+        debug!("Amendment signed by the resolver");
+    } else {
+        debug!("Market has no resolver or oracle quorum to amend its proposal");
+        return Err(Error::ProposalFixed);
+    }
+
+    debug!("Proposal amendment validation passed");
+    Ok(())
+}
+
+/// Validate resolution finalization (outcome pending -> resolved)
+fn validate_finalize(
+    input_data: &MarketData,
+    output_data: &MarketData,
+    input_capacity: u64,
+    output_capacity: u64,
+    input_counts: &TokenCounts,
+    output_counts: &TokenCounts,
+    has_resolver: bool,
+) -> Result<(), Error> {
+    debug!("Market has a pending resolution, only finalization is allowed");
+
+    let pending_outcome = input_data.pending_outcome.ok_or(Error::ResolutionNotProposed)?;
+
+    // Nothing but finalization may touch a market with a pending proposal
+    if !output_data.resolved {
+        debug!("Pending resolution must be finalized before any other operation");
+        return Err(Error::ResolutionPending);
+    }
+
+    if !pending_outcome.is_finalized_in(output_data) {
+        debug!("Finalized outcome must match the proposed outcome");
+        return Err(Error::InvalidMarketData);
+    }
+
+    if output_data.pending_outcome.is_some() || output_data.proposed_at != input_data.proposed_at {
+        debug!("Finalization must clear the proposal and keep proposed_at");
+        return Err(Error::InvalidMarketData);
+    }
+
+    if output_capacity != input_capacity
+        || input_counts.yes_tokens != output_counts.yes_tokens
+        || input_counts.no_tokens != output_counts.no_tokens
+    {
+        debug!("Capacity and token counts cannot change during finalization");
+        return Err(Error::InvalidMarketData);
+    }
+
+    validate_finalize_delay()?;
+
+    // Resolver markets open a dispute period counted from the proposal's block
+    let dispute_until = if has_resolver {
+        market_input_block()?.checked_add(DISPUTE_PERIOD_BLOCKS).ok_or(Error::Encoding)?
+    } else {
+        0
+    };
+    if output_data.dispute_until != dispute_until {
+        debug!("dispute_until must be {}, got {}", dispute_until, output_data.dispute_until);
+        return Err(Error::InvalidMarketData);
+    }
+
+    debug!("Finalization validation passed");
+    Ok(())
+}
+
+/// Block the market input was committed in (its block hash must be a header dep)
+fn market_input_block() -> Result<u64, Error> {
+    Ok(load_header(0, Source::GroupInput)?.raw().number().unpack())
+}
+
+/// Check that the dispute period is over before collateral is paid out
+///
+/// An absolute block-number `since` on the market input proves the tip has
+/// reached `dispute_until`.
+fn validate_dispute_over(dispute_until: u64) -> Result<(), Error> {
+    if dispute_until == 0 {
+        return Ok(());
+    }
+
+    let since = load_input_since(0, Source::GroupInput)?;
+    match absolute_block_since(since) {
+        Some(block) if block >= dispute_until => Ok(()),
+        _ => {
+            debug!("Payouts need an absolute block-number since at or after {}, got {:#x}", dispute_until, since);
+            Err(Error::ClaimDuringDispute)
+        }
+    }
+}
+
+/// Validate the resolver correcting a finalized outcome during the dispute period
+///
+/// Nothing but the outcome may change. The market cell being spent must have
+/// been created before `dispute_until`; since claims only unlock after it, a
+/// correction is impossible once anyone has claimed.
+fn validate_correction(
+    input_data: &MarketData,
+    output_data: &MarketData,
+    input_capacity: u64,
+    output_capacity: u64,
+    market_type_hash: &[u8; 32],
+    market_args: &[u8],
+) -> Result<(), Error> {
+    debug!("Outcome correction detected");
+
+    let Some(resolver) = market_resolver(market_args)? else {
+        debug!("Outcome cannot change after resolution");
+        return Err(Error::InvalidMarketData);
+    };
+
+    if input_data.dispute_until == 0 || market_input_block()? >= input_data.dispute_until {
+        debug!("Dispute period ended at block {}", input_data.dispute_until);
+        return Err(Error::DisputeClosed);
+    }
+
+    if output_capacity != input_capacity {
+        debug!("A correction cannot move collateral");
+        return Err(Error::InvalidMarketData);
+    }
+
+    let corrected = if output_data.cancelled {
+        Proposal::Cancel
+    } else if output_data.outcome {
+        Proposal::Yes
+    } else {
+        Proposal::No
+    };
+    let market_outpoint = load_input(0, Source::GroupInput)?.previous_output();
+    if !has_witness(&resolver, &correction_message(market_type_hash, market_outpoint.as_slice(), corrected)) {
+        debug!("Correction is not signed by the market's resolver");
+        return Err(Error::UnauthorizedResolver);
+    }
+
+    debug!("Outcome corrected by the resolver");
+    Ok(())
+}
+
+/// Validate a sweep of unclaimed collateral (resolved -> resolved and swept)
+///
+/// The market input's absolute timestamp `since` proves the claim deadline has
+/// passed, and an input locked by the sweeper authorizes the withdrawal. The
+/// market keeps only its occupied capacity; tokens are not touched.
+fn validate_sweep(
+    input_data: &MarketData,
+    output_capacity: u64,
+    input_counts: &TokenCounts,
+    output_counts: &TokenCounts,
+) -> Result<(), Error> {
+    debug!("Sweep of unclaimed collateral detected");
+
+    let sweep = input_data.sweep.ok_or(Error::InvalidMarketData)?;
+
+    let since = load_input_since(0, Source::GroupInput)?;
+    match absolute_timestamp_since(since) {
+        Some(timestamp) if timestamp >= sweep.claim_deadline => {}
+        _ => {
+            debug!("Sweep needs an absolute timestamp since at or after the claim deadline {}, got {:#x}",
+                   sweep.claim_deadline, since);
+            return Err(Error::SweepTooEarly);
+        }
+    }
+
+    let authorized = QueryIter::new(load_cell_lock_hash, Source::Input)
+        .any(|lock_hash| lock_hash == sweep.sweeper_lock_hash);
+    if !authorized {
+        debug!("Sweep must spend an input locked by the sweeper");
+        return Err(Error::SweepUnauthorized);
+    }
+
+    let index = find_market_output_index()? as usize;
+    if output_capacity != load_cell_occupied_capacity(index, Source::Output)? {
+        debug!("Sweep must leave the market with exactly its occupied capacity");
+        return Err(Error::InvalidMarketData);
+    }
+
+    if input_counts.yes_tokens != output_counts.yes_tokens || input_counts.no_tokens != output_counts.no_tokens {
+        debug!("Token counts cannot change during a sweep");
+        return Err(Error::InvalidMarketData);
+    }
+
+    debug!("Sweep validation passed");
+    Ok(())
+}
+
+/// Validate a close (market input, no market output)
+///
+/// The market's remaining capacity is released. When the market has sweep
+/// terms, the sweeper must authorize the close as it would a sweep, and no
+/// tokens of the market may be created on the way out.
+fn validate_close(input_data: &MarketData) -> Result<(), Error> {
+    debug!("Market close detected");
+
+    if let Some(sweep) = input_data.sweep {
+        let authorized = QueryIter::new(load_cell_lock_hash, Source::Input)
+            .any(|lock_hash| lock_hash == sweep.sweeper_lock_hash);
+        if !authorized {
+            debug!("Close must spend an input locked by the sweeper");
+            return Err(Error::SweepUnauthorized);
+        }
+    }
+
+    let (expected_yes_hash, expected_no_hash) = expected_token_hashes(input_data)?;
+    let output_counts = count_tokens(Source::Output, &expected_yes_hash, &expected_no_hash)?;
+    if output_counts.yes_tokens != 0 || output_counts.no_tokens != 0 {
+        debug!("A closing market cannot leave tokens behind in outputs");
+        return Err(Error::MintingDuringClaim);
+    }
+
+    debug!("Close validation passed");
+    Ok(())
+}
+
+/// Validate market state transition (input -> output)
+fn validate_transition(input_data: &MarketData, output_data: &MarketData) -> Result<(), Error> {
+    debug!("Validating market transition");
+    debug!("Input: resolved={}, outcome={}", input_data.resolved, input_data.outcome);
+    debug!("Output: resolved={}, outcome={}", output_data.resolved, output_data.outcome);
+
+    // CRITICAL: Ensure lock script doesn't change (prevent hijacking)
+    validate_lock_preserved()?;
+
+    // Validate token_code_hash and hash_type don't change
+    if input_data.token_code_hash != output_data.token_code_hash {
+        debug!("token_code_hash cannot change");
+        return Err(Error::InvalidMarketData);
+    }
+    if input_data.hash_type != output_data.hash_type {
+        debug!("hash_type cannot change");
+        return Err(Error::InvalidMarketData);
+    }
+
+    // Seed reserves are a record of the creation
+    if input_data.seed_reserves != output_data.seed_reserves {
+        debug!("Seed reserves cannot change");
+        return Err(Error::InvalidMarketData);
+    }
+
+    // Token denomination is fixed at creation
+    if input_data.decimals != output_data.decimals {
+        debug!("decimals cannot change");
+        return Err(Error::InvalidMarketData);
+    }
+
+    // So is the collateral unit every mint, burn and claim is priced in
+    if input_data.shannons_per_token != output_data.shannons_per_token {
+        debug!("shannons_per_token cannot change");
+        return Err(Error::InvalidMarketData);
+    }
+
+    // So is the resolution window
+    if input_data.resolve_after != output_data.resolve_after {
+        debug!("resolve_after cannot change");
+        return Err(Error::InvalidMarketData);
+    }
+
+    // So is the operator's fee
+    if input_data.fee != output_data.fee {
+        debug!("Protocol fee cannot change");
+        return Err(Error::InvalidMarketData);
+    }
+
+    // A scalar market's range is fixed; its value is written by the proposal
+    // and carried unchanged from then on
+    let scalar_range = |data: &MarketData| data.scalar.map(|scalar| (scalar.floor, scalar.ceiling));
+    if scalar_range(input_data) != scalar_range(output_data) {
+        debug!("Scalar range cannot change");
+        return Err(Error::InvalidMarketData);
+    }
+    // (or an amendment of one)
+    let proposing = !input_data.resolved && output_data.pending_outcome.is_some();
+    if !proposing && input_data.scalar != output_data.scalar {
+        debug!("Scalar value can only be set by a resolution proposal or its amendment");
+        return Err(Error::InvalidMarketData);
+    }
+
+    // And so is what the market is about
+    if input_data.metadata != output_data.metadata {
+        debug!("Metadata cannot change");
+        return Err(Error::InvalidMarketData);
+    }
+
+    // The dispute period is set by finalization alone (see validate_finalize)
+    let finalizing = input_data.pending_outcome.is_some() && output_data.resolved;
+    if !finalizing && input_data.dispute_until != output_data.dispute_until {
+        debug!("dispute_until cannot change");
+        return Err(Error::InvalidMarketData);
+    }
+
+    // Sweep terms are fixed at creation; only a sweep flips the swept flag
+    let sweep_terms = |data: &MarketData| data.sweep.map(|sweep| (sweep.claim_deadline, sweep.sweeper_lock_hash));
+    if sweep_terms(input_data) != sweep_terms(output_data) {
+        debug!("Sweep terms cannot change");
+        return Err(Error::InvalidMarketData);
+    }
+    let sweeping = !input_data.is_swept() && output_data.is_swept();
+    if input_data.is_swept() && !output_data.is_swept() {
+        debug!("A swept market cannot be unswept");
+        return Err(Error::InvalidMarketData);
+    }
+    if sweeping && !input_data.resolved {
+        debug!("Only resolved markets can be swept");
+        return Err(Error::InvalidMarketData);
+    }
+
+    // Load capacities to determine operation type
+    let input_capacity = load_market_capacity(Source::Input)?;
+    let output_capacity = load_market_capacity(Source::Output)?;
+
+    // Derive expected token type script hashes
+    let market_script = load_script()?;
+    let market_type_hash_full = market_script.calc_script_hash();
+    let mut market_type_hash = [0u8; 32];
+    market_type_hash.copy_from_slice(market_type_hash_full.as_slice());
+    let market_args = market_script.args().raw_data();
+    let (expected_yes_hash, expected_no_hash) = expected_token_hashes(input_data)?;
 
     debug!("Expected YES token hash: {:?}", expected_yes_hash);
     debug!("Expected NO token hash: {:?}", expected_no_hash);
@@ -417,17 +1649,44 @@ fn validate_transition(input_data: &MarketData, output_data: &MarketData) -> Res
     debug!("Input tokens: YES={}, NO={}", input_counts.yes_tokens, input_counts.no_tokens);
     debug!("Output tokens: YES={}, NO={}", output_counts.yes_tokens, output_counts.no_tokens);
 
-    // 1 token = 100 CKB = 10_000_000_000 shannons
-    const SHANNONS_PER_TOKEN: u128 = 10_000_000_000;
+    // Whatever the operation, recorded supplies follow the token cells
+    supplies_tracked(input_data.supplies, output_data.supplies, &input_counts, &output_counts)?;
+
+    // Collateral per complete set and payout per winning token
+    let weights = CollateralWeights::from_args(&market_args)?;
 
     // Check if market is resolved - this determines how we validate
     if input_data.resolved {
-        // RESOLVED MARKET: Only allow claims (winning tokens → CKB)
-        debug!("Market is resolved with outcome: {}", if input_data.outcome { "YES" } else { "NO" });
-
-        if output_capacity < input_capacity {
+        // RESOLVED MARKET: Only allow claims (winning tokens → CKB),
+        // or refunds of complete sets if the market was cancelled
+        debug!("Market is resolved with outcome: {}",
+               if input_data.cancelled { "CANCELLED" } else if input_data.outcome { "YES" } else { "NO" });
+
+        if output_capacity < input_capacity && input_data.is_swept() {
+            // SWEPT: The collateral is gone, claims and refunds are disabled
+            debug!("Market was swept, nothing left to claim");
+            return Err(Error::MarketSwept);
+        } else if sweeping {
+            // SWEEP: The sweeper takes the collateral nobody claimed
+            validate_sweep(input_data, output_capacity, &input_counts, &output_counts)?;
+        } else if output_capacity < input_capacity && input_data.cancelled {
+            // REFUND: Complete-set holders get the full set collateral back
+            validate_dispute_over(input_data.dispute_until)?;
+            validate_complete_set_burn(input_data, &weights, input_capacity, output_capacity, &input_counts, &output_counts)?;
+        } else if let (true, Some(scalar)) = (output_capacity < input_capacity, input_data.scalar) {
+            // SCALAR CLAIM: LONG and SHORT tokens each pay their share of a set
+            validate_dispute_over(input_data.dispute_until)?;
+            validate_scalar_claim(input_data, &scalar, &weights, input_capacity, output_capacity, &input_counts, &output_counts)?;
+        } else if output_capacity < input_capacity && losing_tokens_burned(input_data.outcome, &input_counts, &output_counts) {
+            // REDEEM: A winning and a losing token together still return the
+            // full set collateral, which matters for weighted markets where a
+            // winner alone is paid only its own weight
+            validate_dispute_over(input_data.dispute_until)?;
+            validate_complete_set_burn(input_data, &weights, input_capacity, output_capacity, &input_counts, &output_counts)?;
+        } else if output_capacity < input_capacity {
             // CLAIM: User is burning winning tokens to withdraw CKB
-            validate_claim(input_data, input_capacity, output_capacity, &input_counts, &output_counts)?;
+            validate_dispute_over(input_data.dispute_until)?;
+            validate_claim(input_data, &weights, input_capacity, output_capacity, &input_counts, &output_counts)?;
         } else if output_capacity == input_capacity {
             // NO OPERATION: Token counts must not change
             if output_counts.yes_tokens != input_counts.yes_tokens || output_counts.no_tokens != input_counts.no_tokens {
@@ -446,12 +1705,27 @@ fn validate_transition(input_data: &MarketData, output_data: &MarketData) -> Res
             return Err(Error::InvalidMarketData);
         }
 
-        // Outcome cannot change
-        if output_data.outcome != input_data.outcome {
-            debug!("Outcome cannot change after resolution");
+        // Outcome cannot change, short of a correction by the resolver
+        if output_data.outcome != input_data.outcome || output_data.cancelled != input_data.cancelled {
+            validate_correction(input_data, output_data, input_capacity, output_capacity, &market_type_hash, &market_args)?;
+        }
+
+        // No new proposal on a resolved market
+        if output_data.pending_outcome.is_some() || output_data.proposed_at != input_data.proposed_at {
+            debug!("Resolution proposal cannot change after resolution");
             return Err(Error::InvalidMarketData);
         }
 
+    } else if input_data.pending_outcome.is_some() && !output_data.resolved {
+        // AMENDMENT: The resolver or quorum changes or withdraws the proposal
+        // (no mint/burn/claim)
+        validate_amendment(input_data, output_data, input_capacity, output_capacity, &input_counts, &output_counts, &market_type_hash, &market_args)?;
+
+    } else if input_data.pending_outcome.is_some() {
+        // PENDING RESOLUTION: Finalization once the proposal has stood
+        let has_resolver = market_resolver(&market_args)?.is_some();
+        validate_finalize(input_data, output_data, input_capacity, output_capacity, &input_counts, &output_counts, has_resolver)?;
+
     } else {
         // UNRESOLVED MARKET: Allow minting and burning of complete sets
 
@@ -459,87 +1733,39 @@ fn validate_transition(input_data: &MarketData, output_data: &MarketData) -> Res
             // BURNING: Market capacity decreased
             debug!("Burning operation detected: capacity {} -> {}", input_capacity, output_capacity);
 
-            // Calculate token changes
-            let yes_burned = input_counts.yes_tokens.checked_sub(output_counts.yes_tokens)
-                .ok_or(Error::Encoding)?;
-            let no_burned = input_counts.no_tokens.checked_sub(output_counts.no_tokens)
-                .ok_or(Error::Encoding)?;
-
-            if yes_burned == 0 && no_burned == 0 {
-                debug!("No tokens burned but capacity decreased");
-                return Err(Error::SupplyDecrease);
-            }
-
-            // Validate equal YES/NO burning
-            if yes_burned != no_burned {
-                debug!("Unequal burning: YES -{}, NO -{}", yes_burned, no_burned);
-                return Err(Error::UnequalSupplyIncrease);
-            }
-
-            let capacity_decrease = input_capacity - output_capacity;
-
-            // Validate capacity decrease matches supply decrease
-            // 100 CKB = 1 YES + 1 NO (complete set)
-            // So burning N YES + N NO should return N × 100 CKB
-            let expected_capacity_decrease = yes_burned
-                .checked_mul(SHANNONS_PER_TOKEN)
-                .ok_or(Error::Encoding)?;
-
-            let expected_capacity_u64: u64 = expected_capacity_decrease.try_into()
-                .map_err(|_| Error::Encoding)?;
-
-            if capacity_decrease != expected_capacity_u64 {
-                debug!("Capacity decrease ({}) must equal burned complete sets ({}) at 100 CKB per set",
-                       capacity_decrease, expected_capacity_u64);
-                debug!("Burned {} YES + {} NO complete sets",
-                       yes_burned, no_burned);
-                return Err(Error::InsufficientCollateral);
-            }
-
-            debug!("Burning validation passed: -{} CKB capacity for {} complete sets",
-                   capacity_decrease / 100_000_000, yes_burned);
-
-    } else if output_capacity > input_capacity {
+            validate_complete_set_burn(input_data, &weights, input_capacity, output_capacity, &input_counts, &output_counts)?;
+        } else if output_capacity > input_capacity {
         // MINTING: Market capacity increased
         debug!("Minting operation detected: capacity {} -> {}", input_capacity, output_capacity);
 
-        // Calculate token changes
-        let yes_minted = output_counts.yes_tokens.checked_sub(input_counts.yes_tokens)
-            .ok_or(Error::Encoding)?;
-        let no_minted = output_counts.no_tokens.checked_sub(input_counts.no_tokens)
-            .ok_or(Error::Encoding)?;
-
-        if yes_minted == 0 && no_minted == 0 {
-            debug!("No tokens minted but capacity increased");
-            return Err(Error::SupplyDecrease);
-        }
-
-        // Validate equal YES/NO minting
-        if yes_minted != no_minted {
-            debug!("Unequal minting: YES +{}, NO +{}", yes_minted, no_minted);
-            return Err(Error::UnequalSupplyIncrease);
-        }
+        // Calculate complete sets minted (equal YES/NO increase)
+        let sets_minted = complete_sets_delta(&input_counts, &output_counts)?;
 
         let capacity_increase = output_capacity - input_capacity;
 
         // Validate capacity increase matches supply increase
-        let supply_increase_shannons = yes_minted
-            .checked_mul(SHANNONS_PER_TOKEN)
-            .ok_or(Error::Encoding)?;
+        check_collateral_ratio(capacity_increase, sets_minted, weights.set_ratio(input_data.shannons_per_token), input_data.decimals)?;
+
+        // Prior token cells spent alongside the mint must stay with their locks
+        let input_balances = count_tokens_by_lock(Source::Input, &expected_yes_hash, &expected_no_hash)?;
+        let output_balances = count_tokens_by_lock(Source::Output, &expected_yes_hash, &expected_no_hash)?;
+        prior_tokens_kept(&input_balances, &output_balances)?;
 
-        let supply_increase_u64: u64 = supply_increase_shannons.try_into()
-            .map_err(|_| Error::Encoding)?;
+        // and, if the market names a token lock, the new tokens must go there
+        if let Some(lock_hash) = token_lock(&market_args)? {
+            minted_to_token_lock(&input_balances, &output_balances, &lock_hash)?;
+        }
 
-        if capacity_increase != supply_increase_u64 {
-            debug!("Capacity increase ({}) must equal supply increase in shannons ({})",
-                   capacity_increase, supply_increase_u64);
-            debug!("Token supply increased by {}, which is {} shannons (100 CKB per token)",
-                   yes_minted, supply_increase_u64);
-            return Err(Error::InsufficientCollateral);
+        let (_, flags) = split_market_args(&market_args)?;
+        if flags & FLAG_SINGLE_CELL_MINT != 0 {
+            single_cell_mint(
+                &count_token_cells(Source::Input, &expected_yes_hash, &expected_no_hash)?,
+                &count_token_cells(Source::Output, &expected_yes_hash, &expected_no_hash)?,
+            )?;
         }
 
-        debug!("Minting validation passed: +{} CKB capacity matches +{} tokens at 100 CKB/token",
-               capacity_increase / 100_000_000, yes_minted);
+        debug!("Minting validation passed: +{} CKB capacity matches +{} complete sets",
+               capacity_increase / 100_000_000, sets_minted);
         } else {
             // NO OPERATION: Capacity unchanged, token counts must also be unchanged
             debug!("No capacity change, validating token counts unchanged");
@@ -557,21 +1783,25 @@ fn validate_transition(input_data: &MarketData, output_data: &MarketData) -> Res
 
         // For unresolved markets, check if this is a resolution transaction
         if output_data.resolved {
-            // RESOLUTION TRANSACTION: resolved field changed from false to true
-            debug!("Resolution transaction detected");
-
-            // Token counts must not change during resolution
-            if input_counts.yes_tokens != output_counts.yes_tokens {
-                debug!("YES token count cannot change during resolution");
-                return Err(Error::InvalidMarketData);
+            // Resolution is two-step: an outcome must be proposed and then finalized
+            debug!("Cannot resolve directly, a resolution must be proposed first");
+            return Err(Error::ResolutionNotProposed);
+        } else if output_data.pending_outcome.is_some() {
+            // PROPOSAL TRANSACTION: pending outcome written, finalized later
+            validate_propose(input_data, output_data, input_capacity, output_capacity)?;
+
+            let market_outpoint = load_input(0, Source::GroupInput)?.previous_output();
+            if let (Some(quorum), Some(proposal)) = (oracle_quorum(&market_args)?, output_data.pending_outcome) {
+                quorum.verify(&proposed_message(&market_type_hash, market_outpoint.as_slice(), proposal, output_data.scalar), &load_oracle_signatures()?)?;
+                debug!("Oracle quorum of {} reached", quorum.threshold);
             }
-
-            if input_counts.no_tokens != output_counts.no_tokens {
-                debug!("NO token count cannot change during resolution");
-                return Err(Error::InvalidMarketData);
+            if let (Some(resolver), Some(proposal)) = (market_resolver(&market_args)?, output_data.pending_outcome) {
+                if !has_witness(&resolver, &proposed_message(&market_type_hash, market_outpoint.as_slice(), proposal, output_data.scalar)) {
+                    debug!("Proposal is not signed by the market's resolver");
+                    return Err(Error::UnauthorizedResolver);
+                }
+                debug!("Resolver signature verified");
             }
-
-            debug!("Resolution validation passed");
         } else {
             // MINTING/BURNING TRANSACTION
             // Outcome must not change when market is unresolved
@@ -579,6 +1809,11 @@ fn validate_transition(input_data: &MarketData, output_data: &MarketData) -> Res
                 debug!("Outcome cannot change during minting/burning");
                 return Err(Error::InvalidMarketData);
             }
+
+            if output_data.proposed_at != input_data.proposed_at {
+                debug!("proposed_at cannot change during minting/burning");
+                return Err(Error::InvalidMarketData);
+            }
         }
     }
 
@@ -602,46 +1837,54 @@ fn find_market_output_index() -> Result<u64, Error> {
     Err(Error::ItemMissing)
 }
 
+/// Canonical CKB Type ID: blake2b(first CellInput || output_index as u64 LE)
+///
+/// The whole serialized input (since and outpoint, 44 bytes) is hashed, as
+/// by the built-in type_id script and ckb-sdk's calculate_type_id, so Type
+/// IDs computed by standard tooling are accepted. The preimage comes from
+/// market-common, which clients use to predict the id before creation.
+fn type_id_hash(first_input: &[u8; CELL_INPUT_LEN], output_index: u64) -> [u8; 32] {
+    let data = type_id_preimage(first_input, output_index);
+
+    // calc_data_hash is ckb-default-hash blake2b
+    let hash = ckb_std::ckb_types::packed::CellOutput::calc_data_hash(&data);
+    let mut type_id = [0u8; 32];
+    type_id.copy_from_slice(hash.as_slice());
+    type_id
+}
+
 /// Validate Type ID in type script args
 fn validate_type_id(input_count: usize) -> Result<(), Error> {
     let script = load_script()?;
     let args = script.args().raw_data();
 
-    // Type ID must be exactly 32 bytes
-    if args.len() != 32 {
-        debug!("Type ID args must be 32 bytes, got {}", args.len());
-        return Err(Error::InvalidTypeId);
-    }
+    // Type ID is 32 bytes, optionally followed by 8 bytes of outcome weights
+    // and a flags byte
+    split_market_args(&args)?;
 
     if input_count == 0 {
         // CREATION: Validate Type ID is correctly derived from first input
         debug!("Validating Type ID creation");
 
-        // Load first input's previous output (outpoint)
         let first_input = load_input(0, Source::Input)?;
-        let outpoint = first_input.previous_output();
 
         // Find the output index of the market cell
         let output_index = find_market_output_index()?;
 
-        // Calculate expected Type ID: blake2b(outpoint || output_index)
-        let mut data = Vec::new();
-        data.extend_from_slice(outpoint.as_slice());
-        data.extend_from_slice(&output_index.to_le_bytes());
-
-        // Use CKB's calc_data_hash which uses blake2b internally
-        let hash = ckb_std::ckb_types::packed::CellOutput::calc_data_hash(&data);
-        let mut expected_type_id = [0u8; 32];
-        expected_type_id.copy_from_slice(hash.as_slice());
+        let first_input: &[u8; CELL_INPUT_LEN] = first_input.as_slice().try_into().map_err(|_| Error::Encoding)?;
+        let expected_type_id = type_id_hash(first_input, output_index);
 
-        // Compare with actual args
-        if args.as_ref() != expected_type_id.as_ref() {
+        // Compare with actual args (weights, if any, follow the Type ID)
+        if &args[..TYPE_ID_ARGS_LEN] != expected_type_id.as_ref() {
             debug!("Type ID mismatch on creation");
             debug!("Expected: {:?}", expected_type_id);
-            debug!("Got: {:?}", args.as_ref());
+            debug!("Got: {:?}", &args[..TYPE_ID_ARGS_LEN]);
             return Err(Error::InvalidTypeId);
         }
 
+        // Weights and flags are fixed for the life of the market (persistence checks full args)
+        CollateralWeights::from_args(&args)?;
+
         debug!("Type ID creation validated successfully");
     } else {
         // UPDATE: output args must match input args
@@ -684,7 +1927,18 @@ fn main() -> Result<(), Error> {
 
     debug!("Market cells: {} inputs, {} outputs", input_count, output_count);
 
-    // There should be exactly one market cell in outputs
+    // A finished market may be consumed outright; anything else must carry
+    // exactly one market cell forward
+    if input_count == 1 && output_count == 0 {
+        let input_data = load_market_data(Source::Input)?;
+        if input_data.is_closable() {
+            return validate_close(&input_data);
+        }
+    }
+
+    // There should be exactly one market cell in outputs. Only cells of this
+    // script (same Type ID) count: another market in the same transaction is
+    // its own script group, validated by its own run against its own tokens
     if output_count != 1 {
         debug!("Must have exactly 1 market cell in outputs");
         return Err(Error::MultipleMarketCells);
@@ -698,6 +1952,7 @@ fn main() -> Result<(), Error> {
     if input_count == 0 {
         // MARKET CREATION
         validate_creation(&output_data)?;
+        validate_creation_seed(&output_data)?;
     } else if input_count == 1 {
         // MARKET STATE TRANSITION
         let input_data = load_market_data(Source::Input)?;
@@ -716,3 +1971,492 @@ ckb_std::entry!(program_entry);
 
 #[cfg(not(test))]
 ckb_std::default_alloc!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use market_common::{DEFAULT_SHANNONS_PER_TOKEN, MAX_DECIMALS};
+
+    fn counts(yes_tokens: u128, no_tokens: u128) -> TokenCounts {
+        TokenCounts { yes_tokens, no_tokens }
+    }
+
+    #[test]
+    fn collateral_unit_prices_sets() {
+        let weights = CollateralWeights::from_args(&[0x33; 32]).unwrap();
+        assert_eq!(check_collateral_ratio(10_00000000, 2, weights.set_ratio(5_00000000), 0).ok(), Some(()));
+        assert_eq!(check_collateral_ratio(200_00000000, 2, weights.set_ratio(5_00000000), 0).err(), Some(Error::InsufficientCollateral));
+    }
+
+    #[test]
+    fn supplies_follow_minted_and_burned_tokens() {
+        let supplies = |yes, no| Some(Supplies { yes, no });
+        // Mint 3 sets alongside 2 YES already held
+        assert_eq!(supplies_tracked(supplies(5, 5), supplies(8, 8), &counts(2, 0), &counts(5, 3)).ok(), Some(()));
+        // Claim 2 YES
+        assert_eq!(supplies_tracked(supplies(8, 8), supplies(6, 8), &counts(2, 0), &counts(0, 0)).ok(), Some(()));
+        assert_eq!(supplies_tracked(supplies(8, 8), supplies(8, 8), &counts(2, 0), &counts(0, 0)).err(),
+                   Some(Error::InvalidMarketData));
+        // Burning more than was recorded
+        assert_eq!(supplies_tracked(supplies(1, 1), supplies(0, 0), &counts(2, 2), &counts(0, 0)).err(),
+                   Some(Error::InvalidMarketData));
+        assert_eq!(supplies_tracked(None, None, &counts(0, 0), &counts(5, 5)).ok(), Some(()));
+        assert_eq!(supplies_tracked(supplies(5, 5), None, &counts(0, 0), &counts(0, 0)).err(), Some(Error::InvalidMarketData));
+        assert_eq!(supplies_tracked(None, supplies(0, 0), &counts(0, 0), &counts(0, 0)).err(), Some(Error::InvalidMarketData));
+    }
+
+    #[test]
+    fn creation_rejects_zero_collateral_unit() {
+        let mut market = MarketData::new([0xab; 32], 1);
+        assert_eq!(validate_creation(&market).ok(), Some(()));
+        market.shannons_per_token = 0;
+        assert_eq!(validate_creation(&market).err(), Some(Error::InvalidMarketData));
+    }
+
+    #[test]
+    fn non_boolean_flag_bytes_are_invalid_market_data() {
+        let mut market = MarketData::new([0xab; 32], 1);
+        market.resolved = true;
+        let bytes = market.to_bytes();
+        let parse = |offset: usize, value: u8| {
+            let mut bytes = bytes.clone();
+            bytes[offset] = value;
+            MarketData::from_bytes(&bytes).map_err(Error::from).err()
+        };
+        assert_eq!(parse(33, 0x01), None);
+        assert_eq!(parse(33, 0x02), Some(Error::InvalidMarketData));
+        assert_eq!(parse(34, 0x02), None);
+        assert_eq!(parse(34, 0x03), Some(Error::InvalidMarketData));
+    }
+
+    #[test]
+    fn scalar_payout_at_floor_midpoint_and_ceiling() {
+        let set = DEFAULT_SHANNONS_PER_TOKEN as u128;
+        let at = |value| Scalar { floor: 20, ceiling: 30, value };
+        // At the floor SHORT takes the whole set, at the ceiling LONG does
+        assert_eq!(scalar_payout(&at(20), set, 4, 0, 0).ok(), Some(0));
+        assert_eq!(scalar_payout(&at(20), set, 0, 4, 0).ok(), Some(400_00000000));
+        assert_eq!(scalar_payout(&at(30), set, 4, 0, 0).ok(), Some(400_00000000));
+        assert_eq!(scalar_payout(&at(30), set, 0, 4, 0).ok(), Some(0));
+        // Halfway each side pays half a set, and a LONG + SHORT pair a whole one
+        assert_eq!(scalar_payout(&at(25), set, 4, 0, 0).ok(), Some(200_00000000));
+        assert_eq!(scalar_payout(&at(25), set, 0, 4, 0).ok(), Some(200_00000000));
+        assert_eq!(scalar_payout(&at(27), set, 3, 3, 0).ok(), Some(300_00000000));
+        // Base units at 8 decimals must come to whole shannons
+        assert_eq!(scalar_payout(&at(25), set, 1, 0, 8).ok(), Some(50));
+        assert_eq!(scalar_payout(&at(25), 3, 1, 0, 8).err(), Some(Error::FractionalCollateral));
+    }
+
+    #[test]
+    fn since_timestamp_kinds() {
+        let absolute = (0b10 << 61) | 1_798_761_600;
+        assert_eq!(absolute_timestamp_since(absolute), Some(1_798_761_600));
+        assert_eq!(relative_timestamp_since(absolute), None);
+        assert_eq!(absolute_timestamp_since(absolute | SINCE_RELATIVE_FLAG), None);
+        assert_eq!(absolute_timestamp_since(1_798_761_600), None);
+    }
+
+    #[test]
+    fn derive_token_type_hash_known_vectors() {
+        // blake2b("ckb-default-hash") of the molecule Script
+        // { code_hash: 0xab.., hash_type, args: 0x11.. || token_id [|| flags] }
+        let vectors: [(u8, u8, u8, &str); 6] = [
+            (1, 0x01, 0, "2515b047bf97b65054e61e3b22785054308e5d2f3899dd4e0a3e25b65c2f0b41"),
+            (2, 0x01, 0, "4dd3be66329aee52ff9d887a7410ad77103a24900ad30df1746560adeadaec08"),
+            (4, 0x01, 0, "281b0e4f49351de9d58c8a512de85b234b9db418f90367be13392a8a0266b978"),
+            (2, 0x02, 0, "1a79d19d9bca04528969ccbbc632aaecbc9f52de278749bdf0b9b065b8792111"),
+            (2, 0x03, 0, "228a52fb51f079004af342dac453c7bf86546dd7a2e4b97c54cf491d1f14dcf3"),
+            (2, 0x01, FLAG_FREEZE_AFTER_RESOLUTION,
+             "dab8bb0d6121991550d4872fafd10f6c777e472b6b1baad5ce9ac249b679eede"),
+        ];
+
+        for (hash_type, token_id, flags, expected) in vectors {
+            let hash = derive_token_type_hash(&[0xab; 32], hash_type, &[0x11; 32], token_id, flags)
+                .ok()
+                .unwrap();
+            let mut expected_bytes = [0u8; 32];
+            for (i, byte) in expected_bytes.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&expected[i * 2..i * 2 + 2], 16).unwrap();
+            }
+            assert_eq!(hash, expected_bytes);
+        }
+    }
+
+    #[test]
+    fn derive_token_type_hash_rejects_unknown_hash_type() {
+        assert_eq!(derive_token_type_hash(&[0xab; 32], 3, &[0x11; 32], 0x01, 0).err(), Some(Error::Encoding));
+    }
+
+    #[test]
+    fn complete_sets_delta_requires_equal_change() {
+        assert_eq!(complete_sets_delta(&counts(10, 10), &counts(15, 15)).ok(), Some(5));
+        assert_eq!(complete_sets_delta(&counts(10, 10), &counts(15, 14)).err(), Some(Error::UnequalSupplyIncrease));
+        assert_eq!(complete_sets_delta(&counts(10, 10), &counts(10, 10)).err(), Some(Error::SupplyDecrease));
+        assert_eq!(complete_sets_delta(&counts(10, 10), &counts(9, 9)).err(), Some(Error::Encoding));
+    }
+
+    #[test]
+    fn complete_sets_need_every_outcome() {
+        assert_eq!(complete_sets_delta(&counts(0, 0), &counts(2, 2)).ok(), Some(2));
+        assert_eq!(complete_sets_delta(&counts(0, 0), &counts(2, 0)).err(), Some(Error::UnequalSupplyIncrease));
+        assert_eq!(complete_sets_delta(&counts(5, 5), &counts(5, 7)).err(), Some(Error::UnequalSupplyIncrease));
+    }
+
+    #[test]
+    fn claimed_winning_tokens_leaves_losers_untouched() {
+        assert_eq!(claimed_winning_tokens(true, &counts(10, 4), &counts(3, 4)).ok(), Some(7));
+        assert_eq!(claimed_winning_tokens(false, &counts(10, 4), &counts(10, 1)).ok(), Some(3));
+        assert_eq!(claimed_winning_tokens(true, &counts(10, 4), &counts(3, 3)).err(), Some(Error::InvalidMarketData));
+        assert_eq!(claimed_winning_tokens(true, &counts(10, 4), &counts(10, 4)).err(), Some(Error::SupplyDecrease));
+    }
+
+    #[test]
+    fn burning_losers_marks_a_redemption() {
+        assert!(losing_tokens_burned(true, &counts(10, 4), &counts(8, 2)));
+        assert!(losing_tokens_burned(false, &counts(10, 4), &counts(9, 4)));
+        // A claim of 7 YES
+        assert!(!losing_tokens_burned(true, &counts(10, 4), &counts(3, 4)));
+    }
+
+    #[test]
+    fn claimed_winning_tokens_rejects_minting() {
+        assert_eq!(claimed_winning_tokens(true, &counts(10, 4), &counts(11, 4)).err(), Some(Error::MintingDuringClaim));
+        assert_eq!(claimed_winning_tokens(false, &counts(10, 4), &counts(10, 9)).err(), Some(Error::MintingDuringClaim));
+    }
+
+    #[test]
+    fn uniform_weights_match_100_ckb_ratio() {
+        let weights = CollateralWeights::from_args(&[0x33; 32]).unwrap();
+
+        assert_eq!(weights.set_ratio(DEFAULT_SHANNONS_PER_TOKEN), 100_00000000);
+        assert_eq!(weights.claim_ratio(true, DEFAULT_SHANNONS_PER_TOKEN), 100_00000000);
+        assert_eq!(weights.claim_ratio(false, DEFAULT_SHANNONS_PER_TOKEN), 100_00000000);
+    }
+
+    #[test]
+    fn weighted_args_scale_mint_and_claim() {
+        let mut args = [0x33u8; 40];
+        args[32..36].copy_from_slice(&3u32.to_le_bytes());
+        args[36..40].copy_from_slice(&1u32.to_le_bytes());
+        let weights = CollateralWeights::from_args(&args).unwrap();
+
+        assert_eq!(check_collateral_ratio(800_00000000, 2, weights.set_ratio(DEFAULT_SHANNONS_PER_TOKEN), 0).ok(), Some(()));
+        assert_eq!(check_collateral_ratio(600_00000000, 2, weights.claim_ratio(true, DEFAULT_SHANNONS_PER_TOKEN), 0).ok(), Some(()));
+        assert_eq!(check_collateral_ratio(200_00000000, 2, weights.claim_ratio(false, DEFAULT_SHANNONS_PER_TOKEN), 0).ok(), Some(()));
+
+        args[36..40].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(CollateralWeights::from_args(&args).err(), Some(Error::InvalidTypeId));
+        assert_eq!(CollateralWeights::from_args(&args[..33]).err(), Some(Error::InvalidTypeId));
+    }
+
+    #[test]
+    fn prior_tokens_must_stay_with_their_lock() {
+        let alice = [0xa1; 32];
+        let bob = [0xb0; 32];
+
+        // Alice merges her 5 YES + 5 NO into a fresh mint of 3 sets
+        assert_eq!(prior_tokens_kept(&[(alice, counts(5, 5))], &[(alice, counts(8, 8))]).ok(), Some(()));
+        // Mint goes to Bob while Alice's tokens are untouched
+        assert_eq!(prior_tokens_kept(&[(alice, counts(5, 0))], &[(alice, counts(5, 0)), (bob, counts(3, 3))]).ok(), Some(()));
+
+        // Totals balance, but Alice's YES moved to Bob
+        assert_eq!(prior_tokens_kept(&[(alice, counts(5, 0))], &[(alice, counts(0, 3)), (bob, counts(8, 0))]).err(),
+                   Some(Error::TokenLockChanged));
+        assert_eq!(prior_tokens_kept(&[(alice, counts(5, 5))], &[(bob, counts(8, 8))]).err(),
+                   Some(Error::TokenLockChanged));
+    }
+
+    #[test]
+    fn mint_adds_one_cell_per_outcome() {
+        assert_eq!(single_cell_mint(&[0, 0], &[1, 1]).ok(), Some(()));
+        // Merging a prior YES cell into the minted one
+        assert_eq!(single_cell_mint(&[1, 0], &[1, 1]).ok(), Some(()));
+        // A prior YES cell kept apart from the minted one
+        assert_eq!(single_cell_mint(&[1, 0], &[2, 1]).ok(), Some(()));
+
+        assert_eq!(single_cell_mint(&[0, 0], &[1, 2]).err(), Some(Error::TooManyTokenCells));
+        assert_eq!(single_cell_mint(&[0, 0], &[100, 1]).err(), Some(Error::TooManyTokenCells));
+    }
+
+    #[test]
+    fn minted_tokens_must_go_to_token_lock() {
+        let pool = [0x90; 32];
+        let alice = [0xa1; 32];
+
+        assert_eq!(minted_to_token_lock(&[], &[(pool, counts(3, 3))], &pool).ok(), Some(()));
+        // Alice's prior cells ride along under her own lock
+        assert_eq!(minted_to_token_lock(&[(alice, counts(5, 0))], &[(alice, counts(5, 0)), (pool, counts(3, 3))], &pool).ok(), Some(()));
+
+        assert_eq!(minted_to_token_lock(&[], &[(alice, counts(3, 3))], &pool).err(), Some(Error::MintLockMismatch));
+        // Only the NO side of the mint went astray
+        assert_eq!(minted_to_token_lock(&[(alice, counts(5, 0))], &[(alice, counts(5, 3)), (pool, counts(3, 0))], &pool).err(),
+                   Some(Error::MintLockMismatch));
+    }
+
+    #[test]
+    fn market_args_flags_byte_is_optional() {
+        let mut args = [0x33u8; 41];
+        args[32..36].copy_from_slice(&3u32.to_le_bytes());
+        args[36..40].copy_from_slice(&1u32.to_le_bytes());
+        args[40] = FLAG_FREEZE_AFTER_RESOLUTION;
+
+        assert_eq!(split_market_args(&args[..32]).map(|(_, flags)| flags).ok(), Some(0));
+        assert_eq!(split_market_args(&args[..40]).map(|(_, flags)| flags).ok(), Some(0));
+        let (rest, flags) = split_market_args(&args).ok().unwrap();
+        assert_eq!((rest.len(), flags), (40, FLAG_FREEZE_AFTER_RESOLUTION));
+        assert_eq!(CollateralWeights::from_args(&args).unwrap().set_ratio(DEFAULT_SHANNONS_PER_TOKEN), 400_00000000);
+
+        args[40] = 0x80;
+        assert_eq!(split_market_args(&args).err(), Some(Error::InvalidTypeId));
+        assert_eq!(split_market_args(&args[..34]).err(), Some(Error::InvalidTypeId));
+    }
+
+    fn oracle(seed: u8) -> k256::ecdsa::SigningKey {
+        k256::ecdsa::SigningKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    fn oracle_sign(key: &k256::ecdsa::SigningKey, message: &[u8; 32]) -> Vec<u8> {
+        let (signature, recovery_id) = key.sign_prehash_recoverable(message).unwrap();
+        let mut bytes = signature.to_bytes().to_vec();
+        bytes.push(recovery_id.to_byte());
+        bytes
+    }
+
+    /// Type ID + flags + 2-of-3 quorum over oracles seeded 1, 2, 3
+    fn quorum_args() -> Vec<u8> {
+        let mut args = vec![0x33u8; 32];
+        args.extend_from_slice(&[FLAG_ORACLE_QUORUM, 2, 3]);
+        for seed in 1..=3 {
+            args.extend_from_slice(oracle(seed).verifying_key().to_encoded_point(true).as_bytes());
+        }
+        args
+    }
+
+    #[test]
+    fn oracle_quorum_args_layout() {
+        let args = quorum_args();
+        let (rest, flags) = split_market_args(&args).ok().unwrap();
+        assert_eq!((rest.len(), flags), (32, FLAG_ORACLE_QUORUM));
+        assert_eq!(oracle_quorum(&args).unwrap().map(|quorum| (quorum.threshold, quorum.keys.len())), Some((2, 3)));
+        assert!(oracle_quorum(&args[..33 + 2 + 33]).is_err());
+
+        let mut over_threshold = args.clone();
+        over_threshold[33] = 4;
+        assert!(oracle_quorum(&over_threshold).is_err());
+    }
+
+    #[test]
+    fn oracle_quorum_needs_m_distinct_signers() {
+        let args = quorum_args();
+        let quorum = oracle_quorum(&args).unwrap().unwrap();
+        let message = proposed_message(&[0x44; 32], &[0x01; 36], Proposal::Yes, None);
+        let sign = |seeds: &[u8]| -> Vec<u8> {
+            seeds.iter().flat_map(|seed| oracle_sign(&oracle(*seed), &message)).collect()
+        };
+
+        assert_eq!(quorum.verify(&message, &sign(&[1, 3])).ok(), Some(()));
+        assert_eq!(quorum.verify(&message, &sign(&[3])).err(), Some(Error::OracleQuorumNotMet));
+        assert_eq!(quorum.verify(&message, &sign(&[2, 2])).err(), Some(Error::DuplicateOracleSigner));
+        assert_eq!(quorum.verify(&message, &sign(&[1, 4])).err(), Some(Error::InvalidOracleSignature));
+
+        // Signatures are bound to the proposed outcome
+        let no_message = proposed_message(&[0x44; 32], &[0x01; 36], Proposal::No, None);
+        assert_eq!(quorum.verify(&no_message, &sign(&[1, 3])).err(), Some(Error::InvalidOracleSignature));
+    }
+
+    #[test]
+    fn resolver_args_layout() {
+        let resolver_hash = [0x55u8; RESOLVER_HASH_LEN];
+        let mut args = vec![0x33u8; 32];
+        args.push(FLAG_RESOLVER);
+        args.extend_from_slice(&resolver_hash);
+        assert_eq!(split_market_args(&args).ok().map(|(rest, flags)| (rest.len(), flags)), Some((32, FLAG_RESOLVER)));
+        assert_eq!(market_resolver(&args).ok(), Some(Some(resolver_hash)));
+        assert!(market_resolver(&args[..args.len() - 1]).is_err());
+        assert_eq!(market_resolver(&[0x33; 32]).ok(), Some(None));
+
+        // A market has either a quorum or a single resolver
+        let mut both = quorum_args();
+        both[32] |= FLAG_RESOLVER;
+        assert!(split_market_args(&both).is_err());
+    }
+
+    #[test]
+    fn token_lock_args_layout() {
+        let lock_hash = [0x90u8; TOKEN_LOCK_HASH_LEN];
+        let mut args = vec![0x33u8; 32];
+        args.push(FLAG_TOKEN_LOCK);
+        args.extend_from_slice(&lock_hash);
+        assert_eq!(split_market_args(&args).ok().map(|(rest, flags)| (rest.len(), flags)), Some((32, FLAG_TOKEN_LOCK)));
+        assert_eq!(token_lock(&args).ok(), Some(Some(lock_hash)));
+        assert!(token_lock(&args[..args.len() - 1]).is_err());
+        assert_eq!(token_lock(&[0x33; 32]).ok(), Some(None));
+
+        // The lock hash comes after the resolver hash
+        let resolver_hash = [0x55u8; RESOLVER_HASH_LEN];
+        let mut args = vec![0x33u8; 32];
+        args.push(FLAG_RESOLVER | FLAG_TOKEN_LOCK);
+        args.extend_from_slice(&resolver_hash);
+        args.extend_from_slice(&lock_hash);
+        assert_eq!(market_resolver(&args).ok(), Some(Some(resolver_hash)));
+        assert_eq!(token_lock(&args).ok(), Some(Some(lock_hash)));
+
+        // and after the quorum section
+        let mut args = quorum_args();
+        args[32] |= FLAG_TOKEN_LOCK;
+        args.extend_from_slice(&lock_hash);
+        assert_eq!(oracle_quorum(&args).unwrap().map(|quorum| quorum.threshold), Some(2));
+        assert_eq!(token_lock(&args).ok(), Some(Some(lock_hash)));
+    }
+
+    #[test]
+    fn resolver_signature_must_match_hash() {
+        let resolver = oracle(7);
+        let key = resolver.verifying_key().to_encoded_point(true);
+        let mut resolver_hash = [0u8; RESOLVER_HASH_LEN];
+        resolver_hash.copy_from_slice(&ckb_std::ckb_types::packed::CellOutput::calc_data_hash(key.as_bytes()).as_slice()[..RESOLVER_HASH_LEN]);
+        let message = proposed_message(&[0x44; 32], &[0x01; 36], Proposal::Yes, None);
+
+        assert!(resolver_signed(&resolver_hash, &message, &oracle_sign(&resolver, &message)));
+        assert!(!resolver_signed(&resolver_hash, &message, &oracle_sign(&oracle(8), &message)));
+        assert!(!resolver_signed(&resolver_hash, &message, &[]));
+        let no_message = proposed_message(&[0x44; 32], &[0x01; 36], Proposal::No, None);
+        assert!(!resolver_signed(&resolver_hash, &no_message, &oracle_sign(&resolver, &message)));
+    }
+
+    #[test]
+    fn collateral_overflowing_capacity_is_rejected() {
+        let weights = CollateralWeights::from_args(&[0x33; 32]).unwrap();
+        // Fits in u128 but not in a u64 capacity
+        assert_eq!(check_collateral_ratio(u64::MAX, u64::MAX as u128, weights.set_ratio(DEFAULT_SHANNONS_PER_TOKEN), 0).err(), Some(Error::Encoding));
+        // Overflows u128
+        assert_eq!(check_collateral_ratio(u64::MAX, u128::MAX, weights.claim_ratio(true, DEFAULT_SHANNONS_PER_TOKEN), 0).err(), Some(Error::Encoding));
+    }
+
+    #[test]
+    fn collateral_ratio_must_match_exactly() {
+        assert_eq!(check_collateral_ratio(500_00000000, 5, 100_00000000, 0).ok(), Some(()));
+        assert_eq!(check_collateral_ratio(0, 0, 100_00000000, 0).ok(), Some(()));
+        assert_eq!(check_collateral_ratio(500_00000001, 5, 100_00000000, 0).err(), Some(Error::InsufficientCollateral));
+        assert_eq!(check_collateral_ratio(499_99999999, 5, 100_00000000, 0).err(), Some(Error::InsufficientCollateral));
+        assert_eq!(check_collateral_ratio(u64::MAX, 1, u64::MAX as u128, 0).ok(), Some(()));
+    }
+
+    #[test]
+    fn collateral_ratio_scales_by_decimals() {
+        let ratio = 100_00000000;
+        // 1.5 tokens at 2 decimals
+        assert_eq!(check_collateral_ratio(150_00000000, 150, ratio, 2).ok(), Some(()));
+        assert_eq!(check_collateral_ratio(15_00000000, 150, ratio, 2).err(), Some(Error::InsufficientCollateral));
+        // 1 base unit at 8 decimals is 100 shannons
+        assert_eq!(check_collateral_ratio(100, 1, ratio, 8).ok(), Some(()));
+        // 1 base unit at 10 decimals is 1 shannon, at 12 a hundredth of one
+        assert_eq!(check_collateral_ratio(1, 1, ratio, 10).ok(), Some(()));
+        assert_eq!(check_collateral_ratio(1, 100, ratio, 12).ok(), Some(()));
+        assert_eq!(check_collateral_ratio(0, 1, ratio, 12).err(), Some(Error::FractionalCollateral));
+        assert_eq!(check_collateral_ratio(1, 150, ratio, 12).err(), Some(Error::FractionalCollateral));
+        // Whole tokens at 18 decimals
+        assert_eq!(check_collateral_ratio(200_00000000, 2 * 10u128.pow(18), ratio, MAX_DECIMALS).ok(), Some(()));
+    }
+
+    #[test]
+    fn protocol_fee_must_reach_the_recipient() {
+        let fee = ProtocolFee { fee_bps: 250, recipient_lock_hash: [0xfe; 32] };
+        // 2.5% of a 5-token claim at 100 CKB
+        assert_eq!(check_protocol_fee(&fee, 500_00000000, 12_50000000).ok(), Some(()));
+        assert_eq!(check_protocol_fee(&fee, 500_00000000, 13_00000000).ok(), Some(()));
+        assert_eq!(check_protocol_fee(&fee, 500_00000000, 12_49999999).err(), Some(Error::MissingProtocolFee));
+        assert_eq!(check_protocol_fee(&fee, 500_00000000, 0).err(), Some(Error::MissingProtocolFee));
+        // A fee that rounds down to nothing needs no output
+        assert_eq!(check_protocol_fee(&fee, 39, 0).ok(), Some(()));
+    }
+
+    #[test]
+    fn collateral_ratio_capacity_ceiling() {
+        let ratio = 100_00000000;
+        let max_tokens = (u64::MAX / ratio as u64) as u128;
+        assert_eq!(check_collateral_ratio(max_tokens as u64 * ratio as u64, max_tokens, ratio, 0).ok(), Some(()));
+        // One more token needs more shannons than a capacity holds
+        assert_eq!(check_collateral_ratio(u64::MAX, max_tokens + 1, ratio, 0).err(), Some(Error::Encoding));
+        // Fits after scaling even though the product doesn't fit a u64
+        assert_eq!(check_collateral_ratio(u64::MAX, u64::MAX as u128, 100, 2).ok(), Some(()));
+        // The product itself overflows u128
+        assert_eq!(check_collateral_ratio(u64::MAX, u128::MAX, 2, 0).err(), Some(Error::Encoding));
+    }
+
+    #[test]
+    fn absolute_block_since_parsing() {
+        assert_eq!(absolute_block_since(18_280), Some(18_280));
+        assert_eq!(absolute_block_since(0), Some(0));
+        // Timestamps, epochs and relative block numbers don't count
+        assert_eq!(absolute_block_since(0x4000_0000_0000_4768), None);
+        assert_eq!(absolute_block_since(0x2000_0000_0000_4768), None);
+        assert_eq!(absolute_block_since(0x8000_0000_0000_4768), None);
+    }
+
+    #[test]
+    fn scalar_proposals_sign_the_value() {
+        let at = |value| Some(Scalar { floor: 0, ceiling: 100, value });
+        assert_ne!(proposed_message(&[0x44; 32], &[0x01; 36], Proposal::Yes, at(50)), proposed_message(&[0x44; 32], &[0x01; 36], Proposal::Yes, None));
+        assert_ne!(proposed_message(&[0x44; 32], &[0x01; 36], Proposal::Yes, at(50)), proposed_message(&[0x44; 32], &[0x01; 36], Proposal::Yes, at(51)));
+    }
+
+    #[test]
+    fn proposals_are_bound_to_the_market_cell() {
+        // A YES signed for one market cell can't be replayed after a withdrawal
+        // recreates the cell
+        let yes = proposed_message(&[0x44; 32], &[0x01; 36], Proposal::Yes, None);
+        assert_ne!(yes, proposed_message(&[0x44; 32], &[0x02; 36], Proposal::Yes, None));
+        assert_ne!(yes, proposed_message(&[0x44; 32], &[0x01; 36], Proposal::No, None));
+        assert_eq!(yes, amendment_message(&[0x44; 32], &[0x01; 36], Some(Proposal::Yes), None));
+    }
+
+    #[test]
+    fn corrections_are_bound_to_the_market_cell() {
+        let yes = correction_message(&[0x44; 32], &[0x01; 36], Proposal::Yes);
+        assert_ne!(yes, correction_message(&[0x44; 32], &[0x02; 36], Proposal::Yes));
+        assert_ne!(yes, correction_message(&[0x44; 32], &[0x01; 36], Proposal::No));
+        assert_ne!(yes, correction_message(&[0x33; 32], &[0x01; 36], Proposal::Yes));
+    }
+
+    #[test]
+    fn amendments_are_bound_to_the_market_cell() {
+        let no = amendment_message(&[0x44; 32], &[0x01; 36], Some(Proposal::No), None);
+        assert_ne!(no, amendment_message(&[0x44; 32], &[0x02; 36], Some(Proposal::No), None));
+        assert_ne!(no, amendment_message(&[0x44; 32], &[0x01; 36], Some(Proposal::Yes), None));
+        assert_ne!(no, amendment_message(&[0x44; 32], &[0x01; 36], None, None));
+        assert_ne!(no, correction_message(&[0x44; 32], &[0x01; 36], Proposal::No));
+        // A scalar amendment covers the value
+        let at = |value| Some(Scalar { floor: 0, ceiling: 100, value });
+        assert_ne!(
+            amendment_message(&[0x44; 32], &[0x01; 36], Some(Proposal::Yes), at(50)),
+            amendment_message(&[0x44; 32], &[0x01; 36], Some(Proposal::Yes), at(51)),
+        );
+    }
+
+    #[test]
+    fn type_id_hashes_the_whole_first_input() {
+        // CellInput { since: 0, previous_output: 0x00..01:0 }, as in mock_tx_create_seeded.json
+        let mut input = [0u8; 44];
+        input[39] = 0x01;
+        let expected: [u8; 32] = [
+            0xbe, 0x80, 0x36, 0xd3, 0x57, 0x7c, 0x83, 0x06, 0x52, 0x85, 0x44, 0xb4, 0x7d, 0xb1, 0x75, 0x71,
+            0x1e, 0x46, 0xe1, 0x3b, 0x2f, 0x50, 0x36, 0x68, 0x92, 0x4d, 0x13, 0xb3, 0xfe, 0xbf, 0x1e, 0xad,
+        ];
+        assert_eq!(type_id_hash(&input, 0), expected);
+        assert_ne!(type_id_hash(&input, 1), expected);
+        // since is part of the preimage, not just the outpoint
+        input[0] = 0x01;
+        assert_ne!(type_id_hash(&input, 0), expected);
+    }
+
+    #[test]
+    fn relative_timestamp_since_parsing() {
+        assert_eq!(relative_timestamp_since(0xc000_0000_0001_5180), Some(86_400));
+        // Absolute timestamp
+        assert_eq!(relative_timestamp_since(0x4000_0000_0001_5180), None);
+        // Relative block number
+        assert_eq!(relative_timestamp_since(0x8000_0000_0001_5180), None);
+        assert_eq!(relative_timestamp_since(0), None);
+    }
+}
@@ -0,0 +1,218 @@
+//! ckb-testtool harness for the market type script
+//!
+//! Deploys the compiled `market` and `market-token` binaries into a
+//! ckb-testtool `Context` and builds transactions around them, so contract
+//! behaviour can be checked with `cargo test` instead of hand-written mock
+//! files. Markets use an always-success lock; the user holding tokens and
+//! paying for transactions gets an always-success lock with distinct args.
+
+use ckb_testtool::{
+    builtin::ALWAYS_SUCCESS,
+    ckb_error::Error,
+    ckb_hash::blake2b_256,
+    ckb_types::{
+        bytes::Bytes,
+        core::{Cycle, HeaderBuilder, ScriptHashType, TransactionView},
+        packed::{Byte32, CellDep, CellInput, CellOutput, OutPoint, Script},
+        prelude::*,
+    },
+    context::Context,
+};
+use market_common::{type_id_preimage, MarketData};
+use std::path::PathBuf;
+
+#[cfg(test)]
+mod tests;
+
+/// Cycle limit for a single verification
+pub const MAX_CYCLES: u64 = 70_000_000;
+
+/// Shannons per CKB
+pub const CKB: u64 = 100_000_000;
+
+/// Token ids the market script recognises
+pub const YES: u8 = 0x01;
+pub const NO: u8 = 0x02;
+
+/// hash_type byte of Data2 in market data
+const DATA2: u8 = 4;
+
+/// Read `contracts/<name>/build/<name>`, as written by the contract Makefiles
+pub fn load_binary(name: &str) -> Bytes {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("..").join(name).join("build").join(name);
+    std::fs::read(&path)
+        .unwrap_or_else(|err| panic!("{} not found ({}); build the {} contract first", path.display(), err, name))
+        .into()
+}
+
+/// Panic unless verification failed with script error `code`
+pub fn assert_script_error(err: Error, code: i8) {
+    let message = err.to_string();
+    assert!(
+        message.contains(&format!("error code {} ", code)),
+        "expected script error code {}, got: {}",
+        code,
+        message
+    );
+}
+
+/// Market type args for a market cell created at `output_index` by a
+/// transaction whose first input is `first_input` (the canonical Type ID)
+pub fn type_id_args(first_input: &CellInput, output_index: u64) -> Bytes {
+    let first_input = first_input.as_slice().try_into().expect("44-byte CellInput");
+    Bytes::copy_from_slice(&blake2b_256(type_id_preimage(first_input, output_index)))
+}
+
+/// Relative timestamp `since` of `seconds`
+pub fn relative_timestamp_since(seconds: u64) -> u64 {
+    (1 << 63) | (0b10 << 61) | seconds
+}
+
+/// The market and token binaries deployed in a fresh context
+pub struct Deployment {
+    pub context: Context,
+    market_code: OutPoint,
+    token_code: OutPoint,
+    always_success: OutPoint,
+}
+
+impl Default for Deployment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deployment {
+    pub fn new() -> Self {
+        let mut context = Context::default();
+        let market_code = context.deploy_cell(load_binary("market"));
+        let token_code = context.deploy_cell(load_binary("market-token"));
+        let always_success = context.deploy_cell(ALWAYS_SUCCESS.clone());
+        Deployment { context, market_code, token_code, always_success }
+    }
+
+    /// Lock of every market cell
+    pub fn market_lock(&mut self) -> Script {
+        self.always_success_lock(Bytes::new())
+    }
+
+    /// Lock of the user minting, holding and claiming tokens
+    pub fn user_lock(&mut self) -> Script {
+        self.always_success_lock(Bytes::from_static(&[0x01]))
+    }
+
+    /// Lock of the operator collecting protocol fees
+    pub fn fee_recipient_lock(&mut self) -> Script {
+        self.always_success_lock(Bytes::from_static(&[0x02]))
+    }
+
+    fn always_success_lock(&mut self, args: Bytes) -> Script {
+        self.context
+            .build_script_with_hash_type(&self.always_success, ScriptHashType::Data2, args)
+            .expect("always-success script")
+    }
+
+    /// Market type script with the given args (Type ID, then optional weights and flags)
+    pub fn market_type(&mut self, args: Bytes) -> Script {
+        self.context
+            .build_script_with_hash_type(&self.market_code, ScriptHashType::Data2, args)
+            .expect("market script")
+    }
+
+    /// Open market data pointing at the deployed token binary
+    pub fn market_data(&self) -> MarketData {
+        let token_code_hash = blake2b_256(load_binary("market-token"));
+        MarketData::new(token_code_hash, DATA2)
+    }
+
+    /// Type script of `market_type`'s token `token_id`, as the market script derives it
+    pub fn token_type(&mut self, market_type: &Script, token_id: u8) -> Script {
+        let mut args = market_type.calc_script_hash().as_slice().to_vec();
+        args.push(token_id);
+        self.context
+            .build_script_with_hash_type(&self.token_code, ScriptHashType::Data2, args.into())
+            .expect("token script")
+    }
+
+    /// Market cell holding `capacity` shannons
+    pub fn market_cell(&mut self, market_type: &Script, capacity: u64, data: &MarketData) -> (CellOutput, Bytes) {
+        let output = CellOutput::new_builder()
+            .capacity(capacity.pack())
+            .lock(self.market_lock())
+            .type_(Some(market_type.clone()).pack())
+            .build();
+        (output, data.to_bytes().into())
+    }
+
+    /// User token cell holding `amount` of token `token_id`
+    pub fn token_cell(&mut self, market_type: &Script, token_id: u8, amount: u128) -> (CellOutput, Bytes) {
+        let token_type = self.token_type(market_type, token_id);
+        let output = CellOutput::new_builder()
+            .capacity((200 * CKB).pack())
+            .lock(self.user_lock())
+            .type_(Some(token_type).pack())
+            .build();
+        (output, Bytes::copy_from_slice(&amount.to_le_bytes()))
+    }
+
+    /// Plain user cell holding `capacity` shannons
+    pub fn user_cell(&mut self, capacity: u64) -> (CellOutput, Bytes) {
+        let output = CellOutput::new_builder()
+            .capacity(capacity.pack())
+            .lock(self.user_lock())
+            .build();
+        (output, Bytes::new())
+    }
+
+    /// Cell paying `capacity` shannons to the fee recipient
+    pub fn fee_recipient_cell(&mut self, capacity: u64) -> (CellOutput, Bytes) {
+        let output = CellOutput::new_builder()
+            .capacity(capacity.pack())
+            .lock(self.fee_recipient_lock())
+            .build();
+        (output, Bytes::new())
+    }
+
+    /// Put `cell` on chain and spend it with `since`
+    pub fn input(&mut self, cell: (CellOutput, Bytes), since: u64) -> CellInput {
+        let out_point = self.context.create_cell(cell.0, cell.1);
+        CellInput::new_builder()
+            .previous_output(out_point)
+            .since(since.pack())
+            .build()
+    }
+
+    /// Put `cell` on chain in block `number` and spend it with `since`,
+    /// returning the input and the block hash to add as a header dep
+    pub fn input_in_block(&mut self, cell: (CellOutput, Bytes), since: u64, number: u64) -> (CellInput, Byte32) {
+        let input = self.input(cell, since);
+        let header = HeaderBuilder::default().number(number.pack()).build();
+        self.context.insert_header(header.clone());
+        self.context.link_cell_with_block(input.previous_output(), header.hash(), 0);
+        (input, header.hash())
+    }
+
+    /// Code deps of the market, token and always-success scripts
+    pub fn cell_deps(&self) -> Vec<CellDep> {
+        [&self.market_code, &self.token_code, &self.always_success]
+            .into_iter()
+            .map(|out_point| CellDep::new_builder().out_point(out_point.clone()).build())
+            .collect()
+    }
+
+    /// Transaction spending `inputs` into `outputs`, with the deployment's deps
+    pub fn transaction(&self, inputs: Vec<CellInput>, outputs: Vec<(CellOutput, Bytes)>) -> TransactionView {
+        let (outputs, outputs_data): (Vec<_>, Vec<_>) = outputs.into_iter().unzip();
+        TransactionView::new_advanced_builder()
+            .cell_deps(self.cell_deps())
+            .inputs(inputs)
+            .outputs(outputs)
+            .outputs_data(outputs_data.into_iter().map(|data| data.pack()))
+            .build()
+    }
+
+    /// Run every script in `tx`
+    pub fn verify(&self, tx: &TransactionView) -> Result<Cycle, Error> {
+        self.context.verify_tx(tx, MAX_CYCLES)
+    }
+}
@@ -0,0 +1,630 @@
+use super::*;
+use ckb_testtool::ckb_types::{core::Capacity, packed::WitnessArgs};
+use market_common::{Metadata, Proposal, ProtocolFee, Scalar, Supplies};
+
+/// Type ID of markets that already exist (only creation checks derivation)
+const MARKET_ARGS: [u8; 32] = [0x11; 32];
+
+/// Market cell capacity before any collateral: occupied capacity plus a buffer
+const MARKET_CKB: u64 = 300 * CKB;
+
+/// Collateral of one complete set at the default unit
+const SET: u64 = 100 * CKB;
+
+const PROPOSED_AT: u64 = 1_767_225_600;
+
+fn existing_market(deployment: &mut Deployment) -> Script {
+    deployment.market_type(Bytes::copy_from_slice(&MARKET_ARGS))
+}
+
+/// Mint transaction adding `yes`/`no` tokens to an empty market for `collateral` shannons
+fn mint_tx(deployment: &mut Deployment, yes: u128, no: u128, collateral: u64) -> TransactionView {
+    let market_type = existing_market(deployment);
+    let data = deployment.market_data();
+    let market_cell = deployment.market_cell(&market_type, MARKET_CKB, &data);
+    let market_input = deployment.input(market_cell, 0);
+    let funding_cell = deployment.user_cell(5_000 * CKB);
+    let funding = deployment.input(funding_cell, 0);
+
+    let outputs = vec![
+        deployment.market_cell(&market_type, MARKET_CKB + collateral, &data),
+        deployment.token_cell(&market_type, YES, yes),
+        deployment.token_cell(&market_type, NO, no),
+        deployment.user_cell(5_000 * CKB - collateral - 400 * CKB - 1_000),
+    ];
+    deployment.transaction(vec![market_input, funding], outputs)
+}
+
+#[test]
+fn creation_derives_the_type_id_from_the_first_input() {
+    let mut deployment = Deployment::new();
+    let funding_cell = deployment.user_cell(1_000 * CKB);
+    let funding = deployment.input(funding_cell, 0);
+    let market_type = deployment.market_type(type_id_args(&funding, 0));
+    let data = deployment.market_data();
+
+    let outputs = vec![
+        deployment.market_cell(&market_type, MARKET_CKB, &data),
+        deployment.user_cell(1_000 * CKB - MARKET_CKB - 1_000),
+    ];
+    let tx = deployment.transaction(vec![funding], outputs);
+    deployment.verify(&tx).expect("creation");
+}
+
+#[test]
+fn creation_accepts_the_type_id_ckb_sdk_computes() {
+    let mut deployment = Deployment::new();
+    let funding_cell = deployment.user_cell(1_000 * CKB);
+    // A non-zero since is part of the Type ID preimage
+    let funding = deployment.input(funding_cell, relative_timestamp_since(60));
+    let sdk_input = <ckb_types::packed::CellInput as ckb_types::prelude::Entity>::from_slice(funding.as_slice()).unwrap();
+    let type_id = ckb_sdk::util::calculate_type_id(&sdk_input, 0);
+    assert_eq!(type_id_args(&funding, 0).as_ref(), &type_id[..]);
+
+    let market_type = deployment.market_type(Bytes::copy_from_slice(&type_id));
+    let data = deployment.market_data();
+    let outputs = vec![
+        deployment.market_cell(&market_type, MARKET_CKB, &data),
+        deployment.user_cell(1_000 * CKB - MARKET_CKB - 1_000),
+    ];
+    let tx = deployment.transaction(vec![funding], outputs);
+    deployment.verify(&tx).expect("creation with the ckb-sdk Type ID");
+}
+
+#[test]
+fn creation_with_a_foreign_type_id_fails() {
+    let mut deployment = Deployment::new();
+    let funding_cell = deployment.user_cell(1_000 * CKB);
+    let funding = deployment.input(funding_cell, 0);
+    // Derived for output 1, but the market cell is output 0
+    let market_type = deployment.market_type(type_id_args(&funding, 1));
+    let data = deployment.market_data();
+
+    let outputs = vec![
+        deployment.market_cell(&market_type, MARKET_CKB, &data),
+        deployment.user_cell(1_000 * CKB - MARKET_CKB - 1_000),
+    ];
+    let tx = deployment.transaction(vec![funding], outputs);
+    assert_script_error(deployment.verify(&tx).unwrap_err(), 20);
+}
+
+#[test]
+fn metadata_is_fixed_after_creation() {
+    let mut deployment = Deployment::new();
+    let funding_cell = deployment.user_cell(1_000 * CKB);
+    let funding = deployment.input(funding_cell, 0);
+    let market_type = deployment.market_type(type_id_args(&funding, 0));
+    let metadata = Metadata::new("Will the devnet reach block 1,000,000?", &["No", "Yes"]).unwrap();
+    let open = MarketData { metadata: Some(metadata), ..deployment.market_data() };
+    // The metadata takes the market cell past MARKET_CKB
+    let capacity = MARKET_CKB + 100 * CKB;
+
+    let outputs = vec![
+        deployment.market_cell(&market_type, capacity, &open),
+        deployment.user_cell(1_000 * CKB - capacity - 1_000),
+    ];
+    let create = deployment.transaction(vec![funding], outputs);
+    deployment.verify(&create).expect("creation with metadata");
+
+    // Proposing an outcome keeps the metadata; rewording the question doesn't
+    let proposed = MarketData { pending_outcome: Some(Proposal::Yes), proposed_at: PROPOSED_AT, ..open.clone() };
+    let reworded = MarketData {
+        metadata: Some(Metadata::new("Will the devnet reach block 2,000,000?", &["No", "Yes"]).unwrap()),
+        ..proposed.clone()
+    };
+    let market_type = existing_market(&mut deployment);
+    for (output, verdict) in [(proposed, None), (reworded, Some(10))] {
+        let market_cell = deployment.market_cell(&market_type, capacity, &open);
+        let market_input = deployment.input(market_cell, 0);
+        let outputs = vec![deployment.market_cell(&market_type, capacity, &output)];
+        let propose = deployment.transaction(vec![market_input], outputs);
+        match verdict {
+            None => {
+                deployment.verify(&propose).expect("propose");
+            }
+            // InvalidMarketData
+            Some(code) => assert_script_error(deployment.verify(&propose).unwrap_err(), code),
+        }
+    }
+}
+
+#[test]
+fn mint_of_complete_sets() {
+    let mut deployment = Deployment::new();
+    let tx = mint_tx(&mut deployment, 10, 10, 10 * SET);
+    deployment.verify(&tx).expect("mint");
+}
+
+#[test]
+fn unequal_mint_is_rejected() {
+    let mut deployment = Deployment::new();
+    let tx = mint_tx(&mut deployment, 10, 9, 10 * SET);
+    // UnequalSupplyIncrease
+    assert_script_error(deployment.verify(&tx).unwrap_err(), 13);
+}
+
+#[test]
+fn under_collateralized_mint_is_rejected() {
+    let mut deployment = Deployment::new();
+    let tx = mint_tx(&mut deployment, 10, 10, 9 * SET);
+    // InsufficientCollateral
+    assert_script_error(deployment.verify(&tx).unwrap_err(), 14);
+}
+
+#[test]
+fn resolution_is_proposed_then_finalized() {
+    let mut deployment = Deployment::new();
+    let market_type = existing_market(&mut deployment);
+    let open = deployment.market_data();
+    let proposed = MarketData { pending_outcome: Some(Proposal::Yes), proposed_at: PROPOSED_AT, ..open.clone() };
+    let resolved = MarketData { resolved: true, outcome: true, pending_outcome: None, ..proposed.clone() };
+
+    let market_cell = deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &open);
+    let market_input = deployment.input(market_cell, 0);
+    let outputs = vec![deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &proposed)];
+    let propose = deployment.transaction(vec![market_input], outputs);
+    deployment.verify(&propose).expect("propose");
+
+    // The proposal has to stand for 24 hours
+    let market_cell = deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &proposed);
+    let market_input = deployment.input(market_cell, relative_timestamp_since(24 * 60 * 60));
+    let outputs = vec![deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &resolved)];
+    let finalize = deployment.transaction(vec![market_input], outputs);
+    deployment.verify(&finalize).expect("finalize");
+}
+
+/// Market args with the resolver flag and the blake160 hash of `resolver`
+fn resolver_args(type_id: &[u8], resolver: &k256::ecdsa::SigningKey) -> Bytes {
+    let mut args = type_id.to_vec();
+    args.push(0x08);
+    args.extend_from_slice(&blake2b_256(resolver.verifying_key().to_encoded_point(true).as_bytes())[..20]);
+    args.into()
+}
+
+/// Creation of a resolver market with `data`
+fn resolver_creation_tx(deployment: &mut Deployment, data: &MarketData) -> TransactionView {
+    let funding_cell = deployment.user_cell(1_000 * CKB);
+    let funding = deployment.input(funding_cell, 0);
+    let market_type = deployment.market_type(resolver_args(&type_id_args(&funding, 0), &oracle_key()));
+
+    let outputs = vec![
+        deployment.market_cell(&market_type, MARKET_CKB, data),
+        deployment.user_cell(1_000 * CKB - MARKET_CKB - 1_000),
+    ];
+    deployment.transaction(vec![funding], outputs)
+}
+
+#[test]
+fn resolver_market_without_supplies_fails() {
+    let mut deployment = Deployment::new();
+    // Finalizing would have to add the supplies ahead of dispute_until
+    let data = deployment.market_data();
+    let tx = resolver_creation_tx(&mut deployment, &data);
+    // InvalidMarketData
+    assert_script_error(deployment.verify(&tx).unwrap_err(), 10);
+
+    let data = MarketData { supplies: Some(Supplies::default()), ..data };
+    let tx = resolver_creation_tx(&mut deployment, &data);
+    deployment.verify(&tx).expect("creation with supplies");
+}
+
+#[test]
+fn resolver_market_finalizes_into_a_dispute_period() {
+    let mut deployment = Deployment::new();
+    let market_type = deployment.market_type(resolver_args(&MARKET_ARGS, &oracle_key()));
+    let proposed = MarketData {
+        pending_outcome: Some(Proposal::Yes),
+        proposed_at: PROPOSED_AT,
+        supplies: Some(Supplies { yes: 10, no: 10 }),
+        ..deployment.market_data()
+    };
+    // The proposal was committed in block 1000
+    let resolved = MarketData { resolved: true, outcome: true, pending_outcome: None, dispute_until: 18_280, ..proposed.clone() };
+
+    let market_cell = deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &proposed);
+    let (market_input, block) = deployment.input_in_block(market_cell, relative_timestamp_since(24 * 60 * 60), 1_000);
+    let outputs = vec![deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &resolved)];
+    let finalize = deployment.transaction(vec![market_input], outputs).as_advanced_builder().header_dep(block).build();
+    deployment.verify(&finalize).expect("finalize with a dispute period");
+}
+
+#[test]
+fn claim_pays_winning_tokens() {
+    let mut deployment = Deployment::new();
+    let market_type = existing_market(&mut deployment);
+    let resolved = MarketData { resolved: true, outcome: true, proposed_at: PROPOSED_AT, ..deployment.market_data() };
+
+    let market_cell = deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &resolved);
+    let market_input = deployment.input(market_cell, 0);
+    let yes_cell = deployment.token_cell(&market_type, YES, 10);
+    let yes_input = deployment.input(yes_cell, 0);
+
+    // Burn 5 of 10 YES for 5 units of collateral
+    let outputs = vec![
+        deployment.market_cell(&market_type, MARKET_CKB + 5 * SET, &resolved),
+        deployment.token_cell(&market_type, YES, 5),
+        deployment.user_cell(5 * SET - 1_000),
+    ];
+    let tx = deployment.transaction(vec![market_input, yes_input], outputs);
+    deployment.verify(&tx).expect("claim");
+}
+
+/// Claim of 5 of 10 YES on a market taking a 2.5% fee, paying `fee` to the recipient
+fn fee_claim_tx(deployment: &mut Deployment, fee: u64) -> TransactionView {
+    let market_type = existing_market(deployment);
+    let recipient_lock_hash: [u8; 32] = deployment.fee_recipient_lock().calc_script_hash().as_slice().try_into().unwrap();
+    let resolved = MarketData {
+        resolved: true,
+        outcome: true,
+        proposed_at: PROPOSED_AT,
+        fee: Some(ProtocolFee { fee_bps: 250, recipient_lock_hash }),
+        ..deployment.market_data()
+    };
+
+    let market_cell = deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &resolved);
+    let market_input = deployment.input(market_cell, 0);
+    let yes_cell = deployment.token_cell(&market_type, YES, 10);
+    let yes_input = deployment.input(yes_cell, 0);
+
+    let mut outputs = vec![
+        deployment.market_cell(&market_type, MARKET_CKB + 5 * SET, &resolved),
+        deployment.token_cell(&market_type, YES, 5),
+        deployment.user_cell(5 * SET - fee - 1_000),
+    ];
+    if fee > 0 {
+        outputs.push(deployment.fee_recipient_cell(fee));
+    }
+    deployment.transaction(vec![market_input, yes_input], outputs)
+}
+
+#[test]
+fn fee_claim_pays_the_recipient() {
+    let mut deployment = Deployment::new();
+    // 2.5% of 500 CKB
+    let tx = fee_claim_tx(&mut deployment, 12 * CKB + 50_000_000);
+    deployment.verify(&tx).expect("claim with fee");
+}
+
+#[test]
+fn fee_claim_without_fee_output_is_rejected() {
+    let mut deployment = Deployment::new();
+    let tx = fee_claim_tx(&mut deployment, 0);
+    // MissingProtocolFee
+    assert_script_error(deployment.verify(&tx).unwrap_err(), 44);
+
+    let tx = fee_claim_tx(&mut deployment, 12 * CKB);
+    assert_script_error(deployment.verify(&tx).unwrap_err(), 44);
+}
+
+/// Claim on a scalar market over [0, 100] resolved at 25 burning 4 of 10 LONG
+/// and 4 of 10 SHORT for `payout` shannons
+fn scalar_claim_tx(deployment: &mut Deployment, payout: u64) -> TransactionView {
+    let market_type = existing_market(deployment);
+    let resolved = MarketData {
+        resolved: true,
+        proposed_at: PROPOSED_AT,
+        scalar: Some(Scalar { floor: 0, ceiling: 100, value: 25 }),
+        ..deployment.market_data()
+    };
+
+    let market_cell = deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &resolved);
+    let market_input = deployment.input(market_cell, 0);
+    let long_cell = deployment.token_cell(&market_type, YES, 10);
+    let long_input = deployment.input(long_cell, 0);
+    let short_cell = deployment.token_cell(&market_type, NO, 10);
+    let short_input = deployment.input(short_cell, 0);
+
+    let outputs = vec![
+        deployment.market_cell(&market_type, MARKET_CKB + 10 * SET - payout, &resolved),
+        deployment.token_cell(&market_type, YES, 6),
+        deployment.token_cell(&market_type, NO, 6),
+        deployment.user_cell(payout - 1_000),
+    ];
+    deployment.transaction(vec![market_input, long_input, short_input], outputs)
+}
+
+#[test]
+fn scalar_claim_pays_both_sides_along_the_range() {
+    let mut deployment = Deployment::new();
+    // LONG is worth a quarter of a set at 25, SHORT three quarters
+    let tx = scalar_claim_tx(&mut deployment, 4 * SET / 4 + 4 * SET * 3 / 4);
+    deployment.verify(&tx).expect("scalar claim");
+}
+
+#[test]
+fn scalar_claim_taking_more_than_the_curve_is_rejected() {
+    let mut deployment = Deployment::new();
+    // InsufficientCollateral
+    let tx = scalar_claim_tx(&mut deployment, 4 * SET / 4 + 4 * SET * 3 / 4 + CKB);
+    assert_script_error(deployment.verify(&tx).unwrap_err(), 14);
+}
+
+/// Creation of an AMM market whose pool is seeded with `yes`/`no` tokens,
+/// funded with its occupied capacity plus 5 sets of collateral
+fn seeded_creation_tx(deployment: &mut Deployment, yes: u128, no: u128) -> TransactionView {
+    let funding_cell = deployment.user_cell(2_000 * CKB);
+    let funding = deployment.input(funding_cell, 0);
+    let mut args = type_id_args(&funding, 0).to_vec();
+    // AMM flag
+    args.push(0x04);
+    let market_type = deployment.market_type(args.into());
+    let seeded = MarketData { seed_reserves: Some((5, 5)), ..deployment.market_data() };
+
+    let (probe, data) = deployment.market_cell(&market_type, 0, &seeded);
+    let occupied = probe.occupied_capacity(Capacity::bytes(data.len()).unwrap()).unwrap().as_u64();
+    let outputs = vec![
+        deployment.market_cell(&market_type, occupied + 5 * SET, &seeded),
+        deployment.token_cell(&market_type, YES, yes),
+        deployment.token_cell(&market_type, NO, no),
+        deployment.user_cell(2_000 * CKB - occupied - 5 * SET - 400 * CKB - 1_000),
+    ];
+    deployment.transaction(vec![funding], outputs)
+}
+
+#[test]
+fn amm_creation_mints_the_pool_reserves_in_one_transaction() {
+    let mut deployment = Deployment::new();
+    // Market and token scripts both pass; the reserves exist from the first block
+    let tx = seeded_creation_tx(&mut deployment, 5, 5);
+    deployment.verify(&tx).expect("seeded creation");
+}
+
+#[test]
+fn amm_creation_minting_other_than_the_reserves_fails() {
+    let mut deployment = Deployment::new();
+    let tx = seeded_creation_tx(&mut deployment, 5, 4);
+    // InvalidMarketData
+    assert_script_error(deployment.verify(&tx).unwrap_err(), 10);
+}
+
+/// Creation with the market at output 1, where the index's byte order matters,
+/// under the Type ID `args`
+fn creation_at_output_one_tx(deployment: &mut Deployment, funding: CellInput, args: Bytes) -> TransactionView {
+    let market_type = deployment.market_type(args);
+    let data = deployment.market_data();
+    let outputs = vec![
+        deployment.user_cell(1_000 * CKB - MARKET_CKB - 1_000),
+        deployment.market_cell(&market_type, MARKET_CKB, &data),
+    ];
+    deployment.transaction(vec![funding], outputs)
+}
+
+#[test]
+fn creation_at_a_later_output_accepts_the_ckb_sdk_type_id() {
+    let mut deployment = Deployment::new();
+    let funding_cell = deployment.user_cell(1_000 * CKB);
+    let funding = deployment.input(funding_cell, relative_timestamp_since(60));
+    let sdk_input = <ckb_types::packed::CellInput as ckb_types::prelude::Entity>::from_slice(funding.as_slice()).unwrap();
+    let type_id = ckb_sdk::util::calculate_type_id(&sdk_input, 1);
+    assert_eq!(type_id_args(&funding, 1).as_ref(), &type_id[..]);
+
+    let tx = creation_at_output_one_tx(&mut deployment, funding, Bytes::copy_from_slice(&type_id));
+    deployment.verify(&tx).expect("creation at output 1 with the ckb-sdk Type ID");
+}
+
+#[test]
+fn creation_with_a_big_endian_output_index_fails() {
+    let mut deployment = Deployment::new();
+    let funding_cell = deployment.user_cell(1_000 * CKB);
+    let funding = deployment.input(funding_cell, 0);
+    let mut preimage = funding.as_slice().to_vec();
+    preimage.extend_from_slice(&1u64.to_be_bytes());
+    let args = Bytes::copy_from_slice(&blake2b_256(&preimage));
+
+    let tx = creation_at_output_one_tx(&mut deployment, funding, args);
+    // InvalidTypeId
+    assert_script_error(deployment.verify(&tx).unwrap_err(), 20);
+}
+
+/// Type ID of a second existing market, for transactions spanning two
+const OTHER_MARKET_ARGS: [u8; 32] = [0x22; 32];
+
+/// Mint of `sets` complete sets into each of two markets at once, depositing
+/// `collateral[i]` shannons into market i
+fn basket_mint_tx(deployment: &mut Deployment, second_args: [u8; 32], sets: u128, collateral: [u64; 2]) -> TransactionView {
+    let data = deployment.market_data();
+    let mut inputs = Vec::new();
+    let mut outputs = Vec::new();
+    for (args, collateral) in [MARKET_ARGS, second_args].into_iter().zip(collateral) {
+        let market_type = deployment.market_type(Bytes::copy_from_slice(&args));
+        let market_cell = deployment.market_cell(&market_type, MARKET_CKB, &data);
+        inputs.push(deployment.input(market_cell, 0));
+        outputs.push(deployment.market_cell(&market_type, MARKET_CKB + collateral, &data));
+        outputs.push(deployment.token_cell(&market_type, YES, sets));
+        outputs.push(deployment.token_cell(&market_type, NO, sets));
+    }
+    let funding_cell = deployment.user_cell(10_000 * CKB);
+    inputs.push(deployment.input(funding_cell, 0));
+    let spent = collateral.iter().sum::<u64>() + 800 * CKB + 1_000;
+    outputs.push(deployment.user_cell(10_000 * CKB - spent));
+    deployment.transaction(inputs, outputs)
+}
+
+#[test]
+fn basket_mints_into_two_markets_at_once() {
+    let mut deployment = Deployment::new();
+    let tx = basket_mint_tx(&mut deployment, OTHER_MARKET_ARGS, 10, [10 * SET, 10 * SET]);
+    deployment.verify(&tx).expect("mint into two markets");
+}
+
+#[test]
+fn basket_collateral_is_checked_per_market() {
+    let mut deployment = Deployment::new();
+    // The total covers 20 sets, but the second market is one set short
+    let tx = basket_mint_tx(&mut deployment, OTHER_MARKET_ARGS, 10, [11 * SET, 9 * SET]);
+    // InsufficientCollateral
+    assert_script_error(deployment.verify(&tx).unwrap_err(), 14);
+}
+
+#[test]
+fn two_cells_of_one_market_fail() {
+    let mut deployment = Deployment::new();
+    let tx = basket_mint_tx(&mut deployment, MARKET_ARGS, 10, [10 * SET, 10 * SET]);
+    // MultipleMarketCells
+    assert_script_error(deployment.verify(&tx).unwrap_err(), 11);
+}
+
+/// Oracle of the 1-of-1 quorum market
+fn oracle_key() -> k256::ecdsa::SigningKey {
+    k256::ecdsa::SigningKey::from_slice(&[0x07; 32]).unwrap()
+}
+
+/// Existing market whose proposals (and their amendments) a 1-of-1 oracle quorum signs
+fn quorum_market(deployment: &mut Deployment) -> Script {
+    let mut args = MARKET_ARGS.to_vec();
+    // Oracle quorum flag, threshold 1 of 1 key
+    args.extend_from_slice(&[0x02, 1, 1]);
+    args.extend_from_slice(oracle_key().verifying_key().to_encoded_point(true).as_bytes());
+    deployment.market_type(args.into())
+}
+
+/// Message amending the proposal of the market cell `market_input` spends to
+/// `proposal` (None withdraws it), as the market script builds it
+fn amendment_message(market_type: &Script, market_input: &CellInput, proposal: Option<Proposal>) -> [u8; 32] {
+    let mut data = market_type.calc_script_hash().as_slice().to_vec();
+    data.extend_from_slice(market_input.previous_output().as_slice());
+    data.push(proposal.map_or(0, Proposal::to_byte));
+    blake2b_256(data)
+}
+
+/// Amendment of a quorum market's pending YES to `amended`, with the oracle
+/// signing an amendment to `signed`
+fn amendment_tx(deployment: &mut Deployment, amended: &MarketData, signed: Option<Proposal>) -> TransactionView {
+    let market_type = quorum_market(deployment);
+    let proposed = MarketData { pending_outcome: Some(Proposal::Yes), proposed_at: PROPOSED_AT, ..deployment.market_data() };
+    let market_cell = deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &proposed);
+    let market_input = deployment.input(market_cell, 0);
+
+    let witness = oracle_witness(&amendment_message(&market_type, &market_input, signed));
+
+    let outputs = vec![deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, amended)];
+    deployment
+        .transaction(vec![market_input], outputs)
+        .as_advanced_builder()
+        .witness(witness.as_bytes().pack())
+        .build()
+}
+
+/// Market input witness carrying the quorum oracle's signature over `message`
+fn oracle_witness(message: &[u8; 32]) -> WitnessArgs {
+    let (signature, recovery_id) = oracle_key().sign_prehash_recoverable(message).unwrap();
+    let mut signature = signature.to_bytes().to_vec();
+    signature.push(recovery_id.to_byte());
+    WitnessArgs::new_builder().input_type(Some(Bytes::from(signature)).pack()).build()
+}
+
+/// Proposal of YES on an open quorum market, with the oracle signing it for
+/// the market cell `signed_for` (the spent cell when None)
+fn quorum_proposal_tx(deployment: &mut Deployment, signed_for: Option<&CellInput>) -> TransactionView {
+    let market_type = quorum_market(deployment);
+    let open = deployment.market_data();
+    let proposed = MarketData { pending_outcome: Some(Proposal::Yes), proposed_at: PROPOSED_AT, ..open.clone() };
+    let market_cell = deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &open);
+    let market_input = deployment.input(market_cell, 0);
+
+    // A proposal signs what amending from no proposal to it would
+    let message = amendment_message(&market_type, signed_for.unwrap_or(&market_input), Some(Proposal::Yes));
+    let outputs = vec![deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &proposed)];
+    deployment
+        .transaction(vec![market_input], outputs)
+        .as_advanced_builder()
+        .witness(oracle_witness(&message).as_bytes().pack())
+        .build()
+}
+
+#[test]
+fn proposal_is_amended_then_finalized() {
+    let mut deployment = Deployment::new();
+    let amended = MarketData {
+        pending_outcome: Some(Proposal::No),
+        proposed_at: PROPOSED_AT + 3600,
+        ..deployment.market_data()
+    };
+    let amend = amendment_tx(&mut deployment, &amended, Some(Proposal::No));
+    deployment.verify(&amend).expect("amend YES to NO");
+
+    // The amended proposal stands for 24 hours of its own, then finalizes as NO
+    let market_type = quorum_market(&mut deployment);
+    let resolved = MarketData { resolved: true, outcome: false, pending_outcome: None, ..amended.clone() };
+    let market_cell = deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &amended);
+    let market_input = deployment.input(market_cell, relative_timestamp_since(24 * 60 * 60));
+    let outputs = vec![deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &resolved)];
+    let finalize = deployment.transaction(vec![market_input], outputs);
+    deployment.verify(&finalize).expect("finalize the amended outcome");
+}
+
+#[test]
+fn proposal_is_withdrawn() {
+    let mut deployment = Deployment::new();
+    let open = deployment.market_data();
+    let withdraw = amendment_tx(&mut deployment, &open, None);
+    deployment.verify(&withdraw).expect("withdraw the proposal");
+}
+
+#[test]
+fn quorum_proposal_is_signed_for_the_market_cell() {
+    let mut deployment = Deployment::new();
+    let propose = quorum_proposal_tx(&mut deployment, None);
+    deployment.verify(&propose).expect("propose YES");
+}
+
+#[test]
+fn proposal_signature_replayed_after_withdrawal_fails() {
+    let mut deployment = Deployment::new();
+    // The YES was signed for the market cell a withdrawal has since spent
+    let market_type = quorum_market(&mut deployment);
+    let open = deployment.market_data();
+    let withdrawn_cell = deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &open);
+    let withdrawn = deployment.input(withdrawn_cell, 0);
+    let replay = quorum_proposal_tx(&mut deployment, Some(&withdrawn));
+    // InvalidOracleSignature
+    assert_script_error(deployment.verify(&replay).unwrap_err(), 35);
+}
+
+#[test]
+fn amendment_signed_for_another_outcome_fails() {
+    let mut deployment = Deployment::new();
+    let amended = MarketData {
+        pending_outcome: Some(Proposal::No),
+        proposed_at: PROPOSED_AT + 3600,
+        ..deployment.market_data()
+    };
+    let amend = amendment_tx(&mut deployment, &amended, Some(Proposal::Cancel));
+    // InvalidOracleSignature
+    assert_script_error(deployment.verify(&amend).unwrap_err(), 35);
+}
+
+#[test]
+fn permissionless_proposal_is_fixed() {
+    let mut deployment = Deployment::new();
+    let market_type = existing_market(&mut deployment);
+    let proposed = MarketData { pending_outcome: Some(Proposal::Yes), proposed_at: PROPOSED_AT, ..deployment.market_data() };
+    let amended = MarketData { pending_outcome: Some(Proposal::No), proposed_at: PROPOSED_AT + 3600, ..proposed.clone() };
+
+    let market_cell = deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &proposed);
+    let market_input = deployment.input(market_cell, 0);
+    let outputs = vec![deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &amended)];
+    let amend = deployment.transaction(vec![market_input], outputs);
+    // ProposalFixed
+    assert_script_error(deployment.verify(&amend).unwrap_err(), 45);
+}
+
+#[test]
+fn claim_before_finalization_fails() {
+    let mut deployment = Deployment::new();
+    let market_type = existing_market(&mut deployment);
+    let proposed = MarketData { pending_outcome: Some(Proposal::Yes), proposed_at: PROPOSED_AT, ..deployment.market_data() };
+
+    let market_cell = deployment.market_cell(&market_type, MARKET_CKB + 10 * SET, &proposed);
+    let market_input = deployment.input(market_cell, 0);
+    let yes_cell = deployment.token_cell(&market_type, YES, 10);
+    let yes_input = deployment.input(yes_cell, 0);
+    let outputs = vec![
+        deployment.market_cell(&market_type, MARKET_CKB + 5 * SET, &proposed),
+        deployment.token_cell(&market_type, YES, 5),
+        deployment.user_cell(5 * SET - 1_000),
+    ];
+    let tx = deployment.transaction(vec![market_input, yes_input], outputs);
+    // ResolutionPending
+    assert_script_error(deployment.verify(&tx).unwrap_err(), 31);
+}
@@ -5,38 +5,70 @@
 //! 2. Mint tokens (complete sets)
 //! 3. Resolve market
 //! 4. Claim winnings
+//! 5. Refund complete sets of cancelled markets
+//! 6. Redeem complete sets of resolved markets
 
 use anyhow::{anyhow, Result};
 use axum::{
-    extract::State,
-    http::{StatusCode, Method},
+    async_trait,
+    extract::{FromRequest, MatchedPath, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Method},
+    middleware::{self, Next},
     response::{IntoResponse, Json, Response},
     routing::{get, post},
     Router,
 };
 use ckb_hash::blake2b_256;
+use clap::{Parser, Subcommand, ValueEnum};
 use ckb_sdk::{
     constants::SIGHASH_TYPE_HASH,
-    rpc::CkbRpcClient,
-    rpc::ckb_indexer::{SearchKey, ScriptType, SearchMode, Order},
+    rpc::{CkbRpcClient, RpcError},
+    rpc::ckb_indexer::{self, SearchKey, ScriptType, SearchMode, Order},
 };
 use ckb_types::{
     bytes::Bytes,
-    core::{ScriptHashType, TransactionView},
+    core::{Capacity, ScriptHashType, TransactionView},
     packed::{CellDep, CellInput, CellOutput, OutPoint, Script, WitnessArgs},
     prelude::*,
     H256,
 };
+use market_common::{
+    token_amount, type_id_preimage, MarketData, Metadata, Proposal, ProtocolFee, Scalar, Supplies,
+    DEFAULT_SHANNONS_PER_TOKEN, MAX_FEE_BPS, TOKEN_AMOUNT_LEN,
+};
 use serde::{Deserialize, Serialize};
-use std::{str::FromStr, sync::{Arc, Mutex}};
-use tower_http::cors::{CorsLayer, Any};
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::{Deref, DerefMut},
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tower_http::{
+    cors::{AllowOrigin, Any, CorsLayer},
+    trace::TraceLayer,
+};
+use tracing::{debug, error, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
+
+#[cfg(test)]
+mod mock_rpc;
+#[cfg(test)]
+mod tests;
 
-// Devnet RPC endpoint
+// Devnet RPC endpoint (default for CKB_RPC_URL)
 const DEVNET_RPC: &str = "http://127.0.0.1:8114";
 
-// Account #0 from offckb (pre-funded with 420M CKB)
+// Account #0 from offckb (pre-funded with 420M CKB; default for CKB_PRIVKEY)
 const PRIVKEY: &str = "6109170b275a09ad54877b82f7d9930f88cab5717d484fb4741ae9d1dd078cd6";
 
+// How often the TVL gauge rescans market cells (override with TVL_REFRESH_SECS)
+const DEFAULT_TVL_REFRESH_SECS: u64 = 60;
+
+// How often fee-wallet consolidation runs when CONSOLIDATE_THRESHOLD is set
+// (override with CONSOLIDATE_INTERVAL_SECS)
+const DEFAULT_CONSOLIDATE_INTERVAL_SECS: u64 = 300;
+
 /// Contract deployment info
 struct ContractInfo {
     market_code_hash: H256,
@@ -45,37 +77,148 @@ struct ContractInfo {
     token_tx_hash: H256,
     always_success_code_hash: H256,
     always_success_tx_hash: H256,
+    // How the market lock references always-success (ALWAYS_SUCCESS_HASH_TYPE)
+    always_success_hash_type: ScriptHashType,
+    // Chain the deployment lives on (selects the secp256k1 dep group)
+    network: Network,
 }
 
-/// Market data structure (34 bytes)
-#[derive(Debug, Clone, Default)]
-struct MarketData {
-    yes_supply: u128,
-    no_supply: u128,
-    resolved: bool,
-    outcome: bool,
+/// Chain the server targets (CKB_NETWORK or `network` in config.toml)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Network {
+    Dev,
+    Test,
+    Main,
 }
 
-impl MarketData {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(34);
-        bytes.extend_from_slice(&self.yes_supply.to_le_bytes());
-        bytes.extend_from_slice(&self.no_supply.to_le_bytes());
-        bytes.push(if self.resolved { 1 } else { 0 });
-        bytes.push(if self.outcome { 1 } else { 0 });
-        bytes
+impl Network {
+    /// Parse `dev`, `test` or `main` (also `devnet`, `testnet`, `mainnet`)
+    fn from_name(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().trim_end_matches("net") {
+            "dev" => Ok(Network::Dev),
+            "test" => Ok(Network::Test),
+            "main" => Ok(Network::Main),
+            _ => Err(anyhow!("Unknown network {:?}, expected dev, test or main", name)),
+        }
     }
 
-    fn from_bytes(data: &[u8]) -> Result<Self> {
-        if data.len() < 34 {
-            return Err(anyhow!("Invalid market data length: {}", data.len()));
+    /// Address prefix (ckt for dev and test, ckb for main)
+    fn address_type(self) -> ckb_sdk::NetworkType {
+        match self {
+            Network::Dev => ckb_sdk::NetworkType::Dev,
+            Network::Test => ckb_sdk::NetworkType::Testnet,
+            Network::Main => ckb_sdk::NetworkType::Mainnet,
+        }
+    }
+
+    /// Chain name the node reports in get_blockchain_info (devnets vary)
+    fn matches_chain(self, chain: &str) -> bool {
+        match self {
+            Network::Main => chain == "ckb",
+            Network::Test => chain == "ckb_testnet",
+            Network::Dev => chain != "ckb" && chain != "ckb_testnet",
+        }
+    }
+
+    /// Outpoint of the secp256k1 sighash dep group from the chain's genesis
+    fn secp256k1_dep_group(self) -> OutPoint {
+        let tx_hash = match self {
+            // offckb devnet genesis
+            Network::Dev => "75be96e1871693f030db27ddae47890a28ab180e88e36ebb3575d9f1377d3da7",
+            // Pudge testnet
+            Network::Test => "f8de3bb47d055cdf460d93a2a6e1b05f7432f9777c8c474abf4eec1d4aee5d37",
+            // Mirana mainnet
+            Network::Main => "71a7ba8fc96349fea0ed3a5c47992e3b4084b031a42264a018e0072e8172e46c",
+        };
+        OutPoint::new_builder()
+            .tx_hash(H256::from_str(tx_hash).expect("valid dep group hash").pack())
+            .index(0u32.pack())
+            .build()
+    }
+}
+
+// Token script hash_type byte new markets are created with (Data1)
+const DEFAULT_TOKEN_HASH_TYPE: u8 = 2;
+
+/// Server-side helpers on the market data layout shared with the contract
+/// (see market-common). Devnet markets always record their supplies.
+trait MarketDataExt: Sized {
+    /// Open, empty market for the deployed token contract
+    fn open(contracts: &ContractInfo, shannons_per_token: u64) -> Self;
+    /// Recorded YES supply (0 for markets that don't record supplies)
+    fn yes_supply(&self) -> u128;
+    /// Recorded NO supply (0 for markets that don't record supplies)
+    fn no_supply(&self) -> u128;
+    /// The same market with its supplies replaced
+    fn with_supplies(&self, yes: u128, no: u128) -> Self;
+    /// Collateral backing `tokens` complete sets (or paid for `tokens` winning tokens)
+    fn collateral_for(&self, tokens: u128) -> Option<Shannons>;
+    /// collateral_for, refusing amounts whose collateral can't fit a cell capacity
+    fn checked_collateral(&self, tokens: u128) -> Result<Shannons, Rejected>;
+    /// Shannons paid for claiming `tokens` of side `yes`: the collateral unit
+    /// for a winning token, or the side's share of it on a scalar market
+    fn claim_payout(&self, yes: bool, tokens: u128) -> Result<u64, Rejected>;
+    /// Part of a claim's `payout` owed to the fee recipient (0 without a fee)
+    fn claim_fee(&self, payout: u64) -> u64;
+    /// ScriptHashType the contract derives token hashes with for this market
+    fn token_script_hash_type(&self) -> Result<ScriptHashType>;
+}
+
+impl MarketDataExt for MarketData {
+    fn open(contracts: &ContractInfo, shannons_per_token: u64) -> Self {
+        MarketData {
+            shannons_per_token,
+            supplies: Some(Supplies::default()),
+            ..MarketData::new(contracts.token_code_hash.0, DEFAULT_TOKEN_HASH_TYPE)
+        }
+    }
+
+    fn yes_supply(&self) -> u128 {
+        self.supplies.map_or(0, |supplies| supplies.yes)
+    }
+
+    fn no_supply(&self) -> u128 {
+        self.supplies.map_or(0, |supplies| supplies.no)
+    }
+
+    fn with_supplies(&self, yes: u128, no: u128) -> Self {
+        MarketData { supplies: Some(Supplies { yes, no }), ..self.clone() }
+    }
+
+    fn collateral_for(&self, tokens: u128) -> Option<Shannons> {
+        tokens.checked_mul(self.shannons_per_token as u128)
+            .and_then(|shannons| shannons.try_into().ok())
+            .map(Shannons)
+    }
+
+    fn claim_payout(&self, yes: bool, tokens: u128) -> Result<u64, Rejected> {
+        let Some(scalar) = self.scalar else {
+            return self.checked_collateral(tokens).map(Shannons::as_shannons);
+        };
+        tokens.checked_mul(scalar.side_ratio(yes, self.shannons_per_token as u128))
+            .and_then(|shannons| shannons.try_into().ok())
+            .ok_or_else(|| Rejected(format!("Claim of {} tokens exceeds the u64 capacity limit", tokens)))
+    }
+
+    fn claim_fee(&self, payout: u64) -> u64 {
+        self.fee.map_or(0, |fee| fee.fee_on(payout))
+    }
+
+    fn checked_collateral(&self, tokens: u128) -> Result<Shannons, Rejected> {
+        self.collateral_for(tokens).ok_or_else(|| Rejected(format!(
+            "Amount {} too large for available capacity: at {} shannons per token it exceeds the u64 capacity limit",
+            tokens, self.shannons_per_token
+        )))
+    }
+
+    fn token_script_hash_type(&self) -> Result<ScriptHashType> {
+        match self.hash_type {
+            0 => Ok(ScriptHashType::Data),
+            1 => Ok(ScriptHashType::Type),
+            2 => Ok(ScriptHashType::Data1),
+            4 => Ok(ScriptHashType::Data2),
+            other => Err(anyhow!("Unsupported token hash_type {} in market data", other)),
         }
-        Ok(MarketData {
-            yes_supply: u128::from_le_bytes(data[0..16].try_into()?),
-            no_supply: u128::from_le_bytes(data[16..32].try_into()?),
-            resolved: data[32] != 0,
-            outcome: data[33] != 0,
-        })
     }
 }
 
@@ -85,29 +228,386 @@ impl MarketData {
 
 /// Shared application state
 struct AppState {
-    client: Mutex<CkbRpcClient>,
+    /// Opens the client each request talks to the node through
+    /// (a CkbRpcClient for the configured node outside tests)
+    backend: Box<dyn Fn() -> Box<dyn CkbBackend> + Send + Sync>,
+    /// Held by wallet transactions for their whole collect-sign-confirm cycle,
+    /// so two of them never select the same fee cells; reads don't take it
+    wallet: Mutex<()>,
+    /// Whether request handlers wait for their transactions to commit
+    send_mode: SendMode,
     privkey: secp256k1::SecretKey,
     contracts: ContractInfo,
     lock_script: Script,
     current_market: Mutex<Option<OutPoint>>,
+    /// Where current_market is saved so a restart can pick it up again
+    market_state_file: String,
+    tvl: Mutex<Option<TvlResponse>>,
+    /// Answers to requests sent with an Idempotency-Key
+    idempotency: Mutex<IdempotencyKeys>,
+}
+
+impl AppState {
+    /// Fresh client for read-only queries, which run concurrently with
+    /// everything else
+    fn rpc(&self) -> Box<dyn CkbBackend> {
+        (self.backend)()
+    }
+
+    /// Client for building and sending wallet transactions, holding the
+    /// wallet lock until it is dropped
+    fn wallet_client(&self) -> WalletClient<'_> {
+        WalletClient { client: self.rpc(), _wallet: lock(&self.wallet) }
+    }
+
+    /// Point the server at a new market cell and save it
+    /// A failed save only loses the pointer across a restart, so it is logged
+    /// rather than failing a request whose transaction already committed.
+    fn set_current_market(&self, outpoint: OutPoint) {
+        let market_type_hash = build_market_type(&self.contracts).calc_script_hash();
+        if let Err(err) = save_market_state(&self.market_state_file, &market_type_hash, &outpoint) {
+            warn!(file = %self.market_state_file, error = %err, "Could not save the current market");
+        }
+        *lock(&self.current_market) = Some(outpoint);
+    }
+
+    /// After a conflict on `spent`, move current_market on to the market's
+    /// latest cell if it still points at `spent`
+    fn refresh_current_market(&self, spent: &OutPoint) -> Result<()> {
+        let is_current = lock(&self.current_market).as_ref()
+            .map(|outpoint| outpoint.as_slice()) == Some(spent.as_slice());
+        if !is_current {
+            return Ok(());
+        }
+        let mut client = self.rpc();
+        match follow_market(&mut *client, &self.contracts, spent.clone())? {
+            Some(latest) => {
+                info!(market = %format_market_id(&latest), "Current market was spent elsewhere; following it");
+                self.set_current_market(latest);
+            }
+            None => warn!(market = %format_market_id(spent), "Current market was spent elsewhere and has no successor"),
+        }
+        Ok(())
+    }
+}
+
+/// Node client that holds AppState::wallet while alive
+struct WalletClient<'a> {
+    client: Box<dyn CkbBackend>,
+    _wallet: MutexGuard<'a, ()>,
+}
+
+impl Deref for WalletClient<'_> {
+    type Target = dyn CkbBackend;
+
+    fn deref(&self) -> &(dyn CkbBackend + 'static) {
+        &*self.client
+    }
+}
+
+impl DerefMut for WalletClient<'_> {
+    fn deref_mut(&mut self) -> &mut (dyn CkbBackend + 'static) {
+        &mut *self.client
+    }
+}
+
+/// The node RPC methods the server calls, so tests can run it against an
+/// in-memory node (see mock_rpc) instead of a devnet
+///
+/// Signatures follow CkbRpcClient's, which implements this by forwarding.
+trait CkbBackend: Send {
+    fn get_blockchain_info(&mut self) -> Result<ckb_jsonrpc_types::ChainInfo, RpcError>;
+    fn get_tip_block_number(&mut self) -> Result<ckb_jsonrpc_types::BlockNumber, RpcError>;
+    fn get_header(&mut self, block_hash: H256) -> Result<Option<ckb_jsonrpc_types::HeaderView>, RpcError>;
+    fn get_transaction(&mut self, tx_hash: H256) -> Result<Option<ckb_jsonrpc_types::TransactionWithStatusResponse>, RpcError>;
+    fn get_transaction_proof(
+        &mut self,
+        tx_hashes: Vec<H256>,
+        block_hash: Option<H256>,
+    ) -> Result<ckb_jsonrpc_types::TransactionProof, RpcError>;
+    fn get_live_cell(&mut self, out_point: ckb_jsonrpc_types::OutPoint, with_data: bool) -> Result<ckb_jsonrpc_types::CellWithStatus, RpcError>;
+    fn get_cells(
+        &mut self,
+        search_key: SearchKey,
+        order: Order,
+        limit: ckb_jsonrpc_types::Uint32,
+        after: Option<ckb_jsonrpc_types::JsonBytes>,
+    ) -> Result<ckb_indexer::Pagination<ckb_indexer::Cell>, RpcError>;
+    fn get_transactions(
+        &mut self,
+        search_key: SearchKey,
+        order: Order,
+        limit: ckb_jsonrpc_types::Uint32,
+        after: Option<ckb_jsonrpc_types::JsonBytes>,
+    ) -> Result<ckb_indexer::Pagination<ckb_indexer::Tx>, RpcError>;
+    fn get_cells_capacity(&mut self, search_key: SearchKey) -> Result<Option<ckb_indexer::CellsCapacity>, RpcError>;
+    fn send_transaction(
+        &mut self,
+        tx: ckb_jsonrpc_types::Transaction,
+        outputs_validator: Option<ckb_jsonrpc_types::OutputsValidator>,
+    ) -> Result<H256, RpcError>;
+    fn test_tx_pool_accept(
+        &mut self,
+        tx: ckb_jsonrpc_types::Transaction,
+        outputs_validator: Option<ckb_jsonrpc_types::OutputsValidator>,
+    ) -> Result<ckb_jsonrpc_types::EntryCompleted, RpcError>;
+}
+
+impl CkbBackend for CkbRpcClient {
+    fn get_blockchain_info(&mut self) -> Result<ckb_jsonrpc_types::ChainInfo, RpcError> {
+        CkbRpcClient::get_blockchain_info(self)
+    }
+
+    fn get_tip_block_number(&mut self) -> Result<ckb_jsonrpc_types::BlockNumber, RpcError> {
+        CkbRpcClient::get_tip_block_number(self)
+    }
+
+    fn get_header(&mut self, block_hash: H256) -> Result<Option<ckb_jsonrpc_types::HeaderView>, RpcError> {
+        CkbRpcClient::get_header(self, block_hash)
+    }
+
+    fn get_transaction(&mut self, tx_hash: H256) -> Result<Option<ckb_jsonrpc_types::TransactionWithStatusResponse>, RpcError> {
+        CkbRpcClient::get_transaction(self, tx_hash)
+    }
+
+    fn get_transaction_proof(
+        &mut self,
+        tx_hashes: Vec<H256>,
+        block_hash: Option<H256>,
+    ) -> Result<ckb_jsonrpc_types::TransactionProof, RpcError> {
+        CkbRpcClient::get_transaction_proof(self, tx_hashes, block_hash)
+    }
+
+    fn get_live_cell(&mut self, out_point: ckb_jsonrpc_types::OutPoint, with_data: bool) -> Result<ckb_jsonrpc_types::CellWithStatus, RpcError> {
+        CkbRpcClient::get_live_cell(self, out_point, with_data)
+    }
+
+    fn get_cells(
+        &mut self,
+        search_key: SearchKey,
+        order: Order,
+        limit: ckb_jsonrpc_types::Uint32,
+        after: Option<ckb_jsonrpc_types::JsonBytes>,
+    ) -> Result<ckb_indexer::Pagination<ckb_indexer::Cell>, RpcError> {
+        CkbRpcClient::get_cells(self, search_key, order, limit, after)
+    }
+
+    fn get_transactions(
+        &mut self,
+        search_key: SearchKey,
+        order: Order,
+        limit: ckb_jsonrpc_types::Uint32,
+        after: Option<ckb_jsonrpc_types::JsonBytes>,
+    ) -> Result<ckb_indexer::Pagination<ckb_indexer::Tx>, RpcError> {
+        CkbRpcClient::get_transactions(self, search_key, order, limit, after)
+    }
+
+    fn get_cells_capacity(&mut self, search_key: SearchKey) -> Result<Option<ckb_indexer::CellsCapacity>, RpcError> {
+        CkbRpcClient::get_cells_capacity(self, search_key)
+    }
+
+    fn send_transaction(
+        &mut self,
+        tx: ckb_jsonrpc_types::Transaction,
+        outputs_validator: Option<ckb_jsonrpc_types::OutputsValidator>,
+    ) -> Result<H256, RpcError> {
+        CkbRpcClient::send_transaction(self, tx, outputs_validator)
+    }
+
+    fn test_tx_pool_accept(
+        &mut self,
+        tx: ckb_jsonrpc_types::Transaction,
+        outputs_validator: Option<ckb_jsonrpc_types::OutputsValidator>,
+    ) -> Result<ckb_jsonrpc_types::EntryCompleted, RpcError> {
+        CkbRpcClient::test_tx_pool_accept(self, tx, outputs_validator)
+    }
+}
+
+/// Lock `mutex`, taking over the guard if a panicking request poisoned it
+/// Every value behind the server's locks is replaced whole, so a panic can't
+/// leave one half-written, and refusing every later request would only spread
+/// one request's failure to the whole server.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// API request to create a market (the body is optional)
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CreateMarketRequest {
+    /// Extra capacity on top of the market cell's occupied minimum (shannons)
+    #[serde(default)]
+    buffer_shannons: u64,
+    /// Collateral per complete set (shannons, default 100 CKB)
+    shannons_per_token: Option<u64>,
+    /// Unix seconds before which the market can't be resolved (default: any time)
+    #[serde(default)]
+    resolve_after: u64,
+    /// Question recorded in the market cell (up to 255 bytes)
+    question: Option<String>,
+    /// Outcome labels recorded with the question, NO first (exactly two)
+    outcome_labels: Option<Vec<String>>,
+    /// Share of every claim paid to FEE_RECIPIENT_ADDRESS, in basis points
+    #[serde(default)]
+    fee_bps: u16,
+    /// Lower end of a scalar market's range (with `scalar_ceiling`)
+    scalar_floor: Option<u64>,
+    /// Upper end of a scalar market's range (with `scalar_floor`)
+    scalar_ceiling: Option<u64>,
+}
+
+/// Result of draining a market and creating a fresh one in its place
+#[derive(Debug, Serialize)]
+struct ResetResponse {
+    success: bool,
+    /// Cancellation and refund/claim transactions, in order (empty if nothing could be drained)
+    drain_tx_hashes: Vec<String>,
+    create_tx_hash: String,
+    market_id: String,
 }
 
 /// API request to mint tokens
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct MintRequest {
     amount: u128,
 }
 
-/// API request to resolve market
+/// API request to burn complete sets of the current market
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BurnRequest {
+    amount: u128,
+}
+
+/// Result of burning complete sets
+#[derive(Debug, Serialize)]
+struct BurnResponse {
+    success: bool,
+    message: String,
+    tx_hash: String,
+    market_id: String,
+    refunded_ckb: u64,
+}
+
+/// API request to send tokens of the current market to another address
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TransferRequest {
+    /// "YES" or "NO"
+    token_id: String,
+    amount: u128,
+    /// Destination CKB address
+    to: String,
+}
+
+/// API request to resolve market: an `outcome` for a YES/NO market, a
+/// `value` within the range of a scalar one
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct ResolveRequest {
-    outcome: bool,
+    outcome: Option<bool>,
+    value: Option<u64>,
+}
+
+/// API request to propose the current market's resolution: an `outcome`, a
+/// scalar `value`, or `cancel`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProposeRequest {
+    outcome: Option<bool>,
+    value: Option<u64>,
+    #[serde(default)]
+    cancel: bool,
 }
 
 /// API request to claim tokens
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct ClaimRequest {
     amount: u128,
+    /// "YES" (LONG) or "NO" (SHORT); required on scalar markets, defaults
+    /// to the winning side otherwise
+    side: Option<String>,
+}
+
+/// API request to start managing a market created elsewhere, named by its
+/// live cell (`market_id`) or its Type ID (`type_id`, the first 32 bytes of
+/// its type args)
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImportMarketRequest {
+    market_id: Option<String>,
+    type_id: Option<String>,
+}
+
+/// Query parameters for a token balance (defaults to the server's own lock)
+#[derive(Debug, Deserialize)]
+struct BalanceParams {
+    address: Option<String>,
+}
+
+/// Query parameters for refunding complete sets of a cancelled market
+#[derive(Debug, Deserialize)]
+struct RefundParams {
+    market_id: String,
+    amount: u128,
+}
+
+/// Query parameters for quoting an operation (defaults to the current market)
+#[derive(Debug, Deserialize)]
+struct QuoteParams {
+    amount: u128,
+    market_id: Option<String>,
+}
+
+/// Optional parameters for previewing an operation (unused ones are ignored)
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PreviewRequest {
+    #[serde(default)]
+    amount: u128,
+    #[serde(default)]
+    outcome: bool,
+    /// Resolve only: the value a scalar market is proposed at, instead of `outcome`
+    value: Option<u64>,
+    #[serde(default)]
+    buffer_shannons: u64,
+    shannons_per_token: Option<u64>,
+    #[serde(default)]
+    resolve_after: u64,
+    question: Option<String>,
+    outcome_labels: Option<Vec<String>>,
+    #[serde(default)]
+    fee_bps: u16,
+    /// Also return the unsigned transaction in the node's JSON shape
+    #[serde(default)]
+    include_transaction: bool,
+    market_id: Option<String>,
+    /// Mint only: fund the collateral from these `0x<tx_hash>:<index>` cells
+    /// of the user's wallet instead of the server's
+    funding_inputs: Option<Vec<String>>,
+    /// Mint only: address receiving the tokens and change when
+    /// `funding_inputs` is given
+    change_address: Option<String>,
+}
+
+/// Query parameters of the create, mint, resolve (one- or two-phase) and claim endpoints
+#[derive(Debug, Default, Deserialize)]
+struct DryRunParams {
+    /// Build and sign the transaction but return it instead of broadcasting
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// A signed transaction built by a dry run and not broadcast
+#[derive(Debug, Serialize)]
+struct DryRunResponse {
+    success: bool,
+    dry_run: bool,
+    tx_hash: String,
+    /// In the node's JSON shape, ready for send_transaction
+    transaction: ckb_jsonrpc_types::TransactionView,
 }
 
 /// API response
@@ -133,823 +633,6130 @@ struct MarketDataJson {
     no_supply: String,
     resolved: bool,
     outcome: bool,
+    cancelled: bool,
+    /// Market capacity minus what the cell itself occupies, in shannons
+    collateral: u64,
+    /// Blocks until claims and refunds unlock (0 once the dispute period is
+    /// over or if the market has none)
+    dispute_blocks_left: u64,
+    question: Option<String>,
+    outcome_labels: Option<Vec<String>>,
+    scalar: Option<ScalarJson>,
 }
 
-/// API error type
-struct ApiError(anyhow::Error);
-
-impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ApiResponse {
-                success: false,
-                message: self.0.to_string(),
-                tx_hash: None,
-            }),
-        )
-            .into_response()
-    }
+/// Range and proposed or resolved value of a scalar market
+#[derive(Debug, Clone, Serialize)]
+struct ScalarJson {
+    floor: u64,
+    ceiling: u64,
+    /// Value written by the pending or finalized proposal (the floor until one
+    /// is made); final only once the market is `resolved`
+    value: u64,
 }
 
-impl<E> From<E> for ApiError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+impl From<Scalar> for ScalarJson {
+    fn from(scalar: Scalar) -> Self {
+        ScalarJson { floor: scalar.floor, ceiling: scalar.ceiling, value: scalar.value }
     }
 }
 
-// ============================================================================
-// Main & API Server
-// ============================================================================
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Check if we should run in test mode
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() > 1 && args[1] == "test" {
-        return run_tests();
-    }
+/// Where a transaction stands, for clients polling after a broadcast
+/// `status` is "pending", "committed" or "rejected"; `block_number` is set
+/// once committed and `reason` once rejected.
+#[derive(Debug, Serialize)]
+struct TxStatusResponse {
+    tx_hash: String,
+    status: &'static str,
+    block_number: Option<u64>,
+    reason: Option<String>,
+}
 
-    println!("=== Market Contract API Server ===\n");
+/// YES and NO tokens an address holds in the current market
+/// Amounts are token counts as decimal strings, like the market supplies.
+#[derive(Debug, Serialize)]
+struct BalanceResponse {
+    address: String,
+    yes: String,
+    no: String,
+    yes_cells: usize,
+    no_cells: usize,
+}
 
-    // Initialize state
-    let client = CkbRpcClient::new(DEVNET_RPC);
-    println!("Connected to devnet at {}", DEVNET_RPC);
+/// Outstanding winning value of a resolved market
+/// Supplies are token counts, capacities are shannons.
+#[derive(Debug, Serialize)]
+struct ClaimableResponse {
+    outcome: bool,
+    minted_supply: String,
+    winning_supply: String,
+    claimed_supply: String,
+    claimable_capacity: Shannons,
+    claimed_capacity: Shannons,
+    market_capacity: Shannons,
+}
 
-    let contracts = get_contract_info()?;
-    let privkey_bytes = hex::decode(PRIVKEY)?;
-    let privkey = secp256k1::SecretKey::from_slice(&privkey_bytes)?;
+/// One outcome token of a market as sUDT tooling would index it
+///
+/// Token cells share sUDT's data layout but not its type script: the args are
+/// the market type hash and a token id rather than an owner lock hash, so
+/// wallets have to be told the type script to track.
+#[derive(Debug, Serialize)]
+struct TokenDescriptor {
+    /// "YES" or "NO"
+    symbol: &'static str,
+    token_id: u8,
+    type_script: ckb_jsonrpc_types::Script,
+    type_hash: String,
+    /// The market's declared decimals; amounts count units of 10^-decimals tokens
+    decimals: u8,
+    /// The amount is a u128 LE in cell data bytes 0..amount_len, as in sUDT
+    amount_len: usize,
+}
 
-    let secp = secp256k1::Secp256k1::new();
-    let pubkey = secp256k1::PublicKey::from_secret_key(&secp, &privkey);
-    let pubkey_hash = &blake2b_256(&pubkey.serialize())[0..20];
+/// What a mint, burn or claim of `amount` moves, in shannons
+/// `total_required` is what the fee wallet must hold for the builder to go
+/// ahead: for a mint the collateral, token cells, fee allowance and change
+/// margin; for a burn or claim only the fee cells, since collateral comes out
+/// of the market.
+#[derive(Debug, Serialize)]
+struct QuoteResponse {
+    operation: String,
+    amount: String,
+    /// Locked into (mint) or paid out of (burn, claim) the market cell
+    collateral: Shannons,
+    /// Locked in new token cells
+    token_cell_capacity: Shannons,
+    /// Largest fee the transaction can be charged
+    estimated_fee: Shannons,
+    total_required: Shannons,
+}
 
-    let lock_script = Script::new_builder()
-        .code_hash(SIGHASH_TYPE_HASH.pack())
-        .hash_type(ScriptHashType::Type.into())
-        .args(Bytes::from(pubkey_hash.to_vec()).pack())
-        .build();
+/// Capacity locked in a single market cell and the part of it backing tokens
+#[derive(Debug, Clone, Serialize)]
+struct MarketTvl {
+    tx_hash: String,
+    index: u32,
+    capacity: Shannons,
+    collateral: Shannons,
+}
 
-    let state = Arc::new(AppState {
-        client: Mutex::new(client),
-        privkey,
-        contracts,
-        lock_script,
-        current_market: Mutex::new(None),
-    });
+/// A market found on-chain; `market_id` is accepted wherever an id is taken
+#[derive(Debug, Serialize)]
+struct MarketSummary {
+    market_id: String,
+    yes_supply: String,
+    no_supply: String,
+    resolved: bool,
+    outcome: bool,
+    cancelled: bool,
+    capacity: u64,
+    question: Option<String>,
+    outcome_labels: Option<Vec<String>>,
+    scalar: Option<ScalarJson>,
+}
 
-    // Build API routes
-    let app = Router::new()
-        .route("/", get(serve_frontend))
-        .route("/api/status", get(handle_status))
-        .route("/api/create-market", post(handle_create_market))
-        .route("/api/mint", post(handle_mint))
-        .route("/api/resolve", post(handle_resolve))
-        .route("/api/claim", post(handle_claim))
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods([Method::GET, Method::POST])
-                .allow_headers(Any),
-        )
-        .with_state(state);
+/// Total value locked across all market cells (capacities in shannons)
+/// Collateral excludes the capacity each market cell occupies itself.
+#[derive(Debug, Clone, Serialize)]
+struct TvlResponse {
+    total_capacity: Shannons,
+    total_collateral: Shannons,
+    market_count: usize,
+    markets: Vec<MarketTvl>,
+    updated_at: u64,
+}
 
-    println!("Server starting on http://localhost:3001");
-    println!("API endpoints:");
-    println!("  GET  /api/status");
-    println!("  POST /api/create-market");
-    println!("  POST /api/mint");
-    println!("  POST /api/resolve");
-    println!("  POST /api/claim");
-    println!("\nTo run tests instead: cargo run test\n");
+/// Everything a light client needs to check a market cell without trusting the server:
+/// the committing transaction and its Merkle proof against the block's transactions root
+#[derive(Debug, Serialize)]
+struct MarketProofResponse {
+    outpoint: String,
+    block_hash: String,
+    transaction: ckb_jsonrpc_types::TransactionView,
+    proof: ckb_jsonrpc_types::TransactionProof,
+}
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3001").await?;
-    axum::serve(listener, app).await?;
+/// Audit record of a committed market operation, logged as one JSON line
+/// Deltas are output minus input (supplies in tokens, capacity in shannons).
+#[derive(Debug, Serialize)]
+struct OperationLog {
+    op: String,
+    market_id: String,
+    tx_hash: String,
+    yes_delta: i128,
+    no_delta: i128,
+    capacity_delta: i64,
+    fee: u64,
+}
 
-    Ok(())
+/// Readable breakdown of a transaction the server would submit
+/// Capacities and the fee are in shannons.
+#[derive(Debug, Serialize)]
+struct TxPreview {
+    op: String,
+    inputs: Vec<PreviewInput>,
+    outputs: Vec<PreviewOutput>,
+    cell_deps: Vec<PreviewCellDep>,
+    fee: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction: Option<ckb_jsonrpc_types::TransactionView>,
 }
 
-// ============================================================================
-// API Handlers
-// ============================================================================
+#[derive(Debug, Serialize)]
+struct PreviewInput {
+    outpoint: String,
+    capacity: u64,
+    role: String,
+}
 
-async fn serve_frontend() -> impl IntoResponse {
-    use axum::response::Html;
-    Html(include_str!("../frontend.html"))
+#[derive(Debug, Serialize)]
+struct PreviewOutput {
+    capacity: u64,
+    lock: String,
+    type_script: Option<String>,
+    data: String,
 }
 
-async fn handle_status(
-    State(state): State<Arc<AppState>>,
-) -> Result<Json<StatusResponse>, ApiError> {
-    let mut client = state.client.lock().unwrap();
+#[derive(Debug, Serialize)]
+struct PreviewCellDep {
+    outpoint: String,
+    dep_type: String,
+    role: String,
+}
 
-    let block_height = client.get_tip_block_number().ok().map(|h| h.value());
-    let market_outpoint = state.current_market.lock().unwrap().clone();
+/// JSON body extractor that answers malformed bodies with a 400
+///
+/// Unlike axum's Json it doesn't look at Content-Type, and a body the request
+/// struct rejects (e.g. an unknown field under `deny_unknown_fields`) becomes
+/// an InvalidRequest naming the problem instead of a 422 rejection.
+struct StrictJson<T>(T);
 
-    let market_data = if let Some(ref outpoint) = market_outpoint {
-        get_cell(&mut client, outpoint)
-            .ok()
-            .and_then(|cell| MarketData::from_bytes(&cell.data).ok())
-            .map(|data| MarketDataJson {
-                yes_supply: data.yes_supply.to_string(),
-                no_supply: data.no_supply.to_string(),
-                resolved: data.resolved,
-                outcome: data.outcome,
-            })
-    } else {
-        None
-    };
+#[async_trait]
+impl<T, S> FromRequest<S> for StrictJson<T>
+where
+    T: serde::de::DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
 
-    Ok(Json(StatusResponse {
-        connected: block_height.is_some(),
-        block_height,
-        market_created: market_outpoint.is_some(),
-        market_data,
-    }))
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let body = axum::body::Bytes::from_request(req, state).await
+            .map_err(|rejection| ApiError(InvalidRequest(rejection.body_text()).into()))?;
+        Ok(StrictJson(parse_request(&body)?))
+    }
 }
 
-async fn handle_create_market(
-    State(state): State<Arc<AppState>>,
-) -> Result<Json<ApiResponse>, ApiError> {
-    let mut client = state.client.lock().unwrap();
-
-    let outpoint = create_market(
-        &mut client,
-        &state.privkey,
-        &state.contracts,
-        &state.lock_script,
-    )?;
+/// Deserialize a request body, reporting what's wrong with it as an InvalidRequest
+fn parse_request<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, InvalidRequest> {
+    serde_json::from_slice(body).map_err(|err| InvalidRequest(format!("Invalid request body: {}", err)))
+}
 
-    let tx_hash: H256 = outpoint.tx_hash().unpack();
-    *state.current_market.lock().unwrap() = Some(outpoint);
+/// A request the server could not parse (HTTP 400)
+#[derive(Debug)]
+struct InvalidRequest(String);
 
-    Ok(Json(ApiResponse {
-        success: true,
-        message: "Market created successfully".to_string(),
-        tx_hash: Some(format!("{:#x}", tx_hash)),
-    }))
+impl std::fmt::Display for InvalidRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
-async fn handle_mint(
-    State(state): State<Arc<AppState>>,
-    Json(req): Json<MintRequest>,
-) -> Result<Json<ApiResponse>, ApiError> {
-    let market_outpoint = state.current_market.lock().unwrap().clone()
-        .ok_or_else(|| anyhow!("No market created yet"))?;
-
-    let mut client = state.client.lock().unwrap();
-
-    let new_outpoint = mint_tokens(
-        &mut client,
-        &state.privkey,
-        &state.contracts,
-        &state.lock_script,
-        market_outpoint,
-        req.amount,
-    )?;
+impl std::error::Error for InvalidRequest {}
 
-    let tx_hash: H256 = new_outpoint.tx_hash().unpack();
-    *state.current_market.lock().unwrap() = Some(new_outpoint);
+/// Error code sent with requests the server could not parse
+const INVALID_REQUEST_CODE: &str = "invalid_request";
 
-    Ok(Json(ApiResponse {
-        success: true,
-        message: format!("Minted {} YES + {} NO tokens", req.amount, req.amount),
-        tx_hash: Some(format!("{:#x}", tx_hash)),
-    }))
+/// A request the server refuses to carry out (HTTP 403)
+#[derive(Debug)]
+struct Forbidden(String);
+
+impl std::fmt::Display for Forbidden {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
-async fn handle_resolve(
-    State(state): State<Arc<AppState>>,
-    Json(req): Json<ResolveRequest>,
-) -> Result<Json<ApiResponse>, ApiError> {
-    let market_outpoint = state.current_market.lock().unwrap().clone()
-        .ok_or_else(|| anyhow!("No market created yet"))?;
-
-    let mut client = state.client.lock().unwrap();
-
-    let new_outpoint = resolve_market(
-        &mut client,
-        &state.privkey,
-        &state.contracts,
-        &state.lock_script,
-        market_outpoint,
-        req.outcome,
-    )?;
+impl std::error::Error for Forbidden {}
 
-    let tx_hash: H256 = new_outpoint.tx_hash().unpack();
-    *state.current_market.lock().unwrap() = Some(new_outpoint);
+/// Error code sent with refused requests
+const FORBIDDEN_CODE: &str = "forbidden";
 
-    Ok(Json(ApiResponse {
-        success: true,
-        message: format!("Market resolved: {} wins", if req.outcome { "YES" } else { "NO" }),
-        tx_hash: Some(format!("{:#x}", tx_hash)),
-    }))
+/// A market, cell or transaction the request refers to that doesn't exist (HTTP 404)
+#[derive(Debug)]
+struct NotFound(String);
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
-async fn handle_claim(
-    State(state): State<Arc<AppState>>,
-    Json(req): Json<ClaimRequest>,
-) -> Result<Json<ApiResponse>, ApiError> {
-    let market_outpoint = state.current_market.lock().unwrap().clone()
-        .ok_or_else(|| anyhow!("No market created yet"))?;
-
-    let mut client = state.client.lock().unwrap();
-
-    let new_outpoint = claim_tokens(
-        &mut client,
-        &state.privkey,
-        &state.contracts,
-        &state.lock_script,
-        market_outpoint,
-        req.amount,
-    )?;
+impl std::error::Error for NotFound {}
 
-    let tx_hash: H256 = new_outpoint.tx_hash().unpack();
-    *state.current_market.lock().unwrap() = Some(new_outpoint);
+/// Error code sent when what a request refers to doesn't exist
+const NOT_FOUND_CODE: &str = "not_found";
 
-    let collateral = req.amount * 100;
-    Ok(Json(ApiResponse {
-        success: true,
-        message: format!("Claimed {} tokens for {} CKB", req.amount, collateral),
-        tx_hash: Some(format!("{:#x}", tx_hash)),
-    }))
+/// A well-formed request the market or the caller's tokens don't allow right
+/// now, e.g. claiming before resolution or spending more than is held (HTTP 400)
+#[derive(Debug)]
+struct Rejected(String);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
-// ============================================================================
-// Test Mode
-// ============================================================================
+impl std::error::Error for Rejected {}
 
-fn run_tests() -> Result<()> {
-    println!("=== Market Contract Test Suite ===\n");
+/// Error code sent with requests the market state doesn't allow
+const REJECTED_CODE: &str = "rejected";
 
-    // Connect to devnet
-    let mut client = CkbRpcClient::new(DEVNET_RPC);
-    println!("Connected to devnet at {}", DEVNET_RPC);
+/// The market cell a request would spend was already consumed by another
+/// transaction (HTTP 409); the caller should refetch the market and retry
+#[derive(Debug)]
+struct MarketConflict(OutPoint);
 
-    // Check connection
-    let tip = client.get_tip_block_number()?;
-    println!("Current block height: {}\n", tip);
+impl std::fmt::Display for MarketConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Market {} was updated by another transaction, please refetch", format_market_id(&self.0))
+    }
+}
 
-    // Get contract info
-    let contracts = get_contract_info()?;
-    println!("Market code hash: {:#x}", contracts.market_code_hash);
-    println!("Always-success code hash: {:#x}\n", contracts.always_success_code_hash);
+impl std::error::Error for MarketConflict {}
 
-    // Parse private key and get signer
-    let privkey_bytes = hex::decode(PRIVKEY)?;
-    let privkey = secp256k1::SecretKey::from_slice(&privkey_bytes)?;
+/// Error code sent when the market moved on under a request
+const MARKET_CONFLICT_CODE: &str = "market_conflict";
 
-    // Get address from private key
-    let secp = secp256k1::Secp256k1::new();
-    let pubkey = secp256k1::PublicKey::from_secret_key(&secp, &privkey);
-    let pubkey_hash = &blake2b_256(&pubkey.serialize())[0..20];
+/// A request repeats the Idempotency-Key of one that is still running (HTTP 409)
+#[derive(Debug)]
+struct RequestInProgress(String);
 
-    let lock_script = Script::new_builder()
-        .code_hash(SIGHASH_TYPE_HASH.pack())
-        .hash_type(ScriptHashType::Type.into())
-        .args(Bytes::from(pubkey_hash.to_vec()).pack())
-        .build();
+impl std::fmt::Display for RequestInProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "A request with Idempotency-Key {} is still in progress, retry once it finishes", self.0)
+    }
+}
 
-    println!("Lock script hash: {:#x}", lock_script.calc_script_hash());
+impl std::error::Error for RequestInProgress {}
 
-    // Run tests
-    println!("\n=== Step 1: Create Market Cell ===");
-    let market_outpoint = create_market(&mut client, &privkey, &contracts, &lock_script)?;
-    println!("Market created!\n");
+/// Error code sent when a request's Idempotency-Key is still being handled
+const REQUEST_IN_PROGRESS_CODE: &str = "request_in_progress";
 
-    println!("=== Step 2: Mint 10 Tokens ===");
-    let market_outpoint = mint_tokens(&mut client, &privkey, &contracts, &lock_script, market_outpoint, 10)?;
-    println!("Minted 10 YES + 10 NO tokens!\n");
+/// A request repeats an Idempotency-Key with a different body (HTTP 422)
+#[derive(Debug)]
+struct IdempotencyKeyReused(String);
 
-    println!("=== Step 3: Resolve Market (YES wins) ===");
-    let market_outpoint = resolve_market(&mut client, &privkey, &contracts, &lock_script, market_outpoint, true)?;
-    println!("Market resolved: YES wins!\n");
+impl std::fmt::Display for IdempotencyKeyReused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Idempotency-Key {} was already used for a different request", self.0)
+    }
+}
 
-    println!("=== Step 4: Claim 5 Winning Tokens ===");
-    let _final_outpoint = claim_tokens(&mut client, &privkey, &contracts, &lock_script, market_outpoint, 5)?;
-    println!("Claimed 5 YES tokens for 500 CKB!\n");
+impl std::error::Error for IdempotencyKeyReused {}
 
-    println!("=== All Tests Passed! ===");
-    Ok(())
-}
+/// Error code sent when an Idempotency-Key is reused for another request
+const IDEMPOTENCY_KEY_REUSED_CODE: &str = "idempotency_key_reused";
 
-fn get_contract_info() -> Result<ContractInfo> {
-    // From offckb deployment
-    Ok(ContractInfo {
-        market_code_hash: H256::from_str("fe3a71cfcb556500e7f760b5c853be8fc082d32748aa9e5a98e25d79d4116485")?,
-        market_tx_hash: H256::from_str("6c88542e395d308dc6e08b745473dce80e06ae06e50c69221b54508c5b5335d5")?,
-        token_code_hash: H256::from_str("54f68c08a051facc261167d0a45383cc5fa8b1ea7d1f9d9be5a7e623e27a1320")?,
-        token_tx_hash: H256::from_str("b5580c10ce2545acbf9b05ca8b7e44d62dcc7d837e0557b343222b7dd6c22b0f")?,
-        always_success_code_hash: H256::from_str("21854a7b67a2c4a71a8558c6d4023cf787e71db49d09cb4aa8748dbf6a8ef6ec")?,
-        always_success_tx_hash: H256::from_str("0cc42f03d73e685843da66a6f049107634986572802eb8d0363e7e662125d077")?,
-    })
+/// A transaction was sent but not seen to commit, e.g. confirmation timed out;
+/// it may still commit, so the request must not be sent again (HTTP 500, with
+/// the transaction hash in the body)
+#[derive(Debug)]
+struct Unconfirmed {
+    tx_hash: H256,
+    reason: anyhow::Error,
 }
 
-fn build_cell_deps(contracts: &ContractInfo) -> Vec<CellDep> {
-    vec![
-        // Secp256k1 dep group (for signing fee inputs)
-        CellDep::new_builder()
-            .out_point(
-                OutPoint::new_builder()
-                    .tx_hash(H256::from_str("75be96e1871693f030db27ddae47890a28ab180e88e36ebb3575d9f1377d3da7").unwrap().pack())
-                    .index(0u32.pack())
-                    .build()
-            )
-            .dep_type(ckb_types::core::DepType::DepGroup.into())
-            .build(),
-        // Market contract
-        CellDep::new_builder()
-            .out_point(
-                OutPoint::new_builder()
-                    .tx_hash(contracts.market_tx_hash.pack())
-                    .index(0u32.pack())
-                    .build()
-            )
-            .dep_type(ckb_types::core::DepType::Code.into())
-            .build(),
-        // Always-success contract
-        CellDep::new_builder()
-            .out_point(
-                OutPoint::new_builder()
-                    .tx_hash(contracts.always_success_tx_hash.pack())
-                    .index(0u32.pack())
-                    .build()
-            )
-            .dep_type(ckb_types::core::DepType::Code.into())
-            .build(),
-    ]
+impl std::fmt::Display for Unconfirmed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#} (it may still commit)", self.reason)
+    }
 }
 
-/// Build cell deps for transactions that use tokens
-fn build_cell_deps_with_token(contracts: &ContractInfo) -> Vec<CellDep> {
-    let mut deps = build_cell_deps(contracts);
+impl std::error::Error for Unconfirmed {}
 
-    // Add token contract
-    deps.push(CellDep::new_builder()
-        .out_point(
-            OutPoint::new_builder()
-                .tx_hash(contracts.token_tx_hash.pack())
-                .index(0u32.pack())
-                .build()
-        )
-        .dep_type(ckb_types::core::DepType::Code.into())
-        .build());
+/// Response extension naming the transaction a failed request already sent
+#[derive(Debug, Clone)]
+struct SentTx(H256);
 
-    deps
+/// API error type
+///
+/// Market rule violations get their own status and a stable code in the
+/// X-Error-Code header (see `http_for`), unparseable requests are a 400 with
+/// code `invalid_request`, requests the market state doesn't allow a 400 with
+/// code `rejected`, missing markets or cells a 404 with code `not_found`, a
+/// market spent by another transaction a 409 with code `market_conflict`, a
+/// repeated Idempotency-Key still being handled a 409 with code
+/// `request_in_progress`, one reused for another request a 422 with code
+/// `idempotency_key_reused`, and
+/// everything else (RPC and internal failures) is a 500 with code `internal`.
+/// The body is always an ApiResponse; an Unconfirmed send names its
+/// transaction in `tx_hash` and in a SentTx extension.
+struct ApiError(anyhow::Error);
+
+/// Error code sent with failures that are not market rule violations
+const INTERNAL_ERROR_CODE: &str = "internal";
+
+/// HTTP status and stable error code for each market rule violation
+///
+/// The match is exhaustive on purpose: a new MarketError variant does not
+/// compile until it is given a status here.
+fn http_for(err: &MarketError) -> (StatusCode, &'static str) {
+    match err {
+        MarketError::InvalidMarketData => (StatusCode::UNPROCESSABLE_ENTITY, "invalid_market_data"),
+        MarketError::MultipleMarketCells => (StatusCode::CONFLICT, "multiple_market_cells"),
+        MarketError::SupplyDecrease => (StatusCode::UNPROCESSABLE_ENTITY, "supply_decrease"),
+        MarketError::UnequalSupplyIncrease => (StatusCode::UNPROCESSABLE_ENTITY, "unequal_supply_increase"),
+        MarketError::InsufficientCollateral => (StatusCode::UNPROCESSABLE_ENTITY, "insufficient_collateral"),
+        MarketError::LockScriptChanged => (StatusCode::FORBIDDEN, "lock_script_changed"),
+    }
 }
 
-fn build_market_lock(contracts: &ContractInfo) -> Script {
-    Script::new_builder()
-        .code_hash(contracts.always_success_code_hash.pack())
-        .hash_type(ScriptHashType::Data1.into())
-        .args(Bytes::new().pack())
-        .build()
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, code) = self.0.downcast_ref::<MarketError>()
+            .map(http_for)
+            .or_else(|| self.0.downcast_ref::<InvalidRequest>().map(|_| (StatusCode::BAD_REQUEST, INVALID_REQUEST_CODE)))
+            .or_else(|| self.0.downcast_ref::<Forbidden>().map(|_| (StatusCode::FORBIDDEN, FORBIDDEN_CODE)))
+            .or_else(|| self.0.downcast_ref::<NotFound>().map(|_| (StatusCode::NOT_FOUND, NOT_FOUND_CODE)))
+            .or_else(|| self.0.downcast_ref::<Rejected>().map(|_| (StatusCode::BAD_REQUEST, REJECTED_CODE)))
+            .or_else(|| self.0.downcast_ref::<MarketConflict>().map(|_| (StatusCode::CONFLICT, MARKET_CONFLICT_CODE)))
+            .or_else(|| self.0.downcast_ref::<RequestInProgress>().map(|_| (StatusCode::CONFLICT, REQUEST_IN_PROGRESS_CODE)))
+            .or_else(|| self.0.downcast_ref::<IdempotencyKeyReused>().map(|_| (StatusCode::UNPROCESSABLE_ENTITY, IDEMPOTENCY_KEY_REUSED_CODE)))
+            .unwrap_or((StatusCode::INTERNAL_SERVER_ERROR, INTERNAL_ERROR_CODE));
+        let sent = self.0.downcast_ref::<Unconfirmed>().map(|unconfirmed| SentTx(unconfirmed.tx_hash.clone()));
+        let mut response = (
+            status,
+            [("x-error-code", code)],
+            Json(ApiResponse {
+                success: false,
+                message: self.0.to_string(),
+                tx_hash: sent.as_ref().map(|SentTx(tx_hash)| format!("{:#x}", tx_hash)),
+            }),
+        )
+            .into_response();
+        if let Some(sent) = sent {
+            response.extensions_mut().insert(sent);
+        }
+        response
+    }
 }
 
-fn build_market_type(contracts: &ContractInfo) -> Script {
-    Script::new_builder()
-        .code_hash(contracts.market_code_hash.pack())
-        .hash_type(ScriptHashType::Data1.into())
-        .args(Bytes::new().pack())
-        .build()
+impl<E> From<E> for ApiError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self(err.into())
+    }
 }
 
-/// Build token type script for YES or NO tokens
-/// Args format: market_type_hash (32 bytes) + token_id (1 byte)
-/// token_id: 0x01 = YES, 0x02 = NO
-fn build_token_type(contracts: &ContractInfo, is_yes: bool) -> Script {
-    let market_type = build_market_type(contracts);
-    let market_type_hash = market_type.calc_script_hash();
+// ============================================================================
+// Node Configuration
+// ============================================================================
 
-    // Build args: market_type_hash (32 bytes) + token_id (1 byte)
-    let mut args = Vec::with_capacity(33);
-    args.extend_from_slice(market_type_hash.as_slice());
-    args.push(if is_yes { 0x01 } else { 0x02 });
+/// Optional config file read when the environment doesn't set a value
+const CONFIG_FILE: &str = "config.toml";
 
-    Script::new_builder()
-        .code_hash(contracts.token_code_hash.pack())
-        .hash_type(ScriptHashType::Data1.into())
-        .args(Bytes::from(args).pack())
-        .build()
+/// Node to talk to, the chain it runs, the account that funds and signs and
+/// how the server's sends finish
+#[derive(Debug)]
+struct NodeConfig {
+    rpc_url: String,
+    privkey: secp256k1::SecretKey,
+    network: Network,
+    send_mode: SendMode,
 }
 
-fn create_market(
-    client: &mut CkbRpcClient,
-    privkey: &secp256k1::SecretKey,
-    contracts: &ContractInfo,
-    fee_lock: &Script,
-) -> Result<OutPoint> {
-    println!("  Building transaction...");
+/// Keys of config.toml, all optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    rpc_url: Option<String>,
+    privkey: Option<String>,
+    network: Option<String>,
+}
 
-    // Collect input cells for fee
-    let fee_cells = collect_cells(client, fee_lock, 200_00000000)?; // 200 CKB for fees
-    println!("  Collected {} fee cells", fee_cells.len());
+/// Read CKB_RPC_URL, CKB_PRIVKEY and CKB_NETWORK, falling back to config.toml (if present)
+/// and then to the devnet defaults, and BROADCAST_ONLY
+fn node_config() -> Result<NodeConfig> {
+    let file = match std::fs::read_to_string(CONFIG_FILE) {
+        Ok(contents) => Some(contents),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(anyhow!("Cannot read {}: {}", CONFIG_FILE, err)),
+    };
+    resolve_node_config(|name| std::env::var(name).ok(), file.as_deref())
+}
 
-    // Market cell: 128 CKB minimum
-    let market_capacity = 128_00000000u64; // 128 CKB in shannons
+/// Pick each setting from the environment, else the config file, else the
+/// default, and check the key is a usable secp256k1 secret key
+fn resolve_node_config(env: impl Fn(&str) -> Option<String>, file: Option<&str>) -> Result<NodeConfig> {
+    let file: ConfigFile = match file {
+        Some(contents) => toml::from_str(contents)
+            .map_err(|err| anyhow!("Invalid {}: {}", CONFIG_FILE, err))?,
+        None => ConfigFile::default(),
+    };
 
-    // Calculate total input
-    let total_input: u64 = fee_cells.iter().map(|(_, cap)| cap).sum();
-    let fee = 1000u64; // 1000 shannons fee
-    let change = total_input - market_capacity - fee;
+    let (privkey_hex, source) = match (env("CKB_PRIVKEY"), file.privkey) {
+        (Some(key), _) => (key, "CKB_PRIVKEY"),
+        (None, Some(key)) => (key, "privkey in config.toml"),
+        (None, None) => (PRIVKEY.to_string(), "default key"),
+    };
+    let privkey = hex::decode(privkey_hex.trim().trim_start_matches("0x")).ok()
+        .and_then(|bytes| secp256k1::SecretKey::from_slice(&bytes).ok())
+        .ok_or_else(|| anyhow!("{} is not a valid secp256k1 private key (expected 32 bytes of hex)", source))?;
+
+    let rpc_url = env("CKB_RPC_URL")
+        .or(file.rpc_url)
+        .unwrap_or_else(|| DEVNET_RPC.to_string());
+    let network = match env("CKB_NETWORK").or(file.network) {
+        Some(name) => Network::from_name(&name)?,
+        None => Network::Dev,
+    };
+    let send_mode = match env("BROADCAST_ONLY").as_deref() {
+        Some("1") => SendMode::BroadcastOnly,
+        _ => SendMode::Wait,
+    };
 
-    // Market data (all zeros)
-    let market_data = MarketData::default().to_bytes();
+    Ok(NodeConfig { rpc_url, privkey, network, send_mode })
+}
 
-    // Build outputs
-    let market_output = CellOutput::new_builder()
-        .capacity(market_capacity.pack())
-        .lock(build_market_lock(contracts))
-        .type_(Some(build_market_type(contracts)).pack())
-        .build();
+// ============================================================================
+// Market Persistence
+// ============================================================================
 
-    let change_output = CellOutput::new_builder()
-        .capacity(change.pack())
-        .lock(fee_lock.clone())
-        .build();
+/// File the current market is saved to (override with MARKET_STATE_FILE)
+const DEFAULT_MARKET_STATE_FILE: &str = "market-state.json";
+
+/// The current market as saved between restarts
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SavedMarket {
+    /// Hash of the market's type script (Type ID script), so a file from
+    /// another deployment is not picked up
+    market_type_hash: String,
+    /// Last market cell the server saw, `0x<tx_hash>:<index>`; it may have been
+    /// spent since
+    outpoint: String,
+}
 
-    // Build inputs
-    let inputs: Vec<CellInput> = fee_cells.iter()
-        .map(|(outpoint, _)| {
-            CellInput::new_builder()
-                .previous_output(outpoint.clone())
-                .since(0u64.pack())
-                .build()
-        })
-        .collect();
+/// Save the current market, writing a temporary file and renaming it over
+/// the old one so a crash never leaves a half-written file
+fn save_market_state(path: &str, market_type_hash: &ckb_types::packed::Byte32, outpoint: &OutPoint) -> Result<()> {
+    let tx_hash: H256 = outpoint.tx_hash().unpack();
+    let index: u32 = outpoint.index().unpack();
+    let saved = SavedMarket {
+        market_type_hash: format!("{:#x}", market_type_hash),
+        outpoint: format!("{:#x}:{}", tx_hash, index),
+    };
 
-    // Build transaction
-    let tx = TransactionView::new_advanced_builder()
-        .cell_deps(build_cell_deps(contracts))
-        .inputs(inputs)
-        .outputs(vec![market_output, change_output])
-        .outputs_data(vec![Bytes::from(market_data).pack(), Bytes::new().pack()])
-        .build();
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, serde_json::to_string_pretty(&saved)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
 
-    // Sign and send
-    let tx = sign_transaction(tx, privkey, fee_cells.len())?;
-    let tx_hash = send_transaction(client, &tx)?;
+/// The saved market, or None if nothing was saved yet
+fn load_market_state(path: &str) -> Result<Option<SavedMarket>> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|err| anyhow!("Invalid market state file {}: {}", path, err)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(anyhow!("Cannot read {}: {}", path, err)),
+    }
+}
 
-    println!("  TX: {:#x}", tx_hash);
-    Ok(OutPoint::new_builder()
-        .tx_hash(tx_hash.pack())
-        .index(0u32.pack())
-        .build())
+/// Live market cell for a saved market
+/// The saved cell is re-fetched on-chain and, if it was spent while the
+/// server was down, followed to its successor. None if the file belongs to
+/// another deployment or the market was closed.
+fn restore_market(client: &mut dyn CkbBackend, contracts: &ContractInfo, saved: &SavedMarket) -> Result<Option<OutPoint>> {
+    let market_type_hash = format!("{:#x}", build_market_type(contracts).calc_script_hash());
+    if saved.market_type_hash != market_type_hash {
+        warn!(saved = %saved.market_type_hash, deployed = %market_type_hash,
+              "Saved market belongs to another market type hash; ignoring it");
+        return Ok(None);
+    }
+    follow_market(client, contracts, parse_market_id(&saved.outpoint)?)
 }
 
-fn mint_tokens(
-    client: &mut CkbRpcClient,
-    privkey: &secp256k1::SecretKey,
-    contracts: &ContractInfo,
-    fee_lock: &Script,
-    market_outpoint: OutPoint,
-    amount: u128,
-) -> Result<OutPoint> {
-    println!("  Building transaction...");
+/// Latest live market cell descended from `outpoint`
+///
+/// Every market transaction puts the market back at output 0, so a spent
+/// market cell is followed to output 0 of the transaction that consumed it,
+/// found through the indexer. None if the chain ends without a market output
+/// (a close) or the cell is unknown.
+fn follow_market(client: &mut dyn CkbBackend, contracts: &ContractInfo, outpoint: OutPoint) -> Result<Option<OutPoint>> {
+    let market_type = build_market_type(contracts);
+    let mut current = outpoint;
 
-    // Get current market cell
-    let market_cell = get_cell(client, &market_outpoint)?;
-    let market_data = MarketData::from_bytes(&market_cell.data)?;
-    let market_capacity: u64 = market_cell.capacity;
+    loop {
+        let cell = retry_read(RpcRetry::from_env(), "get_live_cell", || client.get_live_cell(current.clone().into(), false))?;
+        if cell.status == "live" {
+            return Ok(Some(current));
+        }
 
-    // Collect fee cells (need amount * 100 CKB for collateral + 286 CKB for token cells + fees)
-    let collateral = amount as u64 * 100_00000000; // 100 CKB per token
-    let token_cells_capacity = 286_00000000u64; // 143 CKB × 2 for YES and NO token cells
-    let fee_cells = collect_cells(client, fee_lock, collateral + token_cells_capacity + 1_00000000)?;
+        let Some(spender) = find_spending_tx(client, &market_type, &current)? else {
+            return Ok(None);
+        };
+        let tx = get_transaction_view(client, spender.clone())?;
+        let continues = tx.inner.outputs.first()
+            .and_then(|output| output.type_.clone())
+            .map(Script::from)
+            .is_some_and(|type_script| type_script.code_hash().as_slice() == market_type.code_hash().as_slice());
+        if !continues {
+            return Ok(None);
+        }
+        current = OutPoint::new_builder()
+            .tx_hash(spender.pack())
+            .index(0u32.pack())
+            .build();
+    }
+}
 
-    let total_fee_input: u64 = fee_cells.iter().map(|(_, cap)| cap).sum();
-    let fee = 2000u64; // Increased fee for larger transaction with token cells
+/// Transaction that consumed a market cell, searched newest first among the
+/// transactions spending any market cell
+fn find_spending_tx(client: &mut dyn CkbBackend, market_type: &Script, outpoint: &OutPoint) -> Result<Option<H256>> {
+    use ckb_sdk::rpc::ckb_indexer::{CellType, Tx};
 
-    // New market capacity = old + collateral
-    let new_market_capacity = market_capacity + collateral;
-    let change = total_fee_input - collateral - fee;
+    let search_key = SearchKey {
+        script: market_type.clone().into(),
+        script_type: ScriptType::Type,
+        script_search_mode: Some(SearchMode::Prefix),
+        filter: None,
+        with_data: None,
+        group_by_transaction: Some(false),
+    };
 
-    // New market data
-    let new_market_data = MarketData {
-        yes_supply: market_data.yes_supply + amount,
-        no_supply: market_data.no_supply + amount,
-        resolved: false,
-        outcome: false,
-    }.to_bytes();
+    let page_size = 100u32;
+    let mut cursor = None;
+    loop {
+        let page = client.get_transactions(search_key.clone(), Order::Desc, page_size.into(), cursor)?;
+        let page_len = page.objects.len();
 
-    // Token cells need capacity for lock + type + data
-    // Lock (sighash): ~53 bytes, Type (33 bytes args): ~61 bytes, Data: 16 bytes = ~143 CKB
-    let token_cell_capacity = 143_00000000u64; // 143 CKB per token cell
+        for tx in page.objects {
+            let Tx::Ungrouped(cell) = tx else { continue };
+            if cell.io_type != CellType::Input {
+                continue;
+            }
+            let spending = get_transaction_view(client, cell.tx_hash.clone())?;
+            let spent = spending.inner.inputs.get(cell.io_index.value() as usize)
+                .map(|input| OutPoint::from(input.previous_output.clone()));
+            if spent.is_some_and(|spent| spent.as_slice() == outpoint.as_slice()) {
+                return Ok(Some(cell.tx_hash));
+            }
+        }
 
-    // Build outputs
-    let market_output = CellOutput::new_builder()
-        .capacity(new_market_capacity.pack())
-        .lock(build_market_lock(contracts))
-        .type_(Some(build_market_type(contracts)).pack())
-        .build();
+        if page_len < page_size as usize {
+            return Ok(None);
+        }
+        cursor = Some(page.last_cursor);
+    }
+}
 
-    // YES token cell
-    let yes_token_output = CellOutput::new_builder()
-        .capacity(token_cell_capacity.pack())
-        .lock(fee_lock.clone()) // User owns the tokens
-        .type_(Some(build_token_type(contracts, true)).pack())
-        .build();
+// ============================================================================
+// Main & API Server
+// ============================================================================
 
-    // NO token cell
-    let no_token_output = CellOutput::new_builder()
-        .capacity(token_cell_capacity.pack())
-        .lock(fee_lock.clone()) // User owns the tokens
-        .type_(Some(build_token_type(contracts, false)).pack())
-        .build();
+/// Market contract API server and command line
+#[derive(Debug, Parser)]
+#[command(name = "market-tests")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    // Calculate change (need to account for token cell capacities)
-    let change_adjusted = total_fee_input - collateral - (token_cell_capacity * 2) - fee;
-    let change_output = CellOutput::new_builder()
-        .capacity(change_adjusted.pack())
-        .lock(fee_lock.clone())
-        .build();
+/// What to run; `serve` when no subcommand is given
+///
+/// The market operations act on the server's current market (from
+/// MARKET_STATE_FILE) and move it on, like the matching API endpoints.
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Start the HTTP API server
+    Serve,
+    /// Run the scripted devnet test scenario
+    Test,
+    /// Create a market and make it the current one
+    CreateMarket {
+        /// Extra capacity on top of the market cell's occupied minimum (shannons)
+        #[arg(long, default_value_t = 0)]
+        buffer_shannons: u64,
+        /// Collateral per complete set (shannons)
+        #[arg(long, default_value_t = DEFAULT_SHANNONS_PER_TOKEN)]
+        shannons_per_token: u64,
+        /// Unix time before which the market can't be resolved
+        #[arg(long, default_value_t = 0)]
+        resolve_after: u64,
+        /// Question the market answers
+        #[arg(long)]
+        question: Option<String>,
+        /// Outcome label, given twice with NO first
+        #[arg(long = "outcome-label")]
+        outcome_labels: Option<Vec<String>>,
+        /// Share of every claim paid to FEE_RECIPIENT_ADDRESS, in basis points
+        #[arg(long, default_value_t = 0)]
+        fee_bps: u16,
+        /// Lower end of a scalar market's range
+        #[arg(long, requires = "scalar_ceiling")]
+        scalar_floor: Option<u64>,
+        /// Upper end of a scalar market's range
+        #[arg(long, requires = "scalar_floor")]
+        scalar_ceiling: Option<u64>,
+    },
+    /// Mint complete sets on the current market
+    Mint {
+        #[arg(long)]
+        amount: u128,
+    },
+    /// Propose the current market's outcome; `finalize` resolves it once the
+    /// resolution delay is over
+    Resolve {
+        #[arg(long, value_enum, required_unless_present = "value", conflicts_with = "value")]
+        outcome: Option<Outcome>,
+        /// Resolved value of a scalar market
+        #[arg(long)]
+        value: Option<u64>,
+    },
+    /// Resolve the current market as proposed
+    Finalize,
+    /// Claim winning tokens of the current market
+    Claim {
+        #[arg(long)]
+        amount: u128,
+        /// Side to claim, YES (LONG) or NO (SHORT); required on scalar markets
+        #[arg(long)]
+        side: Option<String>,
+    },
+    /// Print the tip and the current market
+    Status,
+}
 
-    // Token cell data: u128 amount (16 bytes)
-    let token_amount_bytes = amount.to_le_bytes().to_vec();
+/// Winning side for `resolve --outcome`
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Outcome {
+    Yes,
+    No,
+}
 
-    // Build inputs: market cell first, then fee cells
-    let mut inputs = vec![
-        CellInput::new_builder()
-            .previous_output(market_outpoint)
+#[tokio::main]
+async fn main() -> Result<()> {
+    // RUST_LOG picks the filter, e.g. RUST_LOG=market_tests=debug for builder detail
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    match Cli::parse().command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(connect()?).await,
+        Command::Test => run_tests(),
+        command => {
+            let state = connect()?;
+            let output = run_command(&state, command)?;
+            println!("{}", serde_json::to_string_pretty(&output)?);
+            Ok(())
+        }
+    }
+}
+
+/// Run one market operation from the command line
+/// Prints the same JSON the matching API endpoint answers with.
+fn run_command(state: &AppState, command: Command) -> Result<serde_json::Value> {
+    let current_market = || lock(&state.current_market).clone()
+        .ok_or_else(|| anyhow!("No current market in {}; run create-market first", state.market_state_file));
+    let submit = |op: &str, message: String, build: &dyn Fn(&mut dyn CkbBackend) -> Result<BuiltTx>| -> Result<serde_json::Value> {
+        let outpoint = match submit_or_dry_run(state, op, false, build)? {
+            Submitted::Sent(outpoint) => outpoint,
+            Submitted::DryRun(_) => unreachable!("dry runs are never requested here"),
+        };
+        let tx_hash: H256 = outpoint.tx_hash().unpack();
+        Ok(serde_json::to_value(ApiResponse { success: true, message, tx_hash: Some(format!("{:#x}", tx_hash)) })?)
+    };
+
+    match command {
+        Command::Serve | Command::Test => unreachable!("handled by main"),
+        Command::CreateMarket {
+            buffer_shannons,
+            shannons_per_token,
+            resolve_after,
+            question,
+            outcome_labels,
+            fee_bps,
+            scalar_floor,
+            scalar_ceiling,
+        } => {
+            let metadata = market_metadata(question.as_deref(), outcome_labels.as_deref())?;
+            let fee = market_fee(state.contracts.network, fee_bps)?;
+            let scalar = market_scalar(scalar_floor, scalar_ceiling)?;
+            submit("create-market", "Market created successfully".to_string(), &|client| {
+                build_create_market_tx(
+                    client,
+                    &state.contracts,
+                    &state.lock_script,
+                    buffer_shannons,
+                    shannons_per_token,
+                    resolve_after,
+                    fee,
+                    scalar,
+                    metadata.clone(),
+                )
+            })
+        }
+        Command::Mint { amount } => {
+            let market_outpoint = current_market()?;
+            submit("mint", format!("Minted {} YES + {} NO tokens", amount, amount), &|client| {
+                build_mint_tx(client, &state.contracts, &state.lock_script, market_outpoint.clone(), amount)
+            })
+        }
+        Command::Resolve { outcome, value } => {
+            let market_outpoint = current_market()?;
+            let (settlement, proposed) = match (outcome, value) {
+                (_, Some(value)) => (Settlement::Value(value), format!("value {}", value)),
+                (outcome, None) => {
+                    let outcome_yes = matches!(outcome, Some(Outcome::Yes));
+                    (Settlement::Outcome(outcome_yes), format!("{} wins", if outcome_yes { "YES" } else { "NO" }))
+                }
+            };
+            let message = format!("Proposed {}; finalize after {}s", proposed, RESOLUTION_DELAY_SECS);
+            submit("propose", message, &|client| {
+                build_proposal_tx(client, &state.contracts, &state.lock_script, market_outpoint.clone(), settlement)
+            })
+        }
+        Command::Finalize => {
+            let market_outpoint = current_market()?;
+            submit("finalize", "Market resolved as proposed".to_string(), &|client| {
+                build_finalize_tx(client, &state.contracts, &state.lock_script, market_outpoint.clone())
+            })
+        }
+        Command::Claim { amount, side } => {
+            let market_outpoint = current_market()?;
+            let side = side.as_deref().map(parse_token_side).transpose()?;
+            let collateral = claim_payout_capacity(&mut *state.rpc(), &market_outpoint, side, amount)?;
+            submit("claim", format!("Claimed {} tokens for {}", amount, collateral), &|client| {
+                build_side_claim_tx(client, &state.contracts, &state.lock_script, market_outpoint.clone(), side, amount)
+            })
+        }
+        Command::Status => Ok(serde_json::to_value(market_status(state)?)?),
+    }
+}
+
+/// Connect to the node and load the wallet and current market
+fn connect() -> Result<AppState> {
+    // Initialize state
+    let config = node_config()?;
+    let mut client = CkbRpcClient::new(&config.rpc_url);
+    info!("Connected to node at {}", config.rpc_url);
+    verify_network(&mut client, config.network)?;
+
+    let contracts = get_contract_info(config.network)?;
+    verify_market_lock(&mut client, &contracts)?;
+    info!("Market lock: always-success ({:?})", contracts.always_success_hash_type);
+
+    let privkey = config.privkey;
+
+    let secp = secp256k1::Secp256k1::new();
+    let pubkey = secp256k1::PublicKey::from_secret_key(&secp, &privkey);
+    let pubkey_format = pubkey_format()?;
+    let lock_script = wallet_lock(&pubkey, pubkey_format)?;
+    verify_wallet_visible(&mut client, &pubkey, &lock_script)?;
+    info!("Wallet lock: {:#x} ({:?} pubkey)", lock_script.calc_script_hash(), pubkey_format);
+
+    // Pick up the market from before a restart, following it to its live cell
+    let market_state_file = std::env::var("MARKET_STATE_FILE")
+        .unwrap_or_else(|_| DEFAULT_MARKET_STATE_FILE.to_string());
+    let current_market = match load_market_state(&market_state_file)? {
+        Some(saved) => restore_market(&mut client, &contracts, &saved)?,
+        None => None,
+    };
+    match &current_market {
+        Some(outpoint) => {
+            let tx_hash: H256 = outpoint.tx_hash().unpack();
+            let index: u32 = outpoint.index().unpack();
+            info!("Current market: {:#x}:{} (from {})", tx_hash, index, market_state_file);
+        }
+        None => info!("No current market saved in {}", market_state_file),
+    }
+
+    let rpc_url = config.rpc_url;
+    Ok(AppState {
+        backend: Box::new(move || Box::new(CkbRpcClient::new(&rpc_url))),
+        wallet: Mutex::new(()),
+        send_mode: config.send_mode,
+        privkey,
+        contracts,
+        lock_script,
+        current_market: Mutex::new(current_market),
+        market_state_file,
+        tvl: Mutex::new(None),
+        idempotency: Mutex::new(IdempotencyKeys::default()),
+    })
+}
+
+/// API routes behind the idempotency guard, request tracing and `cors`
+fn api_router(state: Arc<AppState>, cors: &CorsOrigins) -> Router {
+    Router::new()
+        .route("/", get(serve_frontend))
+        .route("/api/status", get(handle_status))
+        .route("/api/tvl", get(handle_tvl))
+        .route("/api/balance", get(handle_balance))
+        .route("/api/markets", get(handle_list_markets))
+        .route("/api/tx/:hash", get(handle_tx_status))
+        .route("/api/market/:id/claimable", get(handle_claimable))
+        .route("/api/market/:id/tokens", get(handle_tokens))
+        .route("/api/market/:id/proof", get(handle_proof))
+        .route("/api/create-market", post(handle_create_market))
+        .route("/api/import-market", post(handle_import_market))
+        .route("/api/mint", post(handle_mint))
+        .route("/api/burn", post(handle_burn))
+        .route("/api/transfer", post(handle_transfer))
+        .route("/api/resolve", post(handle_resolve))
+        .route("/api/propose", post(handle_propose))
+        .route("/api/finalize", post(handle_finalize))
+        .route("/api/claim", post(handle_claim))
+        .route("/api/refund", post(handle_refund))
+        .route("/api/redeem-sets", post(handle_redeem_sets))
+        .route("/api/preview/:op", post(handle_preview))
+        .route("/api/quote/:op", get(handle_quote))
+        .route("/api/admin/market/:id/reset", post(handle_reset_market))
+        .route_layer(middleware::from_fn_with_state(state.clone(), idempotency_guard))
+        // One span per request, named by its route so /api/tx/:hash groups together
+        .layer(TraceLayer::new_for_http().make_span_with(|request: &Request| {
+            let endpoint = request
+                .extensions()
+                .get::<MatchedPath>()
+                .map_or(request.uri().path(), MatchedPath::as_str);
+            info_span!("request", method = %request.method(), endpoint = %endpoint)
+        }))
+        .layer(cors.layer())
+        .with_state(state)
+}
+
+/// Serve the HTTP API until the process is stopped
+async fn serve(state: AppState) -> Result<()> {
+    info!("=== Market Contract API Server ===");
+    let cors = cors_origins(state.contracts.network, std::env::var("CORS_ORIGINS").ok().as_deref())?;
+    match &cors {
+        CorsOrigins::Any => info!("CORS: any origin"),
+        CorsOrigins::List(origins) if origins.is_empty() => info!("CORS: no browser origins (set CORS_ORIGINS to allow some)"),
+        CorsOrigins::List(origins) => info!("CORS: {} allowed origin(s)", origins.len()),
+    }
+    let state = Arc::new(state);
+
+    // Keep the TVL gauge warm in the background
+    let tvl_refresh_secs = std::env::var("TVL_REFRESH_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_TVL_REFRESH_SECS);
+    tokio::spawn(run_tvl_refresh(state.clone(), Duration::from_secs(tvl_refresh_secs)));
+
+    // Optionally merge fee-wallet change cells once they pile up
+    let consolidate_threshold: Option<usize> = std::env::var("CONSOLIDATE_THRESHOLD")
+        .ok()
+        .and_then(|value| value.parse().ok());
+    if let Some(threshold) = consolidate_threshold {
+        let interval_secs = std::env::var("CONSOLIDATE_INTERVAL_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_CONSOLIDATE_INTERVAL_SECS);
+        info!("Consolidating fee cells above {} every {}s", threshold, interval_secs);
+        tokio::spawn(run_consolidation(state.clone(), threshold, Duration::from_secs(interval_secs)));
+    }
+
+    let app = api_router(state, &cors);
+
+    info!("Server starting on http://localhost:3001");
+    info!("API endpoints:");
+    info!("  GET  /api/status");
+    info!("  GET  /api/tvl");
+    info!("  GET  /api/balance[?address=<ckb address>]");
+    info!("  GET  /api/markets");
+    info!("  GET  /api/tx/{{hash}}");
+    info!("  GET  /api/market/{{id}}/claimable");
+    info!("  GET  /api/market/{{id}}/tokens");
+    info!("  GET  /api/market/{{id}}/proof");
+    info!("  POST /api/create-market");
+    info!("  POST /api/mint");
+    info!("  POST /api/burn");
+    info!("  POST /api/transfer");
+    info!("  POST /api/resolve");
+    info!("  POST /api/propose");
+    info!("  POST /api/finalize");
+    info!("  POST /api/claim");
+    info!("  POST /api/refund?market_id=0x<tx_hash>[:index]&amount=<sets>");
+    info!("  POST /api/redeem-sets");
+    info!("  POST /api/preview/{{op}}");
+    info!("  POST /api/admin/market/{{id}}/reset  (X-Admin-Token)");
+    info!("To run tests instead: cargo run test (cargo run -- --help for every command)");
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:3001").await?;
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+// ============================================================================
+// API Handlers
+// ============================================================================
+
+async fn serve_frontend() -> impl IntoResponse {
+    use axum::response::Html;
+    Html(include_str!("../frontend.html"))
+}
+
+/// Browser origins allowed to call the API (env CORS_ORIGINS)
+#[derive(Debug, Clone)]
+enum CorsOrigins {
+    Any,
+    /// Exactly these origins; empty denies every cross-origin caller
+    List(Vec<HeaderValue>),
+}
+
+impl CorsOrigins {
+    fn allows(&self, origin: &HeaderValue) -> bool {
+        match self {
+            CorsOrigins::Any => true,
+            CorsOrigins::List(origins) => origins.contains(origin),
+        }
+    }
+
+    /// CORS layer answering allowed origins for GET and POST with any headers;
+    /// other origins get no Access-Control-Allow-Origin, so browsers block them
+    fn layer(&self) -> CorsLayer {
+        let origins = self.clone();
+        let allow_origin = match origins {
+            CorsOrigins::Any => AllowOrigin::any(),
+            origins => AllowOrigin::predicate(move |origin, _| origins.allows(origin)),
+        };
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers(Any)
+    }
+}
+
+/// Parse CORS_ORIGINS: `*` for any origin, else comma-separated origins such
+/// as `https://app.example.com`
+/// Unset, any origin is allowed on devnet and none elsewhere.
+fn cors_origins(network: Network, configured: Option<&str>) -> Result<CorsOrigins> {
+    let Some(configured) = configured.map(str::trim) else {
+        return Ok(if network == Network::Dev { CorsOrigins::Any } else { CorsOrigins::List(Vec::new()) });
+    };
+    if configured == "*" {
+        if network != Network::Dev {
+            warn!("CORS_ORIGINS=* lets any website call the API on {:?}", network);
+        }
+        return Ok(CorsOrigins::Any);
+    }
+    configured.split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| {
+            HeaderValue::from_str(origin.trim_end_matches('/'))
+                .map_err(|_| anyhow!("CORS_ORIGINS entry {:?} is not a valid origin", origin))
+        })
+        .collect::<Result<_>>()
+        .map(CorsOrigins::List)
+}
+
+/// Run synchronous RPC work on Tokio's blocking pool
+///
+/// The RPC client is blocking and send_transaction sleeps between polls, so
+/// handlers never call it on a runtime worker thread directly. The request's
+/// span goes along, so builder logs carry the endpoint. Work that hit a
+/// market spent elsewhere moves current_market on, so the next request builds
+/// against the latest cell.
+async fn run_blocking<T, F>(state: &Arc<AppState>, work: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&AppState) -> Result<T> + Send + 'static,
+{
+    let state = state.clone();
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(|| {
+        let result = work(&state);
+        if let Some(MarketConflict(spent)) = result.as_ref().err().and_then(|err| err.downcast_ref()) {
+            if let Err(err) = state.refresh_current_market(spent) {
+                warn!(error = %err, "Could not follow the spent market");
+            }
+        }
+        result
+    }))
+        .await
+        .map_err(|err| anyhow!("Blocking task failed: {}", err))?
+}
+
+/// Header naming a retry-safe request
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Longest Idempotency-Key accepted
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Most answers kept for Idempotency-Keys; the oldest finished ones are
+/// forgotten first
+const MAX_IDEMPOTENCY_KEYS: usize = 1024;
+
+/// Largest request body read to fingerprint an Idempotency-Key (axum's default body limit)
+const MAX_IDEMPOTENT_BODY_LEN: usize = 2 * 1024 * 1024;
+
+/// Endpoint (its route) and Idempotency-Key a request was sent with
+type IdempotencyScope = (String, String);
+
+/// What the server holds for an Idempotency-Key
+#[derive(Debug, Clone)]
+enum Recorded {
+    /// The first request with the key is still running
+    Pending,
+    /// The first request failed after sending this transaction, which may
+    /// still commit; repeats are told it is pending instead of sending another
+    Sent(H256),
+    /// The successful answer it got, replayed to every repeat
+    Done { status: StatusCode, content_type: Option<HeaderValue>, body: axum::body::Bytes },
+}
+
+/// A scope's record and the request it was made for
+#[derive(Debug)]
+struct IdempotencyEntry {
+    /// blake2b of the request's path, query and body
+    request_hash: [u8; 32],
+    recorded: Recorded,
+}
+
+/// Answers by endpoint and Idempotency-Key, in memory
+#[derive(Debug, Default)]
+struct IdempotencyKeys {
+    entries: HashMap<IdempotencyScope, IdempotencyEntry>,
+    /// Scopes oldest first, for forgetting answers past MAX_IDEMPOTENCY_KEYS
+    order: VecDeque<IdempotencyScope>,
+}
+
+impl IdempotencyKeys {
+    /// Start the request hashing to `request_hash` under `scope`, or return
+    /// what the scope already holds; a scope holding another request is an error
+    ///
+    /// Past MAX_IDEMPOTENCY_KEYS the oldest finished scope is forgotten;
+    /// running requests are never forgotten, so their repeats stay refused.
+    fn begin(&mut self, scope: &IdempotencyScope, request_hash: [u8; 32]) -> Result<Option<Recorded>, IdempotencyKeyReused> {
+        if let Some(entry) = self.entries.get(scope) {
+            if entry.request_hash != request_hash {
+                return Err(IdempotencyKeyReused(scope.1.clone()));
+            }
+            return Ok(Some(entry.recorded.clone()));
+        }
+        if self.order.len() >= MAX_IDEMPOTENCY_KEYS {
+            let entries = &self.entries;
+            let finished = self.order.iter()
+                .position(|kept| !matches!(entries.get(kept), Some(IdempotencyEntry { recorded: Recorded::Pending, .. })));
+            if let Some(oldest) = finished.and_then(|position| self.order.remove(position)) {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(scope.clone(), IdempotencyEntry { request_hash, recorded: Recorded::Pending });
+        self.order.push_back(scope.clone());
+        Ok(None)
+    }
+
+    /// Record the answer to the request `begin` started, or forget the scope
+    /// (`None`) so the request can be retried
+    fn finish(&mut self, scope: &IdempotencyScope, done: Option<Recorded>) {
+        match done {
+            Some(recorded) => {
+                if let Some(entry) = self.entries.get_mut(scope) {
+                    entry.recorded = recorded;
+                }
+            }
+            None => {
+                self.entries.remove(scope);
+                self.order.retain(|kept| kept != scope);
+            }
+        }
+    }
+}
+
+/// Answer POSTs that repeat an earlier Idempotency-Key with the earlier answer
+///
+/// Keys are scoped to the endpoint and bound to the request's path, query and
+/// body: reusing one for a different request is a 422. Successful answers are
+/// kept and replayed. A request that failed after sending its transaction
+/// keeps its key too, and repeats get a 202 naming the pending transaction
+/// instead of sending a second one; any other failure forgets the key, so the
+/// request can be retried under it. A repeat that arrives while the first
+/// request is still running gets a 409. The handler runs in its own task, so a
+/// client that gives up waiting doesn't stop its answer from being recorded
+/// for the retry.
+async fn idempotency_guard(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let key = match request.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(key) if request.method() == Method::POST => key,
+        _ => return Ok(next.run(request).await),
+    };
+    let key = key.to_str().ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN)
+        .ok_or_else(|| InvalidRequest(format!(
+            "Idempotency-Key must be 1 to {} visible ASCII characters", MAX_IDEMPOTENCY_KEY_LEN
+        )))?
+        .to_string();
+    let endpoint = request.extensions().get::<MatchedPath>()
+        .map_or(request.uri().path(), MatchedPath::as_str)
+        .to_string();
+    let scope = (endpoint, key);
+
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, MAX_IDEMPOTENT_BODY_LEN).await
+        .map_err(|err| InvalidRequest(format!("Could not read the request body: {}", err)))?;
+    let target = parts.uri.path_and_query().map_or("", |target| target.as_str());
+    let request_hash = blake2b_256([target.as_bytes(), b"\n".as_slice(), &body[..]].concat());
+    let request = Request::from_parts(parts, axum::body::Body::from(body));
+
+    match lock(&state.idempotency).begin(&scope, request_hash)? {
+        Some(Recorded::Pending) => return Err(RequestInProgress(scope.1).into()),
+        Some(Recorded::Sent(tx_hash)) => {
+            info!(key = %scope.1, tx_hash = %format!("{:#x}", tx_hash), "Repeated Idempotency-Key already sent a transaction");
+            return Ok(pending_answer(&tx_hash));
+        }
+        Some(Recorded::Done { status, content_type, body }) => {
+            info!(key = %scope.1, "Replaying the answer to a repeated Idempotency-Key");
+            return Ok(replay(status, content_type, body));
+        }
+        None => {}
+    }
+
+    let response = match tokio::spawn(next.run(request)).await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => {
+            let sent = response.extensions().get::<SentTx>().map(|SentTx(tx_hash)| Recorded::Sent(tx_hash.clone()));
+            lock(&state.idempotency).finish(&scope, sent);
+            return Ok(response);
+        }
+        Err(err) => {
+            lock(&state.idempotency).finish(&scope, None);
+            return Err(anyhow!("Request task failed: {}", err).into());
+        }
+    };
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(err) => {
+            lock(&state.idempotency).finish(&scope, None);
+            return Err(anyhow!("Could not read the answer to record it: {}", err).into());
+        }
+    };
+    lock(&state.idempotency).finish(&scope, Some(Recorded::Done {
+        status: parts.status,
+        content_type: parts.headers.get(header::CONTENT_TYPE).cloned(),
+        body: body.clone(),
+    }));
+    Ok(Response::from_parts(parts, axum::body::Body::from(body)))
+}
+
+/// Answer a repeat whose first request sent `tx_hash` but failed before it committed
+fn pending_answer(tx_hash: &H256) -> Response {
+    (
+        StatusCode::ACCEPTED,
+        Json(ApiResponse {
+            success: true,
+            message: format!("pending {:#x}", tx_hash),
+            tx_hash: Some(format!("{:#x}", tx_hash)),
+        }),
+    )
+        .into_response()
+}
+
+/// Rebuild a recorded answer
+fn replay(status: StatusCode, content_type: Option<HeaderValue>, body: axum::body::Bytes) -> Response {
+    let mut response = (status, body).into_response();
+    if let Some(content_type) = content_type {
+        response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+    }
+    response
+}
+
+async fn handle_status(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    Ok(Json(run_blocking(&state, market_status).await?))
+}
+
+/// Make a market created by another server or tool the current market
+/// Answers with the status, as /api/status would from then on.
+async fn handle_import_market(
+    State(state): State<Arc<AppState>>,
+    StrictJson(req): StrictJson<ImportMarketRequest>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let status = run_blocking(&state, move |state| {
+        let market = import_market(&mut *state.rpc(), &state.contracts, &req)?;
+        info!(market = %format_market_id(&market), "Imported market");
+        state.set_current_market(market);
+        market_status(state)
+    }).await?;
+    Ok(Json(status))
+}
+
+/// Tip and current market summary, read through a client of its own
+fn market_status(state: &AppState) -> Result<StatusResponse> {
+    let mut client = state.rpc();
+
+    let block_height = retry_read(RpcRetry::from_env(), "get_tip_block_number", || client.get_tip_block_number())
+        .ok()
+        .map(|h| h.value());
+    let market_outpoint = lock(&state.current_market).clone();
+
+    let market_data = if let Some(ref outpoint) = market_outpoint {
+        get_cell(&mut *client, outpoint)
+            .ok()
+            .and_then(|cell| Some((MarketData::from_bytes(&cell.data).ok()?, cell.collateral())))
+            .map(|(data, collateral)| MarketDataJson {
+                yes_supply: data.yes_supply().to_string(),
+                no_supply: data.no_supply().to_string(),
+                resolved: data.resolved,
+                outcome: data.outcome,
+                cancelled: data.cancelled,
+                collateral,
+                dispute_blocks_left: block_height.map_or(0, |tip| data.dispute_until.saturating_sub(tip + 1)),
+                question: data.metadata.as_ref().map(|metadata| metadata.question.clone()),
+                outcome_labels: data.metadata.map(|metadata| metadata.outcome_labels),
+                scalar: data.scalar.map(ScalarJson::from),
+            })
+    } else {
+        None
+    };
+
+    Ok(StatusResponse {
+        connected: block_height.is_some(),
+        block_height,
+        market_created: market_outpoint.is_some(),
+        market_data,
+    })
+}
+
+/// The TVL the refresh task cached, or a fresh scan before its first run
+async fn handle_tvl(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<TvlResponse>, ApiError> {
+    let cached = lock(&state.tvl).clone();
+    if let Some(tvl) = cached {
+        return Ok(Json(tvl));
+    }
+
+    let tvl = run_blocking(&state, |state| compute_tvl(&mut *state.rpc(), &state.contracts)).await?;
+    *lock(&state.tvl) = Some(tvl.clone());
+    Ok(Json(tvl))
+}
+
+async fn handle_tx_status(
+    State(state): State<Arc<AppState>>,
+    Path(hash): Path<String>,
+) -> Result<Json<TxStatusResponse>, ApiError> {
+    let tx_hash = H256::from_str(hash.trim_start_matches("0x"))
+        .map_err(|err| InvalidRequest(format!("Invalid transaction hash {}: {}", hash, err)))?;
+
+    let status = run_blocking(&state, move |state| {
+        tx_status(&mut *state.rpc(), &tx_hash)
+    }).await?;
+    Ok(Json(status))
+}
+
+async fn handle_list_markets(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<MarketSummary>>, ApiError> {
+    let markets = run_blocking(&state, |state| {
+        list_markets(&mut *state.rpc(), &state.contracts)
+    }).await?;
+    Ok(Json(markets))
+}
+
+async fn handle_balance(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<BalanceParams>,
+) -> Result<Json<BalanceResponse>, ApiError> {
+    let market_outpoint = lock(&state.current_market).clone()
+        .ok_or_else(|| NotFound("No market created yet".to_string()))?;
+    let lock = match params.address {
+        Some(address) => parse_address(&address, state.contracts.network)?,
+        None => state.lock_script.clone(),
+    };
+
+    let balance = run_blocking(&state, move |state| {
+        token_balance(&mut *state.rpc(), &state.contracts, &market_outpoint, &lock)
+    }).await?;
+    Ok(Json(balance))
+}
+
+async fn handle_claimable(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<ClaimableResponse>, ApiError> {
+    let outpoint = parse_market_id(&id)?;
+
+    let claimable = run_blocking(&state, move |state| {
+        let mut client = state.rpc();
+        market_claimable(&get_cell(&mut *client, &outpoint)?)
+    }).await?;
+    Ok(Json(claimable))
+}
+
+async fn handle_tokens(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<TokenDescriptor>>, ApiError> {
+    let outpoint = parse_market_id(&id)?;
+
+    let tokens = run_blocking(&state, move |state| {
+        let market_cell = get_cell(&mut *state.rpc(), &outpoint)?;
+        let market_data = MarketData::from_bytes(&market_cell.data)?;
+        token_descriptors(&state.contracts, &market_cell.market_type()?, &market_data)
+    }).await?;
+    Ok(Json(tokens))
+}
+
+async fn handle_quote(
+    State(state): State<Arc<AppState>>,
+    Path(op): Path<String>,
+    Query(params): Query<QuoteParams>,
+) -> Result<Json<QuoteResponse>, ApiError> {
+    let market_outpoint = match &params.market_id {
+        Some(id) => parse_market_id(id)?,
+        None => lock(&state.current_market).clone()
+            .ok_or_else(|| NotFound("No market created yet".to_string()))?,
+    };
+
+    let quote = run_blocking(&state, move |state| {
+        let mut client = state.rpc();
+        quote_operation(&mut *client, &state.contracts, &state.lock_script, &market_outpoint, &op, params.amount)
+    }).await?;
+    Ok(Json(quote))
+}
+
+async fn handle_proof(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<MarketProofResponse>, ApiError> {
+    let outpoint = parse_market_id(&id)?;
+
+    let proof = run_blocking(&state, move |state| {
+        market_proof(&mut *state.rpc(), &outpoint)
+    }).await?;
+    Ok(Json(proof))
+}
+
+/// Periodically rescan all market cells and cache the aggregate TVL
+async fn run_tvl_refresh(state: Arc<AppState>, interval: Duration) {
+    loop {
+        let task_state = state.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let mut client = task_state.rpc();
+            compute_tvl(&mut *client, &task_state.contracts)
+        }).await;
+
+        match result {
+            Ok(Ok(tvl)) => *lock(&state.tvl) = Some(tvl),
+            Ok(Err(err)) => warn!(error = %err, "TVL refresh failed"),
+            Err(err) => error!(error = %err, "TVL refresh task failed"),
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Periodically merge small pure-CKB cells in the fee wallet
+async fn run_consolidation(state: Arc<AppState>, threshold: usize, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let task_state = state.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            // Live operations hold the wallet lock for their whole
+            // collect-sign-confirm cycle, so holding it here keeps
+            // consolidation from selecting cells they are spending
+            let mut client = task_state.wallet_client();
+            consolidate_cells(&mut *client, &task_state.privkey, &task_state.lock_script, task_state.contracts.network, threshold)
+        }).await;
+
+        match result {
+            Ok(Ok(Some(consolidation))) => info!(
+                merged = consolidation.merged,
+                tx_hash = %format!("{:#x}", consolidation.tx_hash),
+                "Consolidated fee cells"
+            ),
+            Ok(Ok(None)) => {}
+            Ok(Err(err)) => warn!(error = %err, "Consolidation failed"),
+            Err(err) => error!(error = %err, "Consolidation task failed"),
+        }
+    }
+}
+
+async fn handle_create_market(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DryRunParams>,
+    body: axum::body::Bytes,
+) -> Result<Response, ApiError> {
+    // Without a body the market gets exactly its occupied capacity
+    let req: CreateMarketRequest = if body.is_empty() { CreateMarketRequest::default() } else { parse_request(&body)? };
+    let metadata = market_metadata(req.question.as_deref(), req.outcome_labels.as_deref())?;
+    let fee = market_fee(state.contracts.network, req.fee_bps)?;
+    let scalar = market_scalar(req.scalar_floor, req.scalar_ceiling)?;
+
+    let submitted = run_blocking(&state, move |state| {
+        submit_or_dry_run(state, "create-market", params.dry_run, |client| {
+            build_create_market_tx(
+                client,
+                &state.contracts,
+                &state.lock_script,
+                req.buffer_shannons,
+                req.shannons_per_token.unwrap_or(DEFAULT_SHANNONS_PER_TOKEN),
+                req.resolve_after,
+                fee,
+                scalar,
+                metadata,
+            )
+        })
+    }).await?;
+    let tx_hash = match submitted {
+        Submitted::DryRun(tx) => return Ok(dry_run_response(&tx)),
+        Submitted::Sent(outpoint) => outpoint.tx_hash().unpack(),
+    };
+
+    Ok(Json(ApiResponse {
+        success: true,
+        message: "Market created successfully".to_string(),
+        tx_hash: Some(format!("{:#x}", tx_hash)),
+    }).into_response())
+}
+
+/// Metadata for a new market from the request's question and labels
+/// Markets without either record none; labels name the two binary outcomes.
+fn market_metadata(question: Option<&str>, outcome_labels: Option<&[String]>) -> Result<Option<Metadata>, InvalidRequest> {
+    if question.is_none() && outcome_labels.is_none() {
+        return Ok(None);
+    }
+    let labels: Vec<&str> = outcome_labels.unwrap_or_default().iter().map(String::as_str).collect();
+    if !labels.is_empty() && labels.len() != 2 {
+        return Err(InvalidRequest(format!("outcome_labels must name both outcomes, got {}", labels.len())));
+    }
+    Metadata::new(question.unwrap_or_default(), &labels)
+        .map(Some)
+        .map_err(|_| InvalidRequest("question and outcome labels must be at most 255 bytes each".to_string()))
+}
+
+/// Protocol fee for a new market taking `fee_bps` of every claim
+/// The fee goes to FEE_RECIPIENT_ADDRESS, which claims need to build the
+/// recipient's output, so a fee market can't be created without it.
+fn market_fee(network: Network, fee_bps: u16) -> Result<Option<ProtocolFee>> {
+    if fee_bps == 0 {
+        return Ok(None);
+    }
+    if fee_bps > MAX_FEE_BPS {
+        return Err(InvalidRequest(format!("fee_bps must be at most {}", MAX_FEE_BPS)).into());
+    }
+    let recipient = fee_recipient(network)?
+        .ok_or_else(|| InvalidRequest("fee_bps needs FEE_RECIPIENT_ADDRESS set on the server".to_string()))?;
+    Ok(Some(ProtocolFee { fee_bps, recipient_lock_hash: recipient.calc_script_hash().unpack().into() }))
+}
+
+/// Range of a new scalar market, given both ends or neither
+/// The value starts at the floor and is set when the market resolves.
+fn market_scalar(floor: Option<u64>, ceiling: Option<u64>) -> Result<Option<Scalar>, InvalidRequest> {
+    match (floor, ceiling) {
+        (None, None) => Ok(None),
+        (Some(floor), Some(ceiling)) if floor < ceiling => Ok(Some(Scalar { floor, ceiling, value: floor })),
+        (Some(floor), Some(ceiling)) => {
+            Err(InvalidRequest(format!("scalar_floor {} must be below scalar_ceiling {}", floor, ceiling)))
+        }
+        _ => Err(InvalidRequest("scalar_floor and scalar_ceiling must be given together".to_string())),
+    }
+}
+
+/// Lock collecting protocol fees (env FEE_RECIPIENT_ADDRESS)
+fn fee_recipient(network: Network) -> Result<Option<Script>> {
+    std::env::var("FEE_RECIPIENT_ADDRESS").ok()
+        .filter(|address| !address.is_empty())
+        .map(|address| parse_address(&address, network))
+        .transpose()
+}
+
+/// Outcome of submit_or_dry_run
+enum Submitted {
+    /// Sent, with the new market cell
+    Sent(OutPoint),
+    /// Signed but not sent
+    DryRun(TransactionView),
+}
+
+/// Build a market transaction with the server's client and either send it
+/// as `op`, making its market cell the current market, or, on a dry run,
+/// sign it and hand it back
+fn submit_or_dry_run(
+    state: &AppState,
+    op: &str,
+    dry_run: bool,
+    build: impl FnOnce(&mut dyn CkbBackend) -> Result<BuiltTx>,
+) -> Result<Submitted> {
+    let mut client = state.wallet_client();
+    let built = build(&mut *client)?;
+    if dry_run {
+        return Ok(Submitted::DryRun(sign_market_tx(&mut *client, &state.privkey, &state.contracts, &built)?));
+    }
+
+    let new_outpoint = submit_market_tx(&mut *client, &state.privkey, &state.contracts, op, &built, state.send_mode)?;
+    state.set_current_market(new_outpoint.clone());
+    Ok(Submitted::Sent(new_outpoint))
+}
+
+/// Shannons in one CKB
+const SHANNONS_PER_CKB: u64 = 100_000_000;
+
+/// An amount of capacity, held in shannons and shown in CKB
+///
+/// Constants and responses go through this rather than raw `_00000000`
+/// literals and `/ 100_000_000`, so a CKB figure can't be mistaken for shannons.
+/// Responses serialize it as the plain shannon count.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(transparent)]
+struct Shannons(u64);
+
+impl Shannons {
+    const fn from_ckb(ckb: u64) -> Self {
+        Shannons(ckb * SHANNONS_PER_CKB)
+    }
+
+    const fn as_shannons(self) -> u64 {
+        self.0
+    }
+
+    fn checked_add(self, other: Shannons) -> Option<Shannons> {
+        self.0.checked_add(other.0).map(Shannons)
+    }
+
+    fn saturating_sub(self, other: Shannons) -> Shannons {
+        Shannons(self.0.saturating_sub(other.0))
+    }
+
+    /// Whole CKB, any fraction dropped
+    const fn whole_ckb(self) -> u64 {
+        self.0 / SHANNONS_PER_CKB
+    }
+}
+
+impl std::ops::Add for Shannons {
+    type Output = Shannons;
+
+    fn add(self, other: Shannons) -> Shannons {
+        Shannons(self.0 + other.0)
+    }
+}
+
+impl std::iter::Sum for Shannons {
+    fn sum<I: Iterator<Item = Shannons>>(iter: I) -> Shannons {
+        iter.fold(Shannons(0), |total, shannons| total + shannons)
+    }
+}
+
+/// `500 CKB`, or `1.5 CKB` with the fraction's trailing zeros trimmed
+impl std::fmt::Display for Shannons {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fraction = self.0 % SHANNONS_PER_CKB;
+        if fraction == 0 {
+            return write!(f, "{} CKB", self.whole_ckb());
+        }
+        let digits = format!("{:08}", fraction);
+        write!(f, "{}.{} CKB", self.whole_ckb(), digits.trim_end_matches('0'))
+    }
+}
+
+/// Capacity paid out for `tokens` sets or winning tokens of a market, for responses
+fn payout_capacity(client: &mut dyn CkbBackend, market_outpoint: &OutPoint, tokens: u128) -> Result<Shannons> {
+    let market_data = MarketData::from_bytes(&get_cell(client, market_outpoint)?.data)?;
+    Ok(market_data.checked_collateral(tokens)?)
+}
+
+/// Capacity a claim of `tokens` tokens of `side` (the winning side if None)
+/// pays the claimant, after the market's protocol fee
+fn claim_payout_capacity(client: &mut dyn CkbBackend, market_outpoint: &OutPoint, side: Option<bool>, tokens: u128) -> Result<Shannons> {
+    let market_data = MarketData::from_bytes(&get_cell(client, market_outpoint)?.data)?;
+    let payout = market_data.claim_payout(claim_side(&market_data, side)?, tokens)?;
+    Ok(Shannons(payout - market_data.claim_fee(payout)))
+}
+
+fn dry_run_response(tx: &TransactionView) -> Response {
+    let tx_hash: H256 = tx.hash().unpack();
+    Json(DryRunResponse {
+        success: true,
+        dry_run: true,
+        tx_hash: format!("{:#x}", tx_hash),
+        transaction: transaction_json(tx),
+    }).into_response()
+}
+
+/// Drain a market and create a fresh one (admin only, never on mainnet)
+async fn handle_reset_market(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Json<ResetResponse>, ApiError> {
+    check_admin(admin_token().as_deref(), &headers)?;
+    let market_outpoint = parse_market_id(&id)?;
+    // The replacement is created like /api/create-market, with the same optional body
+    let req: CreateMarketRequest = if body.is_empty() { CreateMarketRequest::default() } else { parse_request(&body)? };
+    let metadata = market_metadata(req.question.as_deref(), req.outcome_labels.as_deref())?;
+    let fee = market_fee(state.contracts.network, req.fee_bps)?;
+    let scalar = market_scalar(req.scalar_floor, req.scalar_ceiling)?;
+
+    let (drained, new_market) = run_blocking(&state, move |state| {
+        let mut client = state.wallet_client();
+        let chain = retry_read(RpcRetry::from_env(), "get_blockchain_info", || client.get_blockchain_info())?.chain;
+        ensure_not_mainnet(&chain)?;
+
+        let (drained, _) = drain_market(&mut *client, &state.privkey, &state.contracts, &state.lock_script, market_outpoint)?;
+        let built = build_create_market_tx(
+            &mut *client,
+            &state.contracts,
+            &state.lock_script,
+            req.buffer_shannons,
+            req.shannons_per_token.unwrap_or(DEFAULT_SHANNONS_PER_TOKEN),
+            req.resolve_after,
+            fee,
+            scalar,
+            metadata,
+        )?;
+        let new_market = submit_market_tx(&mut *client, &state.privkey, &state.contracts, "create-market", &built, state.send_mode)?;
+
+        state.set_current_market(new_market.clone());
+        Ok((drained, new_market))
+    }).await?;
+
+    let tx_hash: H256 = new_market.tx_hash().unpack();
+    let index: u32 = new_market.index().unpack();
+
+    Ok(Json(ResetResponse {
+        success: true,
+        drain_tx_hashes: drained.iter().map(|hash| format!("{:#x}", hash)).collect(),
+        create_tx_hash: format!("{:#x}", tx_hash),
+        market_id: format!("{:#x}:{}", tx_hash, index),
+    }))
+}
+
+/// Shared secret for /api/admin endpoints (env ADMIN_TOKEN); they refuse everything without it
+fn admin_token() -> Option<String> {
+    std::env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty())
+}
+
+/// Check the X-Admin-Token header against the configured admin token
+fn check_admin(expected: Option<&str>, headers: &HeaderMap) -> Result<(), Forbidden> {
+    let expected = expected.ok_or_else(|| Forbidden("Admin endpoints are disabled (ADMIN_TOKEN is not set)".to_string()))?;
+    let given = headers.get("x-admin-token").and_then(|value| value.to_str().ok());
+    if given != Some(expected) {
+        return Err(Forbidden("Missing or wrong X-Admin-Token".to_string()));
+    }
+    Ok(())
+}
+
+/// Refuse destructive devnet helpers on mainnet (chain name "ckb")
+fn ensure_not_mainnet(chain: &str) -> Result<(), Forbidden> {
+    if chain == "ckb" {
+        return Err(Forbidden("Refusing to reset markets on mainnet".to_string()));
+    }
+    Ok(())
+}
+
+async fn handle_mint(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DryRunParams>,
+    StrictJson(req): StrictJson<MintRequest>,
+) -> Result<Response, ApiError> {
+    let market_outpoint = lock(&state.current_market).clone()
+        .ok_or_else(|| NotFound("No market created yet".to_string()))?;
+
+    let submitted = run_blocking(&state, move |state| {
+        submit_or_dry_run(state, "mint", params.dry_run, |client| {
+            build_mint_tx(client, &state.contracts, &state.lock_script, market_outpoint, req.amount)
+        })
+    }).await?;
+    let tx_hash: H256 = match submitted {
+        Submitted::DryRun(tx) => return Ok(dry_run_response(&tx)),
+        Submitted::Sent(outpoint) => outpoint.tx_hash().unpack(),
+    };
+
+    Ok(Json(ApiResponse {
+        success: true,
+        message: format!("Minted {} YES + {} NO tokens", req.amount, req.amount),
+        tx_hash: Some(format!("{:#x}", tx_hash)),
+    }).into_response())
+}
+
+async fn handle_burn(
+    State(state): State<Arc<AppState>>,
+    StrictJson(req): StrictJson<BurnRequest>,
+) -> Result<Json<BurnResponse>, ApiError> {
+    let market_outpoint = lock(&state.current_market).clone()
+        .ok_or_else(|| NotFound("No market created yet".to_string()))?;
+
+    let (new_outpoint, refunded) = run_blocking(&state, move |state| {
+        let mut client = state.wallet_client();
+
+        let refunded = payout_capacity(&mut *client, &market_outpoint, req.amount)?;
+        let new_outpoint = burn_tokens(
+            &mut *client,
+            &state.privkey,
+            &state.contracts,
+            &state.lock_script,
+            market_outpoint,
+            req.amount,
+        )?;
+
+        state.set_current_market(new_outpoint.clone());
+        Ok((new_outpoint, refunded))
+    }).await?;
+
+    let tx_hash: H256 = new_outpoint.tx_hash().unpack();
+    let index: u32 = new_outpoint.index().unpack();
+
+    Ok(Json(BurnResponse {
+        success: true,
+        message: format!("Burned {} complete sets for {}", req.amount, refunded),
+        tx_hash: format!("{:#x}", tx_hash),
+        market_id: format!("{:#x}:{}", tx_hash, index),
+        refunded_ckb: refunded.whole_ckb(),
+    }))
+}
+
+async fn handle_redeem_sets(
+    State(state): State<Arc<AppState>>,
+    StrictJson(req): StrictJson<BurnRequest>,
+) -> Result<Json<BurnResponse>, ApiError> {
+    let market_outpoint = lock(&state.current_market).clone()
+        .ok_or_else(|| NotFound("No market created yet".to_string()))?;
+
+    let (new_outpoint, refunded) = run_blocking(&state, move |state| {
+        let mut client = state.wallet_client();
+
+        let refunded = payout_capacity(&mut *client, &market_outpoint, req.amount)?;
+        let new_outpoint = redeem_sets(
+            &mut *client,
+            &state.privkey,
+            &state.contracts,
+            &state.lock_script,
+            market_outpoint,
+            req.amount,
+        )?;
+
+        state.set_current_market(new_outpoint.clone());
+        Ok((new_outpoint, refunded))
+    }).await?;
+
+    let tx_hash: H256 = new_outpoint.tx_hash().unpack();
+    let index: u32 = new_outpoint.index().unpack();
+
+    Ok(Json(BurnResponse {
+        success: true,
+        message: format!("Redeemed {} complete sets for {}", req.amount, refunded),
+        tx_hash: format!("{:#x}", tx_hash),
+        market_id: format!("{:#x}:{}", tx_hash, index),
+        refunded_ckb: refunded.whole_ckb(),
+    }))
+}
+
+async fn handle_transfer(
+    State(state): State<Arc<AppState>>,
+    StrictJson(req): StrictJson<TransferRequest>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    let market_outpoint = lock(&state.current_market).clone()
+        .ok_or_else(|| NotFound("No market created yet".to_string()))?;
+    let is_yes = parse_token_side(&req.token_id)?;
+    let to = parse_address(&req.to, state.contracts.network)?;
+
+    let amount = req.amount;
+    let tx_hash = run_blocking(&state, move |state| {
+        transfer_tokens(
+            &mut *state.wallet_client(),
+            &state.privkey,
+            &state.contracts,
+            &state.lock_script,
+            &market_outpoint,
+            is_yes,
+            amount,
+            &to,
+            state.send_mode,
+        )
+    }).await?;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        message: format!("Sent {} {} tokens to {}", req.amount, if is_yes { "YES" } else { "NO" }, req.to),
+        tx_hash: Some(format!("{:#x}", tx_hash)),
+    }))
+}
+
+/// Propose the current market's outcome or value; /api/finalize resolves the
+/// market with it once it has stood RESOLUTION_DELAY_SECS
+async fn handle_resolve(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DryRunParams>,
+    StrictJson(req): StrictJson<ResolveRequest>,
+) -> Result<Response, ApiError> {
+    let (settlement, proposed) = match (req.outcome, req.value) {
+        (Some(outcome), None) => (Settlement::Outcome(outcome), format!("{} wins", if outcome { "YES" } else { "NO" })),
+        (None, Some(value)) => (Settlement::Value(value), format!("value {}", value)),
+        _ => return Err(InvalidRequest("Give exactly one of outcome and value".to_string()).into()),
+    };
+    let message = format!("Proposed {}; finalize after {}s", proposed, RESOLUTION_DELAY_SECS);
+    resolution_step(state, params, "propose", message, move |client, state, market_outpoint| {
+        build_proposal_tx(client, &state.contracts, &state.lock_script, market_outpoint, settlement)
+    }).await
+}
+
+/// Propose an outcome, value or cancellation for the current market
+async fn handle_propose(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DryRunParams>,
+    StrictJson(req): StrictJson<ProposeRequest>,
+) -> Result<Response, ApiError> {
+    let (settlement, proposed) = match (req.outcome, req.value, req.cancel) {
+        (Some(outcome), None, false) => (Settlement::Outcome(outcome), format!("{} wins", if outcome { "YES" } else { "NO" })),
+        (None, Some(value), false) => (Settlement::Value(value), format!("value {}", value)),
+        (None, None, true) => (Settlement::Cancelled, "cancellation".to_string()),
+        _ => return Err(InvalidRequest("Give exactly one of outcome, value and cancel".to_string()).into()),
+    };
+    let message = format!("Proposed {}; finalize after {}s", proposed, RESOLUTION_DELAY_SECS);
+    resolution_step(state, params, "propose", message, move |client, state, market_outpoint| {
+        build_proposal_tx(client, &state.contracts, &state.lock_script, market_outpoint, settlement)
+    }).await
+}
+
+/// Promote the pending proposal to the market's resolution
+async fn handle_finalize(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DryRunParams>,
+) -> Result<Response, ApiError> {
+    resolution_step(state, params, "finalize", "Market resolved as proposed".to_string(), |client, state, market_outpoint| {
+        build_finalize_tx(client, &state.contracts, &state.lock_script, market_outpoint)
+    }).await
+}
+
+/// Submit (or dry-run) one step of a two-phase resolution of the current market
+async fn resolution_step(
+    state: Arc<AppState>,
+    params: DryRunParams,
+    op: &'static str,
+    message: String,
+    build: impl FnOnce(&mut dyn CkbBackend, &AppState, OutPoint) -> Result<BuiltTx> + Send + 'static,
+) -> Result<Response, ApiError> {
+    let market_outpoint = lock(&state.current_market).clone()
+        .ok_or_else(|| NotFound("No market created yet".to_string()))?;
+
+    let submitted = run_blocking(&state, move |state| {
+        submit_or_dry_run(state, op, params.dry_run, |client| build(client, state, market_outpoint))
+    }).await?;
+    let tx_hash: H256 = match submitted {
+        Submitted::DryRun(tx) => return Ok(dry_run_response(&tx)),
+        Submitted::Sent(outpoint) => outpoint.tx_hash().unpack(),
+    };
+
+    Ok(Json(ApiResponse {
+        success: true,
+        message,
+        tx_hash: Some(format!("{:#x}", tx_hash)),
+    }).into_response())
+}
+
+async fn handle_claim(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DryRunParams>,
+    StrictJson(req): StrictJson<ClaimRequest>,
+) -> Result<Response, ApiError> {
+    let market_outpoint = lock(&state.current_market).clone()
+        .ok_or_else(|| NotFound("No market created yet".to_string()))?;
+    let side = req.side.as_deref().map(parse_token_side).transpose()?;
+
+    let (submitted, collateral) = run_blocking(&state, move |state| {
+        let mut collateral = Shannons(0);
+        let submitted = submit_or_dry_run(state, "claim", params.dry_run, |client| {
+            collateral = claim_payout_capacity(client, &market_outpoint, side, req.amount)?;
+            build_side_claim_tx(client, &state.contracts, &state.lock_script, market_outpoint, side, req.amount)
+        })?;
+        Ok((submitted, collateral))
+    }).await?;
+    let tx_hash: H256 = match submitted {
+        Submitted::DryRun(tx) => return Ok(dry_run_response(&tx)),
+        Submitted::Sent(outpoint) => outpoint.tx_hash().unpack(),
+    };
+
+    Ok(Json(ApiResponse {
+        success: true,
+        message: format!("Claimed {} tokens for {}", req.amount, collateral),
+        tx_hash: Some(format!("{:#x}", tx_hash)),
+    }).into_response())
+}
+
+async fn handle_refund(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<RefundParams>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    let market_outpoint = parse_market_id(&params.market_id)?;
+
+    let amount = params.amount;
+    let (new_outpoint, collateral) = run_blocking(&state, move |state| {
+        let mut client = state.wallet_client();
+
+        let collateral = payout_capacity(&mut *client, &market_outpoint, amount)?;
+        let new_outpoint = refund_tokens(
+            &mut *client,
+            &state.privkey,
+            &state.contracts,
+            &state.lock_script,
+            market_outpoint.clone(),
+            amount,
+        )?;
+
+        let was_current = lock(&state.current_market).as_ref()
+            .map(|outpoint| outpoint.as_slice()) == Some(market_outpoint.as_slice());
+        if was_current {
+            state.set_current_market(new_outpoint.clone());
+        }
+        Ok((new_outpoint, collateral))
+    }).await?;
+
+    let tx_hash: H256 = new_outpoint.tx_hash().unpack();
+
+    Ok(Json(ApiResponse {
+        success: true,
+        message: format!("Refunded {} complete sets for {}", params.amount, collateral),
+        tx_hash: Some(format!("{:#x}", tx_hash)),
+    }))
+}
+
+/// Build (but don't sign or send) the transaction for an operation and explain it
+async fn handle_preview(
+    State(state): State<Arc<AppState>>,
+    Path(op): Path<String>,
+    body: axum::body::Bytes,
+) -> Result<Json<TxPreview>, ApiError> {
+    // Without a body every parameter takes its default
+    let req: PreviewRequest = if body.is_empty() { PreviewRequest::default() } else { parse_request(&body)? };
+    let market_outpoint = match &req.market_id {
+        Some(id) => Some(parse_market_id(id)?),
+        None => lock(&state.current_market).clone(),
+    };
+
+    let preview = run_blocking(&state, move |state| {
+        let market = || market_outpoint.clone().ok_or_else(|| NotFound("No market created yet".to_string()));
+
+        let mut client = state.rpc();
+        let (contracts, lock) = (&state.contracts, &state.lock_script);
+
+        let funding = match (&req.funding_inputs, &req.change_address) {
+            (None, None) => Funding::ServerWallet,
+            (Some(inputs), Some(change_address)) if op == "mint" => {
+                supplied_funding(&mut *client, contracts.network, inputs, change_address)?
+            }
+            (Some(_), Some(_)) => {
+                return Err(InvalidRequest(format!("{} cannot be funded from supplied inputs", op)).into());
+            }
+            _ => {
+                return Err(InvalidRequest("funding_inputs and change_address must be given together".to_string()).into());
+            }
+        };
+
+        let built = match op.as_str() {
+            "create-market" => build_create_market_tx(
+                &mut *client,
+                contracts,
+                lock,
+                req.buffer_shannons,
+                req.shannons_per_token.unwrap_or(DEFAULT_SHANNONS_PER_TOKEN),
+                req.resolve_after,
+                market_fee(contracts.network, req.fee_bps)?,
+                None,
+                market_metadata(req.question.as_deref(), req.outcome_labels.as_deref())?,
+            )?,
+            "mint" => build_funded_mint_tx(&mut *client, contracts, lock, market()?, req.amount, &funding)?,
+            "resolve" => {
+                let settlement = req.value.map_or(Settlement::Outcome(req.outcome), Settlement::Value);
+                build_proposal_tx(&mut *client, contracts, lock, market()?, settlement)?
+            }
+            "claim" => build_claim_tx(&mut *client, contracts, lock, market()?, req.amount)?,
+            "refund" => build_refund_tx(&mut *client, contracts, lock, market()?, req.amount)?,
+            "burn" => build_burn_tx(&mut *client, contracts, lock, market()?, req.amount)?,
+            "redeem-sets" => build_redeem_sets_tx(&mut *client, contracts, lock, market()?, req.amount)?,
+            other => return Err(NotFound(format!("Unknown operation: {}", other)).into()),
+        };
+
+        let mut preview = describe_transaction(&mut *client, contracts, &op, &built.tx)?;
+        if req.include_transaction {
+            preview.transaction = Some(transaction_json(&built.tx));
+        }
+        Ok(preview)
+    }).await?;
+    Ok(Json(preview))
+}
+
+// ============================================================================
+// Test Mode
+// ============================================================================
+
+/// Buffer that brings test markets to 251 CKB (232 CKB occupied), the capacity
+/// the expected values below were written for
+const TEST_MARKET_BUFFER: u64 = Shannons::from_ckb(19).as_shannons();
+
+fn run_tests() -> Result<()> {
+    info!("=== Market Contract Test Suite ===");
+    // Expected capacities below are written in whole CKB
+    let ckb = |amount: u64| Shannons::from_ckb(amount).as_shannons();
+
+    // Connect to the node
+    let config = node_config()?;
+    // Every step builds on the cells the previous one committed
+    if config.send_mode == SendMode::BroadcastOnly {
+        return Err(anyhow!("BROADCAST_ONLY is for the server; unset it to run the tests"));
+    }
+    let mut client = CkbRpcClient::new(&config.rpc_url);
+    info!("Connected to node at {}", config.rpc_url);
+
+    // Check connection
+    let tip = client.get_tip_block_number()?;
+    info!("Current block height: {}", tip);
+
+    // Get contract info
+    verify_network(&mut client, config.network)?;
+    let contracts = get_contract_info(config.network)?;
+    info!("Market code hash: {:#x}", contracts.market_code_hash);
+    info!("Always-success code hash: {:#x}", contracts.always_success_code_hash);
+    verify_market_lock(&mut client, &contracts)?;
+
+    // Signer (validated when the config was read)
+    let privkey = config.privkey;
+
+    // Get address from private key
+    let secp = secp256k1::Secp256k1::new();
+    let pubkey = secp256k1::PublicKey::from_secret_key(&secp, &privkey);
+    let lock_script = wallet_lock(&pubkey, pubkey_format()?)?;
+    verify_wallet_visible(&mut client, &pubkey, &lock_script)?;
+
+    info!("Lock script hash: {:#x}", lock_script.calc_script_hash());
+
+    // Run tests
+    info!("=== Step 1: Create Market Cell ===");
+    let market_outpoint = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0)?;
+    info!("Market created!");
+
+    info!("=== Step 1b: Preview Mint Of 10 Tokens ===");
+    let mint_tx = build_mint_tx(&mut client, &contracts, &lock_script, market_outpoint.clone(), 10)?;
+    let preview = describe_transaction(&mut client, &contracts, "mint", &mint_tx.tx)?;
+    let roles = |outputs: &[PreviewOutput]| -> Vec<Option<String>> {
+        outputs.iter().map(|output| output.type_script.clone()).collect()
+    };
+    if preview.inputs.first().map(|input| (input.role.as_str(), input.capacity)) != Some(("market cell", ckb(251)))
+        || preview.inputs[1..].iter().any(|input| input.role != "fee cell")
+    {
+        return Err(anyhow!("Unexpected mint preview inputs: {:?}", preview.inputs));
+    }
+    if roles(&preview.outputs) != [Some("market".to_string()), Some("YES token".to_string()),
+                                   Some("NO token".to_string()), None]
+        || preview.outputs[0].capacity != ckb(1251)
+        || preview.outputs[0].data != "YES supply 10, NO supply 10, open"
+        || preview.outputs[1].data != "10 tokens"
+        || preview.outputs[1].capacity != ckb(143)
+        || preview.outputs[3].data != "empty"
+    {
+        return Err(anyhow!("Unexpected mint preview outputs: {:?}", preview.outputs));
+    }
+    let dep_roles: Vec<&str> = preview.cell_deps.iter().map(|dep| dep.role.as_str()).collect();
+    let mint_fee = estimate_fee(&mint_tx.signer.with_placeholder_witnesses(mint_tx.tx.clone()), fee_rate());
+    if preview.fee != mint_fee
+        || dep_roles != ["secp256k1 dep group", "market contract", "always-success contract", "token contract"]
+    {
+        return Err(anyhow!("Unexpected mint preview fee {} / deps {:?}", preview.fee, dep_roles));
+    }
+    info!("Preview: {} inputs, {} outputs, fee {} shannons (not broadcast)",
+          preview.inputs.len(), preview.outputs.len(), preview.fee);
+
+    info!("=== Step 2: Mint 10 Tokens ===");
+    let market_outpoint = mint_tokens(&mut client, &privkey, &contracts, &lock_script, market_outpoint, 10)?;
+    info!("Minted 10 YES + 10 NO tokens!");
+
+    info!("=== Step 2b: Mint Into Market Lock (should be rejected) ===");
+    let market_lock = build_market_lock(&contracts);
+    if mint_tokens(&mut client, &privkey, &contracts, &market_lock, market_outpoint.clone(), 1).is_ok() {
+        return Err(anyhow!("Minting into the market lock was not rejected"));
+    }
+    info!("Rejected client-side as expected");
+
+    info!("=== Step 3: Resolve Market (YES proposed) ===");
+    // Resolving proposes, and finalizing waits out RESOLUTION_DELAY_SECS
+    // of chain time, more than a devnet run covers; payouts from finalized
+    // markets are checked against the mock node (cargo test) instead
+    let market_outpoint = propose_resolution(&mut client, &privkey, &contracts, &lock_script, market_outpoint, Settlement::Outcome(true))?;
+    let market_data = MarketData::from_bytes(&get_cell(&mut client, &market_outpoint)?.data)?;
+    if market_data.pending_outcome != Some(Proposal::Yes) || market_data.resolved {
+        return Err(anyhow!("Resolving left {:?} pending (resolved: {})", market_data.pending_outcome, market_data.resolved));
+    }
+    match build_finalize_tx(&mut client, &contracts, &lock_script, market_outpoint.clone()) {
+        Err(err) if err.downcast_ref::<Rejected>().is_some() => {}
+        other => return Err(anyhow!("Finalizing before the delay was not refused: {:?}", other.map(|built| built.tx.hash()))),
+    }
+    match build_proposal_tx(&mut client, &contracts, &lock_script, market_outpoint.clone(), Settlement::Outcome(false)) {
+        Err(err) if err.downcast_ref::<Rejected>().is_some() => {}
+        other => return Err(anyhow!("Proposing NO over a pending YES was not refused: {:?}", other.map(|built| built.tx.hash()))),
+    }
+    info!("YES proposed; finalizing before the delay, or proposing NO, was refused");
+
+    info!("=== Step 4: Claims Wait For Finalization ===");
+    match build_claim_tx(&mut client, &contracts, &lock_script, market_outpoint.clone(), 5) {
+        Err(err) if err.downcast_ref::<Rejected>().is_some() => {}
+        other => return Err(anyhow!("A claim on a proposed market was not refused: {:?}", other.map(|_| ()))),
+    }
+    if market_claimable(&get_cell(&mut client, &market_outpoint)?).is_ok() {
+        return Err(anyhow!("A proposed market reported claimable capacity"));
+    }
+    info!("Nothing is claimable until the proposal is finalized");
+
+    info!("=== Step 5: TVL Across Two Markets ===");
+    let second_market = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0)?;
+    let tvl = compute_tvl(&mut client, &contracts)?;
+    for outpoint in [&market_outpoint, &second_market] {
+        let tx_hash: H256 = outpoint.tx_hash().unpack();
+        let capacity = get_cell(&mut client, outpoint)?.capacity;
+        let listed = tvl.markets.iter()
+            .any(|m| m.tx_hash == format!("{:#x}", tx_hash) && m.capacity == Shannons(capacity));
+        if !listed {
+            return Err(anyhow!("Market {:#x} missing from TVL breakdown", tx_hash));
+        }
+    }
+    let breakdown_total: Shannons = tvl.markets.iter().map(|m| m.capacity).sum();
+    if breakdown_total != tvl.total_capacity {
+        return Err(anyhow!("TVL total {} != breakdown sum {}", tvl.total_capacity, breakdown_total));
+    }
+    info!("TVL: {} across {} markets", tvl.total_capacity, tvl.market_count);
+
+    info!("=== Step 6: Consolidate Fee Cells ===");
+    let cells_before = list_pure_cells(&mut client, &lock_script)?;
+    let balance_before: u64 = cells_before.iter().map(|(_, cap)| cap).sum();
+    match consolidate_cells(&mut client, &privkey, &lock_script, contracts.network, 2)? {
+        Some(consolidation) => {
+            let cells_after = list_pure_cells(&mut client, &lock_script)?;
+            let balance_after: u64 = cells_after.iter().map(|(_, cap)| cap).sum();
+            if cells_after.len() >= cells_before.len() {
+                return Err(anyhow!("Consolidation did not reduce cell count: {} -> {}",
+                                   cells_before.len(), cells_after.len()));
+            }
+            if balance_after + consolidation.fee != balance_before {
+                return Err(anyhow!("Consolidation changed balance: {} -> {} (fee {})",
+                                   balance_before, balance_after, consolidation.fee));
+            }
+            info!("Merged {} cells: {} -> {} fee cells",
+                  consolidation.merged, cells_before.len(), cells_after.len());
+        }
+        None => info!("Fewer than 2 fee cells, nothing to consolidate"),
+    }
+
+    info!("=== Step 7: Token Hash Type From Market Data ===");
+    let default_market = MarketData::open(&contracts, DEFAULT_SHANNONS_PER_TOKEN);
+    let type_market = MarketData { hash_type: 1, ..default_market.clone() };
+    let parsed = MarketData::from_bytes(&type_market.to_bytes())?;
+    let market_type = build_market_type(&contracts);
+    let type_token = build_token_type(&contracts, &market_type, parsed.token_script_hash_type()?, true);
+    let default_token = build_token_type(&contracts, &market_type, default_market.token_script_hash_type()?, true);
+    if type_token.hash_type().as_slice() != [ScriptHashType::Type as u8]
+        || type_token.calc_script_hash().as_slice() == default_token.calc_script_hash().as_slice()
+    {
+        return Err(anyhow!("Token script ignored the market's hash_type"));
+    }
+    if default_market.hash_type != DEFAULT_TOKEN_HASH_TYPE {
+        return Err(anyhow!("New markets are not created with Data1 tokens"));
+    }
+    let unknown = MarketData { hash_type: 3, ..default_market };
+    if unknown.token_script_hash_type().is_ok() {
+        return Err(anyhow!("Unknown token hash_type was accepted"));
+    }
+    info!("Token scripts follow the market's hash_type");
+
+    info!("=== Step 8: Market Lock Hash Types ===");
+    for (name, hash_type) in [
+        ("data", ScriptHashType::Data),
+        ("type", ScriptHashType::Type),
+        ("data1", ScriptHashType::Data1),
+        ("Data2", ScriptHashType::Data2),
+    ] {
+        let configured = ContractInfo {
+            always_success_hash_type: parse_script_hash_type(name)?,
+            always_success_code_hash: contracts.always_success_code_hash.clone(),
+            ..get_contract_info(config.network)?
+        };
+        let market_lock = build_market_lock(&configured);
+        if market_lock.hash_type().as_slice() != [hash_type as u8] {
+            return Err(anyhow!("Market lock ignored configured hash_type {}", name));
+        }
+        if validate_token_lock(&configured, &market_lock).is_ok() {
+            return Err(anyhow!("Token lock check missed the {} market lock", name));
+        }
+    }
+    if parse_script_hash_type("data3").is_ok() {
+        return Err(anyhow!("Unknown hash_type was accepted"));
+    }
+    info!("Market lock follows the configured hash_type");
+
+    info!("=== Step 9: Propose Cancelling A Market ===");
+    // Refunds need the cancellation finalized; the mock-node tests cover them
+    let base_capacity = get_cell(&mut client, &second_market)?.capacity;
+    let refund_market = mint_tokens(&mut client, &privkey, &contracts, &lock_script, second_market, 2)?;
+    if refund_tokens(&mut client, &privkey, &contracts, &lock_script, refund_market.clone(), 2).is_ok() {
+        return Err(anyhow!("Refund on a market that is not cancelled was not rejected"));
+    }
+    let refund_market = propose_resolution(&mut client, &privkey, &contracts, &lock_script, refund_market, Settlement::Cancelled)?;
+    let cancelling = get_cell(&mut client, &refund_market)?;
+    let cancelling_data = MarketData::from_bytes(&cancelling.data)?;
+    if cancelling_data.pending_outcome != Some(Proposal::Cancel) || cancelling.capacity != base_capacity + 2 * DEFAULT_SHANNONS_PER_TOKEN {
+        return Err(anyhow!("Cancelling left {:?} pending and {} shannons", cancelling_data.pending_outcome, cancelling.capacity));
+    }
+    if refund_tokens(&mut client, &privkey, &contracts, &lock_script, refund_market.clone(), 2).is_ok()
+        || claim_tokens(&mut client, &privkey, &contracts, &lock_script, refund_market, 1).is_ok()
+    {
+        return Err(anyhow!("A refund or claim on a market still proposed cancelled was not rejected"));
+    }
+    info!("Cancellation proposed; the 2 complete sets stay locked until it is finalized");
+
+    info!("=== Step 10: Fee Bump On A Stuck Transaction ===");
+    let stuck = build_create_market_tx(&mut client, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0, None, None, None)?;
+    let config = BumpConfig { poll: Duration::ZERO, polls_per_bump: 2, max_bumps: 3, rate: DEFAULT_MIN_RBF_RATE };
+    let change_index = stuck.change.ok_or_else(|| anyhow!("Market creation kept no change"))?;
+    // Each bump adds the minimum replacement rate over the signed size
+    let step = estimate_fee(&stuck.signer.sign(stuck.tx.clone(), &privkey)?, DEFAULT_MIN_RBF_RATE);
+    let bumped_hash: H256 = stuck.signer.sign(bump_fee(&stuck.tx, change_index, step)?, &privkey)?.hash().unpack();
+    let original_hash: H256 = stuck.tx.hash().unpack();
+    let submitted = std::cell::RefCell::new(Vec::<TransactionView>::new());
+    // The original never commits and is rejected once replaced; the first
+    // replacement commits
+    let committed = wait_with_bumps(&stuck, &privkey, &config,
+        |tx| {
+            submitted.borrow_mut().push(tx.clone());
+            Ok(tx.hash().unpack())
+        },
+        |tx_hash| Ok(if *tx_hash == bumped_hash {
+            Confirmation::Committed
+        } else if *tx_hash == original_hash && submitted.borrow().len() > 1 {
+            Confirmation::Rejected("RBFRejected".to_string())
+        } else {
+            Confirmation::Waiting
+        }))?;
+    let submitted = submitted.into_inner();
+    let change = |tx: &TransactionView| -> u64 {
+        tx.outputs().get(change_index).map(|output| output.capacity().unpack()).unwrap_or(0)
+    };
+    if committed != bumped_hash || submitted.len() != 2 || change(&submitted[0]) - change(&submitted[1]) != step {
+        return Err(anyhow!("Expected one {} shannon bump to commit, submitted {} versions", step, submitted.len()));
+    }
+    if step * 1000 < DEFAULT_MIN_RBF_RATE * stuck.tx.data().serialized_size_in_block() as u64 {
+        return Err(anyhow!("A {} shannon bump is below the minimum replacement rate", step));
+    }
+    if wait_with_bumps(&stuck, &privkey, &config, |tx| Ok(tx.hash().unpack()), |_| Ok(Confirmation::Waiting)).is_ok() {
+        return Err(anyhow!("Bumping did not stop at the configured cap"));
+    }
+    // A rejection of the only version fails before any bump
+    let mut versions = 0;
+    let rejected = wait_with_bumps(&stuck, &privkey, &config,
+        |tx| {
+            versions += 1;
+            Ok(tx.hash().unpack())
+        },
+        |_| Ok(Confirmation::Rejected("PoolRejectedDuplicatedTransaction".to_string())));
+    match rejected {
+        Err(err) if err.downcast_ref::<Unconfirmed>().is_none() && versions == 1 => {}
+        other => return Err(anyhow!("A rejected transaction was waited on: {:?} after {} versions", other, versions)),
+    }
+    info!("Stuck transaction replaced by a bumped one; bumping stops at the cap and a rejection fails at once");
+
+    info!("=== Step 11: Off-Chain Validation Matches The Node ===");
+    let open_market = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0)?;
+    let mint = build_mint_tx(&mut client, &contracts, &lock_script, open_market.clone(), 1)?;
+    let resolved_inputs = resolve_inputs(&mut client, &mint.tx)?;
+    validate_market_tx(&contracts, &mint.tx, &resolved_inputs)?;
+    client.test_tx_pool_accept(mint.signer.sign(mint.tx.clone(), &privkey)?.data().into(), None)
+        .map_err(|err| anyhow!("Node rejected the mint accepted off-chain: {}", err))?;
+    // Same mint with 1 CKB of collateral moved to the change cell
+    let mut outputs: Vec<CellOutput> = mint.tx.outputs().into_iter().collect();
+    let shift = |output: &CellOutput, delta: i64| -> CellOutput {
+        let capacity: u64 = output.capacity().unpack();
+        output.clone().as_builder().capacity(capacity.saturating_add_signed(delta).pack()).build()
+    };
+    let last = outputs.len() - 1;
+    outputs[0] = shift(&outputs[0], -(ckb(1) as i64));
+    outputs[last] = shift(&outputs[last], ckb(1) as i64);
+    let short = BuiltTx { tx: mint.tx.as_advanced_builder().set_outputs(outputs).build(), signer: mint.signer, change: mint.change };
+    let verdict = validate_market_tx(&contracts, &short.tx, &resolved_inputs);
+    if verdict != Err(MarketError::InsufficientCollateral) {
+        return Err(anyhow!("Under-collateralized mint was judged {:?}", verdict));
+    }
+    let signed = short.signer.sign(short.tx.clone(), &privkey)?;
+    if client.test_tx_pool_accept(signed.data().into(), None).is_ok() {
+        return Err(anyhow!("Node accepted the mint rejected off-chain"));
+    }
+    info!("Valid mint accepted, under-collateralized mint rejected off-chain and by the node");
+
+    info!("=== Step 12: Token Cell Minimum Capacity ===");
+    let yes_type = build_token_type(&contracts, &build_market_type(&contracts), ScriptHashType::Data1, true);
+    let minimum = token_cell_minimum(&lock_script, &yes_type)?;
+    if minimum != ckb(143) {
+        return Err(anyhow!("Expected a 143 CKB token cell minimum, got {} shannons", minimum));
+    }
+    if token_cell_output(&lock_script, &yes_type, minimum - 1).is_ok() {
+        return Err(anyhow!("Undersized token cell was accepted"));
+    }
+    let funded = token_cell_output(&lock_script, &yes_type, 2 * minimum + ckb(5))?;
+    let parts = split_token_cell(&funded, 10, &[6, 4])?;
+    let capacities: Vec<u64> = parts.iter().map(|(cell, _)| cell.capacity().unpack()).collect();
+    if capacities != [minimum + ckb(5), minimum] {
+        return Err(anyhow!("Unexpected split capacities {:?}", capacities));
+    }
+    let single = token_cell_output(&lock_script, &yes_type, minimum)?;
+    if split_token_cell(&single, 10, &[6, 4]).is_ok() {
+        return Err(anyhow!("Split producing a dust cell was not rejected"));
+    }
+    info!("Token cells sized from their occupied capacity; dust splits rejected");
+
+    info!("=== Step 13: Collateral Excludes The Market Cell's Own Capacity ===");
+    // 8 capacity + 33 lock + 33 type (no args) + 158 data bytes
+    let fresh = get_cell(&mut client, &open_market)?;
+    if fresh.occupied != ckb(232) || fresh.collateral() != fresh.capacity - ckb(232) {
+        return Err(anyhow!("Market of {} shannons reports {} occupied, {} collateral",
+                           fresh.capacity, fresh.occupied, fresh.collateral()));
+    }
+    let open_market = mint_tokens(&mut client, &privkey, &contracts, &lock_script, open_market, 3)?;
+    let backed = get_cell(&mut client, &open_market)?;
+    if backed.collateral() != fresh.collateral() + ckb(300) {
+        return Err(anyhow!("Minting 3 sets moved collateral from {} to {}", fresh.collateral(), backed.collateral()));
+    }
+    info!("Collateral is capacity minus the 232 CKB the market cell occupies");
+
+    info!("=== Step 14: Fee Margin On A Large Mint ===");
+    let large = build_mint_tx(&mut client, &contracts, &lock_script, open_market.clone(), 1000)?;
+    let large_inputs: u64 = resolve_inputs(&mut client, &large.tx)?.iter()
+        .map(|(output, _)| -> u64 { output.capacity().unpack() })
+        .sum();
+    let large_fee = estimate_fee(&large.signer.with_placeholder_witnesses(large.tx.clone()), fee_rate());
+    verify_fee_margin(large_inputs, &large.tx, large_fee, fee_margin())?;
+    if verify_fee_margin(large_inputs, &large.tx, large_fee, u64::MAX).is_ok() {
+        return Err(anyhow!("Fee margin check accepted an unreachable margin"));
+    }
+    info!("1000-set mint keeps at least {} shannons of change over its fee", fee_margin());
+
+    info!("=== Step 15: Operation Log For A Mint ===");
+    let logged = build_mint_tx(&mut client, &contracts, &lock_script, open_market.clone(), 2)?;
+    let log = operation_log("mint", &logged.tx, &resolve_inputs(&mut client, &logged.tx)?)?;
+    let logged_hash: H256 = logged.tx.hash().unpack();
+    if log.op != "mint" || log.yes_delta != 2 || log.no_delta != 2
+        || log.capacity_delta != ckb(200) as i64
+        || log.fee != estimate_fee(&logged.signer.with_placeholder_witnesses(logged.tx.clone()), fee_rate())
+        || log.tx_hash != format!("{:#x}", logged_hash) || log.market_id != format!("{:#x}:0", logged_hash)
+    {
+        return Err(anyhow!("Unexpected mint log {:?}", log));
+    }
+    info!("OP {}", serde_json::to_string(&log)?);
+
+    info!("=== Step 16: Token Types For Arbitrary Outcome Ids ===");
+    // Vectors shared with derive_token_type_hash_known_vectors in the market contract:
+    // token code hash 0xab.., Data1, market type hash 0x11..
+    let vector_contracts = ContractInfo { token_code_hash: H256([0xab; 32]), ..get_contract_info(config.network)? };
+    for (token_id, expected) in [
+        (YES_TOKEN_ID, "4dd3be66329aee52ff9d887a7410ad77103a24900ad30df1746560adeadaec08"),
+        (0x03, "228a52fb51f079004af342dac453c7bf86546dd7a2e4b97c54cf491d1f14dcf3"),
+    ] {
+        let token_type = build_outcome_token_type(&vector_contracts, &[0x11; 32], ScriptHashType::Data1, token_id);
+        let hash = hex::encode(token_type.calc_script_hash().as_slice());
+        if hash != expected {
+            return Err(anyhow!("Token {:#04x} hashes to {}, contract derives {}", token_id, hash, expected));
+        }
+    }
+    info!("Outcome 0x03 token type matches the contract's derivation");
+
+    info!("=== Step 17: Malformed Token Cells Are Skipped ===");
+    let candidate = |index: u32, data: Option<Vec<u8>>| {
+        let outpoint = OutPoint::new_builder().tx_hash(H256([0x22; 32]).pack()).index(index.pack()).build();
+        (outpoint, ckb(143), data.map(Bytes::from))
+    };
+    let selected = select_token_cell([
+        candidate(0, Some(vec![0x07; 24])),
+        candidate(1, None),
+        candidate(2, Some(9u128.to_le_bytes().to_vec())),
+    ]);
+    let selected = selected.map(|(outpoint, _, amount)| {
+        let index: u32 = outpoint.index().unpack();
+        (index, amount)
+    });
+    if selected != Some((2, 9)) {
+        return Err(anyhow!("Expected 9 tokens from the cell at index 2, got {:?}", selected));
+    }
+    if select_token_cell([candidate(0, Some(vec![0x07; 3]))]).is_some() {
+        return Err(anyhow!("Malformed token cell was selected"));
+    }
+    info!("Malformed token cells skipped, valid one selected");
+
+    info!("=== Step 18: Market Proof Bundle ===");
+    let bundle = market_proof(&mut client, &open_market)?;
+    let block_hash = H256::from_str(bundle.block_hash.trim_start_matches("0x"))?;
+    let header = client.get_header(block_hash)?
+        .ok_or_else(|| anyhow!("Block {} not found", bundle.block_hash))?;
+    verify_market_proof(&header, &bundle)?;
+    // A proof for another transaction must not verify
+    let mut forged = market_proof(&mut client, &market_outpoint)?;
+    forged.proof = bundle.proof.clone();
+    if verify_market_proof(&header, &forged).is_ok() {
+        return Err(anyhow!("Proof verified for a transaction it does not cover"));
+    }
+    info!("Market proof rebuilds the transactions root of block {}", bundle.block_hash);
+
+    info!("=== Step 19: Deterministic Cell Order ===");
+    let previous_order = std::env::var("DETERMINISTIC_CELL_ORDER").ok();
+    std::env::set_var("DETERMINISTIC_CELL_ORDER", "1");
+    let first = build_mint_tx(&mut client, &contracts, &lock_script, open_market.clone(), 1);
+    let second = build_mint_tx(&mut client, &contracts, &lock_script, open_market.clone(), 1);
+    match previous_order {
+        Some(value) => std::env::set_var("DETERMINISTIC_CELL_ORDER", value),
+        None => std::env::remove_var("DETERMINISTIC_CELL_ORDER"),
+    }
+    let (first, second) = (first?, second?);
+    if first.tx.hash().as_slice() != second.tx.hash().as_slice() {
+        return Err(anyhow!("Two builds over identical state produced different transactions"));
+    }
+    let fee_inputs: Vec<(OutPoint, u64)> = first.tx.inputs().into_iter().skip(1)
+        .map(|input| (input.previous_output(), 0))
+        .collect();
+    let mut sorted = fee_inputs.clone();
+    sort_by_outpoint(&mut sorted);
+    if sorted.iter().map(|(o, _)| o.as_slice()).ne(fee_inputs.iter().map(|(o, _)| o.as_slice())) {
+        return Err(anyhow!("Fee inputs are not sorted by outpoint"));
+    }
+    let first_hash: H256 = first.tx.hash().unpack();
+    info!("Both builds hash to {:#x}", first_hash);
+
+    info!("=== Step 20: Unknown Request Fields Are Rejected ===");
+    let typo = br#"{"amont": 10}"#;
+    let err = parse_request::<MintRequest>(typo).err()
+        .ok_or_else(|| anyhow!("Mint request with a misspelled field was accepted"))?;
+    if !err.0.contains("amont") {
+        return Err(anyhow!("Rejection does not name the offending field: {}", err));
+    }
+    let response = ApiError(err.into()).into_response();
+    if response.status() != StatusCode::BAD_REQUEST
+        || response.headers().get("x-error-code").and_then(|value| value.to_str().ok()) != Some(INVALID_REQUEST_CODE)
+    {
+        return Err(anyhow!("Unknown field responded {}", response.status()));
+    }
+    if parse_request::<ResolveRequest>(br#"{"outcome": true, "oucome": false}"#).is_ok()
+        || parse_request::<ClaimRequest>(br#"{"amount": 1, "market": "0x00"}"#).is_ok()
+    {
+        return Err(anyhow!("Resolve/claim request with an unknown field was accepted"));
+    }
+    if parse_request::<MintRequest>(br#"{"amount": 10}"#)?.amount != 10 {
+        return Err(anyhow!("Well-formed mint request did not parse"));
+    }
+    info!("{{\"amont\": 10}} -> 400 {}", INVALID_REQUEST_CODE);
+
+    info!("=== Step 21: Market Capacity Is The Occupied Minimum Plus A Buffer ===");
+    for buffer in [0, ckb(7)] {
+        let built = build_create_market_tx(&mut client, &contracts, &lock_script, buffer, DEFAULT_SHANNONS_PER_TOKEN, 0, None, None, None)?;
+        let market_output = built.tx.output(0).ok_or_else(|| anyhow!("Creation has no market output"))?;
+        let market_data_len = built.tx.outputs_data().get(0).map(|data| data.raw_data().len()).unwrap_or(0);
+        let minimum = occupied_capacity(&market_output, market_data_len)?;
+        let capacity: u64 = market_output.capacity().unpack();
+        if minimum != ckb(232) || capacity != minimum + buffer {
+            return Err(anyhow!("Buffer {} built a {} shannon market over a {} minimum", buffer, capacity, minimum));
+        }
+    }
+    info!("Market cell holds 232 CKB occupied plus exactly the requested buffer");
+
+    info!("=== Step 22: Burn Complete Sets Before Resolution ===");
+    let burn_market = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0)?;
+    let base_capacity = get_cell(&mut client, &burn_market)?.capacity;
+    let burn_market = mint_tokens(&mut client, &privkey, &contracts, &lock_script, burn_market, 3)?;
+    let burn_market = burn_tokens(&mut client, &privkey, &contracts, &lock_script, burn_market, 2)?;
+    let burned = get_cell(&mut client, &burn_market)?;
+    let burned_data = MarketData::from_bytes(&burned.data)?;
+    if burned.capacity != base_capacity + ckb(100) || burned_data.yes_supply() != 1 || burned_data.no_supply() != 1 {
+        return Err(anyhow!("Burning 2 of 3 sets left {} shannons and {} YES / {} NO supply",
+                           burned.capacity, burned_data.yes_supply(), burned_data.no_supply()));
+    }
+    let yes_cell = (burn_market.clone(), ckb(143), 5);
+    for (yes, no, side) in [(Some(yes_cell.clone()), None, "no NO"), (None, Some(yes_cell.clone()), "no YES")] {
+        match complete_set_cells(yes, no, 1) {
+            Err(err) if err.to_string().contains("Need complete sets") && err.to_string().contains(side) => {}
+            other => return Err(anyhow!("Holding one side only was not refused clearly: {:?}", other.map(|_| ()))),
+        }
+    }
+    if complete_set_cells(Some(yes_cell.clone()), Some(yes_cell), 6).is_ok() {
+        return Err(anyhow!("Burning more sets than held was not refused"));
+    }
+    info!("Burned 2 complete sets for 200 CKB; one-sided holdings are refused");
+
+    info!("=== Step 23: Transaction JSON Round-Trips ===");
+    let inspected = build_mint_tx(&mut client, &contracts, &lock_script, open_market.clone(), 1)?;
+    let json = serde_json::to_string(&transaction_json(&inspected.tx))?;
+    let parsed: ckb_jsonrpc_types::TransactionView = serde_json::from_str(&json)?;
+    let rebuilt = ckb_types::packed::Transaction::from(parsed.inner).into_view();
+    let inspected_hash: H256 = inspected.tx.hash().unpack();
+    if rebuilt.data().as_slice() != inspected.tx.data().as_slice() || parsed.hash != inspected_hash {
+        return Err(anyhow!("Transaction JSON did not round-trip to {:#x}", inspected_hash));
+    }
+    info!("Transaction JSON ({} bytes) parses back to {:#x}", json.len(), inspected_hash);
+
+    info!("=== Step 24: Transfer Tokens To Another Address ===");
+    let recipient = Script::new_builder()
+        .code_hash(SIGHASH_TYPE_HASH.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(Bytes::from(vec![0x42u8; 20]).pack())
+        .build();
+    let recipient_address = encode_address(&recipient, contracts.network);
+    if parse_address(&recipient_address, contracts.network)?.as_slice() != recipient.as_slice() {
+        return Err(anyhow!("Address {} does not decode to the recipient lock", recipient_address));
+    }
+    let burn_cell = get_cell(&mut client, &burn_market)?;
+    let token_hash_type = MarketData::from_bytes(&burn_cell.data)?.token_script_hash_type()?;
+    let yes_type = build_token_type(&contracts, &burn_cell.market_type()?, token_hash_type, true);
+    let (_, _, held) = find_token_cell(&mut client, &lock_script, &yes_type)?;
+    let balance: u128 = find_token_cells(&mut client, &lock_script, &yes_type, u128::MAX)?.iter()
+        .map(|(_, _, amount)| amount)
+        .sum();
+    if build_transfer_tx(&mut client, &contracts, &lock_script, &burn_market, true, balance + 1, &recipient).is_ok() {
+        return Err(anyhow!("Transfer of more than the balance was not refused"));
+    }
+    let token_outputs = |tx: &ckb_jsonrpc_types::TransactionView| -> Vec<(Script, u128)> {
+        tx.inner.outputs.iter().zip(&tx.inner.outputs_data)
+            .filter(|(output, _)| output.type_.is_some())
+            .map(|(output, data)| {
+                let amount = data.as_bytes().get(..16)
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .map(u128::from_le_bytes)
+                    .unwrap_or(0);
+                (Script::from(output.lock.clone()), amount)
+            })
+            .collect()
+    };
+    if held > 1 {
+        let partial = transfer_tokens(&mut client, &privkey, &contracts, &lock_script, &burn_market, true, 1, &recipient, SendMode::Wait)?;
+        let outputs = token_outputs(&get_transaction_view(&mut client, partial)?);
+        if outputs.len() != 2 || outputs[0].0.as_slice() != recipient.as_slice() || outputs[0].1 != 1
+            || outputs[1].0.as_slice() != lock_script.as_slice() || outputs[1].1 != held - 1
+        {
+            return Err(anyhow!("Partial transfer produced token cells {:?}", outputs));
+        }
+    }
+    let (_, _, held) = find_token_cell(&mut client, &lock_script, &yes_type)?;
+    let full = transfer_tokens(&mut client, &privkey, &contracts, &lock_script, &burn_market, true, held, &recipient, SendMode::Wait)?;
+    let outputs = token_outputs(&get_transaction_view(&mut client, full)?);
+    if outputs.len() != 1 || outputs[0].0.as_slice() != recipient.as_slice() || outputs[0].1 != held {
+        return Err(anyhow!("Whole-cell transfer produced token cells {:?}", outputs));
+    }
+    info!("Sent YES tokens to {}; a whole-cell transfer leaves no change token cell", recipient_address);
+
+    info!("=== Step 25: Drain And Recreate A Market ===");
+    let mut admin_headers = HeaderMap::new();
+    admin_headers.insert("x-admin-token", "devnet-secret".parse()?);
+    if check_admin(None, &admin_headers).is_ok()
+        || check_admin(Some("other-secret"), &admin_headers).is_ok()
+        || check_admin(Some("devnet-secret"), &HeaderMap::new()).is_ok()
+    {
+        return Err(anyhow!("Admin check let through a request without the configured token"));
+    }
+    check_admin(Some("devnet-secret"), &admin_headers)?;
+    if ensure_not_mainnet("ckb").is_ok() {
+        return Err(anyhow!("Reset was not refused on mainnet"));
+    }
+    ensure_not_mainnet(&client.get_blockchain_info()?.chain)?;
+    let used_market = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0)?;
+    let used_market = mint_tokens(&mut client, &privkey, &contracts, &lock_script, used_market, 2)?;
+    // Draining an open market proposes cancelling it; the finalize and
+    // refunds wait for a drain after the delay (covered by the mock node)
+    let (drained, closed_market) = drain_market(&mut client, &privkey, &contracts, &lock_script, used_market)?;
+    let closed = MarketData::from_bytes(&get_cell(&mut client, &closed_market)?.data)?;
+    if drained.len() != 1 || closed.pending_outcome != Some(Proposal::Cancel) || closed.resolved {
+        return Err(anyhow!("Drain sent {} transactions and left the market {:?}", drained.len(), closed));
+    }
+    let (redrained, still_closing) = drain_market(&mut client, &privkey, &contracts, &lock_script, closed_market.clone())?;
+    if !redrained.is_empty() || still_closing.as_slice() != closed_market.as_slice() {
+        return Err(anyhow!("Draining again within the delay sent {} transactions", redrained.len()));
+    }
+    let fresh_market = create_market(&mut client, &privkey, &contracts, &lock_script, 0, DEFAULT_SHANNONS_PER_TOKEN, 0)?;
+    let fresh = get_cell(&mut client, &fresh_market)?;
+    let fresh_data = MarketData::from_bytes(&fresh.data)?;
+    if fresh_market.as_slice() == closed_market.as_slice() || fresh_data.resolved
+        || fresh_data.yes_supply() != 0 || fresh_data.no_supply() != 0 || fresh.collateral() != 0
+    {
+        return Err(anyhow!("Recreated market is not new and empty: {:?}, {} shannons", fresh_data, fresh.capacity));
+    }
+    info!("Draining proposed cancelling the old market; the new market holds no tokens or collateral");
+
+    info!("=== Step 26: Per-Market Collateral Unit ===");
+    let unit = ckb(5);
+    let cheap_market = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, unit, 0)?;
+    let base_capacity = get_cell(&mut client, &cheap_market)?.capacity;
+    let cheap_market = mint_tokens(&mut client, &privkey, &contracts, &lock_script, cheap_market, 3)?;
+    let minted = get_cell(&mut client, &cheap_market)?;
+    if minted.capacity != base_capacity + 3 * unit || MarketData::from_bytes(&minted.data)?.shannons_per_token != unit {
+        return Err(anyhow!("Minting 3 sets at {} shannons each left the market at {} shannons", unit, minted.capacity));
+    }
+    let cheap_market = burn_tokens(&mut client, &privkey, &contracts, &lock_script, cheap_market, 1)?;
+    let burned = get_cell(&mut client, &cheap_market)?;
+    if burned.capacity != base_capacity + 2 * unit {
+        return Err(anyhow!("Burning 1 set did not refund exactly {} shannons", unit));
+    }
+    // The same mint, with the output market repriced to the default unit
+    let mint = build_mint_tx(&mut client, &contracts, &lock_script, cheap_market, 1)?;
+    let resolved_inputs = resolve_inputs(&mut client, &mint.tx)?;
+    let mut outputs_data: Vec<ckb_types::packed::Bytes> = mint.tx.outputs_data().into_iter().collect();
+    let repriced = MarketData {
+        shannons_per_token: DEFAULT_SHANNONS_PER_TOKEN,
+        ..MarketData::from_bytes(&outputs_data[0].raw_data())?
+    };
+    outputs_data[0] = Bytes::from(repriced.to_bytes()).pack();
+    let repriced = mint.tx.as_advanced_builder().set_outputs_data(outputs_data).build();
+    let verdict = validate_market_tx(&contracts, &repriced, &resolved_inputs);
+    if verdict != Err(MarketError::InvalidMarketData) {
+        return Err(anyhow!("Repricing a market was judged {:?}", verdict));
+    }
+    if build_create_market_tx(&mut client, &contracts, &lock_script, 0, 0, 0, None, None, None).is_ok() {
+        return Err(anyhow!("A market with a zero collateral unit was built"));
+    }
+    info!("Market priced at 5 CKB per set mints and burns at that price; repricing and a zero unit are refused");
+
+    info!("=== Step 27: Wallet Lock Derivation ===");
+    let address_lock = Script::from(&ckb_sdk::AddressPayload::from_pubkey(&pubkey));
+    if wallet_lock(&pubkey, PubkeyFormat::Compressed)?.as_slice() != address_lock.as_slice() {
+        return Err(anyhow!("Compressed-key lock differs from ckb-sdk's address lock"));
+    }
+    if wallet_lock(&pubkey, PubkeyFormat::Uncompressed)?.as_slice() == address_lock.as_slice() {
+        return Err(anyhow!("Uncompressed-key lock hashed the compressed key"));
+    }
+    check_wallet_visible(wallet_capacity(&mut client, &lock_script)?, wallet_capacity(&mut client, &address_lock)?)?;
+    check_wallet_visible(0, 0)?;
+    if check_wallet_visible(0, ckb(100)).is_ok() {
+        return Err(anyhow!("An empty derived lock next to a funded address was not refused"));
+    }
+    info!("Wallet lock matches ckb-sdk's address; a lock that can't see the funds is refused");
+
+    info!("=== Step 28: Mint Funded From Supplied Inputs ===");
+    let user_lock = lock_script.clone().as_builder()
+        .args(Bytes::from(vec![0x11u8; 20]).pack())
+        .build();
+    let supplied = collect_cells(&mut client, &lock_script, ckb(500))?;
+    let funding = Funding::Supplied { inputs: supplied.clone(), change_lock: user_lock.clone() };
+    let mint = build_funded_mint_tx(&mut client, &contracts, &lock_script, fresh_market.clone(), 2, &funding)?;
+    let funding_inputs: Vec<OutPoint> = mint.tx.inputs().into_iter().skip(1).map(|input| input.previous_output()).collect();
+    if funding_inputs.iter().map(|outpoint| outpoint.as_slice()).ne(supplied.iter().map(|(outpoint, _)| outpoint.as_slice())) {
+        return Err(anyhow!("Mint did not spend exactly the supplied inputs: {:?}", funding_inputs));
+    }
+    if mint.tx.outputs().into_iter().skip(1).any(|output| output.lock().as_slice() != user_lock.as_slice()) {
+        return Err(anyhow!("Tokens or change of a user-funded mint left the user's lock"));
+    }
+    let resolved_inputs = resolve_inputs(&mut client, &mint.tx)?;
+    validate_market_tx(&contracts, &mint.tx, &resolved_inputs)
+        .map_err(|err| anyhow!("User-funded mint failed validation: {:?}", err))?;
+    let unfunded = Funding::Supplied { inputs: Vec::new(), change_lock: user_lock };
+    match build_funded_mint_tx(&mut client, &contracts, &lock_script, fresh_market, 2, &unfunded) {
+        Err(err) if err.downcast_ref::<InsufficientFunds>().is_some() => {}
+        other => return Err(anyhow!("An unfunded supplied mint was not refused: {:?}", other.map(|built| built.tx.hash()))),
+    }
+    info!("Mint spends only the supplied cells and pays tokens and change to the supplier (not broadcast)");
+
+    info!("=== Step 29: Token Balance Spread Across Cells ===");
+    let outpoint = |index: u32| OutPoint::new_builder().tx_hash(H256([0x33; 32]).pack()).index(index.pack()).build();
+    let holding = |index: u32, amount: u128| (outpoint(index), ckb(143), Some(Bytes::from(amount.to_le_bytes().to_vec())));
+    let picked = |cells: Vec<(OutPoint, u64, u128)>| -> Vec<u32> {
+        cells.iter().map(|(outpoint, _, _)| outpoint.index().unpack()).collect()
+    };
+    let wallet = || [holding(0, 2), (outpoint(1), ckb(143), None), holding(2, 5), holding(3, 4)];
+    if picked(select_token_cells(wallet(), 5)) != [2] {
+        return Err(anyhow!("A single cell holding enough was not used alone"));
+    }
+    if picked(select_token_cells(wallet(), 6)) != [0, 2] || picked(select_token_cells(wallet(), 12)) != [0, 2, 3] {
+        return Err(anyhow!("Cells were not aggregated in order up to the amount"));
+    }
+    // A fresh mint adds a YES cell next to those left by earlier steps; send
+    // the whole balance in one transfer
+    let burn_market = mint_tokens(&mut client, &privkey, &contracts, &lock_script, burn_market, 3)?;
+    let cells = find_token_cells(&mut client, &lock_script, &yes_type, u128::MAX)?;
+    if cells.len() < 2 {
+        return Err(anyhow!("Expected YES spread over several cells, found {}", cells.len()));
+    }
+    let balance: u128 = cells.iter().map(|(_, _, amount)| amount).sum();
+    let sweep_all = build_transfer_tx(&mut client, &contracts, &lock_script, &burn_market, true, balance, &recipient)?;
+    let token_inputs = sweep_all.tx.inputs().into_iter().take(cells.len())
+        .map(|input| input.previous_output())
+        .collect::<Vec<_>>();
+    let sent: Vec<u128> = sweep_all.tx.outputs().into_iter().zip(sweep_all.tx.outputs_data())
+        .filter(|(output, _)| output.type_().is_some())
+        .map(|(_, data)| u128::from_le_bytes(data.raw_data()[..16].try_into().unwrap_or_default()))
+        .collect();
+    if token_inputs.iter().map(|outpoint| outpoint.as_slice()).ne(cells.iter().map(|(outpoint, _, _)| outpoint.as_slice()))
+        || sent != [balance]
+    {
+        return Err(anyhow!("Transfer of {} YES across {} cells sent {:?}", balance, cells.len(), sent));
+    }
+    info!("A {}-token balance in {} cells transfers in one transaction (not broadcast)", balance, cells.len());
+
+    info!("=== Step 30: Confirmation Timeout ===");
+    let scripted = |statuses: Vec<Confirmation>| {
+        let mut statuses = statuses.into_iter();
+        move || Ok(statuses.next().unwrap_or(Confirmation::Waiting))
+    };
+    let tx_hash = H256([0x34; 32]);
+    wait_for_commit(&tx_hash, 3, Duration::ZERO,
+                    scripted(vec![Confirmation::Waiting, Confirmation::Waiting, Confirmation::Committed]))?;
+    let stuck = wait_for_commit(&tx_hash, 5, Duration::ZERO, scripted(Vec::new()));
+    if !stuck.as_ref().is_err_and(|err| err.to_string().contains("not committed after 5 attempts")) {
+        return Err(anyhow!("A never-committed transaction did not time out: {:?}", stuck));
+    }
+    let mut polls = 0;
+    let rejected = wait_for_commit(&tx_hash, 30, Duration::ZERO, || {
+        polls += 1;
+        Ok(Confirmation::Rejected("Resolve failed Dead".to_string()))
+    });
+    if polls != 1 || !rejected.as_ref().is_err_and(|err| err.to_string().contains("Resolve failed Dead")) {
+        return Err(anyhow!("Rejection surfaced after {} polls as {:?}", polls, rejected));
+    }
+    info!("Stuck transactions time out and rejections fail on the first poll with the node's reason");
+
+    info!("=== Step 31: Node Configuration ===");
+    let env = |vars: &'static [(&'static str, &'static str)]| {
+        move |name: &str| vars.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string())
+    };
+    let other_key = "0x".to_string() + &"11".repeat(32);
+    let defaults = resolve_node_config(env(&[]), None)?;
+    if defaults.rpc_url != DEVNET_RPC || defaults.privkey.secret_bytes() != hex::decode(PRIVKEY)?.as_slice() {
+        return Err(anyhow!("Defaults are not the devnet node and account: {}", defaults.rpc_url));
+    }
+    let file = format!("rpc_url = \"http://staging:8114\"\nprivkey = \"{}\"\n", other_key);
+    let from_file = resolve_node_config(env(&[]), Some(&file))?;
+    let from_env = resolve_node_config(env(&[("CKB_RPC_URL", "http://custom:8114"), ("CKB_PRIVKEY", PRIVKEY)]), Some(&file))?;
+    if from_file.rpc_url != "http://staging:8114" || from_file.privkey.secret_bytes() != [0x11; 32]
+        || from_env.rpc_url != "http://custom:8114" || from_env.privkey.secret_bytes() != defaults.privkey.secret_bytes()
+    {
+        return Err(anyhow!("Environment should override config.toml, which overrides the defaults"));
+    }
+    for bad_key in ["not hex", "0x1234", "00".repeat(32).as_str()] {
+        let file = format!("privkey = \"{}\"\n", bad_key);
+        match resolve_node_config(env(&[]), Some(&file)) {
+            Err(err) if err.to_string().contains("privkey in config.toml is not a valid") => {}
+            other => return Err(anyhow!("Key {:?} was not refused clearly: {:?}", bad_key, other.map(|config| config.rpc_url))),
+        }
+    }
+    if resolve_node_config(env(&[]), Some("rpc_urll = \"typo\"")).is_ok() {
+        return Err(anyhow!("An unknown config.toml key was accepted"));
+    }
+    if defaults.send_mode != SendMode::Wait
+        || resolve_node_config(env(&[("BROADCAST_ONLY", "1")]), None)?.send_mode != SendMode::BroadcastOnly
+    {
+        return Err(anyhow!("BROADCAST_ONLY=1 did not switch the server to broadcast-only sends"));
+    }
+    info!("Environment overrides config.toml overrides the devnet defaults; bad keys are refused");
+
+    info!("=== Step 32: Network Selection ===");
+    for (name, network) in [("dev", Network::Dev), ("testnet", Network::Test), ("Main", Network::Main)] {
+        if Network::from_name(name)? != network {
+            return Err(anyhow!("Network name {:?} did not select {:?}", name, network));
+        }
+    }
+    if Network::from_name("staging").is_ok() {
+        return Err(anyhow!("An unknown network name was accepted"));
+    }
+    let dep_groups: Vec<H256> = [Network::Dev, Network::Test, Network::Main].iter()
+        .map(|network| network.secp256k1_dep_group().tx_hash().unpack())
+        .collect();
+    let deps_tx_hash: H256 = build_cell_deps(&contracts)[0].out_point().tx_hash().unpack();
+    if dep_groups[0] == dep_groups[1] || dep_groups[1] == dep_groups[2] || deps_tx_hash != dep_groups[0] {
+        return Err(anyhow!("Cell deps do not follow the network's secp256k1 dep group"));
+    }
+    let mainnet_address = encode_address(&recipient, Network::Main);
+    let testnet_address = encode_address(&recipient, Network::Test);
+    if !mainnet_address.starts_with("ckb1") || !testnet_address.starts_with("ckt1") {
+        return Err(anyhow!("Unexpected address prefixes: {} / {}", mainnet_address, testnet_address));
+    }
+    if parse_address(&mainnet_address, Network::Dev).is_ok() || parse_address(&testnet_address, Network::Main).is_ok() {
+        return Err(anyhow!("An address for another network was accepted"));
+    }
+    if parse_address(&mainnet_address, Network::Main)?.as_slice() != recipient.as_slice() {
+        return Err(anyhow!("Mainnet address did not decode to the recipient lock"));
+    }
+    if !Network::Dev.matches_chain(&client.get_blockchain_info()?.chain) || Network::Main.matches_chain("ckb_testnet") {
+        return Err(anyhow!("Chain names are not matched to networks"));
+    }
+    info!("Networks select their dep group and address prefix; foreign addresses are refused");
+
+    info!("=== Step 33: Token Balance ===");
+    let yes_held: u128 = find_token_cells(&mut client, &lock_script, &yes_type, u128::MAX)?.iter()
+        .map(|(_, _, amount)| amount)
+        .sum();
+    let balance = token_balance(&mut client, &contracts, &burn_market, &lock_script)?;
+    if balance.yes != yes_held.to_string() || balance.yes_cells < 2 {
+        return Err(anyhow!("Balance reports {} YES in {} cells, wallet holds {}", balance.yes, balance.yes_cells, yes_held));
+    }
+    let stranger = lock_script.clone().as_builder()
+        .args(Bytes::from(vec![0x37u8; 20]).pack())
+        .build();
+    let empty = token_balance(&mut client, &contracts, &burn_market, &stranger)?;
+    if (empty.yes.as_str(), empty.no.as_str(), empty.yes_cells + empty.no_cells) != ("0", "0", 0) {
+        return Err(anyhow!("A lock without tokens reported {} YES / {} NO", empty.yes, empty.no));
+    }
+    info!("Server lock holds {} YES in {} cells and {} NO in {} cells", balance.yes, balance.yes_cells, balance.no, balance.no_cells);
+
+    info!("=== Step 34: Fee From Transaction Size ===");
+    let sized = build_mint_tx(&mut client, &contracts, &lock_script, burn_market.clone(), 1)?;
+    let signed = sized.signer.sign(sized.tx.clone(), &privkey)?;
+    let sized_inputs: u64 = resolve_inputs(&mut client, &sized.tx)?.iter()
+        .map(|(output, _)| -> u64 { output.capacity().unpack() })
+        .sum();
+    let sized_fee = estimate_fee(&signed, fee_rate());
+    verify_fee_margin(sized_inputs, &signed, sized_fee, 0)?;
+    let size = signed.data().serialized_size_in_block() as u64;
+    if estimate_fee(&signed, 2000) != size * 2 || estimate_fee(&signed, 1) != size.div_ceil(1000) {
+        return Err(anyhow!("Fee of a {}-byte transaction does not follow the rate", size));
+    }
+    info!("Signed {}-byte mint pays {} shannons at {} shannons/KB (not broadcast)", size, sized_fee, fee_rate());
+
+    info!("=== Step 35: Market Discovery ===");
+    let listed = list_markets(&mut client, &contracts)?;
+    let burn_market_hash: H256 = burn_market.tx_hash().unpack();
+    let id = format!("{:#x}:0", burn_market_hash);
+    let found = listed.iter().find(|summary| summary.market_id == id)
+        .ok_or_else(|| anyhow!("Market {} missing from the on-chain listing", id))?;
+    let burn_cell = get_cell(&mut client, &burn_market)?;
+    let burn_data = MarketData::from_bytes(&burn_cell.data)?;
+    if found.capacity != burn_cell.capacity || found.yes_supply != burn_data.yes_supply().to_string() {
+        return Err(anyhow!("Listing of {} does not match the cell: {:?}", id, found));
+    }
+    if listed.iter().any(|summary| parse_market_id(&summary.market_id).is_err()) {
+        return Err(anyhow!("A listed market id is not accepted back as a market id"));
+    }
+    info!("Found {} live markets on-chain", listed.len());
+
+    info!("=== Step 36: Market Persistence ===");
+    let state_file = std::env::temp_dir().join(format!("market-state-{}.json", std::process::id()));
+    let state_file = state_file.to_str().ok_or_else(|| anyhow!("Temp path is not UTF-8"))?;
+    let market_type_hash = build_market_type(&contracts).calc_script_hash();
+    save_market_state(state_file, &market_type_hash, &burn_market)?;
+    let saved = load_market_state(state_file)?.ok_or_else(|| anyhow!("Saved market state was not read back"))?;
+    // Advance the market after saving, as if the server had been down meanwhile
+    let advanced = mint_tokens(&mut client, &privkey, &contracts, &lock_script, burn_market, 1)?;
+    let restored = restore_market(&mut client, &contracts, &saved)?;
+    if restored.as_ref().map(|outpoint| outpoint.as_slice()) != Some(advanced.as_slice()) {
+        return Err(anyhow!("Restart from the saved market did not find its live cell: {:?}", restored));
+    }
+    let foreign = SavedMarket { market_type_hash: format!("{:#x}", H256([0x61; 32])), ..saved };
+    if restore_market(&mut client, &contracts, &foreign)?.is_some() {
+        return Err(anyhow!("A market saved by another deployment was restored"));
+    }
+    std::fs::remove_file(state_file)?;
+    if load_market_state(state_file)?.is_some() {
+        return Err(anyhow!("A missing state file did not read as no market"));
+    }
+    info!("Saved market followed to its live cell after being spent");
+
+    info!("=== Step 37: Client Errors Are Not 500 ===");
+    let responded = |result: Result<BuiltTx>| -> Result<(StatusCode, String)> {
+        let err = result.err().ok_or_else(|| anyhow!("Expected the request to fail"))?;
+        let response = ApiError(err).into_response();
+        let code = response.headers().get("x-error-code").and_then(|value| value.to_str().ok()).unwrap_or_default().to_string();
+        Ok((response.status(), code))
+    };
+    let unknown_market = OutPoint::new_builder().tx_hash(H256([0x42; 32]).pack()).index(0u32.pack()).build();
+    let cases = [
+        ("claim on an open market", build_claim_tx(&mut client, &contracts, &lock_script, advanced.clone(), 1), StatusCode::BAD_REQUEST, REJECTED_CODE),
+        ("transfer beyond the balance", build_transfer_tx(&mut client, &contracts, &lock_script, &advanced, true, u128::MAX, &recipient), StatusCode::BAD_REQUEST, REJECTED_CODE),
+        ("mint on a market that doesn't exist", build_mint_tx(&mut client, &contracts, &lock_script, unknown_market, 1), StatusCode::NOT_FOUND, NOT_FOUND_CODE),
+    ];
+    for (case, result, status, code) in cases {
+        let (got_status, got_code) = responded(result)?;
+        if (got_status, got_code.as_str()) != (status, code) {
+            return Err(anyhow!("A {} responded {} with code {:?}, expected {}", case, got_status, got_code, status));
+        }
+    }
+    if !matches!(parse_market_id("0x12:x"), Err(err) if err.downcast_ref::<InvalidRequest>().is_some()) {
+        return Err(anyhow!("A malformed market id is not an invalid request"));
+    }
+    info!("Market state and balance errors are 400, missing markets 404");
+
+    info!("=== Step 38: Transaction Status ===");
+    let committed = tx_status(&mut client, &advanced.tx_hash().unpack())?;
+    if committed.status != "committed" || committed.block_number.is_none() || committed.reason.is_some() {
+        return Err(anyhow!("Committed mint reported as {:?}", committed));
+    }
+    match tx_status(&mut client, &H256([0x43; 32])) {
+        Err(err) if err.downcast_ref::<NotFound>().is_some() => {}
+        other => return Err(anyhow!("An unknown transaction was not NotFound: {:?}", other.map(|status| status.status))),
+    }
+    info!("Mint {} committed in block {:?}", committed.tx_hash, committed.block_number);
+
+    info!("=== Step 39: Resolution Window ===");
+    let chain_time = median_time_secs(&mut client)?;
+    let waiting = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, chain_time + 86_400)?;
+    match propose_resolution(&mut client, &privkey, &contracts, &lock_script, waiting, Settlement::Outcome(true)) {
+        Err(err) if err.downcast_ref::<Rejected>().is_some() => {}
+        other => return Err(anyhow!("Resolving a day before the window opened was not rejected: {:?}", other.map(|_| ()))),
+    }
+    let windowed = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, chain_time)?;
+    let proposed = propose_resolution(&mut client, &privkey, &contracts, &lock_script, windowed, Settlement::Outcome(true))?;
+    let propose_tx = get_transaction_view(&mut client, proposed.tx_hash().unpack())?;
+    let since: u64 = propose_tx.inner.inputs[0].since.into();
+    if since != SINCE_ABSOLUTE_TIMESTAMP | chain_time {
+        return Err(anyhow!("The proposal spent the market with since {:#x}, expected {:#x}", since, SINCE_ABSOLUTE_TIMESTAMP | chain_time));
+    }
+    info!("Early resolution refused, the proposal at {} went through with since {:#x}", chain_time, since);
+
+    info!("=== Step 40: Dispute Period ===");
+    // Server markets have no resolver, so dispute periods come from markets
+    // finalized elsewhere; check the payout gate on such market data
+    let tip = client.get_tip_block_number()?.value();
+    let proposed_data = MarketData::from_bytes(&get_cell(&mut client, &proposed)?.data)?;
+    let resolved_data = MarketData { resolved: true, outcome: true, pending_outcome: None, ..proposed_data };
+    if payout_since(&mut client, &resolved_data)? != 0 {
+        return Err(anyhow!("A market without a dispute period needed a since"));
+    }
+    let disputed = MarketData { dispute_until: tip + 100, ..resolved_data.clone() };
+    match payout_since(&mut client, &disputed) {
+        Err(err) if err.downcast_ref::<Rejected>().is_some() => {}
+        other => return Err(anyhow!("A claim 100 blocks into the dispute period was not rejected: {:?}", other)),
+    }
+    let settled = MarketData { dispute_until: tip, ..resolved_data };
+    if payout_since(&mut client, &settled)? != tip {
+        return Err(anyhow!("A claim after the dispute period did not carry its since"));
+    }
+    info!("Claims wait for dispute_until and then spend the market with since = dispute_until");
+
+    info!("=== Step 41: Redeem Complete Sets After Resolution ===");
+    // Redeeming needs a finalized market; the mock-node tests redeem and claim
+    let redeem_market = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0)?;
+    let redeem_market = mint_tokens(&mut client, &privkey, &contracts, &lock_script, redeem_market, 3)?;
+    if redeem_sets(&mut client, &privkey, &contracts, &lock_script, redeem_market.clone(), 1).is_ok() {
+        return Err(anyhow!("Redeeming sets on an open market was not rejected"));
+    }
+    let redeem_market = propose_resolution(&mut client, &privkey, &contracts, &lock_script, redeem_market, Settlement::Outcome(true))?;
+    if redeem_sets(&mut client, &privkey, &contracts, &lock_script, redeem_market, 1).is_ok() {
+        return Err(anyhow!("Redeeming sets on a proposed market was not rejected"));
+    }
+    info!("Sets are only redeemed once the market is resolved");
+
+    info!("=== Step 42: Dry Run ===");
+    let dry_market = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0)?;
+    let built = build_mint_tx(&mut client, &contracts, &lock_script, dry_market, 2)?;
+    let signed = sign_market_tx(&mut client, &privkey, &contracts, &built)?;
+    let signed_hash: H256 = signed.hash().unpack();
+    if signed_hash != built.tx.hash().unpack() || signed.witnesses().is_empty() {
+        return Err(anyhow!("Dry run returned {:#x}, built {:#x}", signed_hash, built.tx.hash()));
+    }
+    match tx_status(&mut client, &signed_hash) {
+        Err(err) if err.downcast_ref::<NotFound>().is_some() => {}
+        other => return Err(anyhow!("Dry run reached the node: {:?}", other.map(|status| status.status))),
+    }
+    // The returned transaction is complete: broadcasting it later works as is
+    send_transaction(&mut client, &signed, SendMode::Wait)?;
+    info!("Dry-run mint {:#x} stayed off-chain until sent by hand", signed_hash);
+
+    info!("=== Step 43: Capacity Ceiling ===");
+    let ceiling_market = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0)?;
+    let ceiling_data = MarketData::from_bytes(&get_cell(&mut client, &ceiling_market)?.data)?;
+    let max_sets = (u64::MAX / ceiling_data.shannons_per_token) as u128;
+    ceiling_data.checked_collateral(max_sets)?;
+    match build_mint_tx(&mut client, &contracts, &lock_script, ceiling_market.clone(), max_sets + 1) {
+        Err(err) if err.downcast_ref::<Rejected>().is_some() => {}
+        other => return Err(anyhow!("Minting {} sets was not rejected: {:?}", max_sets + 1, other.map(|_| ()))),
+    }
+    info!("Up to {} sets fit a capacity, {} were refused before building", max_sets, max_sets + 1);
+
+    info!("=== Step 44: Quote ===");
+    let quote = quote_operation(&mut client, &contracts, &lock_script, &ceiling_market, "mint", 10)?;
+    let built = build_mint_tx(&mut client, &contracts, &lock_script, ceiling_market.clone(), 10)?;
+    let market_capacity = get_cell(&mut client, &ceiling_market)?.capacity;
+    let outputs: Vec<u64> = built.tx.outputs().into_iter().map(|output| output.capacity().unpack()).collect();
+    if Shannons(outputs[0] - market_capacity) != quote.collateral || Shannons(outputs[1] + outputs[2]) != quote.token_cell_capacity {
+        return Err(anyhow!("Mint quote {:?} does not match the built outputs {:?}", quote, outputs));
+    }
+    info!(collateral = %quote.collateral, token_cells = %quote.token_cell_capacity,
+          total_required = %quote.total_required, "Mint quote matches the built transaction");
+
+    info!("=== Step 45: Market Already Consumed ===");
+    let moved_on = mint_tokens(&mut client, &privkey, &contracts, &lock_script, ceiling_market.clone(), 1)?;
+    match build_mint_tx(&mut client, &contracts, &lock_script, ceiling_market.clone(), 1) {
+        Err(err) if err.downcast_ref::<MarketConflict>().is_some() => {}
+        other => return Err(anyhow!("Minting on a spent market was not a conflict: {:?}", other.map(|_| ()))),
+    }
+    let followed = follow_market(&mut client, &contracts, ceiling_market)?;
+    if followed.as_ref().map(|outpoint| outpoint.as_slice()) != Some(moved_on.as_slice()) {
+        return Err(anyhow!("Spent market was not followed to {}", format_market_id(&moved_on)));
+    }
+    info!(market = %format_market_id(&moved_on), "Spent market cell refused with a conflict and followed to its successor");
+
+    info!("=== Step 46: Market Metadata ===");
+    let labels = ["No".to_string(), "Yes".to_string()];
+    let metadata = market_metadata(Some("Will the devnet pass block 1,000,000?"), Some(&labels))?;
+    let built = build_create_market_tx(&mut client, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0, None, None, metadata)?;
+    let described = submit_market_tx(&mut client, &privkey, &contracts, "create-market", &built, SendMode::Wait)?;
+    let described_id = format_market_id(&described);
+    let summary = list_markets(&mut client, &contracts)?.into_iter()
+        .find(|summary| summary.market_id == described_id)
+        .ok_or_else(|| anyhow!("Market {} is not listed", described_id))?;
+    if summary.question.as_deref() != Some("Will the devnet pass block 1,000,000?") || summary.outcome_labels.as_deref() != Some(&labels[..]) {
+        return Err(anyhow!("Listed metadata {:?} / {:?} does not match the request", summary.question, summary.outcome_labels));
+    }
+    if market_metadata(None, Some(&labels[..1])).is_ok() {
+        return Err(anyhow!("A single outcome label was accepted"));
+    }
+    // Minting rewrites the data but has to carry the metadata along
+    let minted = mint_tokens(&mut client, &privkey, &contracts, &lock_script, described, 1)?;
+    if MarketData::from_bytes(&get_cell(&mut client, &minted)?.data)?.metadata.map(|metadata| metadata.question).is_none() {
+        return Err(anyhow!("Mint dropped the market metadata"));
+    }
+    info!(market = %described_id, "Question and outcome labels recorded on-chain and listed");
+
+    info!("=== Step 47: Protocol Fee ===");
+    let fee_collector = Script::new_builder()
+        .code_hash(SIGHASH_TYPE_HASH.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(Bytes::from(vec![0x52u8; 20]).pack())
+        .build();
+    let previous_recipient = std::env::var("FEE_RECIPIENT_ADDRESS").ok();
+    std::env::set_var("FEE_RECIPIENT_ADDRESS", encode_address(&fee_collector, contracts.network));
+    let fee_run = (|| -> Result<()> {
+        if market_fee(contracts.network, MAX_FEE_BPS + 1).is_ok() {
+            return Err(anyhow!("A fee above {} bps was accepted", MAX_FEE_BPS));
+        }
+        let fee = market_fee(contracts.network, 1500)?;
+        let built = build_create_market_tx(&mut client, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0, fee, None, None)?;
+        let fee_market = submit_market_tx(&mut client, &privkey, &contracts, "create-market", &built, SendMode::Wait)?;
+        let fee_market = mint_tokens(&mut client, &privkey, &contracts, &lock_script, fee_market, 10)?;
+        let fee_market = propose_resolution(&mut client, &privkey, &contracts, &lock_script, fee_market, Settlement::Outcome(true))?;
+        if build_claim_tx(&mut client, &contracts, &lock_script, fee_market.clone(), 5).is_ok() {
+            return Err(anyhow!("A claim on a proposed fee market was accepted"));
+        }
+        // Claims need the proposal finalized, so the split is checked on the
+        // market's data as it will read once resolved
+        let fee_data = MarketData::from_bytes(&get_cell(&mut client, &fee_market)?.data)?;
+        let resolved = MarketData { resolved: true, outcome: true, pending_outcome: None, ..fee_data };
+        // 15% of a single token's 100 CKB can't fill a 61 CKB recipient cell
+        match protocol_fee_output(contracts.network, &resolved, resolved.claim_fee(DEFAULT_SHANNONS_PER_TOKEN)) {
+            Err(err) if err.downcast_ref::<Rejected>().is_some() => {}
+            other => return Err(anyhow!("A claim with an unpayable fee was not rejected: {:?}", other)),
+        }
+        let paid = resolved.claim_fee(5 * DEFAULT_SHANNONS_PER_TOKEN);
+        let recipient_output = protocol_fee_output(contracts.network, &resolved, paid)?;
+        if paid != 75 * SHANNONS_PER_CKB || recipient_output.lock().as_slice() != fee_collector.as_slice() {
+            return Err(anyhow!("Fee recipient would get {} shannons under {}, expected 75 CKB to the collector",
+                               paid, recipient_output.lock()));
+        }
+        Ok(())
+    })();
+    match previous_recipient {
+        Some(value) => std::env::set_var("FEE_RECIPIENT_ADDRESS", value),
+        None => std::env::remove_var("FEE_RECIPIENT_ADDRESS"),
+    }
+    fee_run?;
+    info!("A claim of 5 tokens on a 15% fee market pays 75 of 500 CKB to the fee recipient");
+
+    info!("=== Step 48: Capacity Conservation ===");
+    // Wallet plus market capacity only changes by each transaction's fee, and
+    // the market moves by exactly the collateral the operation owes
+    const CONSERVED_SETS: u128 = 7;
+    let collateral = DEFAULT_SHANNONS_PER_TOKEN * CONSERVED_SETS as u64;
+    let before = conserved_capacity(&mut client, &lock_script, None)?;
+    let conserved = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0)?;
+    let base_capacity = get_cell(&mut client, &conserved)?.capacity;
+    let before = check_conserved(&mut client, &lock_script, &conserved, before, "create")?;
+
+    let minted = mint_tokens(&mut client, &privkey, &contracts, &lock_script, conserved, CONSERVED_SETS)?;
+    let before = check_conserved(&mut client, &lock_script, &minted, before, "mint")?;
+    if get_cell(&mut client, &minted)?.capacity != base_capacity + collateral {
+        return Err(anyhow!("Minting {} sets did not lock exactly {} shannons", CONSERVED_SETS, collateral));
+    }
+
+    // Burning every set returns the collateral before the market is resolved
+    let burned = burn_tokens(&mut client, &privkey, &contracts, &lock_script, minted.clone(), CONSERVED_SETS)?;
+    let before = check_conserved(&mut client, &lock_script, &burned, before, "burn")?;
+    if get_cell(&mut client, &burned)?.capacity != base_capacity {
+        return Err(anyhow!("Burning every set did not release exactly {} shannons", collateral));
+    }
+
+    let proposed = propose_resolution(&mut client, &privkey, &contracts, &lock_script, burned, Settlement::Outcome(true))?;
+    check_conserved(&mut client, &lock_script, &proposed, before, "propose")?;
+    if get_cell(&mut client, &proposed)?.capacity != base_capacity {
+        return Err(anyhow!("Proposing an outcome moved the market's capacity"));
+    }
+    info!("Create, mint, burn and propose conserved wallet + market capacity up to fees");
+
+    info!("=== Step 49: sUDT Token Descriptors ===");
+    let proposed_cell = get_cell(&mut client, &proposed)?;
+    let market_data = MarketData::from_bytes(&proposed_cell.data)?;
+    let descriptors = token_descriptors(&contracts, &proposed_cell.market_type()?, &market_data)?;
+    let yes_type = build_token_type(&contracts, &proposed_cell.market_type()?, market_data.token_script_hash_type()?, true);
+    let yes_descriptor = descriptors.iter().find(|descriptor| descriptor.symbol == "YES")
+        .ok_or_else(|| anyhow!("No YES descriptor"))?;
+    if yes_descriptor.type_hash != format!("{:#x}", yes_type.calc_script_hash()) {
+        return Err(anyhow!("YES descriptor names {}, not the minted token type", yes_descriptor.type_hash));
+    }
+    // The step 48 mint's token cells read as sUDT amounts of exactly the sets minted
+    let minted_tx = get_transaction_view(&mut client, minted.tx_hash().unpack())?;
+    let minted_yes: Vec<Option<u128>> = minted_tx.inner.outputs.iter().zip(&minted_tx.inner.outputs_data)
+        .filter(|(output, _)| output.type_.as_ref().is_some_and(|script| {
+            Script::from(script.clone()).calc_script_hash().as_slice() == yes_type.calc_script_hash().as_slice()
+        }))
+        .map(|(_, data)| token_amount(data.as_bytes()))
+        .collect();
+    if minted_yes != [Some(CONSERVED_SETS)] {
+        return Err(anyhow!("Minted YES cells read as {:?} under the sUDT layout", minted_yes));
+    }
+    info!(yes = %yes_descriptor.type_hash, "Token descriptors match the minted cells, whose data reads as sUDT amounts");
+
+    info!("=== Step 50: Scalar Markets ===");
+    // LONG pays (value - floor) / (ceiling - floor) of a set, SHORT the rest
+    let range = market_scalar(Some(0), Some(100))?;
+    let built = build_create_market_tx(&mut client, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0, None, range, None)?;
+    let scalar_market = submit_market_tx(&mut client, &privkey, &contracts, "create-market", &built, SendMode::Wait)?;
+    let scalar_market = mint_tokens(&mut client, &privkey, &contracts, &lock_script, scalar_market, 4)?;
+    for (settlement, why) in [(Settlement::Outcome(true), "with a YES/NO outcome"), (Settlement::Value(101), "outside its range")] {
+        if build_proposal_tx(&mut client, &contracts, &lock_script, scalar_market.clone(), settlement).is_ok() {
+            return Err(anyhow!("A scalar market was proposed {}", why));
+        }
+    }
+    let scalar_market = propose_resolution(&mut client, &privkey, &contracts, &lock_script, scalar_market, Settlement::Value(25))?;
+    let scalar_data = MarketData::from_bytes(&get_cell(&mut client, &scalar_market)?.data)?;
+    if scalar_data.scalar.map(|scalar| scalar.value) != Some(25) || scalar_data.pending_outcome != Some(Proposal::No) {
+        return Err(anyhow!("Proposing 25 left the market at {:?}, {:?} pending", scalar_data.scalar, scalar_data.pending_outcome));
+    }
+    for (value, long, short) in [(0, 0, 400 * SHANNONS_PER_CKB), (25, 100 * SHANNONS_PER_CKB, 300 * SHANNONS_PER_CKB),
+                                 (50, 200 * SHANNONS_PER_CKB, 200 * SHANNONS_PER_CKB), (100, 400 * SHANNONS_PER_CKB, 0)] {
+        let at = MarketData { scalar: range.map(|scalar| Scalar { value, ..scalar }), ..scalar_data.clone() };
+        if at.claim_payout(true, 4)? != long || at.claim_payout(false, 4)? != short {
+            return Err(anyhow!("4 LONG / SHORT at {} do not pay {} / {} shannons", value, long, short));
+        }
+    }
+    if build_side_claim_tx(&mut client, &contracts, &lock_script, scalar_market.clone(), Some(true), 4).is_ok() {
+        return Err(anyhow!("A claim on a scalar market still proposed at 25 was accepted"));
+    }
+    info!(market = %format_market_id(&scalar_market), "25 of [0, 100] proposed, to pay LONG a quarter and SHORT three quarters of each set");
+
+    info!("=== Step 51: Type ID Prediction ===");
+    // The Type ID the contract accepts in mock_tx_create_seeded.json (first
+    // input 0x00..01:0, since 0, market at output 0)
+    let mut seeded_tx_hash = [0u8; 32];
+    seeded_tx_hash[31] = 0x01;
+    let seeded_input = OutPoint::new_builder()
+        .tx_hash(seeded_tx_hash.pack())
+        .index(0u32.pack())
+        .build();
+    let accepted: [u8; 32] = [
+        0xbe, 0x80, 0x36, 0xd3, 0x57, 0x7c, 0x83, 0x06, 0x52, 0x85, 0x44, 0xb4, 0x7d, 0xb1, 0x75, 0x71,
+        0x1e, 0x46, 0xe1, 0x3b, 0x2f, 0x50, 0x36, 0x68, 0x92, 0x4d, 0x13, 0xb3, 0xfe, 0xbf, 0x1e, 0xad,
+    ];
+    if compute_market_type_id(&seeded_input, 0, 0) != accepted {
+        return Err(anyhow!("Predicted Type ID {} is not the one the contract accepts", hex::encode(compute_market_type_id(&seeded_input, 0, 0))));
+    }
+    // and it is the standard derivation, since included
+    let built = build_create_market_tx(&mut client, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0, None, None, None)?;
+    let first_input = built.tx.inputs().get(0).ok_or_else(|| anyhow!("Creation has no inputs"))?;
+    // (a non-zero output index catches a byte-order slip)
+    for (since, output_index) in [(0, 0), (SINCE_ABSOLUTE_TIMESTAMP | 1_767_225_600, 0), (0, 1), (0, 258)] {
+        let input = first_input.clone().as_builder().since(since.pack()).build();
+        if compute_market_type_id(&input.previous_output(), since, output_index) != ckb_sdk::util::calculate_type_id(&input, output_index) {
+            return Err(anyhow!("Predicted Type ID differs from ckb-sdk's for since {:#x}, output {}", since, output_index));
+        }
+    }
+    info!(type_id = %hex::encode(accepted), "Market Type IDs are predicted exactly as the contract derives them");
+
+    info!("=== Step 52: Market Import ===");
+    // A market created out-of-band: built and sent without the server's tracking
+    let built = build_create_market_tx(&mut client, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0, None, None, None)?;
+    let signed = built.signer.sign(built.tx, &privkey)?;
+    let tx_hash = send_transaction(&mut client, &signed, SendMode::Wait)?;
+    let external = OutPoint::new_builder().tx_hash(tx_hash.pack()).index(0u32.pack()).build();
+    let import = |market_id: &OutPoint| ImportMarketRequest { market_id: Some(format_market_id(market_id)), type_id: None };
+    let imported = import_market(&mut client, &contracts, &import(&external))?;
+    if imported.as_slice() != external.as_slice() {
+        return Err(anyhow!("Imported {} instead of {}", format_market_id(&imported), format_market_id(&external)));
+    }
+    // The change cell next to it is live but no market
+    let change = OutPoint::new_builder().tx_hash(tx_hash.pack()).index(1u32.pack()).build();
+    match import_market(&mut client, &contracts, &import(&change)) {
+        Err(err) if err.downcast_ref::<InvalidRequest>().is_some() => {}
+        other => return Err(anyhow!("Importing a plain cell was not refused: {:?}", other.map(|_| ()))),
+    }
+    // Once spent, the old cell can't be imported
+    let moved = mint_tokens(&mut client, &privkey, &contracts, &lock_script, external.clone(), 1)?;
+    if import_market(&mut client, &contracts, &import(&external)).is_ok() {
+        return Err(anyhow!("A spent market cell was imported"));
+    }
+    if import_market(&mut client, &contracts, &ImportMarketRequest { market_id: None, type_id: Some(format!("0x{}", "77".repeat(32))) }).is_ok() {
+        return Err(anyhow!("An unknown Type ID was imported"));
+    }
+    info!(market = %format_market_id(&moved), "Markets created elsewhere are imported once checked live and well-formed");
+
+    info!("=== Step 53: Typed Cells Are Never Fee Inputs ===");
+    // A Type ID cell with empty data in the wallet: only the type script sets it apart
+    let funding = collect_cells(&mut client, &lock_script, Shannons::from_ckb(500).as_shannons())?;
+    let funding_total: u64 = funding.iter().map(|(_, capacity)| capacity).sum();
+    let typed_capacity = Shannons::from_ckb(200).as_shannons();
+    let type_id = Script::new_builder()
+        .code_hash(ckb_types::core::TYPE_ID_CODE_HASH.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(Bytes::copy_from_slice(&compute_market_type_id(&funding[0].0, 0, 0)).pack())
+        .build();
+    let tx = TransactionView::new_advanced_builder()
+        .cell_dep(secp256k1_dep(contracts.network))
+        .inputs(funding.iter().map(|(outpoint, _)| CellInput::new_builder().previous_output(outpoint.clone()).build()))
+        .outputs(vec![
+            CellOutput::new_builder().capacity(typed_capacity.pack()).lock(lock_script.clone()).type_(Some(type_id).pack()).build(),
+            CellOutput::new_builder().capacity((funding_total - typed_capacity).pack()).lock(lock_script.clone()).build(),
+        ])
+        .outputs_data(vec![Bytes::new().pack(), Bytes::new().pack()])
+        .build();
+    let signer = Signer::FeeCells(funding.len());
+    let (tx, _, _) = charge_fee(tx, signer, fee_rate())?;
+    let typed_hash = send_transaction(&mut client, &signer.sign(tx, &privkey)?, SendMode::Wait)?;
+    let typed = OutPoint::new_builder().tx_hash(typed_hash.pack()).index(0u32.pack()).build();
+    // Asking for more than the wallet holds gathers every cell it would ever offer
+    let (offered, _) = gather_cells(&mut client, &lock_script, u64::MAX)?;
+    if offered.iter().any(|(outpoint, _)| outpoint.as_slice() == typed.as_slice()) {
+        return Err(anyhow!("The typed cell {} was offered as a fee input", format_market_id(&typed)));
+    }
+    if !offered.iter().any(|(outpoint, _)| outpoint.tx_hash().as_slice() == typed.tx_hash().as_slice()) {
+        return Err(anyhow!("The change next to the typed cell was not offered as a fee input"));
+    }
+    info!(cells = offered.len(), "Fee collection passes over a typed cell with empty data and keeps its plain neighbour");
+
+    info!("=== Step 54: Two-Phase Resolution ===");
+    let refused = |result: Result<BuiltTx>, why: &str| -> Result<()> {
+        match result {
+            Err(err) if err.downcast_ref::<Rejected>().is_some() => Ok(()),
+            other => Err(anyhow!("{} was not refused: {:?}", why, other.map(|built| built.tx.hash()))),
+        }
+    };
+    let pending = |client: &mut dyn CkbBackend, market: &OutPoint| -> Result<(Option<Proposal>, u64, bool)> {
+        let data = MarketData::from_bytes(&get_cell(client, market)?.data)?;
+        Ok((data.pending_outcome, data.proposed_at, data.resolved))
+    };
+    let phased = create_market(&mut client, &privkey, &contracts, &lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0)?;
+    let phased = mint_tokens(&mut client, &privkey, &contracts, &lock_script, phased, 2)?;
+    refused(build_finalize_tx(&mut client, &contracts, &lock_script, phased.clone()), "Finalizing with nothing pending")?;
+    let phased = propose_resolution(&mut client, &privkey, &contracts, &lock_script, phased, Settlement::Outcome(true))?;
+    match pending(&mut client, &phased)? {
+        (Some(Proposal::Yes), proposed_at, false) if proposed_at > 0 => {}
+        other => return Err(anyhow!("Proposing YES left the market at {:?}", other)),
+    }
+    // Nothing pays out, and no sets move, until the proposal is final
+    refused(build_claim_tx(&mut client, &contracts, &lock_script, phased.clone(), 1), "A claim on a proposed market")?;
+    refused(build_mint_tx(&mut client, &contracts, &lock_script, phased.clone(), 1), "A mint on a proposed market")?;
+    refused(build_finalize_tx(&mut client, &contracts, &lock_script, phased.clone()), "Finalizing before the delay")?;
+    // Server markets have no resolver or oracle quorum, so the YES is fixed:
+    // the contract would fail an amendment with ProposalFixed
+    if market_authority(&mut client, &phased)? != ResolutionAuthority::Anyone {
+        return Err(anyhow!("A server market has a resolution authority"));
+    }
+    refused(build_proposal_tx(&mut client, &contracts, &lock_script, phased.clone(), Settlement::Outcome(false)), "Amending a permissionless proposal")?;
+    refused(build_proposal_tx(&mut client, &contracts, &lock_script, phased.clone(), Settlement::Cancelled), "Proposing over a pending proposal")?;
+    if pending(&mut client, &phased)?.0 != Some(Proposal::Yes) {
+        return Err(anyhow!("The refused proposals moved the pending one: {:?}", pending(&mut client, &phased)?));
+    }
+    info!("Proposals wait {}s to finalize and stay fixed on permissionless markets; claims wait for them", RESOLUTION_DELAY_SECS);
+
+    if let Ok(cycles) = std::env::var("SOAK_CYCLES") {
+        let cycles: usize = cycles.parse()
+            .map_err(|_| anyhow!("SOAK_CYCLES must be a number"))?;
+        info!("=== Soak: {} Mint/Burn Cycles ===", cycles);
+        run_soak(&mut client, &privkey, &contracts, &lock_script, cycles)?;
+    }
+
+    info!("=== All Tests Passed! ===");
+    Ok(())
+}
+
+/// Soak test: repeated create → mint → burn cycles (SOAK_CYCLES)
+///
+/// Each cycle may only cost the wallet the new market cell's base capacity
+/// plus the fees actually paid, and every market must end holding just its
+/// base capacity once its sets are burned. Resolving can't be cycled on a
+/// devnet, as finalizing waits out RESOLUTION_DELAY_SECS of chain time; the
+/// mock-node tests cycle mint → claim instead. Operations are
+/// serialized through the client, so there is no in-flight state to drain
+/// between cycles; the fee cell count at the end surfaces fragmentation.
+fn run_soak(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    contracts: &ContractInfo,
+    lock_script: &Script,
+    cycles: usize,
+) -> Result<()> {
+    const SOAK_AMOUNT: u128 = 3;
+
+    let starting_balance = wallet_capacity(client, lock_script)?;
+    let mut total_fees = 0u64;
+
+    for cycle in 1..=cycles {
+        let balance_before = wallet_capacity(client, lock_script)?;
+
+        let market = create_market(client, privkey, contracts, lock_script, TEST_MARKET_BUFFER, DEFAULT_SHANNONS_PER_TOKEN, 0)?;
+        let base_capacity = get_cell(client, &market)?.capacity;
+        let mut fees = outpoint_tx_fee(client, &market)?;
+
+        let market = mint_tokens(client, privkey, contracts, lock_script, market, SOAK_AMOUNT)?;
+        fees += outpoint_tx_fee(client, &market)?;
+
+        let market = burn_tokens(client, privkey, contracts, lock_script, market, SOAK_AMOUNT)?;
+        fees += outpoint_tx_fee(client, &market)?;
+
+        // Collateral nets to zero: the market is back to its base capacity
+        let market_capacity = get_cell(client, &market)?.capacity;
+        if market_capacity != base_capacity {
+            return Err(anyhow!("Cycle {}: market holds {} after burning every set, expected {}",
+                               cycle, market_capacity, base_capacity));
+        }
+
+        // Token cells stay under the wallet lock, so only the market cell and fees leave
+        let balance_after = wallet_capacity(client, lock_script)?;
+        if balance_before - balance_after != base_capacity + fees {
+            return Err(anyhow!("Cycle {}: wallet lost {} shannons, expected {} (market {} + fees {})",
+                               cycle, balance_before - balance_after, base_capacity + fees,
+                               base_capacity, fees));
+        }
+        total_fees += fees;
+
+        if cycle % 10 == 0 {
+            info!("  {} cycles done, {} shannons in fees", cycle, total_fees);
+        }
+    }
+
+    let ending_balance = wallet_capacity(client, lock_script)?;
+    let fee_cells = count_pure_cells(client, lock_script)?;
+    info!("Soak complete: {} cycles, wallet {} -> {} shannons, {} in fees, {} fee cells",
+          cycles, starting_balance, ending_balance, total_fees, fee_cells);
+    Ok(())
+}
+
+/// Wallet capacity plus the market cell's, the total a market lifecycle
+/// conserves apart from transaction fees
+fn conserved_capacity(client: &mut dyn CkbBackend, lock: &Script, market: Option<&OutPoint>) -> Result<u64> {
+    let market_capacity = match market {
+        Some(outpoint) => get_cell(client, outpoint)?.capacity,
+        None => 0,
+    };
+    Ok(wallet_capacity(client, lock)? + market_capacity)
+}
+
+/// Check the transaction that created `market` took exactly its fee out of
+/// wallet + market capacity, returning the new total
+fn check_conserved(client: &mut dyn CkbBackend, lock: &Script, market: &OutPoint, before: u64, op: &str) -> Result<u64> {
+    let after = conserved_capacity(client, lock, Some(market))?;
+    let fee = outpoint_tx_fee(client, market)?;
+    if before.checked_sub(after) != Some(fee) {
+        return Err(anyhow!("{} changed wallet + market capacity from {} to {}, but paid a {} shannon fee",
+                           op, before, after, fee));
+    }
+    Ok(after)
+}
+
+/// Deployment of the market, token and always-success contracts on `network`
+///
+/// Devnet defaults to the offckb deployment; elsewhere each transaction and
+/// code hash must be given (MARKET_CODE_HASH, MARKET_TX_HASH, TOKEN_CODE_HASH,
+/// TOKEN_TX_HASH, ALWAYS_SUCCESS_CODE_HASH, ALWAYS_SUCCESS_TX_HASH).
+fn get_contract_info(network: Network) -> Result<ContractInfo> {
+    let deployed = |name: &str, devnet: &str| -> Result<H256> {
+        let value = match std::env::var(name) {
+            Ok(value) => value,
+            Err(_) if network == Network::Dev => devnet.to_string(),
+            Err(_) => return Err(anyhow!("{} must be set on {:?} (the defaults are the offckb devnet deployment)", name, network)),
+        };
+        H256::from_str(value.trim_start_matches("0x")).map_err(|err| anyhow!("Invalid {}: {}", name, err))
+    };
+
+    // The always-success cell may be deployed to be referenced by data hash
+    // (data/data1/data2) or by type hash (type, with ALWAYS_SUCCESS_CODE_HASH
+    // set to the type script hash)
+    let always_success_hash_type = match std::env::var("ALWAYS_SUCCESS_HASH_TYPE") {
+        Ok(value) => parse_script_hash_type(&value)?,
+        Err(_) => ScriptHashType::Data1,
+    };
+    let always_success_code_hash = deployed("ALWAYS_SUCCESS_CODE_HASH", "21854a7b67a2c4a71a8558c6d4023cf787e71db49d09cb4aa8748dbf6a8ef6ec")?;
+
+    // Devnet defaults from the offckb deployment
+    Ok(ContractInfo {
+        market_code_hash: deployed("MARKET_CODE_HASH", "fe3a71cfcb556500e7f760b5c853be8fc082d32748aa9e5a98e25d79d4116485")?,
+        market_tx_hash: deployed("MARKET_TX_HASH", "6c88542e395d308dc6e08b745473dce80e06ae06e50c69221b54508c5b5335d5")?,
+        token_code_hash: deployed("TOKEN_CODE_HASH", "54f68c08a051facc261167d0a45383cc5fa8b1ea7d1f9d9be5a7e623e27a1320")?,
+        token_tx_hash: deployed("TOKEN_TX_HASH", "b5580c10ce2545acbf9b05ca8b7e44d62dcc7d837e0557b343222b7dd6c22b0f")?,
+        always_success_code_hash,
+        always_success_tx_hash: deployed("ALWAYS_SUCCESS_TX_HASH", "0cc42f03d73e685843da66a6f049107634986572802eb8d0363e7e662125d077")?,
+        always_success_hash_type,
+        network,
+    })
+}
+
+/// Refuse to run against a node whose chain isn't the configured network
+fn verify_network(client: &mut dyn CkbBackend, network: Network) -> Result<()> {
+    let chain = retry_read(RpcRetry::from_env(), "get_blockchain_info", || client.get_blockchain_info())?.chain;
+    if !network.matches_chain(&chain) {
+        return Err(anyhow!("Node at the configured RPC runs chain {:?}, but the network is {:?}; set CKB_NETWORK", chain, network));
+    }
+    Ok(())
+}
+
+/// Parse a script hash_type name as used in deployment configs
+fn parse_script_hash_type(value: &str) -> Result<ScriptHashType> {
+    match value.to_ascii_lowercase().as_str() {
+        "data" => Ok(ScriptHashType::Data),
+        "type" => Ok(ScriptHashType::Type),
+        "data1" => Ok(ScriptHashType::Data1),
+        "data2" => Ok(ScriptHashType::Data2),
+        other => Err(anyhow!("Unknown hash_type '{}' (expected data, type, data1 or data2)", other)),
+    }
+}
+
+/// Check that the deployed always-success cell matches the market lock
+///
+/// With a data hash_type the lock's code_hash must be the hash of the cell's
+/// data; with `type` it must be the hash of the cell's type script. Otherwise
+/// no transaction spending a market cell would ever verify.
+fn verify_market_lock(client: &mut dyn CkbBackend, contracts: &ContractInfo) -> Result<()> {
+    let deployment = get_transaction_view(client, contracts.always_success_tx_hash.clone())?;
+    let output = deployment.inner.outputs.first()
+        .ok_or_else(|| anyhow!("Always-success deployment has no outputs"))?;
+    let data = deployment.inner.outputs_data.first()
+        .ok_or_else(|| anyhow!("Always-success deployment has no output data"))?;
+
+    let referenced: H256 = match contracts.always_success_hash_type {
+        ScriptHashType::Type => {
+            let type_script: Script = output.type_.clone()
+                .ok_or_else(|| anyhow!("ALWAYS_SUCCESS_HASH_TYPE is type but the deployed cell has no type script"))?
+                .into();
+            type_script.calc_script_hash().unpack()
+        }
+        _ => blake2b_256(data.as_bytes()).into(),
+    };
+
+    if referenced != contracts.always_success_code_hash {
+        return Err(anyhow!(
+            "Market lock {:#x} ({:?}) does not match the deployed always-success cell ({:#x})",
+            contracts.always_success_code_hash, contracts.always_success_hash_type, referenced
+        ));
+    }
+    Ok(())
+}
+
+/// Public key serialization the wallet's sighash lock args are hashed from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PubkeyFormat {
+    /// blake160 of the 33-byte compressed key, as CKB's default sighash lock and
+    /// ckb-sdk addresses use
+    Compressed,
+    /// blake160 of the 65-byte uncompressed key, for deployments whose lock
+    /// hashes keys that way
+    Uncompressed,
+}
+
+/// Pubkey format from PUBKEY_FORMAT (`compressed`, the default, or `uncompressed`)
+fn pubkey_format() -> Result<PubkeyFormat> {
+    match std::env::var("PUBKEY_FORMAT").as_deref() {
+        Err(_) | Ok("compressed") => Ok(PubkeyFormat::Compressed),
+        Ok("uncompressed") => Ok(PubkeyFormat::Uncompressed),
+        Ok(other) => Err(anyhow!("Unknown PUBKEY_FORMAT {:?}, expected compressed or uncompressed", other)),
+    }
+}
+
+/// Sighash lock owned by `pubkey`, with args = blake160 of its `format` serialization
+///
+/// A compressed-key lock is cross-checked against ckb-sdk's address derivation,
+/// so a hashing bug shows up here instead of as a wallet with no cells.
+fn wallet_lock(pubkey: &secp256k1::PublicKey, format: PubkeyFormat) -> Result<Script> {
+    let serialized = match format {
+        PubkeyFormat::Compressed => pubkey.serialize().to_vec(),
+        PubkeyFormat::Uncompressed => pubkey.serialize_uncompressed().to_vec(),
+    };
+    let pubkey_hash = &blake2b_256(&serialized)[0..20];
+
+    let lock = Script::new_builder()
+        .code_hash(SIGHASH_TYPE_HASH.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(Bytes::from(pubkey_hash.to_vec()).pack())
+        .build();
+
+    if format == PubkeyFormat::Compressed {
+        let address_lock = Script::from(&ckb_sdk::AddressPayload::from_pubkey(pubkey));
+        if address_lock.as_slice() != lock.as_slice() {
+            return Err(anyhow!(
+                "Derived wallet lock {:#x} does not match ckb-sdk's address lock {:#x}",
+                lock.calc_script_hash(), address_lock.calc_script_hash()
+            ));
+        }
+    }
+    Ok(lock)
+}
+
+/// Refuse to run with a wallet lock that can't see the key's funds
+///
+/// If the derived lock owns nothing while the key's standard address (ckb-sdk
+/// derivation) holds capacity, the lock args were hashed the wrong way and
+/// every balance and cell lookup would come back empty.
+fn verify_wallet_visible(client: &mut dyn CkbBackend, pubkey: &secp256k1::PublicKey, lock: &Script) -> Result<()> {
+    let address_lock = Script::from(&ckb_sdk::AddressPayload::from_pubkey(pubkey));
+    let derived = wallet_capacity(client, lock)?;
+    let expected = if address_lock.as_slice() == lock.as_slice() { derived } else { wallet_capacity(client, &address_lock)? };
+    check_wallet_visible(derived, expected)
+}
+
+/// Capacity seen under the derived lock vs under the key's standard address
+fn check_wallet_visible(derived: u64, expected: u64) -> Result<()> {
+    if derived == 0 && expected > 0 {
+        return Err(anyhow!(
+            "No cells under the derived wallet lock, but the key's address holds {} shannons; check PUBKEY_FORMAT",
+            expected
+        ));
+    }
+    Ok(())
+}
+
+/// The network's secp256k1 sighash dep group as a cell dep
+fn secp256k1_dep(network: Network) -> CellDep {
+    CellDep::new_builder()
+        .out_point(network.secp256k1_dep_group())
+        .dep_type(ckb_types::core::DepType::DepGroup.into())
+        .build()
+}
+
+fn build_cell_deps(contracts: &ContractInfo) -> Vec<CellDep> {
+    vec![
+        // Secp256k1 dep group (for signing fee inputs)
+        secp256k1_dep(contracts.network),
+        // Market contract
+        CellDep::new_builder()
+            .out_point(
+                OutPoint::new_builder()
+                    .tx_hash(contracts.market_tx_hash.pack())
+                    .index(0u32.pack())
+                    .build()
+            )
+            .dep_type(ckb_types::core::DepType::Code.into())
+            .build(),
+        // Always-success contract
+        CellDep::new_builder()
+            .out_point(
+                OutPoint::new_builder()
+                    .tx_hash(contracts.always_success_tx_hash.pack())
+                    .index(0u32.pack())
+                    .build()
+            )
+            .dep_type(ckb_types::core::DepType::Code.into())
+            .build(),
+    ]
+}
+
+/// Build cell deps for transactions that use tokens
+fn build_cell_deps_with_token(contracts: &ContractInfo) -> Vec<CellDep> {
+    let mut deps = build_cell_deps(contracts);
+
+    // Add token contract
+    deps.push(CellDep::new_builder()
+        .out_point(
+            OutPoint::new_builder()
+                .tx_hash(contracts.token_tx_hash.pack())
+                .index(0u32.pack())
+                .build()
+        )
+        .dep_type(ckb_types::core::DepType::Code.into())
+        .build());
+
+    deps
+}
+
+fn build_market_lock(contracts: &ContractInfo) -> Script {
+    Script::new_builder()
+        .code_hash(contracts.always_success_code_hash.pack())
+        .hash_type(contracts.always_success_hash_type.into())
+        .args(Bytes::new().pack())
+        .build()
+}
+
+/// Reject token output locks that anyone could spend
+/// The market lock is always-success, so tokens minted into it (with any args)
+/// would be free for the taking.
+fn validate_token_lock(contracts: &ContractInfo, token_lock: &Script) -> Result<()> {
+    let market_lock = build_market_lock(contracts);
+    if token_lock.code_hash().as_slice() == market_lock.code_hash().as_slice()
+        && token_lock.hash_type().as_slice() == market_lock.hash_type().as_slice()
+    {
+        return Err(Rejected("Refusing to put tokens under the always-success market lock".to_string()).into());
+    }
+    Ok(())
+}
+
+fn build_market_type(contracts: &ContractInfo) -> Script {
+    Script::new_builder()
+        .code_hash(contracts.market_code_hash.pack())
+        .hash_type(ScriptHashType::Data1.into())
+        .args(Bytes::new().pack())
+        .build()
+}
+
+/// Type ID of a market created at `output_index` by a transaction whose first
+/// input spends `first_input` with `since`, as the market script derives it
+///
+/// Lets a client co-signing or pre-registering a market know its id (and so
+/// its token types) before the creation is on chain. The `since` is part of
+/// the hashed input, so it has to be the one the transaction will carry.
+fn compute_market_type_id(first_input: &OutPoint, since: u64, output_index: u64) -> [u8; 32] {
+    let input = CellInput::new_builder()
+        .previous_output(first_input.clone())
+        .since(since.pack())
+        .build();
+    let input = input.as_slice().try_into().expect("a CellInput serializes to 44 bytes");
+    blake2b_256(type_id_preimage(input, output_index))
+}
+
+/// Token ids of binary markets (outcomes of categorical markets count up from 0x01)
+const YES_TOKEN_ID: u8 = 0x01;
+const NO_TOKEN_ID: u8 = 0x02;
+
+/// Token id of the YES or NO side
+fn binary_token_id(is_yes: bool) -> u8 {
+    if is_yes { YES_TOKEN_ID } else { NO_TOKEN_ID }
+}
+
+/// Build the token type script for any outcome of a market
+/// Args format: market_type_hash (32 bytes) + token_id (1 byte)
+/// hash_type must come from the market data so the hash matches what the contract derives
+fn build_outcome_token_type(
+    contracts: &ContractInfo,
+    market_type_hash: &[u8],
+    hash_type: ScriptHashType,
+    token_id: u8,
+) -> Script {
+    let mut args = Vec::with_capacity(33);
+    args.extend_from_slice(market_type_hash);
+    args.push(token_id);
+
+    Script::new_builder()
+        .code_hash(contracts.token_code_hash.pack())
+        .hash_type(hash_type.into())
+        .args(Bytes::from(args).pack())
+        .build()
+}
+
+/// Build token type script for YES or NO tokens of the market whose cells
+/// carry `market_type` (see CellInfo::market_type)
+fn build_token_type(contracts: &ContractInfo, market_type: &Script, hash_type: ScriptHashType, is_yes: bool) -> Script {
+    let market_type_hash = market_type.calc_script_hash();
+    build_outcome_token_type(contracts, market_type_hash.as_slice(), hash_type, binary_token_id(is_yes))
+}
+
+/// sUDT-style descriptors of a market's YES and NO tokens
+fn token_descriptors(contracts: &ContractInfo, market_type: &Script, market_data: &MarketData) -> Result<Vec<TokenDescriptor>> {
+    let hash_type = market_data.token_script_hash_type()?;
+    Ok([(true, "YES"), (false, "NO")].into_iter().map(|(is_yes, symbol)| {
+        let type_script = build_token_type(contracts, market_type, hash_type, is_yes);
+        TokenDescriptor {
+            symbol,
+            token_id: binary_token_id(is_yes),
+            type_hash: format!("{:#x}", type_script.calc_script_hash()),
+            type_script: type_script.into(),
+            decimals: market_data.decimals,
+            amount_len: TOKEN_AMOUNT_LEN,
+        }
+    }).collect())
+}
+
+fn create_market(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    buffer: u64,
+    shannons_per_token: u64,
+    resolve_after: u64,
+) -> Result<OutPoint> {
+    let built = build_create_market_tx(client, contracts, fee_lock, buffer, shannons_per_token, resolve_after, None, None, None)?;
+    submit_market_tx(client, privkey, contracts, "create-market", &built, SendMode::Wait)
+}
+
+/// Build a market creation with `buffer` shannons on top of the market cell's
+/// occupied capacity (232 CKB for the devnet market's 158 data bytes, 240 with
+/// a `resolve_after` window, more with a fee, scalar range or metadata)
+#[allow(clippy::too_many_arguments)]
+fn build_create_market_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    buffer: u64,
+    shannons_per_token: u64,
+    resolve_after: u64,
+    fee: Option<ProtocolFee>,
+    scalar: Option<Scalar>,
+    metadata: Option<Metadata>,
+) -> Result<BuiltTx> {
+    debug!(buffer, shannons_per_token, resolve_after, "Building create-market transaction");
+
+    if shannons_per_token == 0 {
+        return Err(InvalidRequest("shannons_per_token must be positive".to_string()).into());
+    }
+
+    // A `since` can only hold timestamps up to 56 bits
+    if resolve_after > SINCE_VALUE_MASK {
+        return Err(InvalidRequest(format!("resolve_after must be at most {}", SINCE_VALUE_MASK)).into());
+    }
+
+    // Market data: the deployed token contract, the collateral unit, zero
+    // supplies and the resolution window, protocol fee, scalar range and
+    // metadata, if any
+    let market_data = MarketData { resolve_after, fee, scalar, metadata, ..MarketData::open(contracts, shannons_per_token) }.to_bytes();
+
+    // Market cell: exactly what its lock, type and data occupy, plus the buffer
+    let market_output = CellOutput::new_builder()
+        .lock(build_market_lock(contracts))
+        .type_(Some(build_market_type(contracts)).pack())
+        .build();
+    let market_capacity = occupied_capacity(&market_output, market_data.len())?
+        .checked_add(buffer)
+        .ok_or_else(|| anyhow!("Market capacity overflow"))?;
+    let market_output = market_output.as_builder()
+        .capacity(market_capacity.pack())
+        .build();
+
+    // Collect input cells for the market, the fee and a committable change cell
+    let fee_rate = fee_rate();
+    let fee_cells = collect_cells_for(client, fee_lock, &[
+        CapacityRequirement { label: "market cell", capacity: Shannons(market_capacity) },
+        CapacityRequirement { label: "fee", capacity: Shannons(fee_allowance(fee_rate) + MIN_CHANGE_CAPACITY) },
+    ])?;
+    debug!(fee_cells = fee_cells.len(), "Collected fee cells");
+
+    // Calculate total input; the fee comes out of the change once the size is known
+    let total_input: u64 = fee_cells.iter().map(|(_, cap)| cap).sum();
+    let change = total_input - market_capacity;
+
+    let change_output = CellOutput::new_builder()
+        .capacity(change.pack())
+        .lock(fee_lock.clone())
+        .build();
+
+    // Build inputs
+    let inputs: Vec<CellInput> = fee_cells.iter()
+        .map(|(outpoint, _)| {
+            CellInput::new_builder()
+                .previous_output(outpoint.clone())
+                .since(0u64.pack())
+                .build()
+        })
+        .collect();
+
+    // Build transaction
+    let tx = TransactionView::new_advanced_builder()
+        .cell_deps(build_cell_deps(contracts))
+        .inputs(inputs)
+        .outputs(vec![market_output, change_output])
+        .outputs_data(vec![Bytes::from(market_data).pack(), Bytes::new().pack()])
+        .build();
+
+    let signer = Signer::FeeCells(fee_cells.len());
+    let (tx, _, change) = charge_fee(tx, signer, fee_rate)?;
+    Ok(BuiltTx { tx, signer, change })
+}
+
+fn mint_tokens(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    amount: u128,
+) -> Result<OutPoint> {
+    let built = build_mint_tx(client, contracts, fee_lock, market_outpoint, amount)?;
+    submit_market_tx(client, privkey, contracts, "mint", &built, SendMode::Wait)
+}
+
+fn build_mint_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    amount: u128,
+) -> Result<BuiltTx> {
+    build_funded_mint_tx(client, contracts, fee_lock, market_outpoint, amount, &Funding::ServerWallet)
+}
+
+/// Build a mint whose collateral and fee come from `funding`
+/// With server funding, tokens and change go to `fee_lock`; with supplied
+/// inputs they go to the supplier's change lock instead.
+fn build_funded_mint_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    amount: u128,
+    funding: &Funding,
+) -> Result<BuiltTx> {
+    let owner_lock = funding.owner_lock(fee_lock);
+
+    // Token cells are locked to the owner; refuse to hand them to the public
+    validate_token_lock(contracts, owner_lock)?;
+
+    debug!(amount = %amount, "Building mint transaction");
+
+    // Get current market cell
+    let market_cell = get_market_cell(client, &market_outpoint)?;
+    let market_data = MarketData::from_bytes(&market_cell.data)?;
+    let market_capacity: u64 = market_cell.capacity;
+    let market_type = market_cell.market_type()?;
+    let token_hash_type = market_data.token_script_hash_type()?;
+    if market_data.pending_outcome.is_some() {
+        return Err(Rejected("Market has a pending resolution; finalize it first".to_string()).into());
+    }
+
+    let yes_token_type = build_token_type(contracts, &market_type, token_hash_type, true);
+    let no_token_type = build_token_type(contracts, &market_type, token_hash_type, false);
+
+    // Token cells get exactly the capacity their lock, type and amount occupy
+    // (143 CKB with a sighash lock and 33-byte token args)
+    let token_cell_capacity = token_cell_minimum(owner_lock, &yes_token_type)?
+        .max(token_cell_minimum(owner_lock, &no_token_type)?);
+
+    // Collect fee cells (need the sets' collateral + both token cells + fees)
+    let collateral = market_data.checked_collateral(amount)?;
+    let token_cells_capacity = token_cell_capacity * 2;
+    let fee_rate = fee_rate();
+    let margin = fee_margin();
+    let fee_cells = funding.cells(client, fee_lock, &[
+        CapacityRequirement { label: "collateral", capacity: collateral },
+        CapacityRequirement { label: "token cells", capacity: Shannons(token_cells_capacity) },
+        CapacityRequirement { label: "fee", capacity: Shannons(fee_allowance(fee_rate) + margin) },
+    ])?;
+
+    let total_fee_input: u64 = fee_cells.iter().map(|(_, cap)| cap).sum();
+
+    // New market capacity = old + collateral
+    let new_market_capacity = market_capacity + collateral.as_shannons();
+
+    // New market data
+    let new_market_data = market_data
+        .with_supplies(market_data.yes_supply() + amount, market_data.no_supply() + amount)
+        .to_bytes();
+
+    // Build outputs
+    let market_output = CellOutput::new_builder()
+        .capacity(new_market_capacity.pack())
+        .lock(build_market_lock(contracts))
+        .type_(Some(market_type).pack())
+        .build();
+
+    // YES and NO token cells, owned by the user
+    let yes_token_output = token_cell_output(owner_lock, &yes_token_type, token_cell_capacity)?;
+    let no_token_output = token_cell_output(owner_lock, &no_token_type, token_cell_capacity)?;
+
+    // Calculate change (need to account for token cell capacities); the fee
+    // comes out of it once the transaction's size is known
+    let change_adjusted = total_fee_input - collateral.as_shannons() - token_cells_capacity;
+    let change_output = CellOutput::new_builder()
+        .capacity(change_adjusted.pack())
+        .lock(owner_lock.clone())
+        .build();
+
+    // Token cell data: u128 amount (16 bytes)
+    let token_amount_bytes = amount.to_le_bytes().to_vec();
+
+    // Build inputs: market cell first, then fee cells
+    let mut inputs = vec![
+        CellInput::new_builder()
+            .previous_output(market_outpoint)
+            .since(0u64.pack())
+            .build()
+    ];
+    for (outpoint, _) in &fee_cells {
+        inputs.push(CellInput::new_builder()
+            .previous_output(outpoint.clone())
             .since(0u64.pack())
+            .build());
+    }
+
+    // Build transaction
+    let tx = TransactionView::new_advanced_builder()
+        .cell_deps(build_cell_deps_with_token(contracts))
+        .inputs(inputs)
+        .outputs(vec![market_output, yes_token_output, no_token_output, change_output])
+        .outputs_data(vec![
+            Bytes::from(new_market_data).pack(),
+            Bytes::from(token_amount_bytes.clone()).pack(),
+            Bytes::from(token_amount_bytes).pack(),
+            Bytes::new().pack()
+        ])
+        .build();
+
+    // Witness 0 is empty for always-success, witnesses 1+ are for fee cells
+    let signer = Signer::Market(fee_cells.len());
+    let (tx, fee, change) = charge_fee(tx, signer, fee_rate)?;
+
+    // Catch near-limit inputs before broadcast rather than in the pool
+    verify_fee_margin(market_capacity + total_fee_input, &tx, fee, margin)?;
+
+    Ok(BuiltTx { tx, signer, change })
+}
+
+/// Propose `settlement` for a market and wait for it to commit; returns the
+/// market's new outpoint
+fn propose_resolution(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    settlement: Settlement,
+) -> Result<OutPoint> {
+    let built = build_proposal_tx(client, contracts, fee_lock, market_outpoint, settlement)?;
+    submit_market_tx(client, privkey, contracts, "propose", &built, SendMode::Wait)
+}
+
+/// How a proposal resolves a market
+#[derive(Debug, Clone, Copy)]
+enum Settlement {
+    /// YES (true) or NO wins a binary market
+    Outcome(bool),
+    /// A scalar market's value, paying LONG and SHORT along its range
+    Value(u64),
+    /// No winners, complete sets are refunded
+    Cancelled,
+}
+
+/// Proposal and scalar data a settlement writes to a market
+/// Scalar markets resolve with a value in their range, the rest with an outcome.
+fn settlement_terms(settlement: Settlement, market_data: &MarketData) -> Result<(Proposal, Option<Scalar>)> {
+    match (settlement, market_data.scalar) {
+        (Settlement::Cancelled, scalar) => Ok((Proposal::Cancel, scalar)),
+        (Settlement::Outcome(outcome_yes), None) => Ok((if outcome_yes { Proposal::Yes } else { Proposal::No }, None)),
+        (Settlement::Outcome(_), Some(_)) => {
+            Err(Rejected("Scalar market resolves with a value, not an outcome".to_string()).into())
+        }
+        (Settlement::Value(_), None) => {
+            Err(Rejected("Market is not scalar, resolve it with an outcome".to_string()).into())
+        }
+        (Settlement::Value(value), Some(scalar)) => {
+            if !(scalar.floor..=scalar.ceiling).contains(&value) {
+                return Err(InvalidRequest(format!(
+                    "value {} is outside the market's range [{}, {}]", value, scalar.floor, scalar.ceiling,
+                )).into());
+            }
+            // The value lives in the scalar data; the proposal only marks it as not cancelled
+            Ok((Proposal::No, Some(Scalar { value, ..scalar })))
+        }
+    }
+}
+
+/// Build a transaction rewriting the data of `market_cell` with its capacity
+/// and type unchanged, spending the market input with `since`; fee cells pay
+/// the fee
+///
+/// `header_deps` are the blocks the market script reads headers of.
+#[allow(clippy::too_many_arguments)]
+fn build_market_data_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    market_cell: &CellInfo,
+    new_market_data: &MarketData,
+    since: u64,
+    header_deps: Vec<ckb_types::packed::Byte32>,
+) -> Result<BuiltTx> {
+    // Collect fee cells
+    let fee_cells = collect_cells(client, fee_lock, FEE_CELL_CAPACITY)?;
+    let total_fee_input: u64 = fee_cells.iter().map(|(_, cap)| cap).sum();
+    let change = total_fee_input;
+
+    // Build outputs (market capacity unchanged)
+    let market_output = CellOutput::new_builder()
+        .capacity(market_cell.capacity.pack())
+        .lock(build_market_lock(contracts))
+        .type_(Some(market_cell.market_type()?).pack())
+        .build();
+
+    let change_output = CellOutput::new_builder()
+        .capacity(change.pack())
+        .lock(fee_lock.clone())
+        .build();
+
+    // Build inputs
+    let mut inputs = vec![
+        CellInput::new_builder()
+            .previous_output(market_outpoint)
+            .since(since.pack())
             .build()
     ];
-    for (outpoint, _) in &fee_cells {
+    for (outpoint, _) in &fee_cells {
+        inputs.push(CellInput::new_builder()
+            .previous_output(outpoint.clone())
+            .since(0u64.pack())
+            .build());
+    }
+
+    let tx = TransactionView::new_advanced_builder()
+        .cell_deps(build_cell_deps(contracts))
+        .header_deps(header_deps)
+        .inputs(inputs)
+        .outputs(vec![market_output, change_output])
+        .outputs_data(vec![Bytes::from(new_market_data.to_bytes()).pack(), Bytes::new().pack()])
+        .build();
+
+    let signer = Signer::Market(fee_cells.len());
+    let (tx, _, change) = charge_fee(tx, signer, fee_rate())?;
+    Ok(BuiltTx { tx, signer, change })
+}
+
+/// Propose `settlement` for an open market with nothing pending
+///
+/// The proposal is recorded as `pending_outcome` with `proposed_at` set to the
+/// chain's median time, and only takes effect once build_finalize_tx promotes
+/// it. Claims stay refused until then, as the market is not resolved.
+///
+/// Only permissionless markets are proposed here. A resolver or oracle-quorum
+/// market needs their signature over the proposal, which the server doesn't
+/// hold, and a permissionless market's proposal is fixed until finalized
+/// (ProposalFixed), so a pending proposal is never amended or withdrawn.
+fn build_proposal_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    settlement: Settlement,
+) -> Result<BuiltTx> {
+    debug!(?settlement, "Building proposal transaction");
+
+    let market_cell = get_market_cell(client, &market_outpoint)?;
+    let market_data = MarketData::from_bytes(&market_cell.data)?;
+    if market_data.resolved {
+        return Err(Rejected("Market is already resolved".to_string()).into());
+    }
+    if let Some(pending) = market_data.pending_outcome {
+        return Err(Rejected(format!(
+            "Market already has a pending proposal ({:?}); finalize it once the delay is over",
+            pending,
+        )).into());
+    }
+    if market_authority(client, &market_outpoint)? != ResolutionAuthority::Anyone {
+        return Err(Rejected(
+            "Market's proposals are signed by its resolver or oracle quorum, whose keys this server doesn't hold".to_string(),
+        ).into());
+    }
+
+    let (proposal, scalar) = settlement_terms(settlement, &market_data)?;
+    // An outcome waits for the market's resolution window; cancelling doesn't
+    let since = if proposal == Proposal::Cancel { 0 } else { resolution_since(client, &market_data)? };
+    let proposed_at = median_time_secs(client)?;
+    let new_market_data = MarketData { pending_outcome: Some(proposal), proposed_at, scalar, ..market_data };
+    build_market_data_tx(client, contracts, fee_lock, market_outpoint, &market_cell, &new_market_data, since, Vec::new())
+}
+
+/// Finalize a market's pending proposal: the proposed outcome becomes the
+/// market's resolution once it has stood for RESOLUTION_DELAY_SECS
+///
+/// The wait is a relative timestamp `since` on the market input, so the node
+/// holds the transaction until the proposal's cell is old enough; a
+/// finalization before then is refused here with the time left. A resolver
+/// market also opens its dispute period, counted from the block the proposal
+/// was committed in, which the market script reads from a header dep.
+fn build_finalize_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+) -> Result<BuiltTx> {
+    let market_cell = get_market_cell(client, &market_outpoint)?;
+    let market_data = MarketData::from_bytes(&market_cell.data)?;
+    let Some(proposal) = market_data.pending_outcome else {
+        return Err(Rejected("Market has no pending proposal to finalize".to_string()).into());
+    };
+
+    let ready_at = market_data.proposed_at.saturating_add(RESOLUTION_DELAY_SECS);
+    let median_time = median_time_secs(client)?;
+    if median_time < ready_at {
+        return Err(Rejected(format!(
+            "Proposal can't be finalized before {} (chain time is {}, {}s to go)",
+            ready_at, median_time, ready_at - median_time,
+        )).into());
+    }
+
+    let (header_deps, dispute_until) = match market_authority(client, &market_outpoint)? {
+        ResolutionAuthority::Resolver => {
+            let (block_hash, block_number) = committed_block(client, &market_outpoint)?;
+            (vec![block_hash.pack()], block_number + DISPUTE_PERIOD_BLOCKS)
+        }
+        _ => (Vec::new(), 0),
+    };
+    let new_market_data = MarketData {
+        resolved: true,
+        outcome: proposal == Proposal::Yes,
+        cancelled: proposal == Proposal::Cancel,
+        pending_outcome: None,
+        dispute_until,
+        ..market_data
+    };
+    let since = SINCE_RELATIVE_TIMESTAMP | RESOLUTION_DELAY_SECS;
+    build_market_data_tx(client, contracts, fee_lock, market_outpoint, &market_cell, &new_market_data, since, header_deps)
+}
+
+/// The market contract's finalize delay: a proposal stands for 24 hours
+///
+/// The contract fixes it, so it isn't configurable here: a shorter wait would
+/// only build finalizations the script rejects.
+const RESOLUTION_DELAY_SECS: u64 = 24 * 60 * 60;
+
+/// Blocks a resolver market's outcome stays open to correction, counted from
+/// the block its proposal was committed in (the market contract's period)
+const DISPUTE_PERIOD_BLOCKS: u64 = 17_280;
+
+/// Who may propose and amend a market's resolution, from its type args
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResolutionAuthority {
+    /// Anyone may propose; the proposal is then fixed until finalized
+    Anyone,
+    /// The resolver named in the args signs proposals and amendments
+    Resolver,
+    /// An M-of-N oracle quorum signs proposals and amendments
+    OracleQuorum,
+}
+
+/// Market type flags naming a resolution authority, and the token lock flag
+/// whose 32-byte lock hash ends the args
+const ORACLE_QUORUM_FLAG: u8 = 0x02;
+const RESOLVER_FLAG: u8 = 0x08;
+const TOKEN_LOCK_FLAG: u8 = 0x10;
+
+/// Resolution authority of market type `args`, laid out as the market script
+/// reads them: a 32-byte Type ID, optional 8 bytes of collateral weights, then
+/// an optional flags byte followed by a quorum or resolver section and a token
+/// lock hash
+///
+/// Args that fit no layout are reported as Anyone; the market script refuses
+/// any transaction on such a market anyway.
+fn resolution_authority(args: &[u8]) -> ResolutionAuthority {
+    for base_len in [32, 40] {
+        let Some(&flags) = args.get(base_len) else {
+            continue;
+        };
+        let mut section = &args[base_len + 1..];
+        if flags & TOKEN_LOCK_FLAG != 0 {
+            let Some(len) = section.len().checked_sub(32) else {
+                continue;
+            };
+            section = &section[..len];
+        }
+        let authority = match (flags & ORACLE_QUORUM_FLAG != 0, flags & RESOLVER_FLAG != 0) {
+            (true, false) if section.len() >= 2 && section.len() == 2 + section[1] as usize * 33 => ResolutionAuthority::OracleQuorum,
+            (false, true) if section.len() == 20 => ResolutionAuthority::Resolver,
+            (false, false) if section.is_empty() => ResolutionAuthority::Anyone,
+            _ => continue,
+        };
+        return authority;
+    }
+    ResolutionAuthority::Anyone
+}
+
+/// Resolution authority of the market at `market_outpoint`
+fn market_authority(client: &mut dyn CkbBackend, market_outpoint: &OutPoint) -> Result<ResolutionAuthority> {
+    let tx_hash: H256 = market_outpoint.tx_hash().unpack();
+    let index: u32 = market_outpoint.index().unpack();
+    let transaction = get_transaction_view(client, tx_hash.clone())?;
+    let output = transaction.inner.outputs.get(index as usize)
+        .ok_or_else(|| NotFound(format!("Output {:#x}:{} not found", tx_hash, index)))?;
+    let args = output.type_.as_ref().map(|script| script.args.as_bytes().to_vec()).unwrap_or_default();
+    Ok(resolution_authority(&args))
+}
+
+/// Hash and number of the block that committed the transaction creating `outpoint`
+fn committed_block(client: &mut dyn CkbBackend, outpoint: &OutPoint) -> Result<(H256, u64)> {
+    let tx_hash: H256 = outpoint.tx_hash().unpack();
+    let status = retry_read(RpcRetry::from_env(), "get_transaction", || client.get_transaction(tx_hash.clone()))?
+        .ok_or_else(|| NotFound(format!("Transaction {:#x} not found", tx_hash)))?
+        .tx_status;
+    match (status.block_hash, status.block_number) {
+        (Some(block_hash), Some(block_number)) => Ok((block_hash, block_number.value())),
+        _ => Err(anyhow!("Transaction {:#x} is not committed", tx_hash)),
+    }
+}
+
+/// Absolute `since` with the timestamp metric; the value is unix seconds
+const SINCE_ABSOLUTE_TIMESTAMP: u64 = 0b10 << 61;
+/// Relative `since` with the timestamp metric; the value is seconds since
+/// the input's cell was committed
+const SINCE_RELATIVE_TIMESTAMP: u64 = (1 << 63) | SINCE_ABSOLUTE_TIMESTAMP;
+const SINCE_VALUE_MASK: u64 = 0x00ff_ffff_ffff_ffff;
+
+/// Absolute timestamp `since` marking a market's resolution window as open
+/// (0 for markets without one)
+///
+/// The node only accepts the `since` once its median time has reached
+/// `resolve_after`, so an early resolution is refused here with the time left
+/// rather than left to sit in the pool.
+fn resolution_since(client: &mut dyn CkbBackend, market_data: &MarketData) -> Result<u64> {
+    if market_data.resolve_after == 0 {
+        return Ok(0);
+    }
+
+    let median_time = median_time_secs(client)?;
+    if median_time < market_data.resolve_after {
+        return Err(Rejected(format!(
+            "Market can't be resolved before {} (chain time is {}, {}s to go)",
+            market_data.resolve_after, median_time, market_data.resolve_after - median_time,
+        )).into());
+    }
+    Ok(SINCE_ABSOLUTE_TIMESTAMP | market_data.resolve_after)
+}
+
+/// Absolute block-number `since` showing a market's dispute period is over
+/// (0 for markets without one)
+///
+/// Payouts before `dispute_until` are refused here with the blocks left; the
+/// earliest block a transaction can land in is the one after the tip.
+fn payout_since(client: &mut dyn CkbBackend, market_data: &MarketData) -> Result<u64> {
+    if market_data.dispute_until == 0 {
+        return Ok(0);
+    }
+
+    let next_block = retry_read(RpcRetry::from_env(), "get_tip_block_number", || client.get_tip_block_number())?.value() + 1;
+    if market_data.in_dispute(next_block) {
+        return Err(Rejected(format!(
+            "Market is in its dispute period until block {} ({} blocks to go)",
+            market_data.dispute_until, market_data.dispute_until - next_block,
+        )).into());
+    }
+    Ok(market_data.dispute_until)
+}
+
+/// Send `amount` YES or NO tokens of a market from `fee_lock` to `to`
+/// Returns the transfer's transaction hash.
+#[allow(clippy::too_many_arguments)]
+fn transfer_tokens(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: &OutPoint,
+    is_yes: bool,
+    amount: u128,
+    to: &Script,
+    mode: SendMode,
+) -> Result<H256> {
+    let built = build_transfer_tx(client, contracts, fee_lock, market_outpoint, is_yes, amount, to)?;
+    send_transaction(client, &built.signer.sign(built.tx, privkey)?, mode)
+}
+
+/// Move tokens out of the sender's token cells
+///
+/// One cell is spent when it holds enough, otherwise as many as the amount
+/// needs. Outputs: the destination token cell, a change token cell for
+/// whatever the sender keeps (omitted when nothing is left), and CKB change. The
+/// market cell isn't involved; the token contract only checks that no tokens
+/// are created. The destination cell is funded from fee cells at its minimum.
+fn build_transfer_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: &OutPoint,
+    is_yes: bool,
+    amount: u128,
+    to: &Script,
+) -> Result<BuiltTx> {
+    debug!(amount = %amount, is_yes, "Building transfer transaction");
+
+    validate_token_lock(contracts, to)?;
+    if amount == 0 {
+        return Err(Rejected("Transfer amount must be positive".to_string()).into());
+    }
+
+    let market_cell = get_cell(client, market_outpoint)?;
+    let market_data = MarketData::from_bytes(&market_cell.data)?;
+    let token_type = build_token_type(contracts, &market_cell.market_type()?, market_data.token_script_hash_type()?, is_yes);
+    let token_cells = find_token_cells(client, fee_lock, &token_type, amount)?;
+    let token_amount: u128 = token_cells.iter().map(|(_, _, held)| held).sum();
+    let token_capacity: u64 = token_cells.iter().map(|(_, capacity, _)| capacity).sum();
+    let remaining = token_amount.checked_sub(amount)
+        .ok_or_else(|| Rejected(format!("Transfer of {} exceeds the token balance of {}", amount, token_amount)))?;
+    // Whatever the sender keeps is merged into one cell at the first cell's capacity
+    let kept_capacity = token_cells[0].1;
+
+    let destination_capacity = token_cell_minimum(to, &token_type)?;
+    let fee_rate = fee_rate();
+    let fee_cells = collect_cells_for(client, fee_lock, &[
+        CapacityRequirement { label: "destination token cell", capacity: Shannons(destination_capacity) },
+        CapacityRequirement { label: "fee", capacity: Shannons(fee_allowance(fee_rate) + MIN_CHANGE_CAPACITY) },
+    ])?;
+    let total_fee_input: u64 = fee_cells.iter().map(|(_, cap)| cap).sum();
+
+    let mut outputs = vec![token_cell_output(to, &token_type, destination_capacity)?];
+    let mut outputs_data = vec![Bytes::from(amount.to_le_bytes().to_vec()).pack()];
+
+    // Consumed token capacity the sender doesn't keep comes back as CKB change
+    // (the fee is taken out of it once the transaction's size is known)
+    let change = if remaining > 0 {
+        outputs.push(token_cell_output(fee_lock, &token_type, kept_capacity)?);
+        outputs_data.push(Bytes::from(remaining.to_le_bytes().to_vec()).pack());
+        total_fee_input + token_capacity - kept_capacity - destination_capacity
+    } else {
+        total_fee_input + token_capacity - destination_capacity
+    };
+    outputs.push(CellOutput::new_builder()
+        .capacity(change.pack())
+        .lock(fee_lock.clone())
+        .build());
+    outputs_data.push(Bytes::new().pack());
+
+    let inputs: Vec<CellInput> = token_cells.iter().map(|(outpoint, _, _)| outpoint.clone())
+        .chain(fee_cells.iter().map(|(outpoint, _)| outpoint.clone()))
+        .map(|outpoint| CellInput::new_builder()
+            .previous_output(outpoint)
+            .since(0u64.pack())
+            .build())
+        .collect();
+
+    let tx = TransactionView::new_advanced_builder()
+        .cell_deps(build_cell_deps_with_token(contracts))
+        .inputs(inputs)
+        .outputs(outputs)
+        .outputs_data(outputs_data)
+        .build();
+
+    // Token cells and fee cells share the sender's lock
+    let signer = Signer::FeeCells(token_cells.len() + fee_cells.len());
+    let (tx, _, change) = charge_fee(tx, signer, fee_rate)?;
+    Ok(BuiltTx { tx, signer, change })
+}
+
+fn claim_tokens(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    amount: u128,
+) -> Result<OutPoint> {
+    let built = build_claim_tx(client, contracts, fee_lock, market_outpoint, amount)?;
+    submit_market_tx(client, privkey, contracts, "claim", &built, SendMode::Wait)
+}
+
+fn build_claim_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    amount: u128,
+) -> Result<BuiltTx> {
+    build_side_claim_tx(client, contracts, fee_lock, market_outpoint, None, amount)
+}
+
+/// Build a claim of `amount` tokens of `side` (YES = true)
+/// Binary markets pay only the winning side, which `None` picks; scalar
+/// markets pay both LONG (YES) and SHORT (NO), so the side must be named.
+fn build_side_claim_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    side: Option<bool>,
+    amount: u128,
+) -> Result<BuiltTx> {
+    debug!(amount = %amount, ?side, "Building claim transaction");
+
+    // Get current market cell
+    let market_cell = get_market_cell(client, &market_outpoint)?;
+    let market_data = MarketData::from_bytes(&market_cell.data)?;
+    let market_capacity: u64 = market_cell.capacity;
+
+    if !market_data.resolved {
+        return Err(Rejected("Market is not resolved".to_string()).into());
+    }
+    if market_data.cancelled {
+        return Err(Rejected("Market was cancelled, refund complete sets instead".to_string()).into());
+    }
+    let since = payout_since(client, &market_data)?;
+
+    // Determine the claimed token type (YES = true, NO = false)
+    let is_winning_yes = claim_side(&market_data, side)?;
+    let market_type = market_cell.market_type()?;
+    let winning_token_type = build_token_type(contracts, &market_type, market_data.token_script_hash_type()?, is_winning_yes);
+
+    // Holding only the losing side gets its own answer rather than "not found"
+    if market_data.scalar.is_none() && lookup_token_cell(client, fee_lock, &winning_token_type)?.is_none() {
+        let losing_token_type = build_token_type(contracts, &market_type, market_data.token_script_hash_type()?, !is_winning_yes);
+        if lookup_token_cell(client, fee_lock, &losing_token_type)?.is_some() {
+            let (won, lost) = if is_winning_yes { ("YES", "NO") } else { ("NO", "YES") };
+            return Err(Rejected(format!("Your {} tokens lost ({} won); nothing to claim", lost, won)).into());
+        }
+    }
+
+    // Find user's winning token cells (several if no single one holds enough)
+    let token_cells = find_token_cells(client, fee_lock, &winning_token_type, amount)?;
+    let token_amount: u128 = token_cells.iter().map(|(_, _, held)| held).sum();
+    let token_capacity: u64 = token_cells.iter().map(|(_, capacity, _)| capacity).sum();
+
+    if token_amount < amount {
+        return Err(Rejected(format!("Insufficient token balance: have {} need {}", token_amount, amount)).into());
+    }
+
+    // Calculate claim amount (the market's collateral unit per winning token,
+    // or the side's share of it along a scalar market's range)
+    let claim_amount = market_data.claim_payout(is_winning_yes, amount)?;
+    if claim_amount == 0 {
+        return Err(Rejected(format!("{} tokens pay nothing at the resolved value", if is_winning_yes { "LONG" } else { "SHORT" })).into());
+    }
+    let new_market_capacity = market_capacity.checked_sub(claim_amount)
+        .ok_or_else(|| anyhow!("Market holds {} shannons, cannot pay {}", market_capacity, claim_amount))?;
+
+    // Catch payout arithmetic bugs before broadcast, with the contract's semantics
+    if market_data.scalar.is_none() {
+        verify_claim_payout(market_capacity, new_market_capacity, amount, market_data.shannons_per_token)?;
+    }
+
+    // Calculate new token amount
+    let new_token_amount = token_amount - amount;
+
+    // Fee markets pay their cut of the claim to the recipient's own cell
+    let protocol_fee = market_data.claim_fee(claim_amount);
+    let fee_output = if protocol_fee > 0 {
+        Some(protocol_fee_output(contracts.network, &market_data, protocol_fee)?)
+    } else {
+        None
+    };
+
+    // Collect fee cells
+    let fee_cells = collect_cells(client, fee_lock, FEE_CELL_CAPACITY)?;
+    let total_fee_input: u64 = fee_cells.iter().map(|(_, cap)| cap).sum();
+
+    // A partial claim re-outputs the remaining tokens in one cell at the first
+    // cell's capacity; a full one re-outputs none
+    let kept_capacity = if new_token_amount > 0 { token_cells[0].1 } else { 0 };
+    let change = claim_change(total_fee_input, claim_amount, protocol_fee, token_capacity, kept_capacity)?;
+
+    // New market data (reduce winning supply)
+    let new_market_data = if is_winning_yes {
+        market_data.with_supplies(market_data.yes_supply() - amount, market_data.no_supply())
+    } else {
+        market_data.with_supplies(market_data.yes_supply(), market_data.no_supply() - amount)
+    }.to_bytes();
+
+    // Build outputs
+    let market_output = CellOutput::new_builder()
+        .capacity(new_market_capacity.pack())
+        .lock(build_market_lock(contracts))
+        .type_(Some(market_type).pack())
+        .build();
+
+    let mut outputs = vec![market_output];
+    let mut outputs_data = vec![Bytes::from(new_market_data).pack()];
+
+    // If there are remaining tokens, output updated token cell
+    if new_token_amount > 0 {
+        outputs.push(token_cell_output(fee_lock, &winning_token_type, kept_capacity)?);
+        outputs_data.push(Bytes::from(new_token_amount.to_le_bytes().to_vec()).pack());
+    }
+
+    if let Some(fee_output) = fee_output {
+        outputs.push(fee_output);
+        outputs_data.push(Bytes::new().pack());
+    }
+
+    // Change output
+    let change_output = CellOutput::new_builder()
+        .capacity(change.pack())
+        .lock(fee_lock.clone())
+        .build();
+    outputs.push(change_output);
+    outputs_data.push(Bytes::new().pack());
+
+    // Build inputs: market cell, token cells, fee cells
+    let mut inputs = vec![
+        CellInput::new_builder()
+            .previous_output(market_outpoint)
+            .since(since.pack())
+            .build(),
+    ];
+    for (outpoint, _, _) in &token_cells {
+        inputs.push(CellInput::new_builder()
+            .previous_output(outpoint.clone())
+            .since(0u64.pack())
+            .build());
+    }
+    for (outpoint, _) in &fee_cells {
+        inputs.push(CellInput::new_builder()
+            .previous_output(outpoint.clone())
+            .since(0u64.pack())
+            .build());
+    }
+
+    let tx = TransactionView::new_advanced_builder()
+        .cell_deps(build_cell_deps_with_token(contracts))
+        .inputs(inputs)
+        .outputs(outputs)
+        .outputs_data(outputs_data)
+        .build();
+
+    // Market (always-success, dummy witness), tokens (signed), fee inputs (signed)
+    let signer = Signer::MarketAndTokens(token_cells.len() + fee_cells.len());
+    let (tx, _, change) = charge_fee(tx, signer, fee_rate())?;
+    Ok(BuiltTx { tx, signer, change })
+}
+
+/// Change of a claim before the transaction fee (charged once the size is
+/// known): fee inputs + claimed CKB - protocol fee + consumed token cells'
+/// capacity not re-output
+///
+/// Outputs then equal inputs whether a token cell is kept (`kept_capacity`
+/// of `token_capacity` goes back into it) or the whole balance is claimed and
+/// all of it is released to change.
+fn claim_change(fee_input: u64, claim_amount: u64, protocol_fee: u64, token_capacity: u64, kept_capacity: u64) -> Result<u64> {
+    let released_token_capacity = token_capacity.checked_sub(kept_capacity)
+        .ok_or_else(|| anyhow!("Kept token capacity {} exceeds the {} consumed", kept_capacity, token_capacity))?;
+    let payout = claim_amount.checked_sub(protocol_fee)
+        .ok_or_else(|| anyhow!("Protocol fee {} exceeds the claim of {}", protocol_fee, claim_amount))?;
+    fee_input.checked_add(payout)
+        .and_then(|change| change.checked_add(released_token_capacity))
+        .ok_or_else(|| anyhow!("Claim change overflows u64"))
+}
+
+/// Side a claim burns: the named one on a scalar market, otherwise the
+/// winning one, which a named side has to match
+fn claim_side(market_data: &MarketData, side: Option<bool>) -> Result<bool, Rejected> {
+    let name = |yes: bool| if yes { "YES" } else { "NO" };
+    match (market_data.scalar, side) {
+        (Some(_), Some(side)) => Ok(side),
+        (Some(_), None) => Err(Rejected("Scalar markets pay LONG (YES) and SHORT (NO), name the side to claim".to_string())),
+        (None, Some(side)) if side != market_data.outcome => {
+            Err(Rejected(format!("{} tokens lost, only {} tokens can be claimed", name(side), name(market_data.outcome))))
+        }
+        (None, _) => Ok(market_data.outcome),
+    }
+}
+
+/// Refund complete sets of a cancelled market (N YES + N NO → N × 100 CKB)
+fn refund_tokens(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    amount: u128,
+) -> Result<OutPoint> {
+    let built = build_refund_tx(client, contracts, fee_lock, market_outpoint, amount)?;
+    submit_market_tx(client, privkey, contracts, "refund", &built, SendMode::Wait)
+}
+
+fn build_refund_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    amount: u128,
+) -> Result<BuiltTx> {
+    build_set_burn_tx(client, contracts, fee_lock, market_outpoint, amount, |market_data| {
+        if !market_data.resolved || !market_data.cancelled {
+            return Err(Rejected("Market is not cancelled".to_string()).into());
+        }
+        Ok(())
+    })
+}
+
+/// Redeem complete sets of a market resolved to YES or NO (N YES + N NO → N sets of collateral)
+fn redeem_sets(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    amount: u128,
+) -> Result<OutPoint> {
+    let built = build_redeem_sets_tx(client, contracts, fee_lock, market_outpoint, amount)?;
+    submit_market_tx(client, privkey, contracts, "redeem-sets", &built, SendMode::Wait)
+}
+
+/// Burn complete sets of a resolved market for their full collateral
+/// The losing half is worthless alone, but paired with a winning token it
+/// returns what the set was minted for.
+fn build_redeem_sets_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    amount: u128,
+) -> Result<BuiltTx> {
+    build_set_burn_tx(client, contracts, fee_lock, market_outpoint, amount, |market_data| {
+        if !market_data.resolved || market_data.cancelled {
+            return Err(Rejected("Market is not resolved to an outcome; burn or refund instead".to_string()).into());
+        }
+        Ok(())
+    })
+}
+
+fn burn_tokens(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    amount: u128,
+) -> Result<OutPoint> {
+    let built = build_burn_tx(client, contracts, fee_lock, market_outpoint, amount)?;
+    submit_market_tx(client, privkey, contracts, "burn", &built, SendMode::Wait)
+}
+
+/// Redeem complete sets of an open market (the reverse of a mint)
+fn build_burn_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    amount: u128,
+) -> Result<BuiltTx> {
+    build_set_burn_tx(client, contracts, fee_lock, market_outpoint, amount, |market_data| {
+        if market_data.resolved {
+            return Err(Rejected("Market is resolved; claim or refund instead of burning".to_string()).into());
+        }
+        if market_data.pending_outcome.is_some() {
+            return Err(Rejected("Market has a pending resolution; finalize it first".to_string()).into());
+        }
+        Ok(())
+    })
+}
+
+/// Pair the YES and NO token cells a complete-set burn spends
+/// Holding only one side can't form a set, so that fails before anything is built.
+fn complete_set_cells(
+    yes: Option<(OutPoint, u64, u128)>,
+    no: Option<(OutPoint, u64, u128)>,
+    amount: u128,
+) -> Result<((OutPoint, u64, u128), (OutPoint, u64, u128))> {
+    match (yes, no) {
+        (Some(yes), Some(no)) => {
+            if yes.2 < amount || no.2 < amount {
+                return Err(Rejected(format!("Insufficient complete sets: have {} YES + {} NO, need {} of each",
+                                            yes.2, no.2, amount)).into());
+            }
+            Ok((yes, no))
+        }
+        (Some(_), None) => Err(Rejected("Need complete sets: holding YES tokens but no NO tokens".to_string()).into()),
+        (None, Some(_)) => Err(Rejected("Need complete sets: holding NO tokens but no YES tokens".to_string()).into()),
+        (None, None) => Err(Rejected("Need complete sets: holding no YES or NO tokens".to_string()).into()),
+    }
+}
+
+/// Burn `amount` complete sets for their collateral once `check` accepts the market
+/// Shared by refunds (cancelled markets) and burns (open markets).
+fn build_set_burn_tx(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+    amount: u128,
+    check: impl Fn(&MarketData) -> Result<()>,
+) -> Result<BuiltTx> {
+    debug!(amount = %amount, "Building set burn transaction");
+
+    // Get current market cell
+    let market_cell = get_market_cell(client, &market_outpoint)?;
+    let market_data = MarketData::from_bytes(&market_cell.data)?;
+    let market_capacity: u64 = market_cell.capacity;
+
+    check(&market_data)?;
+    if amount == 0 {
+        return Err(Rejected("Amount must be positive".to_string()).into());
+    }
+    let since = payout_since(client, &market_data)?;
+
+    // Burning complete sets needs both sides
+    let market_type = market_cell.market_type()?;
+    let hash_type = market_data.token_script_hash_type()?;
+    let yes_token_type = build_token_type(contracts, &market_type, hash_type, true);
+    let no_token_type = build_token_type(contracts, &market_type, hash_type, false);
+    let ((yes_outpoint, yes_capacity, yes_amount), (no_outpoint, no_capacity, no_amount)) = complete_set_cells(
+        lookup_token_cell(client, fee_lock, &yes_token_type)?,
+        lookup_token_cell(client, fee_lock, &no_token_type)?,
+        amount,
+    )?;
+
+    // Full set collateral: the market's collateral unit per complete set
+    let refund_amount = market_data.checked_collateral(amount)?.as_shannons();
+    let new_market_capacity = market_capacity.checked_sub(refund_amount)
+        .ok_or_else(|| anyhow!("Market holds {} shannons, cannot refund {}", market_capacity, refund_amount))?;
+
+    // Collect fee cells
+    let fee_cells = collect_cells(client, fee_lock, FEE_CELL_CAPACITY)?;
+    let total_fee_input: u64 = fee_cells.iter().map(|(_, cap)| cap).sum();
+
+    // Token cells that are fully burned hand their capacity back as change
+    let new_yes_amount = yes_amount - amount;
+    let new_no_amount = no_amount - amount;
+    let released_token_capacity = if new_yes_amount > 0 { 0 } else { yes_capacity }
+        + if new_no_amount > 0 { 0 } else { no_capacity };
+    let change = total_fee_input + refund_amount + released_token_capacity;
+
+    let new_market_data = market_data
+        .with_supplies(market_data.yes_supply() - amount, market_data.no_supply() - amount)
+        .to_bytes();
+
+    // Build outputs
+    let market_output = CellOutput::new_builder()
+        .capacity(new_market_capacity.pack())
+        .lock(build_market_lock(contracts))
+        .type_(Some(market_type).pack())
+        .build();
+
+    let mut outputs = vec![market_output];
+    let mut outputs_data = vec![Bytes::from(new_market_data).pack()];
+
+    for (token_type, capacity, remaining) in [
+        (yes_token_type, yes_capacity, new_yes_amount),
+        (no_token_type, no_capacity, new_no_amount),
+    ] {
+        if remaining > 0 {
+            outputs.push(token_cell_output(fee_lock, &token_type, capacity)?);
+            outputs_data.push(Bytes::from(remaining.to_le_bytes().to_vec()).pack());
+        }
+    }
+
+    outputs.push(CellOutput::new_builder()
+        .capacity(change.pack())
+        .lock(fee_lock.clone())
+        .build());
+    outputs_data.push(Bytes::new().pack());
+
+    // Build inputs: market cell, YES token cell, NO token cell, fee cells
+    let mut inputs = vec![
+        CellInput::new_builder()
+            .previous_output(market_outpoint)
+            .since(since.pack())
+            .build(),
+    ];
+    for outpoint in [yes_outpoint, no_outpoint].into_iter().chain(fee_cells.iter().map(|(outpoint, _)| outpoint.clone())) {
         inputs.push(CellInput::new_builder()
-            .previous_output(outpoint.clone())
+            .previous_output(outpoint)
             .since(0u64.pack())
             .build());
     }
 
-    // Build transaction
     let tx = TransactionView::new_advanced_builder()
         .cell_deps(build_cell_deps_with_token(contracts))
         .inputs(inputs)
-        .outputs(vec![market_output, yes_token_output, no_token_output, change_output])
-        .outputs_data(vec![
-            Bytes::from(new_market_data).pack(),
-            Bytes::from(token_amount_bytes.clone()).pack(),
-            Bytes::from(token_amount_bytes).pack(),
-            Bytes::new().pack()
-        ])
+        .outputs(outputs)
+        .outputs_data(outputs_data)
+        .build();
+
+    // Market (always-success, dummy witness), token cells + fee inputs (signed)
+    let signer = Signer::MarketAndTokens(2 + fee_cells.len());
+    let (tx, _, change) = charge_fee(tx, signer, fee_rate())?;
+    Ok(BuiltTx { tx, signer, change })
+}
+
+/// Close out a market as far as the wallet can
+///
+/// A market resolves through a proposal standing RESOLUTION_DELAY_SECS, so
+/// draining may take more than one call: an open market with nothing pending
+/// is proposed cancelled and left there, and a pending proposal is finalized
+/// once its delay is over, whatever it proposes (left pending before then).
+/// A resolved market has the wallet refund the complete sets it holds
+/// (cancelled) or claim its winning tokens (both sides of a scalar market),
+/// capped at the market's supply since devnet markets share token types.
+/// Returns the transactions sent and the final market outpoint.
+fn drain_market(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    contracts: &ContractInfo,
+    fee_lock: &Script,
+    market_outpoint: OutPoint,
+) -> Result<(Vec<H256>, OutPoint)> {
+    let mut sent = Vec::new();
+    let mut market = market_outpoint;
+
+    let market_data = MarketData::from_bytes(&get_cell(client, &market)?.data)?;
+    if !market_data.resolved {
+        if market_data.pending_outcome.is_none() {
+            market = propose_resolution(client, privkey, contracts, fee_lock, market, Settlement::Cancelled)?;
+            sent.push(market.tx_hash().unpack());
+            return Ok((sent, market));
+        }
+        let built = match build_finalize_tx(client, contracts, fee_lock, market.clone()) {
+            Ok(built) => built,
+            // Still within its delay
+            Err(err) if err.downcast_ref::<Rejected>().is_some() => return Ok((sent, market)),
+            Err(err) => return Err(err),
+        };
+        market = submit_market_tx(client, privkey, contracts, "finalize", &built, SendMode::Wait)?;
+        sent.push(market.tx_hash().unpack());
+    }
+
+    let market_cell = get_cell(client, &market)?;
+    let market_data = MarketData::from_bytes(&market_cell.data)?;
+    let market_type = market_cell.market_type()?;
+    let hash_type = market_data.token_script_hash_type()?;
+    let mut held = |is_yes: bool| -> Result<u128> {
+        let token_type = build_token_type(contracts, &market_type, hash_type, is_yes);
+        Ok(lookup_token_cell(client, fee_lock, &token_type)?.map_or(0, |(_, _, amount)| amount))
+    };
+
+    if market_data.cancelled {
+        let sets = held(true)?.min(held(false)?).min(market_data.yes_supply()).min(market_data.no_supply());
+        if sets > 0 {
+            market = refund_tokens(client, privkey, contracts, fee_lock, market, sets)?;
+            sent.push(market.tx_hash().unpack());
+        }
+    } else if market_data.scalar.is_some() {
+        // Both sides of a scalar market may be worth something
+        let sides = [(true, held(true)?.min(market_data.yes_supply())), (false, held(false)?.min(market_data.no_supply()))];
+        for (is_long, tokens) in sides {
+            if tokens > 0 && market_data.claim_payout(is_long, tokens)? > 0 {
+                let built = build_side_claim_tx(client, contracts, fee_lock, market, Some(is_long), tokens)?;
+                market = submit_market_tx(client, privkey, contracts, "claim", &built, SendMode::Wait)?;
+                sent.push(market.tx_hash().unpack());
+            }
+        }
+    } else {
+        let winning_supply = if market_data.outcome { market_data.yes_supply() } else { market_data.no_supply() };
+        let winners = held(market_data.outcome)?.min(winning_supply);
+        if winners > 0 {
+            market = claim_tokens(client, privkey, contracts, fee_lock, market, winners)?;
+            sent.push(market.tx_hash().unpack());
+        }
+    }
+
+    Ok((sent, market))
+}
+
+/// Result of merging fee-wallet cells
+struct Consolidation {
+    tx_hash: H256,
+    merged: usize,
+    fee: u64,
+}
+
+/// Merge the fee wallet's pure-CKB cells into one once there are at least
+/// `threshold` of them. Returns None when below the threshold.
+fn consolidate_cells(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    fee_lock: &Script,
+    network: Network,
+    threshold: usize,
+) -> Result<Option<Consolidation>> {
+    let cells = list_pure_cells(client, fee_lock)?;
+    if cells.len() < threshold.max(2) {
+        return Ok(None);
+    }
+
+    debug!(cells = cells.len(), "Consolidating fee cells");
+
+    let total_input: u64 = cells.iter().map(|(_, cap)| cap).sum();
+
+    let inputs: Vec<CellInput> = cells.iter()
+        .map(|(outpoint, _)| {
+            CellInput::new_builder()
+                .previous_output(outpoint.clone())
+                .since(0u64.pack())
+                .build()
+        })
+        .collect();
+
+    // The single output is the change, which pays the fee
+    let output = CellOutput::new_builder()
+        .capacity(total_input.pack())
+        .lock(fee_lock.clone())
+        .build();
+
+    let tx = TransactionView::new_advanced_builder()
+        .cell_dep(secp256k1_dep(network))
+        .inputs(inputs)
+        .output(output)
+        .output_data(Bytes::new().pack())
+        .build();
+
+    let signer = Signer::FeeCells(cells.len());
+    let (tx, fee, _) = charge_fee(tx, signer, fee_rate())?;
+    let tx = signer.sign(tx, privkey)?;
+    let tx_hash = send_transaction(client, &tx, SendMode::Wait)?;
+
+    Ok(Some(Consolidation {
+        tx_hash,
+        merged: cells.len(),
+        fee,
+    }))
+}
+
+/// Smallest capacity a token cell with this lock and type can hold,
+/// counting its 16-byte amount
+fn token_cell_minimum(lock: &Script, token_type: &Script) -> Result<u64> {
+    let cell = CellOutput::new_builder()
+        .lock(lock.clone())
+        .type_(Some(token_type.clone()).pack())
+        .build();
+    occupied_capacity(&cell, 16)
+}
+
+/// Build a token cell output, refusing a capacity below the minimum for its lock and type
+fn token_cell_output(lock: &Script, token_type: &Script, capacity: u64) -> Result<CellOutput> {
+    let minimum = token_cell_minimum(lock, token_type)?;
+    if capacity < minimum {
+        return Err(anyhow!(
+            "Token cell of {} shannons is below the {} shannon minimum for its lock and type",
+            capacity, minimum
+        ));
+    }
+    Ok(CellOutput::new_builder()
+        .capacity(capacity.pack())
+        .lock(lock.clone())
+        .type_(Some(token_type.clone()).pack())
+        .build())
+}
+
+/// Split a token cell into one cell per entry of `amounts`, which must add up
+/// to the cell's token amount
+///
+/// Every part gets the minimum token cell capacity and the first part keeps
+/// the rest, so a cell too small to fund all parts is rejected rather than
+/// producing dust.
+fn split_token_cell(cell: &CellOutput, token_amount: u128, amounts: &[u128]) -> Result<Vec<(CellOutput, u128)>> {
+    let token_type = cell.type_().to_opt()
+        .ok_or_else(|| anyhow!("Cell to split has no token type"))?;
+    if amounts.len() < 2 || amounts.contains(&0) {
+        return Err(anyhow!("A split needs at least two non-zero amounts"));
+    }
+    if amounts.iter().try_fold(0u128, |total, amount| total.checked_add(*amount)) != Some(token_amount) {
+        return Err(anyhow!("Split amounts do not add up to the cell's {} tokens", token_amount));
+    }
+
+    let lock = cell.lock();
+    let capacity: u64 = cell.capacity().unpack();
+    let minimum = token_cell_minimum(&lock, &token_type)?;
+    let funded = minimum * (amounts.len() as u64 - 1);
+    let first_capacity = capacity.checked_sub(funded)
+        .filter(|first| *first >= minimum)
+        .ok_or_else(|| anyhow!(
+            "Splitting a {} shannon token cell {} ways would create a cell below the {} shannon minimum",
+            capacity, amounts.len(), minimum
+        ))?;
+
+    amounts.iter().enumerate()
+        .map(|(i, amount)| {
+            let part_capacity = if i == 0 { first_capacity } else { minimum };
+            Ok((token_cell_output(&lock, &token_type, part_capacity)?, *amount))
+        })
+        .collect()
+}
+
+/// Client-side mirror of the market contract's claim payout check
+///
+/// Recomputes capacity_decrease == winning_burned × shannons_per_token with
+/// the same checked arithmetic as `validate_claim`, naming the contract error
+/// the transaction would have been rejected with.
+fn verify_claim_payout(input_capacity: u64, output_capacity: u64, winning_burned: u128, shannons_per_token: u64) -> Result<()> {
+    if winning_burned == 0 {
+        return Err(anyhow!("Claim burns no winning tokens (contract error SupplyDecrease)"));
+    }
+
+    let capacity_decrease = input_capacity.checked_sub(output_capacity)
+        .ok_or_else(|| anyhow!("Claim must decrease market capacity: {} -> {}", input_capacity, output_capacity))?;
+
+    let expected: u64 = winning_burned
+        .checked_mul(shannons_per_token as u128)
+        .and_then(|shannons| shannons.try_into().ok())
+        .ok_or_else(|| anyhow!("Claim payout overflows for {} tokens (contract error Encoding)", winning_burned))?;
+
+    if capacity_decrease != expected {
+        return Err(anyhow!(
+            "Claim payout mismatch: capacity decrease {} != {} tokens × {} shannons = {} (contract error InsufficientCollateral)",
+            capacity_decrease, winning_burned, shannons_per_token, expected
+        ));
+    }
+
+    Ok(())
+}
+
+/// Market invariant violations, named and numbered after the market type
+/// script's error codes so an off-chain verdict reads like the on-chain one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i8)]
+enum MarketError {
+    InvalidMarketData = 10,
+    MultipleMarketCells = 11,
+    SupplyDecrease = 12,
+    UnequalSupplyIncrease = 13,
+    InsufficientCollateral = 14,
+    LockScriptChanged = 15,
+}
+
+impl std::fmt::Display for MarketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} (contract error {})", self, *self as i8)
+    }
+}
+
+impl std::error::Error for MarketError {}
+
+#[cfg(test)]
+impl MarketError {
+    /// Every variant, for checks that must cover them all
+    const ALL: [MarketError; 6] = [
+        MarketError::InvalidMarketData,
+        MarketError::MultipleMarketCells,
+        MarketError::SupplyDecrease,
+        MarketError::UnequalSupplyIncrease,
+        MarketError::InsufficientCollateral,
+        MarketError::LockScriptChanged,
+    ];
+}
+
+/// Client-side mirror of the market contract's transition and claim rules
+///
+/// Checks an unsigned or externally provided transaction against its resolved
+/// input cells (same order as the inputs) before it is signed or broadcast:
+/// one market cell in and out (or a finished market closed outright, see
+/// validate_market_close), a preserved market lock, complete sets minted
+/// and burned against exactly the market's collateral unit each (fixed at
+/// creation, where it must be positive), claims paying only the winning
+/// side, refunds of cancelled markets in complete sets, and supplies that
+/// track the token cells. Transactions without a market cell pass unchecked.
+/// The rules duplicate the contract on purpose and must change with it.
+fn validate_market_tx(
+    contracts: &ContractInfo,
+    tx: &TransactionView,
+    resolved_inputs: &[(CellOutput, Bytes)],
+) -> Result<(), MarketError> {
+    let outputs: Vec<(CellOutput, Bytes)> = tx.outputs().into_iter()
+        .zip(tx.outputs_data().into_iter().map(|data| data.raw_data()))
+        .collect();
+
+    // The market is the one whose cell is spent (or, on creation, output) with
+    // the deployed market code; its type script carries the market's own args
+    let deployed = build_market_type(contracts);
+    let Some(market_type) = resolved_inputs.iter().chain(outputs.iter())
+        .filter_map(|(cell, _)| cell.type_().to_opt())
+        .find(|script| {
+            script.code_hash().as_slice() == deployed.code_hash().as_slice()
+                && script.hash_type().as_slice() == deployed.hash_type().as_slice()
+        })
+    else {
+        return Ok(());
+    };
+    let market_hash = market_type.calc_script_hash();
+    let is_market = |output: &CellOutput| {
+        output.type_().to_opt().map_or(false, |script| script.calc_script_hash().as_slice() == market_hash.as_slice())
+    };
+    let market_inputs: Vec<&(CellOutput, Bytes)> = resolved_inputs.iter().filter(|(cell, _)| is_market(cell)).collect();
+    let market_outputs: Vec<&(CellOutput, Bytes)> = outputs.iter().filter(|(cell, _)| is_market(cell)).collect();
+
+    if market_inputs.is_empty() && market_outputs.is_empty() {
+        return Ok(());
+    }
+    if let ([(_, input_bytes)], []) = (market_inputs.as_slice(), market_outputs.as_slice()) {
+        // Close: a finished market is consumed outright
+        let input_data = MarketData::from_bytes(input_bytes).map_err(|_| MarketError::InvalidMarketData)?;
+        if input_data.is_closable() {
+            return validate_market_close(contracts, &market_type, &input_data, resolved_inputs, &outputs);
+        }
+    }
+    if market_inputs.len() > 1 || market_outputs.len() != 1 {
+        return Err(MarketError::MultipleMarketCells);
+    }
+
+    let (output_cell, output_bytes) = market_outputs[0];
+    let output_data = MarketData::from_bytes(output_bytes).map_err(|_| MarketError::InvalidMarketData)?;
+
+    let Some((input_cell, input_bytes)) = market_inputs.first() else {
+        // Creation: the market starts open and empty
+        if output_data.resolved || output_data.yes_supply() != 0 || output_data.no_supply() != 0
+            || output_data.shannons_per_token == 0
+        {
+            return Err(MarketError::InvalidMarketData);
+        }
+        return Ok(());
+    };
+    let input_data = MarketData::from_bytes(input_bytes).map_err(|_| MarketError::InvalidMarketData)?;
+
+    if input_cell.lock().as_slice() != output_cell.lock().as_slice() {
+        return Err(MarketError::LockScriptChanged);
+    }
+    if input_data.token_code_hash != output_data.token_code_hash
+        || input_data.hash_type != output_data.hash_type
+        || input_data.shannons_per_token != output_data.shannons_per_token
+        || input_data.supplies.is_some() != output_data.supplies.is_some()
+    {
+        return Err(MarketError::InvalidMarketData);
+    }
+
+    let hash_type = input_data.token_script_hash_type().map_err(|_| MarketError::InvalidMarketData)?;
+    let yes_hash = build_token_type(contracts, &market_type, hash_type, true).calc_script_hash();
+    let no_hash = build_token_type(contracts, &market_type, hash_type, false).calc_script_hash();
+    let count_tokens = |cells: &[(CellOutput, Bytes)]| -> Result<(u128, u128), MarketError> {
+        let (mut yes, mut no) = (0u128, 0u128);
+        for (cell, data) in cells {
+            let Some(type_hash) = cell.type_().to_opt().map(|script| script.calc_script_hash()) else {
+                continue;
+            };
+            let side = if type_hash.as_slice() == yes_hash.as_slice() {
+                &mut yes
+            } else if type_hash.as_slice() == no_hash.as_slice() {
+                &mut no
+            } else {
+                continue;
+            };
+            let amount: [u8; 16] = data.get(..16)
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or(MarketError::InvalidMarketData)?;
+            *side = side.checked_add(u128::from_le_bytes(amount)).ok_or(MarketError::InvalidMarketData)?;
+        }
+        Ok((yes, no))
+    };
+    let (yes_in, no_in) = count_tokens(resolved_inputs)?;
+    let (yes_out, no_out) = count_tokens(&outputs)?;
+
+    // Supplies recorded in the market data must follow the token cells
+    let track = |supply: u128, before: u128, after: u128| {
+        supply.checked_add(after).and_then(|total| total.checked_sub(before))
+    };
+    if track(input_data.yes_supply(), yes_in, yes_out) != Some(output_data.yes_supply())
+        || track(input_data.no_supply(), no_in, no_out) != Some(output_data.no_supply())
+    {
+        return Err(MarketError::InvalidMarketData);
+    }
+
+    let to_capacity = |tokens: u128| -> Result<u64, MarketError> {
+        input_data.collateral_for(tokens).map(Shannons::as_shannons).ok_or(MarketError::InsufficientCollateral)
+    };
+    let input_capacity: u64 = input_cell.capacity().unpack();
+    let output_capacity: u64 = output_cell.capacity().unpack();
+
+    if input_data.resolved {
+        if !output_data.resolved
+            || output_data.outcome != input_data.outcome
+            || output_data.cancelled != input_data.cancelled
+        {
+            return Err(MarketError::InvalidMarketData);
+        }
+        let released = input_capacity.checked_sub(output_capacity).ok_or(MarketError::InsufficientCollateral)?;
+        let yes_burned = yes_in.checked_sub(yes_out).ok_or(MarketError::InvalidMarketData)?;
+        let no_burned = no_in.checked_sub(no_out).ok_or(MarketError::InvalidMarketData)?;
+
+        let redeemed = if input_data.cancelled {
+            // Refunds burn complete sets
+            if yes_burned != no_burned {
+                return Err(MarketError::UnequalSupplyIncrease);
+            }
+            yes_burned
+        } else {
+            let (winning, losing) = if input_data.outcome { (yes_burned, no_burned) } else { (no_burned, yes_burned) };
+            if losing != 0 {
+                return Err(MarketError::InvalidMarketData);
+            }
+            winning
+        };
+        if released != to_capacity(redeemed)? {
+            return Err(MarketError::InsufficientCollateral);
+        }
+        return Ok(());
+    }
+
+    // Open market: mint or burn complete sets, or resolve without moving value
+    let (sets, capacity_change) = if output_capacity >= input_capacity {
+        let yes_minted = yes_out.checked_sub(yes_in).ok_or(MarketError::SupplyDecrease)?;
+        let no_minted = no_out.checked_sub(no_in).ok_or(MarketError::SupplyDecrease)?;
+        if yes_minted != no_minted {
+            return Err(MarketError::UnequalSupplyIncrease);
+        }
+        (yes_minted, output_capacity - input_capacity)
+    } else {
+        let yes_burned = yes_in.checked_sub(yes_out).ok_or(MarketError::UnequalSupplyIncrease)?;
+        let no_burned = no_in.checked_sub(no_out).ok_or(MarketError::UnequalSupplyIncrease)?;
+        if yes_burned != no_burned {
+            return Err(MarketError::UnequalSupplyIncrease);
+        }
+        (yes_burned, input_capacity - output_capacity)
+    };
+    if capacity_change != to_capacity(sets)? {
+        return Err(MarketError::InsufficientCollateral);
+    }
+    if output_data.resolved && sets != 0 {
+        return Err(MarketError::InvalidMarketData);
+    }
+
+    Ok(())
+}
+
+/// Client-side mirror of the contract's close rules (see validate_market_tx)
+/// Sweep terms make the sweeper's authorization mandatory, and the closing
+/// market's tokens can't appear in the outputs.
+fn validate_market_close(
+    contracts: &ContractInfo,
+    market_type: &Script,
+    input_data: &MarketData,
+    resolved_inputs: &[(CellOutput, Bytes)],
+    outputs: &[(CellOutput, Bytes)],
+) -> Result<(), MarketError> {
+    if let Some(sweep) = input_data.sweep {
+        let authorized = resolved_inputs.iter()
+            .any(|(cell, _)| cell.lock().calc_script_hash().as_slice() == sweep.sweeper_lock_hash);
+        if !authorized {
+            return Err(MarketError::InvalidMarketData);
+        }
+    }
+
+    let hash_type = input_data.token_script_hash_type().map_err(|_| MarketError::InvalidMarketData)?;
+    let token_hashes = [true, false].map(|is_yes| build_token_type(contracts, market_type, hash_type, is_yes).calc_script_hash());
+    let mints_tokens = outputs.iter().any(|(cell, _)| {
+        cell.type_().to_opt()
+            .is_some_and(|script| token_hashes.iter().any(|hash| hash.as_slice() == script.calc_script_hash().as_slice()))
+    });
+    if mints_tokens {
+        return Err(MarketError::InvalidMarketData);
+    }
+    Ok(())
+}
+
+/// Parse "YES" or "NO" (any case) into the YES side flag
+fn parse_token_side(token_id: &str) -> Result<bool> {
+    match token_id.to_ascii_uppercase().as_str() {
+        "YES" => Ok(true),
+        "NO" => Ok(false),
+        _ => Err(InvalidRequest(format!("Unknown token_id {:?}, expected YES or NO", token_id)).into()),
+    }
+}
+
+/// Cell paying `protocol_fee` shannons to the market's fee recipient
+///
+/// Market data only records the recipient's lock hash, so it has to be the
+/// server's FEE_RECIPIENT_ADDRESS. A fee too small to fill a cell of its own
+/// can't be paid, so such claims are refused rather than overpaying.
+fn protocol_fee_output(network: Network, market_data: &MarketData, protocol_fee: u64) -> Result<CellOutput> {
+    let recipient_lock_hash = market_data.fee.map(|fee| fee.recipient_lock_hash).unwrap_or_default();
+    let recipient = fee_recipient(network)?
+        .filter(|lock| lock.calc_script_hash().as_slice() == recipient_lock_hash)
+        .ok_or_else(|| Rejected(format!(
+            "Fee recipient {:#x} is not this server's FEE_RECIPIENT_ADDRESS", H256(recipient_lock_hash)
+        )))?;
+    let output = CellOutput::new_builder()
+        .capacity(protocol_fee.pack())
+        .lock(recipient)
         .build();
+    let minimum = occupied_capacity(&output, 0)?;
+    if protocol_fee < minimum {
+        return Err(Rejected(format!(
+            "Protocol fee of {} shannons is below the {} a fee recipient cell needs; claim more tokens at once",
+            protocol_fee, minimum
+        )).into());
+    }
+    Ok(output)
+}
+
+/// Lock script of a CKB address, which must carry `network`'s prefix
+fn parse_address(address: &str, network: Network) -> Result<Script> {
+    let parsed = ckb_sdk::Address::from_str(address)
+        .map_err(|err| InvalidRequest(format!("Invalid address {}: {}", address, err)))?;
+    // Dev and test share the ckt prefix, so only the prefix can be checked
+    if parsed.network().to_prefix() != network.address_type().to_prefix() {
+        return Err(InvalidRequest(format!(
+            "Address {} is for {:?}, but the server runs on {:?}", address, parsed.network(), network
+        )).into());
+    }
+    Ok(Script::from(&parsed))
+}
+
+/// Address of a lock script on `network`
+fn encode_address(lock: &Script, network: Network) -> String {
+    ckb_sdk::Address::new(network.address_type(), ckb_sdk::AddressPayload::from(lock.clone()), true).to_string()
+}
 
-    // Sign (witness 0 is empty for always-success, witnesses 1+ are for fee cells)
-    let tx = sign_transaction_with_market(tx, privkey, fee_cells.len())?;
-    let tx_hash = send_transaction(client, &tx)?;
+/// Parse a market id of the form `0x<tx_hash>` or `0x<tx_hash>:<index>`
+/// The index defaults to 0, where the server always places the market cell.
+fn parse_market_id(id: &str) -> Result<OutPoint> {
+    let (tx_hash, index) = match id.split_once(':') {
+        Some((tx_hash, index)) => (tx_hash, index.parse::<u32>()
+            .map_err(|err| InvalidRequest(format!("Invalid market id {}: {}", id, err)))?),
+        None => (id, 0),
+    };
+    let tx_hash = H256::from_str(tx_hash.trim_start_matches("0x"))
+        .map_err(|err| InvalidRequest(format!("Invalid market id {}: {}", id, err)))?;
 
-    println!("  TX: {:#x}", tx_hash);
     Ok(OutPoint::new_builder()
         .tx_hash(tx_hash.pack())
-        .index(0u32.pack())
+        .index(index.pack())
         .build())
 }
 
-fn resolve_market(
-    client: &mut CkbRpcClient,
-    privkey: &secp256k1::SecretKey,
+/// Live market cell an import request names, checked to be one this server
+/// can manage
+fn import_market(client: &mut dyn CkbBackend, contracts: &ContractInfo, req: &ImportMarketRequest) -> Result<OutPoint> {
+    let outpoint = match (&req.market_id, &req.type_id) {
+        (Some(market_id), None) => parse_market_id(market_id)?,
+        (None, Some(type_id)) => {
+            let type_id: [u8; 32] = hex::decode(type_id.trim_start_matches("0x")).ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| InvalidRequest(format!("Invalid type_id {}: expected 32 hex bytes", type_id)))?;
+            find_market_by_type_id(client, contracts, &type_id)?
+                .ok_or_else(|| NotFound(format!("No live market with Type ID 0x{}", hex::encode(type_id))))?
+        }
+        _ => return Err(InvalidRequest("Give exactly one of market_id and type_id".to_string()).into()),
+    };
+    verify_market_cell(client, contracts, &outpoint)?;
+    Ok(outpoint)
+}
+
+/// Live cell of the market whose type args start with `type_id`
+fn find_market_by_type_id(client: &mut dyn CkbBackend, contracts: &ContractInfo, type_id: &[u8; 32]) -> Result<Option<OutPoint>> {
+    // Prefix mode: weights, flags and sections may follow the Type ID
+    let market_type = build_market_type(contracts).as_builder()
+        .args(Bytes::copy_from_slice(type_id).pack())
+        .build();
+    let search_key = SearchKey {
+        script: market_type.into(),
+        script_type: ScriptType::Type,
+        script_search_mode: Some(SearchMode::Prefix),
+        filter: None,
+        with_data: Some(false),
+        group_by_transaction: None,
+    };
+
+    let cells = retry_read(RpcRetry::from_env(), "get_cells", || {
+        client.get_cells(search_key.clone(), Order::Asc, 1.into(), None)
+    })?;
+    Ok(cells.objects.into_iter().next().map(|cell| cell.out_point.into()))
+}
+
+/// Check that `outpoint` is a live cell of the deployed market type script
+/// whose data parses and whose tokens come from the deployed token contract
+fn verify_market_cell(client: &mut dyn CkbBackend, contracts: &ContractInfo, outpoint: &OutPoint) -> Result<MarketData> {
+    let market_id = format_market_id(outpoint);
+    let cell = retry_read(RpcRetry::from_env(), "get_live_cell", || client.get_live_cell(outpoint.clone().into(), false))?;
+    if cell.status != "live" {
+        return Err(NotFound(format!("{} is not a live cell", market_id)).into());
+    }
+
+    let tx = get_transaction_view(client, outpoint.tx_hash().unpack())?;
+    let index: u32 = outpoint.index().unpack();
+    let (output, data) = tx.inner.outputs.get(index as usize).zip(tx.inner.outputs_data.get(index as usize))
+        .ok_or_else(|| NotFound(format!("Output {} not found", market_id)))?;
+
+    let market_type = build_market_type(contracts);
+    let is_market = output.type_.clone().map(Script::from).is_some_and(|script| {
+        script.code_hash().as_slice() == market_type.code_hash().as_slice()
+            && script.hash_type().as_slice() == market_type.hash_type().as_slice()
+    });
+    if !is_market {
+        return Err(InvalidRequest(format!("{} does not carry the market type script", market_id)).into());
+    }
+
+    let market_data = MarketData::from_bytes(data.as_bytes())
+        .map_err(|err| InvalidRequest(format!("{} does not hold valid market data: {}", market_id, err)))?;
+    if market_data.token_code_hash != contracts.token_code_hash.0 {
+        return Err(InvalidRequest(format!("{} mints tokens of another token contract", market_id)).into());
+    }
+    Ok(market_data)
+}
+
+/// The `0x<tx_hash>:<index>` id of a market cell, as parse_market_id reads it
+fn format_market_id(outpoint: &OutPoint) -> String {
+    let tx_hash: H256 = outpoint.tx_hash().unpack();
+    let index: u32 = outpoint.index().unpack();
+    format!("{:#x}:{}", tx_hash, index)
+}
+
+/// Compute claimed and still-claimable winning value from a market cell
+///
+/// Complete sets mint and burn YES and NO equally, and claims only burn the
+/// winning side, so the losing supply is the winning supply as it stood at
+/// resolution.
+/// Quote `op` of `amount` on a market without building a transaction, from
+/// the same collateral unit, token cell minimum and fee constants the builders use
+fn quote_operation(
+    client: &mut dyn CkbBackend,
     contracts: &ContractInfo,
-    fee_lock: &Script,
-    market_outpoint: OutPoint,
-    outcome_yes: bool,
-) -> Result<OutPoint> {
-    println!("  Building transaction...");
+    lock: &Script,
+    market_outpoint: &OutPoint,
+    op: &str,
+    amount: u128,
+) -> Result<QuoteResponse> {
+    let market_cell = get_cell(client, market_outpoint)?;
+    let market_data = MarketData::from_bytes(&market_cell.data)?;
+    let collateral = market_data.checked_collateral(amount)?;
+    let estimated_fee = Shannons(fee_allowance(fee_rate()));
+
+    let (token_cell_capacity, total_required) = match op {
+        "mint" => {
+            let (market_type, hash_type) = (market_cell.market_type()?, market_data.token_script_hash_type()?);
+            let token_cell_capacity = Shannons(token_cell_minimum(lock, &build_token_type(contracts, &market_type, hash_type, true))?
+                .max(token_cell_minimum(lock, &build_token_type(contracts, &market_type, hash_type, false))?) * 2);
+            let total = [collateral, token_cell_capacity, estimated_fee, Shannons(fee_margin())].into_iter()
+                .try_fold(Shannons(0), Shannons::checked_add)
+                .ok_or_else(|| Rejected(format!("Amount {} too large for available capacity", amount)))?;
+            (token_cell_capacity, total)
+        }
+        "burn" | "claim" => (Shannons(0), Shannons(FEE_CELL_CAPACITY)),
+        other => return Err(NotFound(format!("Unknown operation: {}", other)).into()),
+    };
 
-    // Get current market cell
-    let market_cell = get_cell(client, &market_outpoint)?;
+    Ok(QuoteResponse {
+        operation: op.to_string(),
+        amount: amount.to_string(),
+        collateral,
+        token_cell_capacity,
+        estimated_fee,
+        total_required,
+    })
+}
+
+fn market_claimable(market_cell: &CellInfo) -> Result<ClaimableResponse> {
     let market_data = MarketData::from_bytes(&market_cell.data)?;
-    let market_capacity: u64 = market_cell.capacity;
+    if !market_data.resolved {
+        return Err(Rejected("Market is not resolved".to_string()).into());
+    }
+    if market_data.cancelled {
+        return Err(Rejected("Market was cancelled, nothing is claimable".to_string()).into());
+    }
 
-    // Collect fee cells
-    let fee_cells = collect_cells(client, fee_lock, 1_00000000)?;
-    let total_fee_input: u64 = fee_cells.iter().map(|(_, cap)| cap).sum();
-    let fee = 1000u64;
-    let change = total_fee_input - fee;
+    let (winning_supply, minted_supply) = if market_data.outcome {
+        (market_data.yes_supply(), market_data.no_supply())
+    } else {
+        (market_data.no_supply(), market_data.yes_supply())
+    };
+    let claimed_supply = minted_supply.checked_sub(winning_supply)
+        .ok_or_else(|| anyhow!("Winning supply {} exceeds minted supply {}", winning_supply, minted_supply))?;
 
-    // New market data (resolved)
-    let new_market_data = MarketData {
-        yes_supply: market_data.yes_supply,
-        no_supply: market_data.no_supply,
-        resolved: true,
-        outcome: outcome_yes,
-    }.to_bytes();
+    let to_capacity = |tokens: u128| -> Result<Shannons> {
+        market_data.collateral_for(tokens).ok_or_else(|| anyhow!("Capacity overflow for {} tokens", tokens))
+    };
 
-    // Build outputs (market capacity unchanged)
-    let market_output = CellOutput::new_builder()
-        .capacity(market_capacity.pack())
-        .lock(build_market_lock(contracts))
-        .type_(Some(build_market_type(contracts)).pack())
-        .build();
+    Ok(ClaimableResponse {
+        outcome: market_data.outcome,
+        minted_supply: minted_supply.to_string(),
+        winning_supply: winning_supply.to_string(),
+        claimed_supply: claimed_supply.to_string(),
+        claimable_capacity: to_capacity(winning_supply)?,
+        claimed_capacity: to_capacity(claimed_supply)?,
+        market_capacity: Shannons(market_cell.capacity),
+    })
+}
 
-    let change_output = CellOutput::new_builder()
-        .capacity(change.pack())
-        .lock(fee_lock.clone())
-        .build();
+// Helper functions
 
-    // Build inputs
-    let mut inputs = vec![
-        CellInput::new_builder()
-            .previous_output(market_outpoint)
-            .since(0u64.pack())
-            .build()
-    ];
-    for (outpoint, _) in &fee_cells {
-        inputs.push(CellInput::new_builder()
-            .previous_output(outpoint.clone())
-            .since(0u64.pack())
-            .build());
+/// A live market cell found on-chain
+struct MarketCell {
+    outpoint: OutPoint,
+    capacity: u64,
+    occupied: u64,
+    data: Bytes,
+}
+
+/// Scan the indexer for every live cell carrying the market type script
+fn scan_market_cells(client: &mut dyn CkbBackend, contracts: &ContractInfo) -> Result<Vec<MarketCell>> {
+    // Empty args + prefix mode matches every market regardless of its Type ID args
+    let search_key = SearchKey {
+        script: build_market_type(contracts).into(),
+        script_type: ScriptType::Type,
+        script_search_mode: Some(SearchMode::Prefix),
+        filter: None,
+        with_data: Some(true),
+        group_by_transaction: None,
+    };
+
+    let page_size = 100u32;
+    let mut markets = Vec::new();
+    let mut cursor = None;
+
+    loop {
+        let page = retry_read(RpcRetry::from_env(), "get_cells", || {
+            client.get_cells(search_key.clone(), Order::Asc, page_size.into(), cursor.clone())
+        })?;
+        let page_len = page.objects.len();
+
+        for cell in page.objects {
+            let data = cell.output_data.map(|data| data.into_bytes()).unwrap_or_default();
+            let output: CellOutput = cell.output.clone().into();
+            markets.push(MarketCell {
+                outpoint: cell.out_point.into(),
+                capacity: cell.output.capacity.into(),
+                occupied: occupied_capacity(&output, data.len())?,
+                data,
+            });
+        }
+
+        if page_len < page_size as usize {
+            break;
+        }
+        cursor = Some(page.last_cursor);
     }
 
-    let tx = TransactionView::new_advanced_builder()
-        .cell_deps(build_cell_deps(contracts))
-        .inputs(inputs)
-        .outputs(vec![market_output, change_output])
-        .outputs_data(vec![Bytes::from(new_market_data).pack(), Bytes::new().pack()])
-        .build();
+    Ok(markets)
+}
 
-    let tx = sign_transaction_with_market(tx, privkey, fee_cells.len())?;
-    let tx_hash = send_transaction(client, &tx)?;
+/// Every live market on-chain, whoever created it
+/// Cells whose data doesn't decode as MarketData are skipped with a warning.
+fn list_markets(client: &mut dyn CkbBackend, contracts: &ContractInfo) -> Result<Vec<MarketSummary>> {
+    Ok(scan_market_cells(client, contracts)?
+        .into_iter()
+        .filter_map(|market| {
+            let tx_hash: H256 = market.outpoint.tx_hash().unpack();
+            let index: u32 = market.outpoint.index().unpack();
+            let data = match MarketData::from_bytes(&market.data) {
+                Ok(data) => data,
+                Err(err) => {
+                    warn!(cell = %format!("{:#x}:{}", tx_hash, index), error = %err, "Skipping market cell");
+                    return None;
+                }
+            };
+            Some(MarketSummary {
+                market_id: format!("{:#x}:{}", tx_hash, index),
+                yes_supply: data.yes_supply().to_string(),
+                no_supply: data.no_supply().to_string(),
+                resolved: data.resolved,
+                outcome: data.outcome,
+                cancelled: data.cancelled,
+                capacity: market.capacity,
+                question: data.metadata.as_ref().map(|metadata| metadata.question.clone()),
+                outcome_labels: data.metadata.map(|metadata| metadata.outcome_labels),
+                scalar: data.scalar.map(ScalarJson::from),
+            })
+        })
+        .collect())
+}
 
-    println!("  TX: {:#x}", tx_hash);
-    Ok(OutPoint::new_builder()
-        .tx_hash(tx_hash.pack())
-        .index(0u32.pack())
-        .build())
+/// Sum the capacity of every market cell into a TVL snapshot
+fn compute_tvl(client: &mut dyn CkbBackend, contracts: &ContractInfo) -> Result<TvlResponse> {
+    let markets: Vec<MarketTvl> = scan_market_cells(client, contracts)?
+        .into_iter()
+        .map(|market| {
+            let tx_hash: H256 = market.outpoint.tx_hash().unpack();
+            MarketTvl {
+                tx_hash: format!("{:#x}", tx_hash),
+                index: market.outpoint.index().unpack(),
+                capacity: Shannons(market.capacity),
+                collateral: Shannons(market.capacity.saturating_sub(market.occupied)),
+            }
+        })
+        .collect();
+
+    let total_capacity = markets.iter()
+        .try_fold(Shannons(0), |total, market| total.checked_add(market.capacity))
+        .ok_or_else(|| anyhow!("TVL overflow"))?;
+    let total_collateral = markets.iter().map(|market| market.collateral).sum();
+
+    Ok(TvlResponse {
+        total_capacity,
+        total_collateral,
+        market_count: markets.len(),
+        markets,
+        updated_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    })
 }
 
-fn claim_tokens(
-    client: &mut CkbRpcClient,
-    privkey: &secp256k1::SecretKey,
-    contracts: &ContractInfo,
-    fee_lock: &Script,
-    market_outpoint: OutPoint,
-    amount: u128,
-) -> Result<OutPoint> {
-    println!("  Building transaction...");
+struct CellInfo {
+    capacity: u64,
+    /// Capacity the cell needs for its lock, type and data
+    occupied: u64,
+    type_script: Option<Script>,
+    data: Vec<u8>,
+}
 
-    // Get current market cell
-    let market_cell = get_cell(client, &market_outpoint)?;
-    let market_data = MarketData::from_bytes(&market_cell.data)?;
-    let market_capacity: u64 = market_cell.capacity;
+impl CellInfo {
+    /// Capacity beyond what the cell occupies, i.e. what actually backs tokens
+    /// for a market cell
+    fn collateral(&self) -> u64 {
+        self.capacity.saturating_sub(self.occupied)
+    }
 
-    if !market_data.resolved {
-        return Err(anyhow!("Market is not resolved"));
+    /// Type script of a market cell, which the market's next cell must carry
+    /// and whose hash its token args start with
+    ///
+    /// Taken from the cell rather than built from the deployment, so markets
+    /// created elsewhere keep their own Type ID args.
+    fn market_type(&self) -> Result<Script> {
+        self.type_script.clone().ok_or_else(|| anyhow!("Market cell has no type script"))
     }
+}
 
-    // Determine winning token type (YES = true, NO = false)
-    let is_winning_yes = market_data.outcome;
-    let winning_token_type = build_token_type(contracts, is_winning_yes);
+/// Capacity a cell must hold for its lock, type and `data_len` bytes of data
+fn occupied_capacity(output: &CellOutput, data_len: usize) -> Result<u64> {
+    Capacity::bytes(data_len)
+        .and_then(|data| output.occupied_capacity(data))
+        .map(|occupied| occupied.as_u64())
+        .map_err(|err| anyhow!("Cell capacity overflow: {:?}", err))
+}
 
-    // Find user's winning token cell
-    let (token_outpoint, token_capacity, token_amount) = find_token_cell(client, fee_lock, &winning_token_type)?;
+/// Look up the cell each input of a transaction spends, in input order
+fn resolve_inputs(client: &mut dyn CkbBackend, tx: &TransactionView) -> Result<Vec<(CellOutput, Bytes)>> {
+    tx.inputs().into_iter()
+        .map(|input| {
+            let outpoint = input.previous_output();
+            let index: u32 = outpoint.index().unpack();
+            let previous = get_transaction_view(client, outpoint.tx_hash().unpack())?;
+            let output = previous.inner.outputs.get(index as usize)
+                .ok_or_else(|| anyhow!("Output not found"))?;
+            let data = previous.inner.outputs_data.get(index as usize)
+                .ok_or_else(|| anyhow!("Output data not found"))?;
+            Ok((output.clone().into(), data.clone().into_bytes()))
+        })
+        .collect()
+}
 
-    if token_amount < amount {
-        return Err(anyhow!("Insufficient token balance: have {} need {}", token_amount, amount));
+/// A market cell a transaction is about to spend
+/// A dead cell means another transaction moved the market on since the
+/// caller read it, which is a MarketConflict rather than an RPC failure.
+fn get_market_cell(client: &mut dyn CkbBackend, outpoint: &OutPoint) -> Result<CellInfo> {
+    let cell = retry_read(RpcRetry::from_env(), "get_live_cell", || client.get_live_cell(outpoint.clone().into(), false))?;
+    if cell.status == "dead" {
+        return Err(MarketConflict(outpoint.clone()).into());
     }
+    get_cell(client, outpoint)
+}
 
-    // Calculate claim amount (100 CKB per winning token)
-    let claim_amount = amount as u64 * 100_00000000;
-    let new_market_capacity = market_capacity - claim_amount;
+fn get_cell(client: &mut dyn CkbBackend, outpoint: &OutPoint) -> Result<CellInfo> {
+    let tx_hash: H256 = outpoint.tx_hash().unpack();
+    let index: u32 = outpoint.index().unpack();
 
-    // Calculate new token amount
-    let new_token_amount = token_amount - amount;
+    let inner = get_transaction_view(client, tx_hash)?;
 
-    // Collect fee cells
-    let fee_cells = collect_cells(client, fee_lock, 1_00000000)?;
-    let total_fee_input: u64 = fee_cells.iter().map(|(_, cap)| cap).sum();
-    let fee = 2000u64;
+    let output = inner.inner.outputs.get(index as usize)
+        .ok_or_else(|| NotFound(format!("Output {:#x}:{} not found", tx_hash, index)))?;
+    let data = inner.inner.outputs_data.get(index as usize)
+        .ok_or_else(|| anyhow!("Output data not found"))?;
+
+    Ok(CellInfo {
+        capacity: output.capacity.into(),
+        occupied: occupied_capacity(&output.clone().into(), data.len())?,
+        type_script: output.type_.clone().map(Script::from),
+        data: data.as_bytes().to_vec(),
+    })
+}
 
-    // Change calculation: fee inputs + claimed CKB - fee
-    // Note: token_capacity cancels out (appears in both inputs and outputs)
-    let change = total_fee_input + claim_amount - fee;
+/// Fetch a transaction and return its JSON view
+fn get_transaction_view(client: &mut dyn CkbBackend, tx_hash: H256) -> Result<ckb_jsonrpc_types::TransactionView> {
+    let tx_with_status = retry_read(RpcRetry::from_env(), "get_transaction", || client.get_transaction(tx_hash.clone()))?
+        .ok_or_else(|| NotFound(format!("Transaction {:#x} not found", tx_hash)))?;
 
-    // New market data (reduce winning supply)
-    let new_market_data = if is_winning_yes {
-        MarketData {
-            yes_supply: market_data.yes_supply - amount,
-            no_supply: market_data.no_supply,
-            resolved: true,
-            outcome: true,
+    let tx = tx_with_status.transaction
+        .ok_or_else(|| anyhow!("Transaction inner not found"))?;
+
+    // Get the inner transaction view
+    match tx.inner {
+        ckb_jsonrpc_types::Either::Left(view) => Ok(view),
+        ckb_jsonrpc_types::Either::Right(_) => Err(anyhow!("Transaction is in bytes format")),
+    }
+}
+
+/// Proof bundle for the transaction that created a market cell
+fn market_proof(client: &mut dyn CkbBackend, outpoint: &OutPoint) -> Result<MarketProofResponse> {
+    let tx_hash: H256 = outpoint.tx_hash().unpack();
+    let index: u32 = outpoint.index().unpack();
+
+    let status = retry_read(RpcRetry::from_env(), "get_transaction", || client.get_transaction(tx_hash.clone()))?
+        .ok_or_else(|| NotFound(format!("Transaction {:#x} not found", tx_hash)))?;
+    let block_hash = status.tx_status.block_hash
+        .ok_or_else(|| anyhow!("Transaction {:#x} is not committed", tx_hash))?;
+    let transaction = get_transaction_view(client, tx_hash.clone())?;
+    if transaction.inner.outputs.len() <= index as usize {
+        return Err(NotFound(format!("Output {:#x}:{} not found", tx_hash, index)).into());
+    }
+    let proof = client.get_transaction_proof(vec![tx_hash.clone()], Some(block_hash.clone()))?;
+
+    Ok(MarketProofResponse {
+        outpoint: format!("{:#x}:{}", tx_hash, index),
+        block_hash: format!("{:#x}", block_hash),
+        transaction,
+        proof,
+    })
+}
+
+/// Check a proof bundle the way a light client would: the transaction hashes to
+/// the proven leaf, and the proof plus witnesses root rebuild the header's
+/// transactions root
+fn verify_market_proof(header: &ckb_jsonrpc_types::HeaderView, bundle: &MarketProofResponse) -> Result<()> {
+    use ckb_types::utilities::{merkle_root, MerkleProof};
+
+    let tx: ckb_types::packed::Transaction = bundle.transaction.inner.clone().into();
+    let tx_hash = tx.calc_tx_hash();
+    let bundle_hash: ckb_types::packed::Byte32 = bundle.transaction.hash.pack();
+    if tx_hash.as_slice() != bundle_hash.as_slice() {
+        return Err(anyhow!("Transaction does not hash to {:#x}", bundle.transaction.hash));
+    }
+
+    let proof = MerkleProof::new(
+        bundle.proof.proof.indices.iter().map(|index| index.value()).collect(),
+        bundle.proof.proof.lemmas.iter().map(|lemma| lemma.pack()).collect(),
+    );
+    let raw_root = proof.root(&[tx_hash])
+        .ok_or_else(|| anyhow!("Malformed transaction proof"))?;
+    let transactions_root = merkle_root(&[raw_root, bundle.proof.witnesses_root.pack()]);
+
+    let expected: ckb_types::packed::Byte32 = header.inner.transactions_root.pack();
+    if transactions_root.as_slice() != expected.as_slice() {
+        return Err(anyhow!("Proof does not rebuild the transactions root of block {:#x}", header.hash));
+    }
+    Ok(())
+}
+
+/// Verify the output shape of a committed claim transaction
+///
+/// A partial claim must leave exactly one winning token cell holding the
+/// unclaimed amount at the original token cell capacity; a full claim
+/// (expected_remaining = 0) must leave none. Capacity must be conserved:
+/// inputs - outputs == the size-based fee at the current fee rate, which pins
+/// the change output to fee inputs + claimed CKB - fee.
+#[cfg(test)]
+fn verify_claim_outputs(
+    client: &mut dyn CkbBackend,
+    winning_token_type: &Script,
+    claim_outpoint: &OutPoint,
+    expected_remaining: u128,
+    expected_token_capacity: u64,
+) -> Result<()> {
+    let tx_hash: H256 = claim_outpoint.tx_hash().unpack();
+    let tx = get_transaction_view(client, tx_hash)?;
+
+    // Collect winning token cells among the outputs
+    let mut token_outputs = Vec::new();
+    for (output, data) in tx.inner.outputs.iter().zip(tx.inner.outputs_data.iter()) {
+        let output_type: Option<Script> = output.type_.clone().map(Into::into);
+        if output_type.as_ref() == Some(winning_token_type) {
+            let amount_bytes: [u8; 16] = data.as_bytes()
+                .try_into()
+                .map_err(|_| anyhow!("Invalid token amount data"))?;
+            let capacity: u64 = output.capacity.into();
+            token_outputs.push((capacity, u128::from_le_bytes(amount_bytes)));
+        }
+    }
+
+    if expected_remaining == 0 {
+        if !token_outputs.is_empty() {
+            return Err(anyhow!("Full claim produced {} token cells, expected none", token_outputs.len()));
         }
     } else {
-        MarketData {
-            yes_supply: market_data.yes_supply,
-            no_supply: market_data.no_supply - amount,
-            resolved: true,
-            outcome: false,
+        if token_outputs.len() != 1 {
+            return Err(anyhow!("Partial claim produced {} token cells, expected 1", token_outputs.len()));
         }
-    }.to_bytes();
+        let (capacity, amount) = token_outputs[0];
+        if amount != expected_remaining {
+            return Err(anyhow!("Remaining token amount {} != expected {}", amount, expected_remaining));
+        }
+        if capacity != expected_token_capacity {
+            return Err(anyhow!("Remaining token capacity {} != expected {}", capacity, expected_token_capacity));
+        }
+    }
 
-    // Build outputs
-    let market_output = CellOutput::new_builder()
-        .capacity(new_market_capacity.pack())
-        .lock(build_market_lock(contracts))
-        .type_(Some(build_market_type(contracts)).pack())
-        .build();
+    // Capacity conservation: whatever the market released must land in change
+    let fee = transaction_fee(client, &tx)?;
+    let expected_fee = estimate_fee(&ckb_types::packed::Transaction::from(tx.inner.clone()).into_view(), fee_rate());
+    if fee != expected_fee {
+        return Err(anyhow!("Claim capacity not conserved: paid fee {} (expected fee {})",
+                           fee, expected_fee));
+    }
 
-    let mut outputs = vec![market_output];
-    let mut outputs_data = vec![Bytes::from(new_market_data).pack()];
+    Ok(())
+}
 
-    // If there are remaining tokens, output updated token cell
-    if new_token_amount > 0 {
-        let token_output = CellOutput::new_builder()
-            .capacity(token_capacity.pack())
-            .lock(fee_lock.clone())
-            .type_(Some(winning_token_type).pack())
-            .build();
-        outputs.push(token_output);
-        outputs_data.push(Bytes::from(new_token_amount.to_le_bytes().to_vec()).pack());
+/// Fee paid by a committed transaction (input capacity - output capacity)
+fn transaction_fee(client: &mut dyn CkbBackend, tx: &ckb_jsonrpc_types::TransactionView) -> Result<u64> {
+    let mut total_input = 0u64;
+    for input in &tx.inner.inputs {
+        let previous_output: OutPoint = input.previous_output.clone().into();
+        total_input += get_cell(client, &previous_output)?.capacity;
     }
+    let total_output: u64 = tx.inner.outputs.iter()
+        .map(|output| -> u64 { output.capacity.into() })
+        .sum();
 
-    // Change output
-    let change_output = CellOutput::new_builder()
-        .capacity(change.pack())
-        .lock(fee_lock.clone())
-        .build();
-    outputs.push(change_output);
-    outputs_data.push(Bytes::new().pack());
+    total_input.checked_sub(total_output)
+        .ok_or_else(|| anyhow!("Transaction outputs {} exceed inputs {}", total_output, total_input))
+}
 
-    // Build inputs: market cell, token cell, fee cells
-    let mut inputs = vec![
-        CellInput::new_builder()
-            .previous_output(market_outpoint)
-            .since(0u64.pack())
-            .build(),
-        CellInput::new_builder()
-            .previous_output(token_outpoint)
-            .since(0u64.pack())
-            .build(),
-    ];
-    for (outpoint, _) in &fee_cells {
-        inputs.push(CellInput::new_builder()
-            .previous_output(outpoint.clone())
-            .since(0u64.pack())
-            .build());
+/// Fee paid by the committed transaction that created an outpoint
+fn outpoint_tx_fee(client: &mut dyn CkbBackend, outpoint: &OutPoint) -> Result<u64> {
+    let tx = get_transaction_view(client, outpoint.tx_hash().unpack())?;
+    transaction_fee(client, &tx)
+}
+
+/// Total capacity of every live cell owned by a lock
+fn wallet_capacity(client: &mut dyn CkbBackend, lock: &Script) -> Result<u64> {
+    let search_key = SearchKey {
+        script: lock.clone().into(),
+        script_type: ScriptType::Lock,
+        script_search_mode: Some(SearchMode::Exact),
+        filter: None,
+        with_data: None,
+        group_by_transaction: None,
+    };
+
+    let capacity = retry_read(RpcRetry::from_env(), "get_cells_capacity", || client.get_cells_capacity(search_key.clone()))?
+        .ok_or_else(|| anyhow!("Indexer returned no capacity for lock"))?;
+    Ok(capacity.capacity.value())
+}
+
+/// Indexer search for the pure-CKB cells (no type script, no data) owned by a lock
+fn pure_cells_key(lock: &Script) -> SearchKey {
+    use ckb_sdk::rpc::ckb_indexer::SearchKeyFilter;
+
+    let filter = SearchKeyFilter {
+        script: None,
+        script_len_range: Some([0.into(), 1.into()]), // No type script
+        output_data: None,
+        output_data_filter_mode: None,
+        output_data_len_range: Some([0.into(), 1.into()]), // No data
+        output_capacity_range: None,
+        block_range: None,
+    };
+
+    SearchKey {
+        script: lock.clone().into(),
+        script_type: ScriptType::Lock,
+        script_search_mode: Some(SearchMode::Exact),
+        filter: Some(filter),
+        with_data: Some(false),
+        group_by_transaction: None,
     }
+}
 
-    let tx = TransactionView::new_advanced_builder()
-        .cell_deps(build_cell_deps_with_token(contracts))
-        .inputs(inputs)
-        .outputs(outputs)
-        .outputs_data(outputs_data)
-        .build();
+/// List pure-CKB cells (no type script, no data) owned by a lock
+/// Only the first page of 100, which bounds a consolidation's inputs.
+fn list_pure_cells(client: &mut dyn CkbBackend, lock: &Script) -> Result<Vec<(OutPoint, u64)>> {
+    let search_key = pure_cells_key(lock);
+    let cells = retry_read(RpcRetry::from_env(), "get_cells", || {
+        client.get_cells(search_key.clone(), Order::Asc, 100.into(), None)
+    })?;
+
+    Ok(cells.objects.into_iter()
+        .map(|cell| -> (OutPoint, u64) { (cell.out_point.into(), cell.output.capacity.into()) })
+        .collect())
+}
+
+/// Number of pure-CKB cells owned by a lock, paging through all of them
+fn count_pure_cells(client: &mut dyn CkbBackend, lock: &Script) -> Result<usize> {
+    let search_key = pure_cells_key(lock);
+    let page_size = 100u32;
+    let mut count = 0;
+    let mut cursor = None;
+
+    loop {
+        let page = retry_read(RpcRetry::from_env(), "get_cells", || {
+            client.get_cells(search_key.clone(), Order::Asc, page_size.into(), cursor.clone())
+        })?;
+        count += page.objects.len();
+        if page.objects.len() < page_size as usize {
+            return Ok(count);
+        }
+        cursor = Some(page.last_cursor);
+    }
+}
+
+/// One labeled component of an operation's capacity requirement
+struct CapacityRequirement {
+    label: &'static str,
+    capacity: Shannons,
+}
+
+/// The fee wallet could not cover a requirement component
+/// Components are funded in order, so `component` is the first one the
+/// available balance runs out on and `shortfall` is how much more is needed.
+#[derive(Debug)]
+struct InsufficientFunds {
+    component: &'static str,
+    shortfall: Shannons,
+    required: Shannons,
+    available: Shannons,
+}
+
+impl std::fmt::Display for InsufficientFunds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Insufficient balance for {}: short by {} (need {} total, have {})",
+            self.component, self.shortfall, self.required, self.available
+        )
+    }
+}
+
+impl std::error::Error for InsufficientFunds {}
+
+/// Find the first requirement component that `available` cannot cover
+fn attribute_shortfall(requirements: &[CapacityRequirement], available: Shannons) -> Option<InsufficientFunds> {
+    let required: Shannons = requirements.iter().map(|r| r.capacity).sum();
+    let mut cumulative = Shannons(0);
+
+    for requirement in requirements {
+        cumulative = cumulative + requirement.capacity;
+        if cumulative > available {
+            return Some(InsufficientFunds {
+                component: requirement.label,
+                shortfall: cumulative.saturating_sub(available),
+                required,
+                available,
+            });
+        }
+    }
+
+    None
+}
+
+/// Collect fee cells covering all requirement components
+/// On failure, reports which component could not be met and by how much.
+fn collect_cells_for(
+    client: &mut dyn CkbBackend,
+    lock: &Script,
+    requirements: &[CapacityRequirement],
+) -> Result<Vec<(OutPoint, u64)>> {
+    let required: Shannons = requirements.iter().map(|r| r.capacity).sum();
+    let (collected, total) = gather_cells(client, lock, required.as_shannons())?;
+
+    if let Some(shortfall) = attribute_shortfall(requirements, Shannons(total)) {
+        return Err(shortfall.into());
+    }
+
+    Ok(collected)
+}
+
+/// Where an operation's collateral and fee come from
+enum Funding {
+    /// Empty cells of the server's fee lock, collected as needed
+    ServerWallet,
+    /// Cells the user pre-selected from their own wallet; whatever the
+    /// operation produces for the user goes to `change_lock`
+    Supplied {
+        inputs: Vec<(OutPoint, u64)>,
+        change_lock: Script,
+    },
+}
+
+impl Funding {
+    /// Lock that receives the operation's change (and tokens, for a mint)
+    fn owner_lock<'a>(&'a self, fee_lock: &'a Script) -> &'a Script {
+        match self {
+            Funding::ServerWallet => fee_lock,
+            Funding::Supplied { change_lock, .. } => change_lock,
+        }
+    }
+
+    /// Cells covering all requirement components
+    /// Supplied inputs are used as given, never topped up from the server wallet.
+    fn cells(
+        &self,
+        client: &mut dyn CkbBackend,
+        fee_lock: &Script,
+        requirements: &[CapacityRequirement],
+    ) -> Result<Vec<(OutPoint, u64)>> {
+        match self {
+            Funding::ServerWallet => collect_cells_for(client, fee_lock, requirements),
+            Funding::Supplied { inputs, .. } => {
+                let total = inputs.iter()
+                    .try_fold(0u64, |total, (_, capacity)| total.checked_add(*capacity))
+                    .ok_or_else(|| anyhow!("Funding input capacity overflow"))?;
+                if let Some(shortfall) = attribute_shortfall(requirements, Shannons(total)) {
+                    return Err(shortfall.into());
+                }
+                Ok(inputs.clone())
+            }
+        }
+    }
+}
 
-    // Sign: market (always-success, dummy witness), token (signed), fee inputs (signed)
-    let tx = sign_transaction_with_market_and_token(tx, privkey, 1 + fee_cells.len())?;
-    let tx_hash = send_transaction(client, &tx)?;
+/// Funding from user-selected cells, each given as `0x<tx_hash>:<index>`
+/// Only plain cells (no type script, no data) are accepted, so a request
+/// cannot smuggle a market or token cell in as collateral.
+fn supplied_funding(
+    client: &mut dyn CkbBackend,
+    network: Network,
+    outpoints: &[String],
+    change_address: &str,
+) -> Result<Funding> {
+    if outpoints.is_empty() {
+        return Err(InvalidRequest("funding_inputs must not be empty".to_string()).into());
+    }
+    let change_lock = parse_address(change_address, network)?;
 
-    println!("  TX: {:#x}", tx_hash);
-    Ok(OutPoint::new_builder()
-        .tx_hash(tx_hash.pack())
-        .index(0u32.pack())
-        .build())
+    let mut inputs = Vec::with_capacity(outpoints.len());
+    for id in outpoints {
+        if !id.contains(':') {
+            return Err(InvalidRequest(format!("Funding input {} needs an explicit :<index>", id)).into());
+        }
+        let outpoint = parse_market_id(id)?;
+        if inputs.iter().any(|(seen, _): &(OutPoint, u64)| seen == &outpoint) {
+            return Err(InvalidRequest(format!("Funding input {} is listed twice", id)).into());
+        }
+        let index: u32 = outpoint.index().unpack();
+        let previous = get_transaction_view(client, outpoint.tx_hash().unpack())?;
+        let (output, data) = previous.inner.outputs.get(index as usize)
+            .zip(previous.inner.outputs_data.get(index as usize))
+            .ok_or_else(|| InvalidRequest(format!("Funding input {} does not exist", id)))?;
+        if output.type_.is_some() || !data.as_bytes().is_empty() {
+            return Err(InvalidRequest(format!("Funding input {} is not a plain CKB cell", id)).into());
+        }
+        inputs.push((outpoint, output.capacity.into()));
+    }
+
+    Ok(Funding::Supplied { inputs, change_lock })
 }
 
-// Helper functions
+fn collect_cells(client: &mut dyn CkbBackend, lock: &Script, min_capacity: u64) -> Result<Vec<(OutPoint, u64)>> {
+    let (collected, total) = gather_cells(client, lock, min_capacity)?;
 
-struct CellInfo {
-    capacity: u64,
-    data: Vec<u8>,
+    if total < min_capacity {
+        return Err(anyhow!("Insufficient balance: need {} have {}", min_capacity, total));
+    }
+
+    Ok(collected)
 }
 
-fn get_cell(client: &mut CkbRpcClient, outpoint: &OutPoint) -> Result<CellInfo> {
-    let tx_hash: H256 = outpoint.tx_hash().unpack();
-    let index: u32 = outpoint.index().unpack();
+/// Smallest fee cell collected ahead of dust, in shannons
+const DEFAULT_MIN_FEE_CELL: u64 = Shannons::from_ckb(100).as_shannons();
 
-    let tx_with_status = client.get_transaction(tx_hash)?
-        .ok_or_else(|| anyhow!("Transaction not found"))?;
+/// Capacity below which an empty cell is dust, only collected when the
+/// larger cells can't cover a transaction (env MIN_FEE_CELL_SHANNONS)
+/// Defaults to DEFAULT_MIN_FEE_CELL; 0 collects every cell in indexer order.
+fn min_fee_cell() -> u64 {
+    std::env::var("MIN_FEE_CELL_SHANNONS").ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MIN_FEE_CELL)
+}
 
-    let tx = tx_with_status.transaction
-        .ok_or_else(|| anyhow!("Transaction inner not found"))?;
+/// Fee cells picked as the wallet's pages arrive: cells of at least
+/// `dust_below` shannons first, dust only to top up what those can't cover
+struct FeeCellPicker {
+    target: u64,
+    dust_below: u64,
+    picked: Vec<(OutPoint, u64)>,
+    total: u64,
+    dust: Vec<(OutPoint, u64)>,
+}
 
-    // Get the inner transaction view
-    let inner = match tx.inner {
-        ckb_jsonrpc_types::Either::Left(view) => view,
-        ckb_jsonrpc_types::Either::Right(_) => return Err(anyhow!("Transaction is in bytes format")),
-    };
+impl FeeCellPicker {
+    fn new(target: u64, dust_below: u64) -> Self {
+        FeeCellPicker { target, dust_below, picked: Vec::new(), total: 0, dust: Vec::new() }
+    }
 
-    let output = inner.inner.outputs.get(index as usize)
-        .ok_or_else(|| anyhow!("Output not found"))?;
-    let data = inner.inner.outputs_data.get(index as usize)
-        .ok_or_else(|| anyhow!("Output data not found"))?;
+    /// Take the wallet's next cell; true once the non-dust cells cover the target
+    fn offer(&mut self, outpoint: OutPoint, capacity: u64) -> bool {
+        if capacity < self.dust_below {
+            self.dust.push((outpoint, capacity));
+            return false;
+        }
+        self.picked.push((outpoint, capacity));
+        self.total += capacity;
+        self.total >= self.target
+    }
 
-    Ok(CellInfo {
-        capacity: output.capacity.into(),
-        data: data.as_bytes().to_vec(),
-    })
+    /// The picked cells and their total, topped up from dust in wallet order
+    /// if they fall short
+    fn finish(mut self) -> (Vec<(OutPoint, u64)>, u64) {
+        for (outpoint, capacity) in self.dust {
+            if self.total >= self.target {
+                break;
+            }
+            self.picked.push((outpoint, capacity));
+            self.total += capacity;
+        }
+        (self.picked, self.total)
+    }
 }
 
-fn collect_cells(client: &mut CkbRpcClient, lock: &Script, min_capacity: u64) -> Result<Vec<(OutPoint, u64)>> {
+/// Gather empty cells until `min_capacity` is reached or cells run out,
+/// passing over dust (see min_fee_cell) while larger cells remain
+/// Returns the cells and their total capacity without judging sufficiency.
+fn gather_cells(client: &mut dyn CkbBackend, lock: &Script, min_capacity: u64) -> Result<(Vec<(OutPoint, u64)>, u64)> {
     use ckb_sdk::rpc::ckb_indexer::SearchKeyFilter;
 
-    // Filter to exclude cells with data (e.g., contract binaries)
-    // Only collect empty cells to avoid spending contract deployments
+    // Only collect pure CKB cells: no data (e.g. contract binaries) and no
+    // type script (market, token or any other typed cell, even with empty data)
     let filter = SearchKeyFilter {
         script: None,
-        script_len_range: None,
+        script_len_range: Some([0.into(), 1.into()]), // No type script
         output_data: None,
         output_data_filter_mode: None,
         output_data_len_range: Some([0.into(), 1.into()]), // Data length 0-1 bytes (empty)
@@ -966,71 +6773,246 @@ fn collect_cells(client: &mut CkbRpcClient, lock: &Script, min_capacity: u64) ->
         group_by_transaction: None,
     };
 
-    let cells = client.get_cells(search_key, Order::Asc, 100.into(), None)?;
-
-    let mut collected = Vec::new();
-    let mut total = 0u64;
-
-    for cell in cells.objects {
-        let capacity: u64 = cell.output.capacity.into();
-        let outpoint = OutPoint::new_builder()
-            .tx_hash(cell.out_point.tx_hash.pack())
-            .index((cell.out_point.index.value() as u32).pack())
-            .build();
+    // Page through the wallet: many small cells can cover what the first
+    // page alone cannot
+    let page_size = 100u32;
+    let mut picker = FeeCellPicker::new(min_capacity, min_fee_cell());
+    let mut cursor = None;
+
+    'pages: loop {
+        let page = retry_read(RpcRetry::from_env(), "get_cells", || {
+            client.get_cells(search_key.clone(), Order::Asc, page_size.into(), cursor.clone())
+        })?;
+        if page.objects.is_empty() {
+            break;
+        }
+        let page_len = page.objects.len();
 
-        collected.push((outpoint, capacity));
-        total += capacity;
+        for cell in page.objects {
+            // The indexer filter already excludes these; never trust a typed
+            // cell to it, spending one as a fee input would destroy its state
+            if cell.output.type_.is_some() {
+                continue;
+            }
+            let capacity: u64 = cell.output.capacity.into();
+            let outpoint = OutPoint::new_builder()
+                .tx_hash(cell.out_point.tx_hash.pack())
+                .index((cell.out_point.index.value() as u32).pack())
+                .build();
+
+            if picker.offer(outpoint, capacity) {
+                break 'pages;
+            }
+        }
 
-        if total >= min_capacity {
+        if page_len < page_size as usize {
             break;
         }
+        cursor = Some(page.last_cursor);
     }
 
-    if total < min_capacity {
-        return Err(anyhow!("Insufficient balance: need {} have {}", min_capacity, total));
+    let (mut collected, total) = picker.finish();
+    if deterministic_cell_order() {
+        sort_by_outpoint(&mut collected);
     }
 
-    Ok(collected)
+    Ok((collected, total))
+}
+
+/// Whether selected cells are sorted by outpoint before building (env DETERMINISTIC_CELL_ORDER)
+/// Indexer order can differ between nodes; sorting makes the same wallet state
+/// yield the same transaction hash.
+fn deterministic_cell_order() -> bool {
+    std::env::var("DETERMINISTIC_CELL_ORDER")
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Sort cells by (tx hash, index)
+fn sort_by_outpoint(cells: &mut [(OutPoint, u64)]) {
+    cells.sort_by(|(a, _), (b, _)| {
+        let a_index: u32 = a.index().unpack();
+        let b_index: u32 = b.index().unpack();
+        a.tx_hash().as_slice().cmp(b.tx_hash().as_slice()).then(a_index.cmp(&b_index))
+    });
 }
 
 /// Find token cells by lock and type script
 /// Returns (outpoint, capacity, amount) for the first matching cell
-fn find_token_cell(client: &mut CkbRpcClient, lock: &Script, token_type: &Script) -> Result<(OutPoint, u64, u128)> {
+fn find_token_cell(client: &mut dyn CkbBackend, lock: &Script, token_type: &Script) -> Result<(OutPoint, u64, u128)> {
+    lookup_token_cell(client, lock, token_type)?.ok_or_else(|| Rejected("Token cell not found".to_string()).into())
+}
+
+/// Like find_token_cell, but holding no such tokens is Ok(None) rather than an error
+fn lookup_token_cell(client: &mut dyn CkbBackend, lock: &Script, token_type: &Script) -> Result<Option<(OutPoint, u64, u128)>> {
+    Ok(select_token_cell(token_cell_candidates(client, lock, token_type)?))
+}
+
+/// Find token cells by lock and type script holding at least `amount` tokens
+/// One cell that covers the amount is used alone; otherwise cells are taken
+/// in indexer order until their total does. Returns every cell it took, which
+/// may still fall short when the whole balance does.
+fn find_token_cells(
+    client: &mut dyn CkbBackend,
+    lock: &Script,
+    token_type: &Script,
+    amount: u128,
+) -> Result<Vec<(OutPoint, u64, u128)>> {
+    let cells = select_token_cells(token_cell_candidates(client, lock, token_type)?, amount);
+    if cells.is_empty() {
+        return Err(Rejected("Token cell not found".to_string()).into());
+    }
+    Ok(cells)
+}
+
+/// Cells under `lock` with the given token type, data not yet checked
+/// Pages through every such cell: a wallet may hold more than one page of
+/// them, and other cells under the lock must not crowd them out.
+fn token_cell_candidates(
+    client: &mut dyn CkbBackend,
+    lock: &Script,
+    token_type: &Script,
+) -> Result<Vec<(OutPoint, u64, Option<Bytes>)>> {
+    use ckb_sdk::rpc::ckb_indexer::SearchKeyFilter;
+
+    let filter = SearchKeyFilter {
+        script: Some(token_type.clone().into()),
+        script_len_range: None,
+        output_data: None,
+        output_data_filter_mode: None,
+        output_data_len_range: None,
+        output_capacity_range: None,
+        block_range: None,
+    };
+
     let search_key = SearchKey {
         script: lock.clone().into(),
         script_type: ScriptType::Lock,
         script_search_mode: Some(SearchMode::Exact),
-        filter: None,
+        filter: Some(filter),
         with_data: Some(true), // Need data to get token amount
         group_by_transaction: None,
     };
 
-    let cells = client.get_cells(search_key, Order::Asc, 100.into(), None)?;
+    let page_size = 100u32;
+    let mut candidates = Vec::new();
+    let mut cursor = None;
 
-    for cell in cells.objects {
-        // Check if this cell has the matching type script
-        if let Some(cell_type) = &cell.output.type_ {
-            let cell_type_script: Script = cell_type.clone().into();
-            if cell_type_script == *token_type {
-                let capacity: u64 = cell.output.capacity.into();
+    loop {
+        let page = retry_read(RpcRetry::from_env(), "get_cells", || {
+            client.get_cells(search_key.clone(), Order::Asc, page_size.into(), cursor.clone())
+        })?;
+        let page_len = page.objects.len();
+
+        // The filter matches type args by prefix; keep the exact type only
+        candidates.extend(page.objects.into_iter()
+            .filter(|cell| {
+                cell.output.type_.clone().map(Script::from).as_ref() == Some(token_type)
+            })
+            .map(|cell| {
                 let outpoint = OutPoint::new_builder()
                     .tx_hash(cell.out_point.tx_hash.pack())
                     .index((cell.out_point.index.value() as u32).pack())
                     .build();
+                (outpoint, cell.output.capacity.into(), cell.output_data.map(|data| data.into_bytes()))
+            }));
+
+        if page_len < page_size as usize {
+            break;
+        }
+        cursor = Some(page.last_cursor);
+    }
 
-                // Parse token amount from data (u128, 16 bytes, little endian)
-                let data = cell.output_data.ok_or_else(|| anyhow!("Token cell missing data"))?;
-                let amount_bytes: [u8; 16] = data.as_bytes()
-                    .try_into()
-                    .map_err(|_| anyhow!("Invalid token amount data"))?;
-                let amount = u128::from_le_bytes(amount_bytes);
+    Ok(candidates)
+}
 
-                return Ok((outpoint, capacity, amount));
+/// YES and NO tokens held under `lock` for a market
+/// Token types come from the market's cell (see build_token_type); cells with
+/// malformed data are skipped like everywhere else.
+fn token_balance(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    market_outpoint: &OutPoint,
+    lock: &Script,
+) -> Result<BalanceResponse> {
+    let market_cell = get_cell(client, market_outpoint)?;
+    let market_data = MarketData::from_bytes(&market_cell.data)?;
+    let (market_type, hash_type) = (market_cell.market_type()?, market_data.token_script_hash_type()?);
+
+    let mut holdings = [(0u128, 0usize); 2];
+    for (holding, is_yes) in holdings.iter_mut().zip([true, false]) {
+        let token_type = build_token_type(contracts, &market_type, hash_type, is_yes);
+        for (outpoint, _, data) in token_cell_candidates(client, lock, &token_type)? {
+            if let Some(amount) = token_cell_amount(&outpoint, data.as_ref()) {
+                holding.0 = holding.0.checked_add(amount)
+                    .ok_or_else(|| anyhow!("Token balance overflows u128"))?;
+                holding.1 += 1;
             }
         }
     }
 
-    Err(anyhow!("Token cell not found"))
+    let [(yes, yes_cells), (no, no_cells)] = holdings;
+    Ok(BalanceResponse {
+        address: encode_address(lock, contracts.network),
+        yes: yes.to_string(),
+        no: no.to_string(),
+        yes_cells,
+        no_cells,
+    })
+}
+
+/// Token amount of a cell whose data is a 16-byte little-endian u128
+/// Missing or malformed data is reported with a warning and yields None.
+fn token_cell_amount(outpoint: &OutPoint, data: Option<&Bytes>) -> Option<u128> {
+    let amount = data
+        .and_then(|data| <[u8; 16]>::try_from(data.as_ref()).ok())
+        .map(u128::from_le_bytes);
+    if amount.is_none() {
+        let tx_hash: H256 = outpoint.tx_hash().unpack();
+        let index: u32 = outpoint.index().unpack();
+        warn!(cell = %format!("{:#x}:{}", tx_hash, index), data_len = data.map_or(0, |data| data.len()),
+              "Skipping token cell without a 16-byte amount");
+    }
+    amount
+}
+
+/// First token cell whose data is a 16-byte little-endian u128 amount
+/// Cells with missing or malformed data are skipped with a warning, so one bad
+/// cell under the lock doesn't hide the valid ones.
+fn select_token_cell(
+    candidates: impl IntoIterator<Item = (OutPoint, u64, Option<Bytes>)>,
+) -> Option<(OutPoint, u64, u128)> {
+    candidates.into_iter().find_map(|(outpoint, capacity, data)| {
+        token_cell_amount(&outpoint, data.as_ref()).map(|amount| (outpoint, capacity, amount))
+    })
+}
+
+/// Valid token cells covering `amount`: the first single cell that does, or
+/// else cells in order until their total does (all of them if it never does)
+fn select_token_cells(
+    candidates: impl IntoIterator<Item = (OutPoint, u64, Option<Bytes>)>,
+    amount: u128,
+) -> Vec<(OutPoint, u64, u128)> {
+    let valid: Vec<(OutPoint, u64, u128)> = candidates.into_iter()
+        .filter_map(|(outpoint, capacity, data)| {
+            token_cell_amount(&outpoint, data.as_ref()).map(|held| (outpoint, capacity, held))
+        })
+        .collect();
+
+    if let Some(cell) = valid.iter().find(|(_, _, held)| *held >= amount) {
+        return vec![cell.clone()];
+    }
+
+    let mut selected = Vec::new();
+    let mut total = 0u128;
+    for cell in valid {
+        total = total.saturating_add(cell.2);
+        selected.push(cell);
+        if total >= amount {
+            break;
+        }
+    }
+    selected
 }
 
 fn sign_transaction(tx: TransactionView, privkey: &secp256k1::SecretKey, num_inputs: usize) -> Result<TransactionView> {
@@ -1040,7 +7022,7 @@ fn sign_transaction(tx: TransactionView, privkey: &secp256k1::SecretKey, num_inp
     for i in 0..num_inputs {
         if i == 0 {
             // First witness contains the signature
-            let witness = sign_witness(tx.hash(), privkey)?;
+            let witness = sign_witness(tx.hash(), privkey, num_inputs)?;
             witnesses.push(witness);
         } else {
             witnesses.push(Bytes::new());
@@ -1066,7 +7048,7 @@ fn sign_transaction_with_market(tx: TransactionView, privkey: &secp256k1::Secret
     // Sign fee inputs
     for i in 0..num_fee_inputs {
         if i == 0 {
-            let witness = sign_witness(tx.hash(), privkey)?;
+            let witness = sign_witness(tx.hash(), privkey, num_fee_inputs)?;
             witnesses.push(witness);
         } else {
             witnesses.push(Bytes::new());
@@ -1151,7 +7133,10 @@ fn sign_transaction_with_market_and_token(tx: TransactionView, privkey: &secp256
         .build())
 }
 
-fn sign_witness(tx_hash: ckb_types::packed::Byte32, privkey: &secp256k1::SecretKey) -> Result<Bytes> {
+/// Sign a secp256k1 witness group of `group_size` inputs
+/// The first witness carries the signature; the rest are empty but still
+/// hashed (length prefix only) as required by sighash-all.
+fn sign_witness(tx_hash: ckb_types::packed::Byte32, privkey: &secp256k1::SecretKey, group_size: usize) -> Result<Bytes> {
     use ckb_hash::new_blake2b;
 
     let secp = secp256k1::Secp256k1::new();
@@ -1162,12 +7147,17 @@ fn sign_witness(tx_hash: ckb_types::packed::Byte32, privkey: &secp256k1::SecretK
         .build();
     let witness_len = witness_args.as_bytes().len() as u64;
 
-    // Hash: tx_hash || witness_len || witness
+    // Hash: tx_hash || witness_len || witness || (len || witness) for the rest of the group
     let mut hasher = new_blake2b();
     hasher.update(tx_hash.as_slice());
     hasher.update(&witness_len.to_le_bytes());
     hasher.update(&witness_args.as_bytes());
 
+    // Remaining witnesses in the group are empty
+    for _ in 1..group_size {
+        hasher.update(&0u64.to_le_bytes());
+    }
+
     let mut message = [0u8; 32];
     hasher.finalize(&mut message);
 
@@ -1188,20 +7178,699 @@ fn sign_witness(tx_hash: ckb_types::packed::Byte32, privkey: &secp256k1::SecretK
     Ok(witness.as_bytes())
 }
 
-fn send_transaction(client: &mut CkbRpcClient, tx: &TransactionView) -> Result<H256> {
+/// How a transaction's inputs are witnessed (see the sign_transaction* fns)
+#[derive(Debug, Clone, Copy)]
+enum Signer {
+    /// Every input is a fee cell
+    FeeCells(usize),
+    /// Market cell first, then this many fee cells
+    Market(usize),
+    /// Market cell first, then this many token + fee cells in one group
+    MarketAndTokens(usize),
+}
+
+impl Signer {
+    fn sign(self, tx: TransactionView, privkey: &secp256k1::SecretKey) -> Result<TransactionView> {
+        match self {
+            Signer::FeeCells(inputs) => sign_transaction(tx, privkey, inputs),
+            Signer::Market(fee_inputs) => sign_transaction_with_market(tx, privkey, fee_inputs),
+            Signer::MarketAndTokens(signed_inputs) => sign_transaction_with_market_and_token(tx, privkey, signed_inputs),
+        }
+    }
+
+    /// The transaction with witnesses the size signing gives them, so its
+    /// size (and fee) can be known before it is signed
+    fn with_placeholder_witnesses(self, tx: TransactionView) -> TransactionView {
+        let (market_inputs, signed_inputs) = match self {
+            Signer::FeeCells(inputs) => (0, inputs),
+            Signer::Market(inputs) | Signer::MarketAndTokens(inputs) => (1, inputs),
+        };
+        // The market's dummy witness and each group's signature are both
+        // WitnessArgs with a 65-byte lock; the rest of a group is empty
+        let placeholder = WitnessArgs::new_builder()
+            .lock(Some(Bytes::from(vec![0u8; 65])).pack())
+            .build()
+            .as_bytes();
+        let witnesses = std::iter::repeat(placeholder.clone()).take(market_inputs)
+            .chain((0..signed_inputs).map(|i| if i == 0 { placeholder.clone() } else { Bytes::new() }))
+            .map(|witness| witness.pack())
+            .collect();
+
+        tx.as_advanced_builder().set_witnesses(witnesses).build()
+    }
+}
+
+/// An unsigned transaction and how to sign it
+struct BuiltTx {
+    tx: TransactionView,
+    signer: Signer,
+    /// Index of the change output, None when it was paid into the fee
+    change: Option<usize>,
+}
+
+/// Fee cells collected by transactions the market cell funds (resolve, claim,
+/// burn, refund); their collateral comes out of the market, not the wallet
+const FEE_CELL_CAPACITY: u64 = Shannons::from_ckb(1).as_shannons();
+
+/// Smallest change cell that can still be committed (secp256k1 lock, no data)
+const MIN_CHANGE_CAPACITY: u64 = Shannons::from_ckb(61).as_shannons();
+
+/// Capacity a mint keeps spare in its change on top of the fee (shannons)
+///
+/// Set with FEE_MARGIN_SHANNONS; defaults to MIN_CHANGE_CAPACITY so the change
+/// cell can always be committed whatever the fee turns out to be.
+fn fee_margin() -> u64 {
+    std::env::var("FEE_MARGIN_SHANNONS").ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(MIN_CHANGE_CAPACITY)
+}
+
+/// Fee rate the node accepts by default, in shannons per 1000 bytes
+const DEFAULT_FEE_RATE: u64 = 1000;
+
+/// Largest transaction a node accepts into its pool (bytes)
+const MAX_TX_SIZE: u64 = 512_000;
+
+/// Fee rate for built transactions, in shannons per 1000 bytes
+/// Set with FEE_RATE; defaults to DEFAULT_FEE_RATE.
+fn fee_rate() -> u64 {
+    std::env::var("FEE_RATE").ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_FEE_RATE)
+}
+
+/// Fee for a transaction at `fee_rate` shannons per 1000 bytes of its size in
+/// a block, rounded up. Witnesses count, so pass the signed transaction or
+/// one with placeholder witnesses (see Signer::with_placeholder_witnesses).
+fn estimate_fee(tx: &TransactionView, fee_rate: u64) -> u64 {
+    let size = tx.data().serialized_size_in_block() as u64;
+    size.saturating_mul(fee_rate).div_ceil(1000)
+}
+
+/// Capacity set aside for the fee while collecting inputs, before the
+/// transaction's size is known: the fee of the largest acceptable transaction
+fn fee_allowance(fee_rate: u64) -> u64 {
+    MAX_TX_SIZE.saturating_mul(fee_rate).div_ceil(1000)
+}
+
+/// Take the size-based fee of an unsigned transaction out of its change
+/// (the last output) and return the updated transaction with the fee paid
+/// and where its change ended up (see settle_change)
+/// The capacity field has a fixed size, so the fee still matches afterwards;
+/// dropping dust change only shrinks the transaction.
+fn charge_fee(tx: TransactionView, signer: Signer, fee_rate: u64) -> Result<(TransactionView, u64, Option<usize>)> {
+    let fee = estimate_fee(&signer.with_placeholder_witnesses(tx.clone()), fee_rate);
+    settle_change(&tx, fee)
+}
+
+/// Most change that may be given up as fee rather than kept in a cell (shannons)
+const MAX_FOLDED_CHANGE: u64 = Shannons::from_ckb(1).as_shannons();
+
+/// Pay `fee` out of the change (the last output) and return the transaction
+/// with the fee actually paid and the change output's index, if it is kept
+///
+/// Change left at zero, or below what its cell occupies, can't be committed:
+/// the change output is dropped and what was left goes to the fee too. More
+/// than MAX_FOLDED_CHANGE going that way is refused instead.
+fn settle_change(tx: &TransactionView, fee: u64) -> Result<(TransactionView, u64, Option<usize>)> {
+    let mut outputs: Vec<CellOutput> = tx.outputs().into_iter().collect();
+    let mut outputs_data: Vec<ckb_types::packed::Bytes> = tx.outputs_data().into_iter().collect();
+    let change = outputs.pop().ok_or_else(|| anyhow!("Transaction has no outputs"))?;
+    outputs_data.pop();
+    if change.type_().to_opt().is_some() {
+        return Err(anyhow!("Last output is not a change cell"));
+    }
+
+    let capacity: u64 = change.capacity().unpack();
+    let remaining = capacity.checked_sub(fee)
+        .ok_or_else(|| anyhow!("Change of {} shannons cannot cover a {} shannon fee", capacity, fee))?;
+    let minimum = occupied_capacity(&change, 0)?;
+
+    if remaining >= minimum {
+        let index = outputs.len();
+        outputs.push(change.as_builder().capacity(remaining.pack()).build());
+        outputs_data.push(Bytes::new().pack());
+        let tx = tx.as_advanced_builder().set_outputs(outputs).set_outputs_data(outputs_data).build();
+        return Ok((tx, fee, Some(index)));
+    }
+    if remaining > MAX_FOLDED_CHANGE {
+        return Err(anyhow!(
+            "Change of {} shannons after the fee is below the {} a change cell needs; \
+             add a larger fee cell instead of paying it as fee", remaining, minimum
+        ));
+    }
+
+    let tx = tx.as_advanced_builder().set_outputs(outputs).set_outputs_data(outputs_data).build();
+    Ok((tx, capacity, None))
+}
+
+/// Check that a built transaction's inputs cover its outputs plus exactly `fee`,
+/// and that its change (the last output) keeps at least `margin`
+fn verify_fee_margin(input_capacity: u64, tx: &TransactionView, fee: u64, margin: u64) -> Result<()> {
+    let output_capacity = tx.outputs().into_iter()
+        .try_fold(0u64, |total, output| total.checked_add(output.capacity().unpack()))
+        .ok_or_else(|| anyhow!("Output capacity overflow"))?;
+    let actual_fee = input_capacity.checked_sub(output_capacity)
+        .ok_or_else(|| anyhow!("Outputs {} exceed inputs {}", output_capacity, input_capacity))?;
+    if actual_fee != fee {
+        return Err(anyhow!("Transaction pays {} shannons of fee, expected {}", actual_fee, fee));
+    }
+
+    let change: u64 = tx.outputs().into_iter().last()
+        .map(|output| output.capacity().unpack())
+        .unwrap_or(0);
+    if change < margin {
+        return Err(anyhow!("Change of {} shannons is below the {} shannon fee margin", change, margin));
+    }
+    Ok(())
+}
+
+/// Fee bumping for transactions stuck in the pool
+///
+/// Enabled by TX_BUMP_AFTER_SECS: once a transaction has waited that long
+/// uncommitted, a replacement spending the same inputs is sent, at most
+/// TX_BUMP_MAX times (default 3). Each one pays the previous fee plus
+/// TX_BUMP_RATE shannons per 1000 bytes of the transaction (default
+/// DEFAULT_MIN_RBF_RATE), the least the pool takes to replace it.
+struct BumpConfig {
+    poll: Duration,
+    polls_per_bump: u32,
+    max_bumps: u32,
+    /// Fee rate each bump adds, in shannons per 1000 bytes
+    rate: u64,
+}
+
+/// Rate a replacement's fee must rise by for the pool to take it (the node's
+/// default min_rbf_rate), in shannons per 1000 bytes
+const DEFAULT_MIN_RBF_RATE: u64 = 1500;
+
+impl BumpConfig {
+    fn from_env() -> Option<Self> {
+        let after_secs: u64 = std::env::var("TX_BUMP_AFTER_SECS").ok()?.parse().ok()?;
+        let env_or = |name: &str, default: u64| -> u64 {
+            std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+        };
+        let poll = Duration::from_secs(2);
+        Some(BumpConfig {
+            poll,
+            polls_per_bump: (after_secs / poll.as_secs()).max(1) as u32,
+            max_bumps: env_or("TX_BUMP_MAX", 3) as u32,
+            rate: env_or("TX_BUMP_RATE", DEFAULT_MIN_RBF_RATE),
+        })
+    }
+}
+
+/// Raise the fee of an unsigned transaction by taking `extra_fee` from its
+/// change output (at index `change`, as charge_fee reported it)
+fn bump_fee(tx: &TransactionView, change: usize, extra_fee: u64) -> Result<TransactionView> {
+    let mut outputs: Vec<CellOutput> = tx.outputs().into_iter().collect();
+    let output = outputs.get(change).ok_or_else(|| anyhow!("Transaction has no output {}", change))?;
+    if output.type_().to_opt().is_some() {
+        return Err(anyhow!("Output {} is not a change cell", change));
+    }
+
+    let capacity: u64 = output.capacity().unpack();
+    let bumped = capacity.checked_sub(extra_fee)
+        .filter(|bumped| *bumped >= MIN_CHANGE_CAPACITY)
+        .ok_or_else(|| anyhow!("Change of {} shannons cannot cover {} more shannons of fee", capacity, extra_fee))?;
+    outputs[change] = output.clone().as_builder().capacity(bumped.pack()).build();
+
+    Ok(tx.as_advanced_builder().set_outputs(outputs).build())
+}
+
+/// Wait for a submitted transaction to commit, replacing it with higher-fee
+/// versions while it stays pending; returns whichever version commits
+/// The pool rejecting the latest version fails at once with its reason, as
+/// in wait_for_commit; earlier versions are rejected once replaced.
+fn wait_with_bumps(
+    built: &BuiltTx,
+    privkey: &secp256k1::SecretKey,
+    config: &BumpConfig,
+    mut submit: impl FnMut(&TransactionView) -> Result<H256>,
+    mut check: impl FnMut(&H256) -> Result<Confirmation>,
+) -> Result<H256> {
+    let signed = built.signer.sign(built.tx.clone(), privkey)?;
+    let step = estimate_fee(&signed, config.rate);
+    let mut sent = vec![submit(&signed)?];
+    let mut bumps = 0u32;
+    let mut polls = 0u32;
+
+    // Once sent, a failed poll leaves the latest version possibly committing;
+    // a rejection (the inner error) does not
+    let waited = (|| -> Result<Result<H256>> { loop {
+        std::thread::sleep(config.poll);
+        for (index, tx_hash) in sent.iter().enumerate() {
+            match check(tx_hash)? {
+                Confirmation::Committed => return Ok(Ok(tx_hash.clone())),
+                Confirmation::Rejected(reason) if index == sent.len() - 1 => {
+                    return Ok(Err(anyhow!("Transaction {:#x} was rejected: {}", tx_hash, reason)));
+                }
+                _ => {}
+            }
+        }
+
+        polls += 1;
+        if polls < config.polls_per_bump {
+            continue;
+        }
+        polls = 0;
+
+        if bumps == config.max_bumps {
+            return Err(anyhow!("Transaction not committed after {} fee bumps: {:?}", bumps, sent));
+        }
+        bumps += 1;
+
+        let Some(change) = built.change else {
+            warn!(bump = bumps, "No change output to pay a fee bump from");
+            continue;
+        };
+        let bumped = built.signer.sign(bump_fee(&built.tx, change, step * bumps as u64)?, privkey)?;
+        match submit(&bumped) {
+            Ok(tx_hash) => {
+                info!(bump = bumps, tx_hash = %format!("{:#x}", tx_hash), "Fee bumped");
+                sent.push(tx_hash);
+            }
+            // The original may have committed in the meantime; keep polling
+            Err(err) => warn!(bump = bumps, error = %err, "Fee bump not accepted"),
+        }
+    } })();
+    waited.unwrap_or_else(|reason| Err(Unconfirmed { tx_hash: sent[sent.len() - 1].clone(), reason }.into()))
+}
+
+/// Check and sign a market transaction without sending it
+fn sign_market_tx(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    contracts: &ContractInfo,
+    built: &BuiltTx,
+) -> Result<TransactionView> {
+    let resolved_inputs = resolve_inputs(client, &built.tx)?;
+    validate_market_tx(contracts, &built.tx, &resolved_inputs)?;
+    built.signer.sign(built.tx.clone(), privkey)
+}
+
+/// Check, sign and send a market transaction as `mode` says and return the
+/// new market cell (output 0)
+fn submit_market_tx(
+    client: &mut dyn CkbBackend,
+    privkey: &secp256k1::SecretKey,
+    contracts: &ContractInfo,
+    op: &str,
+    built: &BuiltTx,
+    mode: SendMode,
+) -> Result<OutPoint> {
+    let resolved_inputs = resolve_inputs(client, &built.tx)?;
+    validate_market_tx(contracts, &built.tx, &resolved_inputs)?;
+    let mut log = operation_log(op, &built.tx, &resolved_inputs)?;
+
+    // Bumping needs to watch the transaction, so it only applies when waiting
+    let tx_hash = match BumpConfig::from_env().filter(|_| mode == SendMode::Wait) {
+        Some(config) => {
+            debug!("Waiting for confirmation");
+            // Both callbacks use the client, one call at a time
+            let client = std::cell::RefCell::new(client);
+            wait_with_bumps(
+                built,
+                privkey,
+                &config,
+                |tx| {
+                    let tx_json: ckb_jsonrpc_types::Transaction = tx.data().into();
+                    Ok(client.borrow_mut().send_transaction(tx_json, None)?)
+                },
+                |tx_hash| tx_confirmation(&mut **client.borrow_mut(), tx_hash),
+            )?
+        }
+        None => send_transaction(client, &built.signer.sign(built.tx.clone(), privkey)?, mode)?,
+    };
+
+    info!(tx_hash = %format!("{:#x}", tx_hash), op, "Transaction sent");
+    let market = OutPoint::new_builder()
+        .tx_hash(tx_hash.pack())
+        .index(0u32.pack())
+        .build();
+
+    // A fee bump only moves capacity from change to fee
+    if tx_hash != built.tx.hash().unpack() {
+        log.fee = outpoint_tx_fee(client, &market)?;
+    }
+    log.market_id = format!("{:#x}:0", tx_hash);
+    log.tx_hash = format!("{:#x}", tx_hash);
+    info!(
+        op = %log.op,
+        market_id = %log.market_id,
+        tx_hash = %log.tx_hash,
+        yes_delta = log.yes_delta,
+        no_delta = log.no_delta,
+        capacity_delta = log.capacity_delta,
+        fee = log.fee,
+        "OP {}", serde_json::to_string(&log)?
+    );
+
+    Ok(market)
+}
+
+/// Decode a market transaction's effect: supply and capacity deltas of the
+/// market cell (output 0) and the fee, with market_id and tx_hash naming the
+/// unsigned transaction until it commits
+fn operation_log(op: &str, tx: &TransactionView, resolved_inputs: &[(CellOutput, Bytes)]) -> Result<OperationLog> {
+    let market_output = tx.outputs().get(0).ok_or_else(|| anyhow!("Transaction has no outputs"))?;
+    let after = MarketData::from_bytes(&tx.outputs_data().get(0).map(|data| data.raw_data()).unwrap_or_default())?;
+    let after_capacity: u64 = market_output.capacity().unpack();
+
+    // The market input carries the same type script as the market output
+    let market_input = resolved_inputs.iter()
+        .find(|(output, _)| output.type_().as_slice() == market_output.type_().as_slice());
+    let (before, before_capacity) = match market_input {
+        Some((output, data)) => (MarketData::from_bytes(data)?, output.capacity().unpack()),
+        None => (MarketData::new([0; 32], DEFAULT_TOKEN_HASH_TYPE), 0u64),
+    };
+
+    let input_capacity = resolved_inputs.iter()
+        .try_fold(0u64, |total, (output, _)| total.checked_add(output.capacity().unpack()))
+        .ok_or_else(|| anyhow!("Input capacity overflow"))?;
+    let output_capacity = tx.outputs().into_iter()
+        .try_fold(0u64, |total, output| total.checked_add(output.capacity().unpack()))
+        .ok_or_else(|| anyhow!("Output capacity overflow"))?;
+    let tx_hash: H256 = tx.hash().unpack();
+
+    Ok(OperationLog {
+        op: op.to_string(),
+        market_id: format!("{:#x}:0", tx_hash),
+        tx_hash: format!("{:#x}", tx_hash),
+        yes_delta: after.yes_supply() as i128 - before.yes_supply() as i128,
+        no_delta: after.no_supply() as i128 - before.no_supply() as i128,
+        capacity_delta: after_capacity as i64 - before_capacity as i64,
+        fee: input_capacity.checked_sub(output_capacity)
+            .ok_or_else(|| anyhow!("Outputs {} exceed inputs {}", output_capacity, input_capacity))?,
+    })
+}
+
+/// Explain a built transaction: roles of its cells and deps, data summaries, fee
+fn describe_transaction(
+    client: &mut dyn CkbBackend,
+    contracts: &ContractInfo,
+    op: &str,
+    tx: &TransactionView,
+) -> Result<TxPreview> {
+    let mut inputs = Vec::new();
+    let mut total_input = 0u64;
+    for input in tx.inputs() {
+        let outpoint = input.previous_output();
+        let tx_hash: H256 = outpoint.tx_hash().unpack();
+        let index: u32 = outpoint.index().unpack();
+        let previous = get_transaction_view(client, tx_hash.clone())?;
+        let output = previous.inner.outputs.get(index as usize)
+            .ok_or_else(|| anyhow!("Output not found"))?;
+
+        let capacity: u64 = output.capacity.into();
+        let role = match output.type_.clone() {
+            Some(type_script) => format!("{} cell", script_role(contracts, &type_script.into())),
+            None => "fee cell".to_string(),
+        };
+        total_input += capacity;
+        inputs.push(PreviewInput { outpoint: format!("{:#x}:{}", tx_hash, index), capacity, role });
+    }
+
+    let mut outputs = Vec::new();
+    let mut total_output = 0u64;
+    for (output, data) in tx.outputs().into_iter().zip(tx.outputs_data()) {
+        let capacity: u64 = output.capacity().unpack();
+        let type_script = output.type_().to_opt();
+        let data = data.raw_data();
+        total_output += capacity;
+        outputs.push(PreviewOutput {
+            capacity,
+            lock: script_role(contracts, &output.lock()),
+            data: summarize_cell_data(contracts, type_script.as_ref(), &data),
+            type_script: type_script.map(|script| script_role(contracts, &script)),
+        });
+    }
+
+    let cell_deps = tx.cell_deps().into_iter()
+        .map(|dep| {
+            let tx_hash: H256 = dep.out_point().tx_hash().unpack();
+            let index: u32 = dep.out_point().index().unpack();
+            let is_group = dep.dep_type().as_slice() == [ckb_types::core::DepType::DepGroup as u8];
+            let role = if is_group {
+                "secp256k1 dep group"
+            } else if tx_hash == contracts.market_tx_hash {
+                "market contract"
+            } else if tx_hash == contracts.token_tx_hash {
+                "token contract"
+            } else if tx_hash == contracts.always_success_tx_hash {
+                "always-success contract"
+            } else {
+                "unknown"
+            };
+            PreviewCellDep {
+                outpoint: format!("{:#x}:{}", tx_hash, index),
+                dep_type: if is_group { "dep_group" } else { "code" }.to_string(),
+                role: role.to_string(),
+            }
+        })
+        .collect();
+
+    let fee = total_input.checked_sub(total_output)
+        .ok_or_else(|| anyhow!("Transaction outputs {} exceed inputs {}", total_output, total_input))?;
+
+    Ok(TxPreview { op: op.to_string(), inputs, outputs, cell_deps, fee, transaction: None })
+}
+
+/// Name the role a lock or type script plays in market transactions
+fn script_role(contracts: &ContractInfo, script: &Script) -> String {
+    let code_hash: H256 = script.code_hash().unpack();
+    let args = script.args().raw_data();
+
+    if code_hash == contracts.market_code_hash {
+        "market".to_string()
+    } else if code_hash == contracts.token_code_hash {
+        match args.get(32) {
+            Some(&YES_TOKEN_ID) => "YES token".to_string(),
+            Some(&NO_TOKEN_ID) => "NO token".to_string(),
+            Some(token_id) => format!("outcome {:#04x} token", token_id),
+            None => "unknown token".to_string(),
+        }
+    } else if code_hash == contracts.always_success_code_hash {
+        "market lock (always-success)".to_string()
+    } else if code_hash == SIGHASH_TYPE_HASH {
+        format!("secp256k1 0x{}", hex::encode(&args))
+    } else {
+        format!("unknown script {:#x}", code_hash)
+    }
+}
+
+/// Short description of cell data, decoded according to the cell's type
+fn summarize_cell_data(contracts: &ContractInfo, type_script: Option<&Script>, data: &[u8]) -> String {
+    let code_hash: Option<H256> = type_script.map(|script| script.code_hash().unpack());
+
+    if code_hash.as_ref() == Some(&contracts.market_code_hash) {
+        match MarketData::from_bytes(data) {
+            Ok(market) => {
+                let state = if market.cancelled {
+                    "cancelled"
+                } else if !market.resolved {
+                    "open"
+                } else if market.outcome {
+                    "resolved YES"
+                } else {
+                    "resolved NO"
+                };
+                format!("YES supply {}, NO supply {}, {}", market.yes_supply(), market.no_supply(), state)
+            }
+            Err(err) => format!("invalid market data: {}", err),
+        }
+    } else if code_hash.as_ref() == Some(&contracts.token_code_hash) && data.len() >= 16 {
+        let mut amount = [0u8; 16];
+        amount.copy_from_slice(&data[..16]);
+        format!("{} tokens", u128::from_le_bytes(amount))
+    } else if data.is_empty() {
+        "empty".to_string()
+    } else {
+        format!("{} bytes", data.len())
+    }
+}
+
+/// A transaction as the node's RPC returns it, for ckb-cli, explorers and other tools
+fn transaction_json(tx: &TransactionView) -> ckb_jsonrpc_types::TransactionView {
+    ckb_jsonrpc_types::TransactionView {
+        inner: tx.data().into(),
+        hash: tx.hash().unpack(),
+    }
+}
+
+fn send_transaction(client: &mut dyn CkbBackend, tx: &TransactionView, mode: SendMode) -> Result<H256> {
     let tx_json: ckb_jsonrpc_types::Transaction = tx.data().into();
     let tx_hash = client.send_transaction(tx_json, None)?;
+    if mode == SendMode::BroadcastOnly {
+        return Ok(tx_hash);
+    }
 
     // Wait for confirmation
-    println!("  Waiting for confirmation...");
+    debug!("Waiting for confirmation");
+    wait_for_commit(&tx_hash, confirm_attempts(), Duration::from_secs(2), || {
+        tx_confirmation(client, &tx_hash)
+    })?;
+
+    Ok(tx_hash)
+}
+
+/// Default retries of a failed RPC read
+const DEFAULT_RPC_RETRIES: u32 = 3;
+
+/// Default wait before the first retry of an RPC read (milliseconds)
+const DEFAULT_RPC_BACKOFF_MS: u64 = 200;
+
+/// How idempotent RPC reads (tip, cells, transactions) are retried while a
+/// busy or restarting node fails to answer
+///
+/// Set with RPC_RETRIES and RPC_BACKOFF_MS; each retry waits twice as long as
+/// the one before. Sends never retry: one that failed in transit may still
+/// have reached the pool, and sending it again would broadcast twice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RpcRetry {
+    retries: u32,
+    backoff: Duration,
+}
+
+impl RpcRetry {
+    fn from_env() -> Self {
+        let parsed = |name: &str| std::env::var(name).ok().and_then(|value| value.parse().ok());
+        RpcRetry {
+            retries: parsed("RPC_RETRIES").map_or(DEFAULT_RPC_RETRIES, |retries: u64| retries as u32),
+            backoff: Duration::from_millis(parsed("RPC_BACKOFF_MS").unwrap_or(DEFAULT_RPC_BACKOFF_MS)),
+        }
+    }
+
+    /// Wait before retry number `retry`, counting from 0
+    fn delay(&self, retry: u32) -> Duration {
+        self.backoff.saturating_mul(1 << retry.min(16))
+    }
+}
+
+/// The chain's median time in seconds, the clock `since` time locks and
+/// resolution delays are measured against
+fn median_time_secs(client: &mut dyn CkbBackend) -> Result<u64> {
+    let info = retry_read(RpcRetry::from_env(), "get_blockchain_info", || client.get_blockchain_info())?;
+    Ok(info.median_time.value() / 1000)
+}
+
+/// Run an idempotent RPC read, retrying failures to reach the node
+///
+/// An error the node itself answered with (RpcError::Rpc) is its verdict on
+/// the request and is returned at once; transport and decoding failures are
+/// retried as `policy` allows, and the last one is returned.
+fn retry_read<T>(policy: RpcRetry, method: &str, mut read: impl FnMut() -> Result<T, RpcError>) -> Result<T, RpcError> {
+    let mut retry = 0;
     loop {
-        std::thread::sleep(std::time::Duration::from_secs(2));
-        if let Some(status) = client.get_transaction(tx_hash.clone())? {
-            if status.tx_status.status == ckb_jsonrpc_types::Status::Committed {
-                break;
+        match read() {
+            Err(err) if retry < policy.retries && !matches!(err, RpcError::Rpc(_)) => {
+                let delay = policy.delay(retry);
+                warn!(method, retry = retry + 1, delay_ms = delay.as_millis() as u64, error = %err, "RPC read failed, retrying");
+                std::thread::sleep(delay);
+                retry += 1;
             }
+            result => return result,
         }
     }
+}
 
-    Ok(tx_hash)
+/// Default number of confirmation polls before a send gives up
+const DEFAULT_CONFIRM_ATTEMPTS: u32 = 30;
+
+/// How many times send_transaction polls for a commit, 2 seconds apart
+///
+/// Set with CONFIRM_ATTEMPTS; defaults to DEFAULT_CONFIRM_ATTEMPTS (about a
+/// minute), so a dropped transaction can't hold the wallet lock forever.
+fn confirm_attempts() -> u32 {
+    std::env::var("CONFIRM_ATTEMPTS").ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|attempts| *attempts > 0)
+        .unwrap_or(DEFAULT_CONFIRM_ATTEMPTS)
+}
+
+/// Where a sent transaction stands
+#[derive(Debug, PartialEq)]
+enum Confirmation {
+    Committed,
+    /// Pending, proposed or not (yet) known to the node
+    Waiting,
+    /// Rejected by the pool, with the node's reason
+    Rejected(String),
+}
+
+/// Look up a sent transaction's status
+fn tx_confirmation(client: &mut dyn CkbBackend, tx_hash: &H256) -> Result<Confirmation> {
+    use ckb_jsonrpc_types::Status;
+
+    let Some(status) = retry_read(RpcRetry::from_env(), "get_transaction", || client.get_transaction(tx_hash.clone()))? else {
+        return Ok(Confirmation::Waiting);
+    };
+    Ok(match status.tx_status.status {
+        Status::Committed => Confirmation::Committed,
+        Status::Rejected => Confirmation::Rejected(status.tx_status.reason.unwrap_or_else(|| "no reason given".to_string())),
+        _ => Confirmation::Waiting,
+    })
+}
+
+/// Confirmation status of any transaction the node knows
+/// Pending and proposed both read as "pending"; a hash the node has never
+/// seen (or has forgotten) is NotFound.
+fn tx_status(client: &mut dyn CkbBackend, tx_hash: &H256) -> Result<TxStatusResponse> {
+    use ckb_jsonrpc_types::Status;
+
+    let unknown = || NotFound(format!("Transaction {:#x} not found", tx_hash));
+    let tx = retry_read(RpcRetry::from_env(), "get_transaction", || client.get_transaction(tx_hash.clone()))?
+        .ok_or_else(unknown)?;
+    let status = match tx.tx_status.status {
+        Status::Pending | Status::Proposed => "pending",
+        Status::Committed => "committed",
+        Status::Rejected => "rejected",
+        Status::Unknown => return Err(unknown().into()),
+    };
+
+    Ok(TxStatusResponse {
+        tx_hash: format!("{:#x}", tx_hash),
+        status,
+        block_number: tx.tx_status.block_number.map(|number| number.value()),
+        reason: tx.tx_status.reason,
+    })
+}
+
+/// How a send finishes
+///
+/// The server's request handlers broadcast only with BROADCAST_ONLY=1 (see
+/// NodeConfig); everything else waits. Callers then poll /api/tx/{hash};
+/// the next operation should wait for the commit, since the indexer only
+/// sees the new cells (and stops offering the spent fee cells) from then on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendMode {
+    /// Wait for the commit, bumping the fee if TX_BUMP_AFTER_SECS is set
+    Wait,
+    /// Return as soon as the node accepts the transaction
+    BroadcastOnly,
+}
+
+/// Poll `check` up to `attempts` times, `poll` apart, until the transaction
+/// commits; a rejection fails immediately with the node's reason, and a
+/// timeout or failed poll is Unconfirmed, since the transaction may still commit
+fn wait_for_commit(
+    tx_hash: &H256,
+    attempts: u32,
+    poll: Duration,
+    mut check: impl FnMut() -> Result<Confirmation>,
+) -> Result<()> {
+    for _ in 0..attempts {
+        std::thread::sleep(poll);
+        match check() {
+            Ok(Confirmation::Committed) => return Ok(()),
+            Ok(Confirmation::Rejected(reason)) => {
+                return Err(anyhow!("Transaction {:#x} was rejected: {}", tx_hash, reason));
+            }
+            Ok(Confirmation::Waiting) => {}
+            Err(reason) => return Err(Unconfirmed { tx_hash: tx_hash.clone(), reason }.into()),
+        }
+    }
+
+    let reason = anyhow!("Transaction {:#x} not committed after {} attempts", tx_hash, attempts);
+    Err(Unconfirmed { tx_hash: tx_hash.clone(), reason }.into())
 }
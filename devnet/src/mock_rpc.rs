@@ -0,0 +1,771 @@
+//! In-memory node for testing the server without a devnet
+//!
+//! MockNode implements CkbBackend over a UTXO set and commits every
+//! transaction it is sent at once, one block each. It refuses what a node
+//! would refuse before running scripts: inputs that aren't live cells, outputs
+//! below their occupied capacity and outputs worth more than the inputs.
+//! Scripts and signatures are not checked, and headers, proofs and transaction
+//! history are not served. Chain time is the wall clock plus however far a
+//! test has advanced it, so time locks can be waited out at once.
+//!
+//! Responses are built from the node's JSON, so they decode into the same
+//! types the real RPC answers with.
+
+use super::*;
+use ckb_jsonrpc_types::{JsonBytes, Uint32};
+use serde_json::json;
+
+/// A live cell and the block that created it
+struct LiveCell {
+    outpoint: OutPoint,
+    output: CellOutput,
+    data: Bytes,
+    block_number: u64,
+}
+
+#[derive(Default)]
+struct Chain {
+    /// Committed transactions; the one at index i is in block i + 1
+    committed: Vec<TransactionView>,
+    /// Live cells in creation order, the order the indexer returns them in
+    live: Vec<LiveCell>,
+    /// Seconds the chain's median time runs ahead of the wall clock
+    clock_ahead_secs: u64,
+}
+
+/// Handle on one in-memory chain; clones share it, as clients of one node do
+#[derive(Clone, Default)]
+struct MockNode {
+    chain: Arc<Mutex<Chain>>,
+}
+
+impl MockNode {
+    /// Give `owner` an empty cell of `capacity`, as a genesis issue would
+    fn fund(&self, owner: &Script, capacity: u64) {
+        self.issue(vec![(CellOutput::new_builder().capacity(capacity.pack()).lock(owner.clone()).build(), Bytes::new())]);
+    }
+
+    /// Create `cells` out of nothing in one block, as a genesis issue would
+    fn issue(&self, cells: Vec<(CellOutput, Bytes)>) {
+        let (outputs, outputs_data): (Vec<_>, Vec<_>) = cells.into_iter()
+            .map(|(output, data)| (output, data.pack()))
+            .unzip();
+        let tx = TransactionView::new_advanced_builder()
+            .outputs(outputs)
+            .outputs_data(outputs_data)
+            .build();
+        lock(&self.chain).commit(tx);
+    }
+
+    /// Move chain time `secs` forward
+    fn advance_clock(&self, secs: u64) {
+        lock(&self.chain).clock_ahead_secs += secs;
+    }
+
+    /// Every transaction committed so far, oldest first
+    fn committed(&self) -> Vec<TransactionView> {
+        lock(&self.chain).committed.clone()
+    }
+
+    /// Capacity of the empty cells under `owner`
+    fn balance(&self, owner: &Script) -> u64 {
+        lock(&self.chain).live.iter()
+            .filter(|cell| cell.output.lock().as_slice() == owner.as_slice() && cell.output.type_().is_none())
+            .map(|cell| Unpack::<u64>::unpack(&cell.output.capacity()))
+            .sum()
+    }
+}
+
+impl Chain {
+    fn commit(&mut self, tx: TransactionView) {
+        for input in tx.inputs() {
+            let spent = input.previous_output();
+            self.live.retain(|cell| cell.outpoint.as_slice() != spent.as_slice());
+        }
+        let block_number = self.committed.len() as u64 + 1;
+        for (index, (output, data)) in tx.outputs_with_data_iter().enumerate() {
+            let outpoint = OutPoint::new(tx.hash(), index as u32);
+            self.live.push(LiveCell { outpoint, output, data, block_number });
+        }
+        self.committed.push(tx);
+    }
+
+    /// Refuse `tx` the way a node would before running its scripts
+    fn check(&self, tx: &TransactionView) -> Result<(), RpcError> {
+        let mut inputs = 0u64;
+        for input in tx.inputs() {
+            let spent = input.previous_output();
+            let cell = self.live_cell(&spent)
+                .ok_or_else(|| refused(format!("input {} is not a live cell", format_market_id(&spent))))?;
+            inputs += Unpack::<u64>::unpack(&cell.output.capacity());
+        }
+
+        let mut outputs = 0u64;
+        for (index, (output, data)) in tx.outputs_with_data_iter().enumerate() {
+            let capacity: u64 = output.capacity().unpack();
+            let occupied = occupied_capacity(&output, data.len()).map_err(RpcError::Other)?;
+            if capacity < occupied {
+                return Err(refused(format!("output {} holds {} shannons, below its occupied {}", index, capacity, occupied)));
+            }
+            outputs += capacity;
+        }
+        if outputs > inputs {
+            return Err(refused(format!("outputs hold {} shannons, more than the inputs' {}", outputs, inputs)));
+        }
+        Ok(())
+    }
+
+    fn live_cell(&self, outpoint: &OutPoint) -> Option<&LiveCell> {
+        self.live.iter().find(|cell| cell.outpoint.as_slice() == outpoint.as_slice())
+    }
+
+    /// A committed transaction and its block number
+    fn transaction(&self, tx_hash: &H256) -> Option<(&TransactionView, u64)> {
+        self.committed.iter().zip(1..)
+            .find(|(tx, _)| Unpack::<H256>::unpack(&tx.hash()) == *tx_hash)
+    }
+
+    /// Live cells `search_key` selects, in `order`
+    fn search(&self, search_key: &SearchKey, order: Order) -> Vec<&LiveCell> {
+        let mut cells: Vec<&LiveCell> = self.live.iter()
+            .filter(|cell| selects(search_key, &cell.output, &cell.data))
+            .collect();
+        if matches!(order, Order::Desc) {
+            cells.reverse();
+        }
+        cells
+    }
+}
+
+fn refused(reason: String) -> RpcError {
+    RpcError::Other(anyhow!("Transaction refused: {}", reason))
+}
+
+fn unsupported(method: &str) -> RpcError {
+    RpcError::Other(anyhow!("{} is not served by the mock node", method))
+}
+
+/// Fake hash of block `number`
+fn block_hash(number: u64) -> H256 {
+    H256(blake2b_256(number.to_le_bytes()))
+}
+
+/// Whether an indexer search key, filter included, selects a cell
+fn selects(search_key: &SearchKey, output: &CellOutput, data: &Bytes) -> bool {
+    let (searched, other) = match search_key.script_type {
+        ScriptType::Lock => (Some(output.lock()), output.type_().to_opt()),
+        ScriptType::Type => (output.type_().to_opt(), Some(output.lock())),
+    };
+    let exact = matches!(search_key.script_search_mode, Some(SearchMode::Exact));
+    if !script_matches(&search_key.script.clone().into(), searched.as_ref(), exact) {
+        return false;
+    }
+
+    let Some(filter) = &search_key.filter else {
+        return true;
+    };
+    // The indexer measures a script as its code hash, hash type and args
+    let other_len = other.as_ref().map_or(0, |script| 33 + script.args().raw_data().len() as u64);
+    let capacity: u64 = output.capacity().unpack();
+    filter.script.as_ref().is_none_or(|script| script_matches(&script.clone().into(), other.as_ref(), false))
+        && within(&filter.script_len_range, other_len)
+        && within(&filter.output_data_len_range, data.len() as u64)
+        && within(&filter.output_capacity_range, capacity)
+}
+
+/// Whether `script` is `wanted`, or starts with its args unless `exact`
+fn script_matches(wanted: &Script, script: Option<&Script>, exact: bool) -> bool {
+    let Some(script) = script else {
+        return false;
+    };
+    let (wanted_args, args) = (wanted.args().raw_data(), script.args().raw_data());
+    wanted.code_hash().as_slice() == script.code_hash().as_slice()
+        && wanted.hash_type().as_slice() == script.hash_type().as_slice()
+        && if exact { args == wanted_args } else { args.starts_with(&wanted_args) }
+}
+
+/// Whether `value` is in a half-open indexer range, if there is one
+fn within<T: Clone + Into<u64>>(range: &Option<[T; 2]>, value: u64) -> bool {
+    match range {
+        Some([low, high]) => (low.clone().into()..high.clone().into()).contains(&value),
+        None => true,
+    }
+}
+
+impl CkbBackend for MockNode {
+    fn get_blockchain_info(&mut self) -> Result<ckb_jsonrpc_types::ChainInfo, RpcError> {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64)
+            + lock(&self.chain).clock_ahead_secs * 1000;
+        Ok(serde_json::from_value(json!({
+            "chain": "ckb_dev",
+            "median_time": format!("{:#x}", now_ms),
+            "epoch": "0x0",
+            "difficulty": "0x1",
+            "is_initial_block_download": false,
+            "alerts": [],
+        }))?)
+    }
+
+    fn get_tip_block_number(&mut self) -> Result<ckb_jsonrpc_types::BlockNumber, RpcError> {
+        Ok((lock(&self.chain).committed.len() as u64).into())
+    }
+
+    fn get_header(&mut self, _block_hash: H256) -> Result<Option<ckb_jsonrpc_types::HeaderView>, RpcError> {
+        Err(unsupported("get_header"))
+    }
+
+    fn get_transaction(&mut self, tx_hash: H256) -> Result<Option<ckb_jsonrpc_types::TransactionWithStatusResponse>, RpcError> {
+        let chain = lock(&self.chain);
+        let Some((tx, block_number)) = chain.transaction(&tx_hash) else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_value(json!({
+            "transaction": transaction_json(tx),
+            "tx_status": {
+                "status": "committed",
+                "block_number": format!("{:#x}", block_number),
+                "block_hash": block_hash(block_number),
+            },
+        }))?))
+    }
+
+    fn get_transaction_proof(
+        &mut self,
+        _tx_hashes: Vec<H256>,
+        _block_hash: Option<H256>,
+    ) -> Result<ckb_jsonrpc_types::TransactionProof, RpcError> {
+        Err(unsupported("get_transaction_proof"))
+    }
+
+    fn get_live_cell(&mut self, out_point: ckb_jsonrpc_types::OutPoint, with_data: bool) -> Result<ckb_jsonrpc_types::CellWithStatus, RpcError> {
+        let chain = lock(&self.chain);
+        let outpoint: OutPoint = out_point.into();
+        let response = match chain.live_cell(&outpoint) {
+            Some(cell) => json!({
+                "cell": {
+                    "output": ckb_jsonrpc_types::CellOutput::from(cell.output.clone()),
+                    "data": with_data.then(|| json!({
+                        "content": JsonBytes::from_bytes(cell.data.clone()),
+                        "hash": H256(blake2b_256(&cell.data)),
+                    })),
+                },
+                "status": "live",
+            }),
+            // Every output of a committed transaction that isn't live was spent
+            None => {
+                let index: u32 = outpoint.index().unpack();
+                let created = chain.transaction(&outpoint.tx_hash().unpack())
+                    .is_some_and(|(tx, _)| (index as usize) < tx.outputs().len());
+                json!({ "cell": null, "status": if created { "dead" } else { "unknown" } })
+            }
+        };
+        Ok(serde_json::from_value(response)?)
+    }
+
+    fn get_cells(
+        &mut self,
+        search_key: SearchKey,
+        order: Order,
+        limit: Uint32,
+        after: Option<JsonBytes>,
+    ) -> Result<ckb_indexer::Pagination<ckb_indexer::Cell>, RpcError> {
+        let chain = lock(&self.chain);
+        // Cursors are the number of cells already returned
+        let skip = after
+            .and_then(|cursor| <[u8; 8]>::try_from(cursor.as_bytes()).ok())
+            .map_or(0, u64::from_be_bytes) as usize;
+        let with_data = search_key.with_data.unwrap_or(true);
+        let objects: Vec<serde_json::Value> = chain.search(&search_key, order).into_iter()
+            .skip(skip)
+            .take(limit.value() as usize)
+            .map(|cell| json!({
+                "output": ckb_jsonrpc_types::CellOutput::from(cell.output.clone()),
+                "output_data": with_data.then(|| JsonBytes::from_bytes(cell.data.clone())),
+                "out_point": ckb_jsonrpc_types::OutPoint::from(cell.outpoint.clone()),
+                "block_number": format!("{:#x}", cell.block_number),
+                "tx_index": "0x0",
+            }))
+            .collect();
+        let cursor = (skip + objects.len()) as u64;
+        Ok(serde_json::from_value(json!({
+            "objects": objects,
+            "last_cursor": JsonBytes::from_vec(cursor.to_be_bytes().to_vec()),
+        }))?)
+    }
+
+    fn get_transactions(
+        &mut self,
+        _search_key: SearchKey,
+        _order: Order,
+        _limit: Uint32,
+        _after: Option<JsonBytes>,
+    ) -> Result<ckb_indexer::Pagination<ckb_indexer::Tx>, RpcError> {
+        Err(unsupported("get_transactions"))
+    }
+
+    fn get_cells_capacity(&mut self, search_key: SearchKey) -> Result<Option<ckb_indexer::CellsCapacity>, RpcError> {
+        let chain = lock(&self.chain);
+        let capacity: u64 = chain.search(&search_key, Order::Asc).into_iter()
+            .map(|cell| Unpack::<u64>::unpack(&cell.output.capacity()))
+            .sum();
+        let tip = chain.committed.len() as u64;
+        Ok(Some(serde_json::from_value(json!({
+            "capacity": format!("{:#x}", capacity),
+            "block_hash": block_hash(tip),
+            "block_number": format!("{:#x}", tip),
+        }))?))
+    }
+
+    fn send_transaction(
+        &mut self,
+        tx: ckb_jsonrpc_types::Transaction,
+        _outputs_validator: Option<ckb_jsonrpc_types::OutputsValidator>,
+    ) -> Result<H256, RpcError> {
+        let tx = ckb_types::packed::Transaction::from(tx).into_view();
+        let mut chain = lock(&self.chain);
+        chain.check(&tx)?;
+        let tx_hash = tx.hash().unpack();
+        chain.commit(tx);
+        Ok(tx_hash)
+    }
+
+    fn test_tx_pool_accept(
+        &mut self,
+        tx: ckb_jsonrpc_types::Transaction,
+        _outputs_validator: Option<ckb_jsonrpc_types::OutputsValidator>,
+    ) -> Result<ckb_jsonrpc_types::EntryCompleted, RpcError> {
+        let tx = ckb_types::packed::Transaction::from(tx).into_view();
+        let chain = lock(&self.chain);
+        chain.check(&tx)?;
+        let inputs: u64 = tx.inputs().into_iter()
+            .filter_map(|input| chain.live_cell(&input.previous_output()))
+            .map(|cell| Unpack::<u64>::unpack(&cell.output.capacity()))
+            .sum();
+        let fee = inputs - tx.outputs_capacity().map_or(0, |capacity| capacity.as_u64());
+        Ok(serde_json::from_value(json!({ "cycles": "0x0", "fee": format!("{:#x}", fee) }))?)
+    }
+}
+
+/// Server state for the wallet of the default devnet key on `node`
+fn mock_state(node: &MockNode, market_state_file: String) -> Arc<AppState> {
+    let config = resolve_node_config(|_| None, None).expect("default node config");
+    let secp = secp256k1::Secp256k1::new();
+    let pubkey = secp256k1::PublicKey::from_secret_key(&secp, &config.privkey);
+    let node = node.clone();
+    Arc::new(AppState {
+        backend: Box::new(move || Box::new(node.clone())),
+        wallet: Mutex::new(()),
+        // The mock commits what it accepts, so waiting for the commit would
+        // only add send_transaction's two-second polls
+        send_mode: SendMode::BroadcastOnly,
+        privkey: config.privkey,
+        contracts: get_contract_info(config.network).expect("devnet contracts"),
+        lock_script: wallet_lock(&pubkey, PubkeyFormat::Compressed).expect("wallet lock"),
+        current_market: Mutex::new(None),
+        market_state_file,
+        tvl: Mutex::new(None),
+        idempotency: Mutex::new(IdempotencyKeys::default()),
+    })
+}
+
+/// The current market's cell and data
+fn current_market(state: &AppState, node: &MockNode) -> (OutPoint, CellInfo, MarketData) {
+    let outpoint = lock(&state.current_market).clone().expect("a current market");
+    let cell = get_cell(&mut node.clone(), &outpoint).expect("market cell");
+    let market_data = MarketData::from_bytes(&cell.data).expect("market data");
+    (outpoint, cell, market_data)
+}
+
+fn expect_ok(step: &str, response: Result<Response, ApiError>) {
+    match response {
+        Ok(response) => assert_eq!(response.status(), StatusCode::OK, "{} answered {}", step, response.status()),
+        Err(err) => panic!("{} failed: {:#}", step, err.0),
+    }
+}
+
+/// Create, mint, resolve in two phases and claim through the handlers
+///
+/// The mock runs no scripts, so this checks what the server builds and how it
+/// tracks the market, not that the contracts accept it; the devnet suite and
+/// the contract tests cover that.
+#[tokio::test]
+async fn market_lifecycle_runs_against_the_mock_node() {
+    let node = MockNode::default();
+    let state_file = std::env::temp_dir().join(format!("mock-market-{}.json", std::process::id()));
+    let state = mock_state(&node, state_file.to_string_lossy().into_owned());
+    let wallet = state.lock_script.clone();
+    node.fund(&wallet, Shannons::from_ckb(1_000_000).as_shannons());
+    let no_dry_run = || Query(DryRunParams::default());
+
+    // Create: an open market holding exactly its occupied capacity
+    expect_ok("create", handle_create_market(State(state.clone()), no_dry_run(), axum::body::Bytes::new()).await);
+    let (created, cell, market_data) = current_market(&state, &node);
+    assert_eq!((market_data.yes_supply(), market_data.no_supply()), (0, 0));
+    assert_eq!(cell.capacity, cell.occupied);
+    assert_eq!(node.committed().len(), 2);
+
+    // Mint: two sets move their collateral into the market and tokens to the wallet
+    let collateral = 2 * DEFAULT_SHANNONS_PER_TOKEN;
+    expect_ok("mint", handle_mint(State(state.clone()), no_dry_run(), StrictJson(MintRequest { amount: 2 })).await);
+    let (minted, cell, market_data) = current_market(&state, &node);
+    assert_ne!(minted.as_slice(), created.as_slice());
+    assert_eq!((market_data.yes_supply(), market_data.no_supply()), (2, 2));
+    assert_eq!(cell.capacity, cell.occupied + collateral);
+    let mint_tx = node.committed().pop().expect("mint transaction");
+    assert_eq!(mint_tx.inputs().get(0).expect("market input").previous_output().as_slice(), created.as_slice());
+    let balance = token_balance(&mut node.clone(), &state.contracts, &minted, &wallet).expect("token balance");
+    assert_eq!((balance.yes.as_str(), balance.no.as_str()), ("2", "2"));
+
+    // Propose: YES is pending, the market is not resolved and pays nothing yet
+    let propose = ProposeRequest { outcome: Some(true), value: None, cancel: false };
+    expect_ok("propose", handle_propose(State(state.clone()), no_dry_run(), StrictJson(propose)).await);
+    let (_, cell, market_data) = current_market(&state, &node);
+    assert_eq!(market_data.pending_outcome, Some(Proposal::Yes));
+    assert!(!market_data.resolved);
+    assert_eq!(cell.capacity, cell.occupied + collateral);
+    let early_claim = ClaimRequest { amount: 2, side: None };
+    assert!(handle_claim(State(state.clone()), no_dry_run(), StrictJson(early_claim)).await.is_err(), "a pending market paid a claim");
+    assert!(handle_finalize(State(state.clone()), no_dry_run()).await.is_err(), "a proposal was finalized before the delay");
+
+    // Finalize once the proposal has stood for the contract's delay
+    node.advance_clock(RESOLUTION_DELAY_SECS);
+    expect_ok("finalize", handle_finalize(State(state.clone()), no_dry_run()).await);
+    let (_, cell, market_data) = current_market(&state, &node);
+    assert!(market_data.resolved && market_data.outcome && market_data.pending_outcome.is_none());
+    assert_eq!(cell.capacity, cell.occupied + collateral);
+    let finalize_tx = node.committed().pop().expect("finalize transaction");
+    let since: u64 = finalize_tx.inputs().get(0).expect("market input").since().unpack();
+    assert_eq!(since, SINCE_RELATIVE_TIMESTAMP | RESOLUTION_DELAY_SECS);
+
+    // Claim: the YES tokens burn for their collateral, the losing NO tokens stay
+    let before_claim = node.balance(&wallet);
+    let claim = ClaimRequest { amount: 2, side: None };
+    expect_ok("claim", handle_claim(State(state.clone()), no_dry_run(), StrictJson(claim)).await);
+    let (claimed, cell, market_data) = current_market(&state, &node);
+    assert_eq!((market_data.yes_supply(), market_data.no_supply()), (0, 2));
+    assert_eq!(cell.capacity, cell.occupied);
+    assert!(node.balance(&wallet) > before_claim + collateral, "the payout and the spent token cell go to the wallet");
+    let balance = token_balance(&mut node.clone(), &state.contracts, &claimed, &wallet).expect("token balance");
+    assert_eq!((balance.yes.as_str(), balance.no.as_str()), ("0", "2"));
+    assert_eq!(node.committed().len(), 6);
+
+    let _ = std::fs::remove_file(state_file);
+}
+
+/// A funded wallet on a fresh mock node whose current market, created with
+/// the `create` body, holds `sets` complete sets
+async fn minted_market(name: &str, create: &'static str, sets: u128) -> (MockNode, Arc<AppState>) {
+    let node = MockNode::default();
+    let state_file = std::env::temp_dir().join(format!("mock-{}-{}.json", name, std::process::id()));
+    let state = mock_state(&node, state_file.to_string_lossy().into_owned());
+    node.fund(&state.lock_script, Shannons::from_ckb(1_000_000).as_shannons());
+    let body = axum::body::Bytes::from_static(create.as_bytes());
+    expect_ok("create", handle_create_market(State(state.clone()), Query(DryRunParams::default()), body).await);
+    let mint = MintRequest { amount: sets };
+    expect_ok("mint", handle_mint(State(state.clone()), Query(DryRunParams::default()), StrictJson(mint)).await);
+    (node, state)
+}
+
+/// Resolve the current market: propose through /api/resolve, wait out the
+/// delay, then finalize
+async fn resolve(state: &Arc<AppState>, node: &MockNode, outcome: Option<bool>, value: Option<u64>) {
+    let resolve = ResolveRequest { outcome, value };
+    expect_ok("propose", handle_resolve(State(state.clone()), Query(DryRunParams::default()), StrictJson(resolve)).await);
+    node.advance_clock(RESOLUTION_DELAY_SECS);
+    expect_ok("finalize", handle_finalize(State(state.clone()), Query(DryRunParams::default())).await);
+}
+
+async fn claim(state: &Arc<AppState>, amount: u128, side: Option<&str>) -> Result<Response, ApiError> {
+    let claim = ClaimRequest { amount, side: side.map(str::to_string) };
+    handle_claim(State(state.clone()), Query(DryRunParams::default()), StrictJson(claim)).await
+}
+
+#[tokio::test]
+async fn resolve_proposes_and_finalize_pays_claims() {
+    let (node, state) = minted_market("resolve", "", 10).await;
+    let resolve_yes = || ResolveRequest { outcome: Some(true), value: None };
+    let no_dry_run = || Query(DryRunParams::default());
+
+    expect_ok("propose", handle_resolve(State(state.clone()), no_dry_run(), StrictJson(resolve_yes())).await);
+    let (_, _, market_data) = current_market(&state, &node);
+    assert_eq!(market_data.pending_outcome, Some(Proposal::Yes));
+    assert!(!market_data.resolved);
+    assert!(handle_finalize(State(state.clone()), no_dry_run()).await.is_err(), "finalized before the delay");
+    let resolve_no = ResolveRequest { outcome: Some(false), value: None };
+    assert!(handle_resolve(State(state.clone()), no_dry_run(), StrictJson(resolve_no)).await.is_err(), "NO replaced a pending YES");
+    assert!(claim(&state, 5, None).await.is_err(), "a proposed market paid a claim");
+
+    node.advance_clock(RESOLUTION_DELAY_SECS);
+    // Proposing stays refused while the proposal waits to be finalized
+    assert!(handle_resolve(State(state.clone()), no_dry_run(), StrictJson(resolve_yes())).await.is_err(), "proposed twice");
+    expect_ok("finalize", handle_finalize(State(state.clone()), no_dry_run()).await);
+    let (_, market_cell, market_data) = current_market(&state, &node);
+    assert!(market_data.resolved && market_data.outcome && market_data.pending_outcome.is_none());
+    let finalize_tx = node.committed().pop().expect("finalize transaction");
+    let since: u64 = finalize_tx.inputs().get(0).expect("market input").since().unpack();
+    assert_eq!(since, SINCE_RELATIVE_TIMESTAMP | RESOLUTION_DELAY_SECS);
+    assert!(handle_resolve(State(state.clone()), no_dry_run(), StrictJson(resolve_yes())).await.is_err(), "a resolved market was proposed");
+
+    // A partial claim keeps one token cell for the rest, a full one keeps none
+    let yes_type = build_token_type(&state.contracts, &market_cell.market_type().unwrap(), market_data.token_script_hash_type().unwrap(), true);
+    let kept_capacity = token_cell_minimum(&state.lock_script, &yes_type).unwrap();
+    expect_ok("claim", claim(&state, 5, None).await);
+    let (partial, cell, _) = current_market(&state, &node);
+    verify_claim_outputs(&mut node.clone(), &yes_type, &partial, 5, kept_capacity).expect("partial claim outputs");
+    let claimable = market_claimable(&cell).unwrap();
+    assert_eq!((claimable.claimable_capacity, claimable.claimed_capacity), (Shannons(5 * DEFAULT_SHANNONS_PER_TOKEN), Shannons(5 * DEFAULT_SHANNONS_PER_TOKEN)));
+    expect_ok("claim", claim(&state, 5, None).await);
+    let (full, cell, _) = current_market(&state, &node);
+    verify_claim_outputs(&mut node.clone(), &yes_type, &full, 0, 0).expect("full claim outputs");
+    assert_eq!(cell.capacity, cell.occupied);
+
+    let _ = std::fs::remove_file(&state.market_state_file);
+}
+
+#[tokio::test]
+async fn cancelled_markets_refund_complete_sets() {
+    let (node, state) = minted_market("refund", "", 2).await;
+    let no_dry_run = || Query(DryRunParams::default());
+    let refund = |market: &OutPoint| Query(RefundParams { market_id: format_market_id(market), amount: 2 });
+
+    let cancel = ProposeRequest { outcome: None, value: None, cancel: true };
+    expect_ok("propose", handle_propose(State(state.clone()), no_dry_run(), StrictJson(cancel)).await);
+    let (proposed, _, _) = current_market(&state, &node);
+    assert!(handle_refund(State(state.clone()), refund(&proposed)).await.is_err(), "a market proposed cancelled refunded");
+
+    node.advance_clock(RESOLUTION_DELAY_SECS);
+    expect_ok("finalize", handle_finalize(State(state.clone()), no_dry_run()).await);
+    let (cancelled, _, market_data) = current_market(&state, &node);
+    assert!(market_data.resolved && market_data.cancelled);
+    assert!(claim(&state, 1, None).await.is_err(), "a cancelled market paid a claim");
+
+    expect_ok("refund", handle_refund(State(state.clone()), refund(&cancelled)).await.map(IntoResponse::into_response));
+    let (_, cell, market_data) = current_market(&state, &node);
+    assert_eq!((market_data.yes_supply(), market_data.no_supply()), (0, 0));
+    assert_eq!(cell.capacity, cell.occupied);
+
+    let _ = std::fs::remove_file(&state.market_state_file);
+}
+
+#[tokio::test]
+async fn resolved_markets_redeem_sets_and_pay_winners() {
+    let (node, state) = minted_market("redeem", "", 3).await;
+    let redeem = || StrictJson(BurnRequest { amount: 1 });
+    assert!(handle_redeem_sets(State(state.clone()), redeem()).await.is_err(), "an open market redeemed sets");
+
+    resolve(&state, &node, Some(true), None).await;
+    expect_ok("redeem", handle_redeem_sets(State(state.clone()), redeem()).await.map(IntoResponse::into_response));
+    expect_ok("claim", claim(&state, 2, None).await);
+    let (_, cell, market_data) = current_market(&state, &node);
+    // The two losing NO tokens are all that is left
+    assert_eq!((market_data.yes_supply(), market_data.no_supply()), (0, 2));
+    assert_eq!(cell.capacity, cell.occupied);
+
+    let _ = std::fs::remove_file(&state.market_state_file);
+}
+
+#[tokio::test]
+async fn scalar_markets_pay_both_sides_along_their_range() {
+    let (node, state) = minted_market("scalar", r#"{"scalar_floor": 0, "scalar_ceiling": 100}"#, 4).await;
+    resolve(&state, &node, None, Some(25)).await;
+    let (_, before, market_data) = current_market(&state, &node);
+    assert_eq!(market_data.scalar.map(|scalar| scalar.value), Some(25));
+    assert!(claim(&state, 4, None).await.is_err(), "a scalar claim without a side was accepted");
+
+    // 25 of [0, 100]: LONG gets a quarter of each set, SHORT the rest
+    expect_ok("claim LONG", claim(&state, 4, Some("YES")).await);
+    let (_, after_long, _) = current_market(&state, &node);
+    expect_ok("claim SHORT", claim(&state, 4, Some("NO")).await);
+    let (_, after_short, _) = current_market(&state, &node);
+    assert_eq!(before.capacity - after_long.capacity, DEFAULT_SHANNONS_PER_TOKEN);
+    assert_eq!(after_long.capacity - after_short.capacity, 3 * DEFAULT_SHANNONS_PER_TOKEN);
+    assert_eq!(after_short.capacity, after_short.occupied);
+
+    let _ = std::fs::remove_file(&state.market_state_file);
+}
+
+#[tokio::test]
+async fn claims_holding_only_losing_tokens_are_told_they_lost() {
+    let (node, state) = minted_market("lost", "", 1).await;
+    resolve(&state, &node, Some(true), None).await;
+    let (market, _, _) = current_market(&state, &node);
+    let loser = Script::new_builder()
+        .code_hash(SIGHASH_TYPE_HASH.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(Bytes::from(vec![0x60u8; 20]).pack())
+        .build();
+    transfer_tokens(&mut node.clone(), &state.privkey, &state.contracts, &state.lock_script, &market, false, 1, &loser, SendMode::BroadcastOnly)
+        .expect("NO token transfer");
+
+    match build_claim_tx(&mut node.clone(), &state.contracts, &loser, market, 1) {
+        Err(err) => assert!(err.downcast_ref::<Rejected>().is_some_and(|rejected| rejected.0.contains("NO tokens lost")), "{:#}", err),
+        Ok(_) => panic!("a claim holding only NO tokens was built"),
+    }
+
+    let _ = std::fs::remove_file(&state.market_state_file);
+}
+
+/// Draining an open market proposes cancelling it; once the delay is over the
+/// next drain finalizes the cancellation and refunds the wallet's sets
+#[tokio::test]
+async fn draining_waits_out_the_cancellation_it_proposes() {
+    let (node, state) = minted_market("drain", "", 2).await;
+    let (open, _, _) = current_market(&state, &node);
+    let drain = |market: OutPoint| {
+        drain_market(&mut node.clone(), &state.privkey, &state.contracts, &state.lock_script, market).expect("drain")
+    };
+
+    let (sent, proposed) = drain(open);
+    let market_data = MarketData::from_bytes(&get_cell(&mut node.clone(), &proposed).unwrap().data).unwrap();
+    assert_eq!((sent.len(), market_data.pending_outcome), (1, Some(Proposal::Cancel)));
+    let (sent, unchanged) = drain(proposed.clone());
+    assert!(sent.is_empty() && unchanged.as_slice() == proposed.as_slice(), "drained within the delay");
+
+    node.advance_clock(RESOLUTION_DELAY_SECS);
+    let (sent, drained) = drain(proposed);
+    let cell = get_cell(&mut node.clone(), &drained).unwrap();
+    let market_data = MarketData::from_bytes(&cell.data).unwrap();
+    assert_eq!(sent.len(), 2, "finalize and refund");
+    assert!(market_data.cancelled);
+    assert_eq!((market_data.yes_supply(), market_data.no_supply()), (0, 0));
+    assert_eq!(cell.capacity, cell.occupied);
+
+    let _ = std::fs::remove_file(&state.market_state_file);
+}
+
+/// Preflight `/api/mint` from `origin` through the API router
+#[tokio::test]
+async fn mint_claim_cycles_cost_only_market_cells_and_fees() {
+    const CYCLES: usize = 25;
+    const SETS: u128 = 3;
+    let node = MockNode::default();
+    let state_file = std::env::temp_dir().join(format!("mock-soak-{}.json", std::process::id()));
+    let state = mock_state(&node, state_file.to_string_lossy().into_owned());
+    node.fund(&state.lock_script, Shannons::from_ckb(1_000_000).as_shannons());
+    let wallet = || wallet_capacity(&mut node.clone(), &state.lock_script).unwrap();
+    let mut steady_cells = None;
+
+    for cycle in 1..=CYCLES {
+        let (before, first_tx) = (wallet(), node.committed().len());
+        let body = axum::body::Bytes::new();
+        expect_ok("create", handle_create_market(State(state.clone()), Query(DryRunParams::default()), body).await);
+        let (_, created, _) = current_market(&state, &node);
+        let mint = MintRequest { amount: SETS };
+        expect_ok("mint", handle_mint(State(state.clone()), Query(DryRunParams::default()), StrictJson(mint)).await);
+        resolve(&state, &node, Some(true), None).await;
+        expect_ok("claim", claim(&state, SETS, None).await);
+
+        // Collateral nets to zero: the market is back to its base capacity
+        let (_, claimed, _) = current_market(&state, &node);
+        assert_eq!(claimed.capacity, created.capacity, "cycle {}: collateral left in the market", cycle);
+        // Token cells stay under the wallet lock, so only the market cell and fees leave
+        let fees: u64 = node.committed()[first_tx..].iter()
+            .map(|tx| outpoint_tx_fee(&mut node.clone(), &OutPoint::new(tx.hash(), 0)).unwrap())
+            .sum();
+        assert_eq!(before - wallet(), created.capacity + fees, "cycle {}: wallet lost more than the market cell and fees", cycle);
+
+        // Fee cells don't pile up from one cycle to the next
+        let cells = count_pure_cells(&mut node.clone(), &state.lock_script).unwrap();
+        assert_eq!(*steady_cells.get_or_insert(cells), cells, "cycle {}: fee cells fragmented", cycle);
+    }
+}
+
+#[test]
+fn token_cells_are_found_past_the_first_page() {
+    let node = MockNode::default();
+    let contracts = get_contract_info(Network::Dev).unwrap();
+    let owner = Script::new_builder().args(Bytes::from(vec![0x42u8; 20]).pack()).build();
+    let token_type = build_token_type(&contracts, &build_market_type(&contracts), ScriptHashType::Data1, true);
+    let plain_cell = CellOutput::new_builder().capacity(Shannons::from_ckb(100).as_shannons().pack()).lock(owner.clone()).build();
+    let token_cell = CellOutput::new_builder()
+        .capacity(Shannons::from_ckb(200).as_shannons().pack())
+        .lock(owner.clone())
+        .type_(Some(token_type.clone()).pack())
+        .build();
+
+    // A full page of plain cells ahead of more than a page of token cells
+    node.issue((0..150).map(|_| (plain_cell.clone(), Bytes::new())).collect());
+    node.issue((0..120).map(|_| (token_cell.clone(), Bytes::from(3u128.to_le_bytes().to_vec()))).collect());
+
+    let cells = token_cell_candidates(&mut node.clone(), &owner, &token_type).unwrap();
+    assert_eq!(cells.len(), 120);
+    assert_eq!(count_pure_cells(&mut node.clone(), &owner).unwrap(), 150);
+    let cells = find_token_cells(&mut node.clone(), &owner, &token_type, 360).unwrap();
+    assert_eq!(cells.iter().map(|(_, _, amount)| amount).sum::<u128>(), 360);
+}
+
+#[tokio::test]
+async fn balances_count_every_token_cell() {
+    let (node, state) = minted_market("balance", "", 10).await;
+    let (_, market_cell, market_data) = current_market(&state, &node);
+    let yes_type = build_token_type(&state.contracts, &market_cell.market_type().unwrap(), market_data.token_script_hash_type().unwrap(), true);
+    let plain_cell = CellOutput::new_builder().capacity(Shannons::from_ckb(100).as_shannons().pack()).lock(state.lock_script.clone()).build();
+    let yes_cell = CellOutput::new_builder()
+        .capacity(Shannons::from_ckb(200).as_shannons().pack())
+        .lock(state.lock_script.clone())
+        .type_(Some(yes_type).pack())
+        .build();
+    node.issue((0..150).map(|_| (plain_cell.clone(), Bytes::new())).collect());
+    node.issue((0..120).map(|_| (yes_cell.clone(), Bytes::from(1u128.to_le_bytes().to_vec()))).collect());
+
+    let Json(balance) = handle_balance(State(state.clone()), Query(BalanceParams { address: None })).await.expect("balance");
+    assert_eq!((balance.yes.as_str(), balance.yes_cells), ("130", 121));
+    assert_eq!((balance.no.as_str(), balance.no_cells), ("10", 1));
+}
+
+#[tokio::test]
+async fn tvl_is_computed_before_the_first_refresh() {
+    let (node, state) = minted_market("tvl", "", 10).await;
+    let (_, market_cell, _) = current_market(&state, &node);
+    assert!(lock(&state.tvl).is_none());
+
+    let Json(tvl) = handle_tvl(State(state.clone())).await.expect("tvl");
+    assert_eq!((tvl.market_count, tvl.total_collateral), (1, Shannons(market_cell.collateral())));
+    assert_eq!(lock(&state.tvl).as_ref().map(|cached| cached.updated_at), Some(tvl.updated_at));
+}
+
+#[tokio::test]
+async fn previews_check_their_body_and_market() {
+    let node = MockNode::default();
+    let state_file = std::env::temp_dir().join(format!("mock-preview-{}.json", std::process::id()));
+    let state = mock_state(&node, state_file.to_string_lossy().into_owned());
+    node.fund(&state.lock_script, Shannons::from_ckb(1_000_000).as_shannons());
+    let preview = |op: &str, body: &'static str| {
+        handle_preview(State(state.clone()), Path(op.to_string()), axum::body::Bytes::from_static(body.as_bytes()))
+    };
+    let status = |result: Result<Json<TxPreview>, ApiError>| result.map_or_else(|err| err.into_response().status(), |_| StatusCode::OK);
+
+    assert_eq!(status(preview("mint", "").await), StatusCode::NOT_FOUND);
+    assert_eq!(status(preview("create-market", "").await), StatusCode::OK);
+    assert_eq!(status(preview("create-market", r#"{"amount": 1, "amonut": 2}"#).await), StatusCode::BAD_REQUEST);
+
+    let body = axum::body::Bytes::from_static(br#"{"scalar_floor": 0, "scalar_ceiling": 100}"#);
+    expect_ok("create", handle_create_market(State(state.clone()), Query(DryRunParams::default()), body).await);
+    assert_eq!(status(preview("resolve", r#"{"value": 25}"#).await), StatusCode::OK);
+    assert_eq!(status(preview("resolve", r#"{"value": 101}"#).await), StatusCode::BAD_REQUEST);
+}
+
+async fn preflight(cors: &CorsOrigins, origin: &'static str) -> Response {
+    use tower::ServiceExt;
+    let state = mock_state(&MockNode::default(), String::new());
+    let request = axum::http::Request::builder()
+        .method(Method::OPTIONS)
+        .uri("/api/mint")
+        .header(header::ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+        .body(axum::body::Body::empty())
+        .expect("preflight request");
+    api_router(state, cors).oneshot(request).await.expect("router answers")
+}
+
+#[tokio::test]
+async fn router_preflight_refuses_unlisted_origins() {
+    let cors = cors_origins(Network::Main, Some("https://app.example.com")).unwrap();
+    let allowed = preflight(&cors, "https://app.example.com").await;
+    assert_eq!(
+        allowed.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).and_then(|value| value.to_str().ok()),
+        Some("https://app.example.com"),
+    );
+    let refused = preflight(&cors, "https://evil.example.com").await;
+    assert!(refused.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none(), "{:?}", refused.headers());
+}
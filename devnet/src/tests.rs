@@ -0,0 +1,342 @@
+//! Unit tests for the server's pure pieces: error mapping, retries,
+//! idempotency, CORS, resolution rules, market cell checks and capacity
+//! arithmetic. Handler flows run in `mock_rpc`, chain flows in `run_tests`.
+
+use super::*;
+
+#[test]
+fn every_market_error_has_a_status_and_stable_code() {
+    let expected_errors = [
+        (MarketError::InvalidMarketData, StatusCode::UNPROCESSABLE_ENTITY, "invalid_market_data"),
+        (MarketError::MultipleMarketCells, StatusCode::CONFLICT, "multiple_market_cells"),
+        (MarketError::SupplyDecrease, StatusCode::UNPROCESSABLE_ENTITY, "supply_decrease"),
+        (MarketError::UnequalSupplyIncrease, StatusCode::UNPROCESSABLE_ENTITY, "unequal_supply_increase"),
+        (MarketError::InsufficientCollateral, StatusCode::UNPROCESSABLE_ENTITY, "insufficient_collateral"),
+        (MarketError::LockScriptChanged, StatusCode::FORBIDDEN, "lock_script_changed"),
+    ];
+    assert_eq!(expected_errors.len(), MarketError::ALL.len(), "error table misses a MarketError variant");
+    for (err, status, code) in expected_errors {
+        assert_eq!(http_for(&err), (status, code), "{:?}", err);
+        let response = ApiError(err.into()).into_response();
+        let header = response.headers().get("x-error-code").and_then(|value| value.to_str().ok());
+        assert_eq!((response.status(), header), (status, Some(code)), "{:?}", err);
+    }
+}
+
+#[test]
+fn untyped_errors_are_internal() {
+    let response = ApiError(anyhow!("RPC unreachable")).into_response();
+    let header = response.headers().get("x-error-code").and_then(|value| value.to_str().ok());
+    assert_eq!((response.status(), header), (StatusCode::INTERNAL_SERVER_ERROR, Some(INTERNAL_ERROR_CODE)));
+}
+
+/// A mock read that fails to reach the node `failures` times, then answers
+fn flaky(calls: &mut u32, failures: u32) -> Result<u64, RpcError> {
+    *calls += 1;
+    if *calls <= failures { Err(RpcError::Other(anyhow!("connection refused"))) } else { Ok(42) }
+}
+
+#[test]
+fn failed_reads_are_retried_with_backoff() {
+    let policy = RpcRetry { retries: 3, backoff: Duration::from_millis(10) };
+    let mut calls = 0;
+    let started = std::time::Instant::now();
+    assert_eq!(retry_read(policy, "mock", || flaky(&mut calls, 2)).ok(), Some(42));
+    assert_eq!(calls, 3);
+    // Backoff doubles: 10 ms, then 20 ms
+    assert!(started.elapsed() >= Duration::from_millis(30), "retries did not back off ({:?})", started.elapsed());
+}
+
+#[test]
+fn reads_give_up_after_rpc_retries() {
+    let policy = RpcRetry { retries: 1, backoff: Duration::from_millis(1) };
+    let mut calls = 0;
+    assert!(retry_read(policy, "mock", || flaky(&mut calls, 2)).is_err());
+    assert_eq!(calls, 2);
+}
+
+#[test]
+fn only_configured_origins_pass_cors_off_devnet() {
+    let app_origin = HeaderValue::from_static("https://app.example.com");
+    let other_origin = HeaderValue::from_static("https://evil.example.com");
+    let listed = cors_origins(Network::Main, Some("https://app.example.com/, http://localhost:5173")).unwrap();
+    assert!(listed.allows(&app_origin) && listed.allows(&HeaderValue::from_static("http://localhost:5173")), "{:?}", listed);
+    assert!(!listed.allows(&other_origin), "an origin missing from CORS_ORIGINS was allowed");
+    assert!(!cors_origins(Network::Test, None).unwrap().allows(&app_origin), "unset CORS_ORIGINS allowed an origin off devnet");
+    assert!(cors_origins(Network::Dev, None).unwrap().allows(&other_origin));
+    assert!(cors_origins(Network::Main, Some("*")).unwrap().allows(&other_origin));
+}
+
+fn scope(endpoint: &str, key: &str) -> IdempotencyScope {
+    (endpoint.to_string(), key.to_string())
+}
+
+const MINT_BODY: [u8; 32] = [0x01; 32];
+
+fn done() -> Recorded {
+    Recorded::Done { status: StatusCode::OK, content_type: None, body: Default::default() }
+}
+
+#[test]
+fn repeated_idempotency_keys_replay_their_answer() {
+    let mut keys = IdempotencyKeys::default();
+    let mint_key = scope("/api/mint", "retry-1");
+    assert!(keys.begin(&mint_key, MINT_BODY).unwrap().is_none(), "a new Idempotency-Key was already recorded");
+    assert!(matches!(keys.begin(&mint_key, MINT_BODY), Ok(Some(Recorded::Pending))), "a repeat during the first request was not in progress");
+    // The same key on another endpoint is a different request
+    assert!(keys.begin(&scope("/api/burn", "retry-1"), MINT_BODY).unwrap().is_none());
+    keys.finish(&mint_key, Some(Recorded::Done {
+        status: StatusCode::OK,
+        content_type: Some(HeaderValue::from_static("application/json")),
+        body: axum::body::Bytes::from_static(b"{\"success\":true}"),
+    }));
+    match keys.begin(&mint_key, MINT_BODY) {
+        Ok(Some(Recorded::Done { body, .. })) => assert_eq!(&body[..], b"{\"success\":true}"),
+        other => panic!("repeated key did not replay the first answer: {:?}", other),
+    }
+}
+
+#[test]
+fn idempotency_keys_refuse_a_different_request() {
+    let mut keys = IdempotencyKeys::default();
+    let mint_key = scope("/api/mint", "retry-1");
+    keys.begin(&mint_key, MINT_BODY).unwrap();
+    keys.finish(&mint_key, Some(done()));
+    let reused = keys.begin(&mint_key, [0x02; 32]).unwrap_err();
+    let response = ApiError(reused.into()).into_response();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(response.headers().get("x-error-code").and_then(|value| value.to_str().ok()), Some(IDEMPOTENCY_KEY_REUSED_CODE));
+}
+
+#[test]
+fn failed_requests_stay_retryable() {
+    let mut keys = IdempotencyKeys::default();
+    let failed_key = scope("/api/mint", "retry-2");
+    keys.begin(&failed_key, MINT_BODY).unwrap();
+    keys.finish(&failed_key, None);
+    assert!(keys.begin(&failed_key, MINT_BODY).unwrap().is_none(), "a failed request's key was kept");
+}
+
+#[test]
+fn requests_that_sent_a_transaction_keep_their_key() {
+    let tx_hash = H256([0x39; 32]);
+    let response = ApiError(Unconfirmed { tx_hash: tx_hash.clone(), reason: anyhow!("not committed") }.into()).into_response();
+    let sent = response.extensions().get::<SentTx>().map(|SentTx(sent)| sent.clone());
+    assert_eq!(sent.as_ref(), Some(&tx_hash), "an unconfirmed send did not name its transaction");
+
+    let mut keys = IdempotencyKeys::default();
+    let mint_key = scope("/api/mint", "retry-3");
+    keys.begin(&mint_key, MINT_BODY).unwrap();
+    keys.finish(&mint_key, sent.map(Recorded::Sent));
+    match keys.begin(&mint_key, MINT_BODY) {
+        Ok(Some(Recorded::Sent(pending))) => {
+            let answer = pending_answer(&pending);
+            assert_eq!(answer.status(), StatusCode::ACCEPTED);
+        }
+        other => panic!("a key that sent a transaction was not kept: {:?}", other),
+    }
+}
+
+#[test]
+fn idempotency_keys_are_forgotten_oldest_first() {
+    let mut keys = IdempotencyKeys::default();
+    let first = scope("/api/mint", "first");
+    keys.begin(&first, MINT_BODY).unwrap();
+    keys.finish(&first, Some(done()));
+    for index in 0..MAX_IDEMPOTENCY_KEYS {
+        let filler = scope("/api/mint", &format!("fill-{}", index));
+        keys.begin(&filler, MINT_BODY).unwrap();
+        keys.finish(&filler, Some(done()));
+    }
+    assert!(keys.begin(&first, MINT_BODY).unwrap().is_none(), "the oldest key was not forgotten");
+    assert!(keys.entries.len() <= MAX_IDEMPOTENCY_KEYS);
+}
+
+#[test]
+fn running_requests_are_never_forgotten() {
+    let mut keys = IdempotencyKeys::default();
+    let running = scope("/api/mint", "running");
+    keys.begin(&running, MINT_BODY).unwrap();
+    let finished = scope("/api/mint", "finished");
+    keys.begin(&finished, MINT_BODY).unwrap();
+    keys.finish(&finished, Some(done()));
+    for index in 0..MAX_IDEMPOTENCY_KEYS - 1 {
+        let filler = scope("/api/mint", &format!("fill-{}", index));
+        keys.begin(&filler, MINT_BODY).unwrap();
+        keys.finish(&filler, Some(done()));
+    }
+    assert!(matches!(keys.begin(&running, MINT_BODY), Ok(Some(Recorded::Pending))), "a running request's key was evicted");
+    assert!(keys.begin(&finished, MINT_BODY).unwrap().is_none(), "the oldest finished key was kept instead");
+}
+
+#[test]
+fn resolution_authority_follows_the_type_args_layout() {
+    let type_id = [0x11u8; 32];
+    let args = |base: &[u8], flags: u8, section: &[u8]| -> Vec<u8> { [base, &[flags][..], section].concat() };
+    let weighted = [0x11u8; 40];
+    let quorum = [&[2u8, 3][..], &[0x02; 3 * 33][..]].concat();
+
+    assert_eq!(resolution_authority(&type_id), ResolutionAuthority::Anyone);
+    assert_eq!(resolution_authority(&[]), ResolutionAuthority::Anyone);
+    assert_eq!(resolution_authority(&args(&type_id, RESOLVER_FLAG, &[0x08; 20])), ResolutionAuthority::Resolver);
+    assert_eq!(resolution_authority(&args(&weighted, RESOLVER_FLAG, &[0x08; 20])), ResolutionAuthority::Resolver);
+    let locked = [&[0x08u8; 20][..], &[0x10; 32][..]].concat();
+    assert_eq!(resolution_authority(&args(&type_id, RESOLVER_FLAG | TOKEN_LOCK_FLAG, &locked)), ResolutionAuthority::Resolver);
+    assert_eq!(resolution_authority(&args(&type_id, ORACLE_QUORUM_FLAG, &quorum)), ResolutionAuthority::OracleQuorum);
+    // A section that fits no layout leaves the market to anyone
+    assert_eq!(resolution_authority(&args(&type_id, RESOLVER_FLAG, &[0x08; 19])), ResolutionAuthority::Anyone);
+    assert_eq!(resolution_authority(&args(&type_id, ORACLE_QUORUM_FLAG, &quorum[..quorum.len() - 1])), ResolutionAuthority::Anyone);
+}
+
+#[test]
+fn settlements_fit_the_market_they_resolve() {
+    let contracts = get_contract_info(Network::Dev).unwrap();
+    let binary = MarketData::open(&contracts, DEFAULT_SHANNONS_PER_TOKEN);
+    let range = Scalar { floor: 0, ceiling: 100, value: 0 };
+    let scalar = MarketData { scalar: Some(range), ..binary.clone() };
+
+    assert_eq!(settlement_terms(Settlement::Outcome(true), &binary).unwrap(), (Proposal::Yes, None));
+    assert_eq!(settlement_terms(Settlement::Outcome(false), &binary).unwrap(), (Proposal::No, None));
+    assert_eq!(settlement_terms(Settlement::Cancelled, &scalar).unwrap(), (Proposal::Cancel, Some(range)));
+    assert_eq!(settlement_terms(Settlement::Value(25), &scalar).unwrap(), (Proposal::No, Some(Scalar { value: 25, ..range })));
+    assert!(settlement_terms(Settlement::Value(25), &binary).is_err());
+    assert!(settlement_terms(Settlement::Outcome(true), &scalar).is_err());
+    assert!(settlement_terms(Settlement::Value(101), &scalar).is_err());
+}
+
+#[test]
+fn market_outputs_keep_the_spent_market_type() {
+    let contracts = get_contract_info(Network::Dev).unwrap();
+    let deployed = build_market_type(&contracts);
+    let imported = deployed.clone().as_builder().args(Bytes::from(vec![0x11u8; 32]).pack()).build();
+    let data = Bytes::from(MarketData::open(&contracts, DEFAULT_SHANNONS_PER_TOKEN).to_bytes());
+    let market_cell = |type_script: &Script| {
+        CellOutput::new_builder().capacity(Shannons::from_ckb(300).as_shannons().pack()).type_(Some(type_script.clone()).pack()).build()
+    };
+    let spend_into = |type_script: &Script| {
+        let tx = TransactionView::new_advanced_builder()
+            .output(market_cell(type_script))
+            .output_data(data.pack())
+            .build();
+        validate_market_tx(&contracts, &tx, &[(market_cell(&imported), data.clone())])
+    };
+
+    assert!(spend_into(&imported).is_ok());
+    assert!(matches!(spend_into(&deployed), Err(MarketError::MultipleMarketCells)), "the market's Type ID args were dropped");
+    let hash_type = MarketData::open(&contracts, DEFAULT_SHANNONS_PER_TOKEN).token_script_hash_type().unwrap();
+    let cell = CellInfo { capacity: 0, occupied: 0, type_script: Some(imported.clone()), data: Vec::new() };
+    assert_eq!(
+        build_token_type(&contracts, &cell.market_type().unwrap(), hash_type, true).calc_script_hash(),
+        build_token_type(&contracts, &imported, hash_type, true).calc_script_hash(),
+    );
+    assert_ne!(
+        build_token_type(&contracts, &imported, hash_type, true).calc_script_hash(),
+        build_token_type(&contracts, &deployed, hash_type, true).calc_script_hash(),
+    );
+}
+
+#[test]
+fn token_descriptors_report_the_market_decimals() {
+    let contracts = get_contract_info(Network::Dev).unwrap();
+    let market = MarketData { decimals: 8, ..MarketData::open(&contracts, DEFAULT_SHANNONS_PER_TOKEN) };
+    let descriptors = token_descriptors(&contracts, &build_market_type(&contracts), &market).unwrap();
+    assert_eq!(descriptors.iter().map(|token| (token.symbol, token.decimals)).collect::<Vec<_>>(), [("YES", 8), ("NO", 8)]);
+}
+
+#[test]
+fn shortfalls_name_the_first_uncovered_requirement() {
+    let requirements = [
+        CapacityRequirement { label: "collateral", capacity: Shannons::from_ckb(1000) },
+        CapacityRequirement { label: "token cells", capacity: Shannons::from_ckb(286) },
+        CapacityRequirement { label: "fee", capacity: Shannons::from_ckb(1) },
+    ];
+    for (available, component, shortfall) in [
+        (Shannons::from_ckb(400), "collateral", Shannons::from_ckb(600)),
+        (Shannons::from_ckb(1100), "token cells", Shannons::from_ckb(186)),
+        (Shannons(1286_50000000), "fee", Shannons(50000000)),
+    ] {
+        let err = attribute_shortfall(&requirements, available).expect("a shortfall");
+        assert_eq!((err.component, err.shortfall), (component, shortfall), "{} available", available);
+    }
+    assert!(attribute_shortfall(&requirements, Shannons::from_ckb(1287)).is_none(), "shortfall reported for a fully funded mint");
+}
+
+#[test]
+fn claim_payouts_are_checked_before_broadcast() {
+    let ckb = |amount: u64| Shannons::from_ckb(amount).as_shannons();
+    verify_claim_payout(ckb(1128), ckb(628), 5, DEFAULT_SHANNONS_PER_TOKEN).unwrap();
+    for (output_capacity, burned) in [(ckb(629), 5u128), (ckb(628), 4), (ckb(628), 0), (ckb(1129), 5)] {
+        assert!(
+            verify_claim_payout(ckb(1128), output_capacity, burned, DEFAULT_SHANNONS_PER_TOKEN).is_err(),
+            "{} tokens for a market going to {} shannons", burned, output_capacity,
+        );
+    }
+    assert!(verify_claim_payout(u64::MAX, 0, u128::MAX, DEFAULT_SHANNONS_PER_TOKEN).is_err(), "an overflowing payout passed");
+}
+
+#[test]
+fn dust_change_is_folded_into_the_fee() {
+    let contracts = get_contract_info(Network::Dev).unwrap();
+    let lock = Script::new_builder().args(Bytes::from(vec![0x42u8; 20]).pack()).build();
+    let with_change = |change: u64| {
+        TransactionView::new_advanced_builder()
+            .outputs(vec![
+                CellOutput::new_builder().capacity(Shannons::from_ckb(300).as_shannons().pack()).lock(lock.clone())
+                    .type_(Some(build_market_type(&contracts)).pack()).build(),
+                CellOutput::new_builder().capacity(change.pack()).lock(lock.clone()).build(),
+            ])
+            .outputs_data(vec![Bytes::new().pack(), Bytes::new().pack()])
+            .build()
+    };
+    let paid = |change: u64| settle_change(&with_change(change), 1000)
+        .map(|(tx, fee, kept)| (tx.outputs().len(), fee, kept));
+
+    assert_eq!(paid(Shannons::from_ckb(100).as_shannons()).unwrap(), (2, 1000, Some(1)), "change above the cell minimum was not kept");
+    assert_eq!(paid(1000).unwrap(), (1, 1000, None));
+    assert_eq!(paid(50_000_000).unwrap(), (1, 50_000_000, None));
+    assert!(paid(Shannons::from_ckb(30).as_shannons()).is_err(), "30 CKB of dust was paid as fee");
+    assert!(paid(999).is_err(), "change short of the fee was accepted");
+}
+
+#[test]
+fn fee_cells_pass_over_dust_unless_larger_ones_fall_short() {
+    let outpoint = |index: u32| OutPoint::new_builder().tx_hash(H256([0x63; 32]).pack()).index(index.pack()).build();
+    let wallet = [61, 500, 62, 300, 70].map(|ckb| ckb * SHANNONS_PER_CKB);
+    let pick = |target: u64, dust_below: u64| -> (Vec<u32>, u64) {
+        let mut picker = FeeCellPicker::new(target * SHANNONS_PER_CKB, dust_below);
+        for (index, capacity) in wallet.iter().enumerate() {
+            if picker.offer(outpoint(index as u32), *capacity) {
+                break;
+            }
+        }
+        let (cells, total) = picker.finish();
+        (cells.iter().map(|(outpoint, _)| outpoint.index().unpack()).collect(), total / SHANNONS_PER_CKB)
+    };
+
+    assert_eq!(pick(600, DEFAULT_MIN_FEE_CELL), (vec![1, 3], 800), "dust collected although larger cells covered the target");
+    assert_eq!(pick(850, DEFAULT_MIN_FEE_CELL), (vec![1, 3, 0], 861));
+    assert_eq!(pick(1_000, DEFAULT_MIN_FEE_CELL), (vec![1, 3, 0, 2, 4], 993));
+    assert_eq!(pick(600, 0), (vec![0, 1, 2], 623), "a zero dust threshold did not collect in wallet order");
+}
+
+#[test]
+fn shannons_convert_and_show_exactly() {
+    assert_eq!(Shannons::from_ckb(61).as_shannons(), MIN_CHANGE_CAPACITY);
+    assert_eq!(Shannons::from_ckb(0), Shannons(0));
+    for (shannons, shown, whole) in [
+        (500_00000000, "500 CKB", 500), (1_50000000, "1.5 CKB", 1), (61_00000001, "61.00000001 CKB", 61),
+        (1, "0.00000001 CKB", 0), (0, "0 CKB", 0),
+    ] {
+        assert_eq!((Shannons(shannons).to_string().as_str(), Shannons(shannons).whole_ckb()), (shown, whole));
+    }
+}
+
+#[test]
+fn claim_change_releases_token_cells_and_refuses_to_wrap() {
+    // Kept token cells hold their capacity; a full claim releases all of it
+    assert_eq!(claim_change(1_000, 500, 0, 300, 143).unwrap(), 1_657);
+    assert_eq!(claim_change(1_000, 500, 50, 300, 0).unwrap(), 1_750);
+    assert!(claim_change(1_000, 500, 0, 143, 300).is_err(), "kept more token capacity than was consumed");
+    assert!(claim_change(1_000, 50, 500, 143, 0).is_err(), "protocol fee above the claim");
+    assert!(claim_change(u64::MAX, 500, 0, 143, 0).is_err(), "change overflowed");
+}
@@ -35,6 +35,14 @@ const CONFIG = {
     appIcon: 'https://fav.farm/🎲'
 };
 
+// Cell output holding exactly its occupied capacity: 1 CKB per byte of the
+// capacity field, lock, type and data (143 CKB for a secp256k1 token cell)
+function minimalCellOutput(lock, type, dataHex) {
+    const output = ccc.CellOutput.from({ capacity: 0n, lock, type });
+    output.capacity = ccc.fixedPointFrom(output.occupiedSize + ccc.bytesFrom(dataHex).length);
+    return output;
+}
+
 class PredictionMarketApp {
     constructor() {
         this.client = null;
@@ -562,11 +570,7 @@ class PredictionMarketApp {
             // Build transaction
             const tx = ccc.Transaction.from({
                 outputs: [
-                    ccc.CellOutput.from({
-                        capacity: ccc.fixedPointFrom(200), // 200 CKB for market cell
-                        lock: lockScript,
-                        type: marketTypeScript
-                    })
+                    minimalCellOutput(lockScript, marketTypeScript, marketDataHex)
                 ],
                 outputsData: [marketDataHex]
             });
@@ -791,25 +795,17 @@ class PredictionMarketApp {
                         type: marketCell.cellOutput.type
                     }),
                     // YES token cell
-                    ccc.CellOutput.from({
-                        capacity: ccc.fixedPointFrom(150),
-                        lock: userLock,
-                        type: ccc.Script.from({
-                            codeHash: CONFIG.tokenCodeHash,
-                            hashType: 'data1',
-                            args: yesTokenArgs
-                        })
-                    }),
+                    minimalCellOutput(userLock, ccc.Script.from({
+                        codeHash: CONFIG.tokenCodeHash,
+                        hashType: 'data1',
+                        args: yesTokenArgs
+                    }), tokenDataHex),
                     // NO token cell
-                    ccc.CellOutput.from({
-                        capacity: ccc.fixedPointFrom(150),
-                        lock: userLock,
-                        type: ccc.Script.from({
-                            codeHash: CONFIG.tokenCodeHash,
-                            hashType: 'data1',
-                            args: noTokenArgs
-                        })
-                    })
+                    minimalCellOutput(userLock, ccc.Script.from({
+                        codeHash: CONFIG.tokenCodeHash,
+                        hashType: 'data1',
+                        args: noTokenArgs
+                    }), tokenDataHex)
                 ],
                 outputsData: [newMarketDataHex, tokenDataHex, tokenDataHex]
             });
@@ -1128,11 +1124,7 @@ class PredictionMarketApp {
                 remainingView.setBigUint64(8, 0n, true);
                 const remainingTokenDataHex = '0x' + Array.from(remainingTokenData).map(b => b.toString(16).padStart(2, '0')).join('');
 
-                outputs.push(ccc.CellOutput.from({
-                    capacity: ccc.fixedPointFrom(150), // 150 CKB for token cell
-                    lock: userLock,
-                    type: tokenTypeScript
-                }));
+                outputs.push(minimalCellOutput(userLock, tokenTypeScript, remainingTokenDataHex));
                 outputsData.push(remainingTokenDataHex);
             }
 
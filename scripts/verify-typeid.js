@@ -3,20 +3,24 @@ import { ccc } from "@ckb-ccc/ccc";
 // Test data from the logs
 const outpointHex = "0xbe740427bb71ef87e76fdb436bf8d3dff0f8e97d33b5399114f3e6b8269d584301000000";
 const outputIndex = 0;
+const since = 0n;
 
-// Build the 44-byte input
+// The market script uses the canonical Type ID: blake2b(first CellInput || output_index),
+// where the 44-byte CellInput is since (u64 LE) followed by the 36-byte outpoint
 const outpointBytes = ccc.bytesFrom(outpointHex);
-const typeIdInput = new Uint8Array(44);
-typeIdInput.set(outpointBytes, 0);
-const view = new DataView(typeIdInput.buffer, 36, 8);
-view.setBigUint64(0, BigInt(outputIndex), true);
+const typeIdInput = new Uint8Array(52);
+new DataView(typeIdInput.buffer, 0, 8).setBigUint64(0, since, true);
+typeIdInput.set(outpointBytes, 8);
+new DataView(typeIdInput.buffer, 44, 8).setBigUint64(0, BigInt(outputIndex), true);
 
-console.log("Type ID input (44 bytes):", ccc.hexFrom(typeIdInput));
+console.log("Type ID preimage (52 bytes):", ccc.hexFrom(typeIdInput));
 
-// Calculate Type ID using CCC
 const typeId = ccc.hashCkb(ccc.hexFrom(typeIdInput));
-console.log("Type ID (CCC):", typeId);
+console.log("Type ID (manual):", typeId);
 
-// Try alternative: use CKB's Type ID generation helper if it exists
-// Type ID should be: blake2b(first_input_outpoint || output_index)
-console.log("\nExpected from logs: 0x6bc85ae5b3ab20563637e0efff3a66f5b9b3ffaf959eca91aacb01d5daeb899e");
+// CCC's helper must agree
+const firstInput = ccc.CellInput.from({
+  previousOutput: ccc.OutPoint.fromBytes(outpointBytes),
+  since,
+});
+console.log("Type ID (ccc.hashTypeId):", ccc.hashTypeId(firstInput, outputIndex));